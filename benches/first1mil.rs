@@ -13,7 +13,7 @@ fn criterion_benchmark(c: &mut Criterion) {
                 let matchers = &mut data.1;
                 let mut counter = 0;
                 loop {
-                    if counter == 100_000 {
+                    if counter == 1_000_000 {
                         break;
                     }
                     cpu.step(matchers);
@@ -22,6 +22,16 @@ fn criterion_benchmark(c: &mut Criterion) {
             },
         )
     });
+    c.bench_function("1mil blocks", move |b| {
+        b.iter_with_large_setup(
+            || (Processor::new(build_memory()), build_matchers()),
+            |mut data| {
+                let mut cpu = data.0;
+                let matchers = &mut data.1;
+                cpu.run(matchers, 1_000_000);
+            },
+        )
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
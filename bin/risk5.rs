@@ -1,5 +1,10 @@
+#[macro_use]
+extern crate log;
 use risk5;
 
 fn main() {
-    risk5::risk5_main()
+    if let Err(e) = risk5::risk5_main() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 }
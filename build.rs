@@ -0,0 +1,210 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/*
+ *
+ * Decoder generator
+ * -----------------
+ * Generates the instruction decoder from `assets/opcodes`. Every
+ * instruction in the riscv-opcodes description becomes an entry in
+ * `OPCODES`, and `decode` switches on opcode and funct fields to find
+ * it. Two instructions that can match the same word fail the build.
+ *
+ */
+
+const OPCODES: &str = "assets/opcodes";
+
+// fields the decode tree may switch on, tried in order
+const FIELDS: [(u32, u32); 6] = [(0, 7), (12, 3), (25, 7), (27, 5), (20, 5), (20, 12)];
+
+struct Opcode {
+    name: String,
+    args: Vec<String>,
+    mask: u32,
+    mtch: u32,
+}
+
+fn parse_value(s: &str) -> u32 {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    }
+    .unwrap_or_else(|_| panic!("bad value {:?} in {}", s, OPCODES))
+}
+
+fn parse_line(line: &str) -> Opcode {
+    let mut parts = line.split_whitespace();
+    let name = parts.next().expect("name").to_string();
+    let mut op = Opcode {
+        name,
+        args: vec![],
+        mask: 0,
+        mtch: 0,
+    };
+
+    for part in parts {
+        let (bits, value) = match part.find('=') {
+            Some(i) => (&part[..i], parse_value(&part[i + 1..])),
+            None => {
+                op.args.push(part.to_string());
+                continue;
+            }
+        };
+        let (hi, lo) = match bits.find("..") {
+            Some(i) => (parse_value(&bits[..i]), parse_value(&bits[i + 2..])),
+            None => {
+                let bit = parse_value(bits);
+                (bit, bit)
+            }
+        };
+        assert!(hi >= lo && hi < 32, "bad range {} for {}", bits, op.name);
+        let width = hi - lo + 1;
+        let field = if width == 32 {
+            !0
+        } else {
+            ((1 << width) - 1) << lo
+        };
+        assert!(
+            value >> width == 0,
+            "value {} does not fit {} for {}",
+            value,
+            bits,
+            op.name
+        );
+        assert!(op.mask & field == 0, "{} sets bits {} twice", op.name, bits);
+        op.mask |= field;
+        op.mtch |= value << lo;
+    }
+    op
+}
+
+fn check_overlaps(ops: &[Opcode]) {
+    for (i, a) in ops.iter().enumerate() {
+        for b in &ops[i + 1..] {
+            assert!(a.name != b.name, "{} is defined twice", a.name);
+            let common = a.mask & b.mask;
+            if (a.mtch ^ b.mtch) & common == 0 {
+                panic!(
+                    "{} (0x{:08x}/0x{:08x}) overlaps {} (0x{:08x}/0x{:08x})",
+                    a.name, a.mtch, a.mask, b.name, b.mtch, b.mask
+                );
+            }
+        }
+    }
+}
+
+fn field(shift: u32, width: u32) -> (u32, u32) {
+    let mask = ((1u64 << width) - 1) as u32;
+    (mask << shift, mask)
+}
+
+fn emit(out: &mut String, ops: &[Opcode], entries: &[usize], depth: usize) {
+    let indent = "    ".repeat(depth + 1);
+
+    let split = FIELDS.iter().cloned().find(|&(shift, width)| {
+        let (bits, _) = field(shift, width);
+        let mut values: Vec<_> = entries.iter().map(|&i| ops[i].mtch & bits).collect();
+        values.dedup();
+        entries.iter().all(|&i| ops[i].mask & bits == bits) && values.len() > 1
+    });
+
+    match split {
+        Some((shift, width)) => {
+            let (bits, mask) = field(shift, width);
+            if shift == 0 {
+                writeln!(out, "match insn & 0x{:x} {{", mask).unwrap();
+            } else {
+                writeln!(out, "match (insn >> {}) & 0x{:x} {{", shift, mask).unwrap();
+            }
+            let mut values: Vec<_> = entries.iter().map(|&i| ops[i].mtch & bits).collect();
+            values.sort();
+            values.dedup();
+            for value in values {
+                let arm: Vec<_> = entries
+                    .iter()
+                    .cloned()
+                    .filter(|&i| ops[i].mtch & bits == value)
+                    .collect();
+                write!(out, "{}    0x{:x} => ", indent, value >> shift).unwrap();
+                emit(out, ops, &arm, depth + 1);
+                writeln!(out, ",").unwrap();
+            }
+            writeln!(out, "{}    _ => None,", indent).unwrap();
+            write!(out, "{}}}", indent).unwrap();
+        }
+        None => {
+            // few enough left to compare one by one
+            writeln!(out, "{{").unwrap();
+            for &i in entries {
+                let op = &ops[i];
+                let test = if op.mask == !0 {
+                    "insn".to_string()
+                } else {
+                    format!("insn & 0x{:08x}", op.mask)
+                };
+                writeln!(
+                    out,
+                    "{}    if {} == 0x{:08x} {{\n{}        return Some({});\n{}    }}",
+                    indent, test, op.mtch, indent, i, indent
+                )
+                .unwrap();
+            }
+            writeln!(out, "{}    None", indent).unwrap();
+            write!(out, "{}}}", indent).unwrap();
+        }
+    }
+}
+
+fn generate(ops: &[Opcode]) -> String {
+    let mut out = String::new();
+    writeln!(out, "// generated by build.rs from {}", OPCODES).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "/// Instructions described by `{}`", OPCODES).unwrap();
+    writeln!(out, "pub static OPCODES: [Opcode; {}] = [", ops.len()).unwrap();
+    for op in ops {
+        let args: Vec<_> = op.args.iter().map(|a| format!("{:?}", a)).collect();
+        writeln!(
+            out,
+            "    Opcode {{ name: {:?}, mask: 0x{:08x}, mtch: 0x{:08x}, args: &[{}] }},",
+            op.name,
+            op.mask,
+            op.mtch,
+            args.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "/// Index into `OPCODES` of the instruction `insn` encodes"
+    )
+    .unwrap();
+    writeln!(out, "pub fn decode(insn: u32) -> Option<usize> {{").unwrap();
+    write!(out, "    ").unwrap();
+    let all: Vec<_> = (0..ops.len()).collect();
+    emit(&mut out, ops, &all, 0);
+    writeln!(out).unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", OPCODES);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let text = fs::read_to_string(OPCODES).expect("read opcodes");
+    let ops: Vec<_> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(parse_line)
+        .collect();
+    check_overlaps(&ops);
+
+    let out = Path::new(&env::var("OUT_DIR").expect("OUT_DIR")).join("opcodes.rs");
+    fs::write(out, generate(&ops)).expect("write opcodes.rs");
+}
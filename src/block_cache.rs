@@ -0,0 +1,212 @@
+use crate::{Matcher, Processor};
+use std::collections::HashMap;
+
+/*
+ *
+ * Block cache
+ * -----------
+ * Predecoded basic blocks keyed by the physical address of their
+ * first instruction. Each entry holds the instruction word and the
+ * handler its matcher resolved to, so executing a cached block skips
+ * both the fetch translation and the matcher search. The typed
+ * operand views (`Rtype`, `Itype`, ...) are shifts over that word.
+ *
+ * A block ends at an unconditional jump, at a SYSTEM instruction
+ * other than a CSR access, at a satp access, at fence.i, at the end
+ * of its page or after `MAX_BLOCK_INSNS`. A satp write may leave the
+ * next pc unmapped, as when Linux relocates itself, so the fetch
+ * after it has to be translated again. A taken branch or a trap
+ * simply leaves the block early.
+ *
+ * Blocks live in an arena and are referred to by index. Stores into
+ * a page holding blocks drop that page's blocks and fence.i drops
+ * everything. Being physical, blocks survive satp writes and
+ * sfence.vma. A dropped block has no instructions left, so a hart
+ * that is part way through it stops at the next instruction.
+ *
+ */
+
+pub(crate) const MAX_BLOCK_INSNS: usize = 64;
+// flush everything rather than grow past this
+const MAX_BLOCKS: usize = 1 << 16;
+pub(crate) const PAGE_SHIFT: u64 = 12;
+
+pub(crate) struct Decoded<M> {
+    pub insn: u32,
+    pub exec: fn(&mut Processor<M>, u32),
+}
+
+// derive would require M: Copy
+impl<M> Clone for Decoded<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M> Copy for Decoded<M> {}
+
+impl<M> Decoded<M> {
+    pub fn new(insn: u32, matcher: &Matcher<M>) -> Self {
+        Decoded {
+            insn,
+            exec: matcher.handler(),
+        }
+    }
+}
+
+/// True if decoding should stop after `insn`
+pub(crate) fn ends_block(insn: u32) -> bool {
+    match insn & 0x7f {
+        // jal, jalr
+        0x6f | 0x67 => true,
+        // ecall, ebreak, xret, wfi, sfence.vma and satp accesses
+        0x73 => (insn >> 12) & 0x7 == 0 || insn >> 20 == 0x180,
+        // fence.i
+        0x0f => (insn >> 12) & 0x7 == 1,
+        _ => false,
+    }
+}
+
+pub(crate) struct BlockCache<M> {
+    blocks: Vec<Vec<Decoded<M>>>,
+    // physical pc to block
+    index: HashMap<u64, usize>,
+    // physical page to the blocks starting in it
+    pages: HashMap<u64, Vec<(u64, usize)>>,
+    // bumped whenever a block comes or goes
+    generation: u64,
+    // bumped when block indices start over
+    epoch: u64,
+    hit: u64,
+    miss: u64,
+}
+
+impl<M> BlockCache<M> {
+    pub fn new() -> Self {
+        BlockCache {
+            blocks: vec![],
+            index: HashMap::new(),
+            pages: HashMap::new(),
+            generation: 0,
+            epoch: 0,
+            hit: 0,
+            miss: 0,
+        }
+    }
+
+    pub fn find(&mut self, addr: u64) -> Option<usize> {
+        let block = self.index.get(&addr).cloned();
+        if block.is_some() {
+            self.hit += 1;
+        } else {
+            self.miss += 1;
+        }
+        block
+    }
+
+    pub fn insert(&mut self, addr: u64, insns: Vec<Decoded<M>>) -> usize {
+        if self.blocks.len() >= MAX_BLOCKS {
+            self.flush();
+        }
+        let block = self.blocks.len();
+        self.generation += 1;
+        self.blocks.push(insns);
+        self.index.insert(addr, block);
+        self.pages
+            .entry(addr >> PAGE_SHIFT)
+            .or_default()
+            .push((addr, block));
+        block
+    }
+
+    /// Instruction `i` of `block`, none once it was dropped
+    #[inline(always)]
+    pub fn insn(&self, block: usize, i: usize) -> Option<Decoded<M>> {
+        self.blocks.get(block).and_then(|b| b.get(i)).cloned()
+    }
+
+    pub fn is_code_page(&self, page: u64) -> bool {
+        self.pages.contains_key(&page)
+    }
+
+    /// Drop the blocks starting in physical page `page`
+    pub fn invalidate_page(&mut self, page: u64) {
+        if let Some(blocks) = self.pages.remove(&page) {
+            trace!("Dropping {} blocks in page 0x{:x}", blocks.len(), page);
+            self.generation += 1;
+            for (addr, block) in blocks {
+                self.blocks[block].clear();
+                self.index.remove(&addr);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        trace!(
+            "Flushing {} blocks ({} hits, {} misses)",
+            self.blocks.len(),
+            self.hit,
+            self.miss
+        );
+        self.blocks.clear();
+        self.index.clear();
+        self.pages.clear();
+        self.generation += 1;
+        self.epoch += 1;
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    #[test]
+    fn block_ends() {
+        // jal, jalr, ecall, sret, csrw satp, fence.i
+        for insn in &[
+            0x0000_006f,
+            0x0000_8067,
+            0x0000_0073,
+            0x1020_0073,
+            0x1805_1073,
+            0x0000_100f,
+        ] {
+            assert!(ends_block(*insn), "0x{:x}", insn);
+        }
+        // beq, addi, csrr, fence
+        for insn in &[0x0000_0063, 0x0000_0013, 0xf140_2573, 0x0ff0_000f] {
+            assert!(!ends_block(*insn), "0x{:x}", insn);
+        }
+    }
+
+    #[test]
+    fn invalidation() {
+        let matcher = Matcher::new(0, 0, |_: &mut Processor<FakeMemory>, _| {});
+        let mut cache = BlockCache::new();
+        let a = cache.insert(0x8000_0000, vec![Decoded::new(0x13, &matcher); 2]);
+        let b = cache.insert(0x8000_1000, vec![Decoded::new(0x13, &matcher)]);
+
+        assert_eq!(cache.find(0x8000_0000), Some(a));
+        assert!(cache.is_code_page(0x80000));
+
+        cache.invalidate_page(0x80000);
+        assert_eq!(cache.find(0x8000_0000), None);
+        assert!(cache.insn(a, 0).is_none());
+        assert!(cache.insn(b, 0).is_some());
+
+        cache.flush();
+        assert!(cache.insn(b, 0).is_none());
+        assert!(!cache.is_code_page(0x80001));
+    }
+}
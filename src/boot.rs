@@ -0,0 +1,253 @@
+use crate::fdt::Fdt;
+use crate::Memory;
+use std::io;
+
+/*
+ *
+ * Linux Boot
+ * ----------
+ * Loads a raw RISC-V Linux `Image` the way firmware would:
+ *
+ * - kernel at ram_base + text_offset (from the Image header)
+ * - initrd after the kernel, half way up RAM (capped at 128MB)
+ * - patched DTB at the top of RAM
+ *
+ * The kernel is entered in S-mode with a0=hartid and a1=dtb.
+ *
+ */
+
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC: &[u8] = b"RISCV\0\0\0";
+const IMAGE_MAGIC2: &[u8] = b"RSC\x05";
+
+const PAGE_SIZE: u64 = 0x1000;
+const INITRD_MAX_OFFSET: u64 = 128 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct ImageHeader {
+    pub text_offset: u64,
+    pub image_size: u64,
+    pub flags: u64,
+    pub version: u32,
+}
+
+#[derive(Debug)]
+pub struct BootInfo {
+    pub entry: u64,
+    pub dtb: u64,
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    let mut n = [0; 4];
+    n.copy_from_slice(&b[..4]);
+    u32::from_le_bytes(n)
+}
+
+fn le_u64(b: &[u8]) -> u64 {
+    let mut n = [0; 8];
+    n.copy_from_slice(&b[..8]);
+    u64::from_le_bytes(n)
+}
+
+fn align_up(n: u64, align: u64) -> Option<u64> {
+    n.checked_add(align - 1).map(|n| n & !(align - 1))
+}
+
+fn align_down(n: u64, align: u64) -> u64 {
+    n & !(align - 1)
+}
+
+fn boot_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl ImageHeader {
+    pub fn parse(image: &[u8]) -> io::Result<ImageHeader> {
+        if image.len() < IMAGE_HEADER_SIZE {
+            return Err(boot_error("Image smaller than its header".into()));
+        }
+        if &image[48..56] != IMAGE_MAGIC && &image[56..60] != IMAGE_MAGIC2 {
+            return Err(boot_error("not a RISC-V Linux Image (bad magic)".into()));
+        }
+
+        let header = ImageHeader {
+            text_offset: le_u64(&image[8..]),
+            image_size: le_u64(&image[16..]),
+            flags: le_u64(&image[24..]),
+            version: le_u32(&image[32..]),
+        };
+        debug!("Image header {:?}", header);
+
+        if header.flags & 0x1 != 0 {
+            return Err(boot_error("big endian Image is not supported".into()));
+        }
+        Ok(header)
+    }
+}
+
+fn write_bytes<M: Memory>(mem: &mut M, addr: u64, bytes: &[u8]) {
+    for (i, b) in bytes.iter().enumerate() {
+        mem.write_b(addr + i as u64, *b);
+    }
+}
+
+/// Patch `/chosen` with the kernel command line and initrd location.
+pub fn patch_chosen(fdt: &mut Fdt, cmdline: Option<&str>, initrd: Option<(u64, u64)>) {
+    let chosen = fdt.root.child_mut("chosen");
+    if let Some(cmdline) = cmdline {
+        chosen.set_string("bootargs", cmdline);
+    }
+    if let Some((start, end)) = initrd {
+        chosen.set_u64("linux,initrd-start", start);
+        chosen.set_u64("linux,initrd-end", end);
+    }
+}
+
+/// Load a Linux `Image`, optional initrd and patched DTB into RAM.
+pub fn load_linux<M: Memory>(
+    mem: &mut M,
+    ram: (u64, u64),
+    image: &[u8],
+    initrd: Option<&[u8]>,
+    cmdline: Option<&str>,
+    dtb: &[u8],
+) -> io::Result<BootInfo> {
+    let (ram_base, ram_size) = ram;
+    let ram_end = ram_base
+        .checked_add(ram_size)
+        .ok_or_else(|| boot_error("RAM runs past the end of memory".into()))?;
+    // end of `len` bytes at `start`, if they fit in RAM. The Image
+    // header is not to be trusted, so nothing may wrap.
+    let fits = |start: u64, len: u64| start.checked_add(len).filter(|&end| end <= ram_end);
+
+    let header = ImageHeader::parse(image)?;
+    let kernel_size = header.image_size.max(image.len() as u64);
+    let (entry, kernel_end) = ram_base
+        .checked_add(header.text_offset)
+        .and_then(|entry| Some((entry, fits(entry, kernel_size)?)))
+        .ok_or_else(|| {
+            boot_error(format!(
+                "Image of 0x{:x} bytes at offset 0x{:x} does not fit in RAM",
+                kernel_size, header.text_offset
+            ))
+        })?;
+    info!("Loading Image at 0x{:x} (0x{:x} bytes)", entry, image.len());
+    write_bytes(mem, entry, image);
+
+    let initrd = match initrd {
+        None => None,
+        Some(initrd) => {
+            let offset = (ram_size / 2).min(INITRD_MAX_OFFSET);
+            let (start, end) = entry
+                .checked_add(offset)
+                .and_then(|start| align_up(start.max(kernel_end), PAGE_SIZE))
+                .and_then(|start| Some((start, fits(start, initrd.len() as u64)?)))
+                .ok_or_else(|| {
+                    boot_error(format!(
+                        "initrd of 0x{:x} bytes does not fit in RAM",
+                        initrd.len()
+                    ))
+                })?;
+            info!("Loading initrd at 0x{:x}-0x{:x}", start, end);
+            write_bytes(mem, start, initrd);
+            Some((start, end))
+        }
+    };
+
+    let mut fdt = Fdt::from_bytes(dtb)?;
+    patch_chosen(&mut fdt, cmdline, initrd);
+    let dtb = fdt.to_bytes();
+
+    let lowest_free = initrd.map(|(_, end)| end).unwrap_or(kernel_end);
+    let dtb_addr = ram_end
+        .checked_sub(dtb.len() as u64)
+        .map(|addr| align_down(addr, PAGE_SIZE))
+        .filter(|&addr| addr >= lowest_free)
+        .ok_or_else(|| boot_error("no room in RAM for the DTB".into()))?;
+    info!("Loading DTB at 0x{:x} (0x{:x} bytes)", dtb_addr, dtb.len());
+    write_bytes(mem, dtb_addr, &dtb);
+
+    Ok(BootInfo {
+        entry,
+        dtb: dtb_addr,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::memory::ByteMap;
+
+    fn image(text_offset: u64, size: usize) -> Vec<u8> {
+        let mut image = vec![0; size];
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&(size as u64).to_le_bytes());
+        image[48..56].copy_from_slice(IMAGE_MAGIC);
+        image[56..60].copy_from_slice(IMAGE_MAGIC2);
+        image
+    }
+
+    #[test]
+    fn image_header() {
+        let header = ImageHeader::parse(&image(0x20_0000, 128)).expect("header");
+        assert_eq!(header.text_offset, 0x20_0000);
+        assert_eq!(header.image_size, 128);
+
+        assert!(ImageHeader::parse(&[0; 128]).is_err());
+        assert!(ImageHeader::parse(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn loads_linux() {
+        const RAM: (u64, u64) = (0x8000_0000, 0x100_0000);
+        let mut kernel = image(0x20_0000, 0x1000);
+        kernel[0x100] = 0x5a;
+        let initrd = vec![0xa5; 0x2000];
+        let dtb = Machine::default().dtb();
+
+        let mut mem = ByteMap::default();
+        let info = load_linux(
+            &mut mem,
+            RAM,
+            &kernel,
+            Some(&initrd),
+            Some("console=hvc0 root=/dev/ram"),
+            &dtb,
+        )
+        .expect("load");
+        assert_eq!(info.entry, 0x8020_0000);
+        assert_eq!(mem.read_b(0x8020_0100), 0x5a);
+        // half way up RAM from the kernel
+        let initrd_start = 0x80a0_0000;
+        assert_eq!(mem.read_b(initrd_start), 0xa5);
+        assert_eq!(mem.read_b(initrd_start + 0x1fff), 0xa5);
+        assert_eq!(mem.read_b(initrd_start + 0x2000), 0);
+
+        assert_eq!(info.dtb % PAGE_SIZE, 0);
+        assert!(info.dtb > initrd_start + 0x2000);
+        let blob: Vec<u8> = (info.dtb..RAM.0 + RAM.1).map(|a| mem.read_b(a)).collect();
+        let fdt = Fdt::from_bytes(&blob).expect("patched dtb");
+        let chosen = fdt.root.child("chosen").expect("chosen");
+        assert_eq!(
+            chosen.property("bootargs"),
+            Some(&b"console=hvc0 root=/dev/ram\0"[..])
+        );
+        let cell = |n: u64| n.to_be_bytes().to_vec();
+        assert_eq!(
+            chosen.property("linux,initrd-start"),
+            Some(&cell(initrd_start)[..])
+        );
+        assert_eq!(
+            chosen.property("linux,initrd-end"),
+            Some(&cell(initrd_start + 0x2000)[..])
+        );
+
+        // header values that would wrap are refused, not added up
+        let mut bad = image(u64::MAX - 0x10, 0x1000);
+        assert!(load_linux(&mut mem, RAM, &bad, None, None, &dtb).is_err());
+        bad[8..16].copy_from_slice(&0u64.to_le_bytes());
+        bad[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(load_linux(&mut mem, RAM, &bad, None, None, &dtb).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::config::Config;
+use crate::console::ConsoleOutput;
+use crate::logrunner::tracer::{Format, TraceFilter};
+use crate::machine::{parse_count, parse_isa, parse_number, parse_prv, parse_range};
+use std::io;
+
+#[derive(Debug, Default, PartialEq)]
+pub enum Command {
+    #[default]
+    Run,
+    /// Print the generated device tree as DTS and exit
+    Dts,
+    /// Disassemble an ELF like `objdump -d` and exit
+    Disasm(String),
+    /// Run the ELF alongside spike, comparing after every instruction
+    Lockstep,
+    /// Print usage and exit
+    Help,
+}
+
+/// Command line options for the `risk5` binary. Anything given
+/// here overrides the config file.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub command: Command,
+    pub config: Option<String>,
+    pub ram: Option<u64>,
+    pub harts: Option<usize>,
+    pub quantum: Option<u64>,
+    pub threaded: bool,
+    pub time_ratio: Option<u64>,
+    pub realtime: bool,
+    pub isa: Option<u64>,
+    pub elf: Option<String>,
+    pub sbi: bool,
+    /// Raw Linux `Image` to boot in S-mode with the built-in SBI
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub append: Option<String>,
+    pub console: Option<ConsoleOutput>,
+    /// Spike binary for `lockstep`
+    pub spike: Option<String>,
+    /// Step one instruction at a time and write statistics as JSON here
+    pub stats: Option<String>,
+    /// Stop a `--stats` run after this many instructions
+    pub stats_count: Option<u64>,
+    /// Step one instruction at a time and write a trace here
+    pub trace: Option<String>,
+    pub trace_format: Format,
+    pub trace_filter: TraceFilter,
+    /// Write spike's commit log, to stderr unless `--trace` names a file
+    pub log_commits: bool,
+    /// Journal the run's nondeterministic inputs here
+    pub record: Option<String>,
+    /// Feed the run the inputs journaled here instead
+    pub replay: Option<String>,
+}
+
+pub(crate) const USAGE: &str = "usage: risk5 [dts] [options]
+       risk5 disasm FILE
+       risk5 lockstep [--spike SPIKE] [options]
+  --config FILE     JSON machine config
+  --ram SIZE        size of the first RAM region, e.g. 512M
+  --harts N         number of harts
+  --quantum N       instructions per hart before switching
+  --threaded        run each hart on its own host thread
+  --time-ratio N    instructions per timebase tick
+  --realtime        follow the host clock instead of counting instructions
+  --isa ISA         ISA string, e.g. rv64ima
+  --elf FILE        ELF to enter through the reset vector
+  --sbi             enter the ELF in S-mode with the built-in SBI
+  --kernel Image    raw Linux Image to boot with the built-in SBI
+  --initrd FILE     initrd for --kernel
+  --append CMDLINE  kernel command line for --kernel
+  --console OUT     stderr, stdout, null or file:PATH
+  --spike SPIKE     spike binary for lockstep
+  --stats FILE      write instruction statistics as JSON to FILE
+  --stats-count N   stop a --stats run after N instructions
+  --trace FILE      write a trace of the run to FILE
+  --trace-format F  json, bincode, container or commits, bincode by default
+  --trace-from N    trace from instruction N on
+  --trace-count N   stop a --trace run after tracing N instructions
+  --trace-pc LO:HI  only trace instructions with pc in LO..=HI
+  --trace-prv P     only trace instructions run in u, s or m mode
+  --log-commits     write a spike commit log, to stderr unless --trace is given
+  --record FILE     journal console, timer and time inputs to FILE
+  --replay FILE     rerun with the inputs journaled in FILE";
+
+fn usage_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", msg, USAGE))
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> io::Result<Options> {
+        let mut opts = Options::default();
+        let mut args = args.peekable();
+        match args.peek().map(String::as_str) {
+            Some("dts") => {
+                args.next();
+                opts.command = Command::Dts;
+            }
+            Some("disasm") => {
+                args.next();
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("disasm needs an ELF file"))?;
+                opts.command = Command::Disasm(path);
+            }
+            Some("lockstep") => {
+                args.next();
+                opts.command = Command::Lockstep;
+            }
+            _ => (),
+        }
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| usage_error(&format!("missing value for {}", arg)))
+            };
+            let bad_value = |e: String| usage_error(&format!("{}: {}", arg, e));
+            match arg.as_str() {
+                "--config" => opts.config = Some(value()?),
+                "--ram" => opts.ram = Some(parse_number(&value()?).map_err(bad_value)?),
+                "--harts" => {
+                    let harts = parse_number(&value()?).map_err(bad_value)?;
+                    opts.harts = Some(harts as usize);
+                }
+                "--quantum" => opts.quantum = Some(parse_number(&value()?).map_err(bad_value)?),
+                "--threaded" => opts.threaded = true,
+                "--time-ratio" => {
+                    opts.time_ratio = Some(parse_number(&value()?).map_err(bad_value)?)
+                }
+                "--realtime" => opts.realtime = true,
+                "--isa" => opts.isa = Some(parse_isa(&value()?).map_err(bad_value)?),
+                "--elf" => opts.elf = Some(value()?),
+                "--sbi" => opts.sbi = true,
+                "--kernel" => opts.kernel = Some(value()?),
+                "--initrd" => opts.initrd = Some(value()?),
+                "--append" => opts.append = Some(value()?),
+                "--console" => {
+                    opts.console = Some(ConsoleOutput::parse(&value()?).map_err(bad_value)?)
+                }
+                "--spike" => opts.spike = Some(value()?),
+                "--stats" => opts.stats = Some(value()?),
+                "--stats-count" => {
+                    opts.stats_count = Some(parse_count(&value()?).map_err(bad_value)?)
+                }
+                "--trace" => opts.trace = Some(value()?),
+                "--log-commits" => opts.log_commits = true,
+                "--trace-format" => {
+                    opts.trace_format = Format::parse(&value()?).map_err(bad_value)?
+                }
+                "--trace-from" => {
+                    opts.trace_filter.from = parse_count(&value()?).map_err(bad_value)?
+                }
+                "--trace-count" => {
+                    opts.trace_filter.count = Some(parse_count(&value()?).map_err(bad_value)?)
+                }
+                "--trace-pc" => {
+                    opts.trace_filter.pc = Some(parse_range(&value()?).map_err(bad_value)?)
+                }
+                "--trace-prv" => {
+                    opts.trace_filter.prv = Some(parse_prv(&value()?).map_err(bad_value)?)
+                }
+                "--record" => opts.record = Some(value()?),
+                "--replay" => opts.replay = Some(value()?),
+                "-h" | "--help" => {
+                    opts.command = Command::Help;
+                    return Ok(opts);
+                }
+                _ => return Err(usage_error(&format!("unknown argument {}", arg))),
+            }
+        }
+
+        if opts.record.is_some() && opts.replay.is_some() {
+            return Err(usage_error("--record and --replay can't be combined"));
+        }
+        Ok(opts)
+    }
+
+    /// Load the config file, if any, and apply the overrides
+    pub fn config(&self) -> io::Result<Config> {
+        let mut config = match self.config {
+            Some(ref path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        let machine = &mut config.machine;
+        if let Some(ram) = self.ram {
+            match machine.ram.first_mut() {
+                Some(r) => r.size = ram,
+                None => return Err(usage_error("--ram given but the machine has no RAM")),
+            }
+        }
+        if let Some(harts) = self.harts {
+            machine.harts = harts;
+        }
+        if let Some(quantum) = self.quantum {
+            machine.quantum = quantum;
+        }
+        machine.threaded |= self.threaded;
+        if let Some(ratio) = self.time_ratio {
+            machine.time_ratio = ratio;
+        }
+        machine.realtime |= self.realtime;
+        if let Some(misa) = self.isa {
+            machine.misa = misa;
+        }
+
+        let boot = &mut config.boot;
+        if let Some(ref elf) = self.elf {
+            boot.elf = elf.clone();
+        }
+        boot.sbi |= self.sbi;
+        if self.kernel.is_some() {
+            boot.kernel = self.kernel.clone();
+        }
+        if self.initrd.is_some() {
+            boot.initrd = self.initrd.clone();
+        }
+        if self.append.is_some() {
+            boot.append = self.append.clone();
+        }
+
+        if let Some(ref console) = self.console {
+            config.console.output = console.clone();
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> io::Result<Options> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse(&["dts"]).expect("options").command, Command::Dts);
+        assert_eq!(
+            parse(&["disasm", "vmlinux"]).expect("options").command,
+            Command::Disasm("vmlinux".to_string())
+        );
+        assert!(parse(&["disasm"]).is_err());
+        assert_eq!(
+            parse(&["--ram", "1G", "--help", "--bogus"])
+                .expect("options")
+                .command,
+            Command::Help
+        );
+
+        let opts = parse(&["lockstep", "--spike", "/opt/spike"]).expect("options");
+        assert_eq!(opts.command, Command::Lockstep);
+        assert_eq!(opts.spike.as_deref(), Some("/opt/spike"));
+    }
+
+    #[test]
+    fn machine() {
+        let opts = parse(&["--ram", "256M", "--isa", "rv64i", "--time-ratio", "100"]);
+        let config = opts.expect("options").config().expect("config");
+        assert_eq!(config.machine.main_ram().size, 256 << 20);
+        assert_eq!(config.machine.time_ratio, 100);
+        assert_eq!(config.machine.isa(), "rv64i");
+
+        assert!(parse(&["--ram", "lots"]).is_err());
+    }
+
+    #[test]
+    fn boot() {
+        // an initrd needs a kernel to go with it
+        assert!(parse(&["--initrd", "rootfs"])
+            .expect("options")
+            .config()
+            .is_err());
+    }
+
+    #[test]
+    fn console() {
+        let config = parse(&["--console", "null"])
+            .expect("options")
+            .config()
+            .expect("config");
+        assert_eq!(config.console.output, ConsoleOutput::Null);
+    }
+
+    #[test]
+    fn stats() {
+        let opts = parse(&["--stats", "-", "--stats-count", "1M"]).expect("options");
+        assert_eq!(opts.stats.as_deref(), Some("-"));
+        assert_eq!(opts.stats_count, Some(1_000_000));
+    }
+
+    #[test]
+    fn trace() {
+        let opts = parse(&[
+            "--trace-prv",
+            "s",
+            "--trace",
+            "run.json",
+            "--trace-format",
+            "json",
+            "--trace-pc",
+            "0x80000000:0x80000fff",
+        ])
+        .expect("options");
+        assert_eq!(opts.trace.as_deref(), Some("run.json"));
+        assert_eq!(opts.trace_format, Format::Json);
+        assert_eq!(opts.trace_filter.pc, Some((0x8000_0000, 0x8000_0fff)));
+        assert_eq!(opts.trace_filter.prv, Some(1));
+        assert!(parse(&["--trace-format", "text"]).is_err());
+        assert!(parse(&["--log-commits"]).expect("options").log_commits);
+    }
+
+    #[test]
+    fn record_replay() {
+        let opts = parse(&["--replay", "bug.jsonl"]).expect("options");
+        assert_eq!(opts.replay.as_deref(), Some("bug.jsonl"));
+        assert!(parse(&["--record", "a", "--replay", "b"]).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::machine::TIMEBASE_FREQUENCY;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ *
+ * Clock
+ * -----
+ * Timebase of a hart, read through the `time` CSR and compared
+ * against its timers. Time advances a tick for every `ratio`
+ * retired instructions. A hart waiting in wfi with nothing pending
+ * skips straight to its next timer, so idle guests cost nothing
+ * and runs stay reproducible.
+ *
+ * In real time mode the timebase follows the host clock at the
+ * device tree's timebase-frequency instead, and waiting harts sleep
+ * on the host. Guest time then matches wall time for interactive
+ * use, at the cost of reproducibility.
+ *
+ */
+
+// longest a waiting hart sleeps in real time mode before looking
+// for work again
+const MAX_SLEEP: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    /// retired instructions per tick
+    ratio: u64,
+    /// ticks skipped while waiting in wfi
+    skipped: u64,
+    /// host time of tick zero in real time mode
+    start: Option<Instant>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new(1)
+    }
+}
+
+impl Clock {
+    pub fn new(ratio: u64) -> Clock {
+        Clock {
+            ratio: ratio.max(1),
+            skipped: 0,
+            start: None,
+        }
+    }
+
+    pub fn realtime() -> Clock {
+        Clock {
+            start: Some(Instant::now()),
+            ..Clock::default()
+        }
+    }
+
+    /// Current tick of a hart that retired `insns` instructions
+    pub fn now(&self, insns: u64) -> u64 {
+        match self.start {
+            Some(start) => {
+                let ticks = start.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000;
+                ticks as u64
+            }
+            None => insns / self.ratio + self.skipped,
+        }
+    }
+
+    /// Wait for tick `until`. Virtual time skips to it, real time
+    /// sleeps on the host for a while. There is nothing to skip to
+    /// when no timer is set.
+    pub fn wait(&mut self, insns: u64, until: u64) {
+        let now = self.now(insns);
+        if until <= now {
+            return;
+        }
+        match self.start {
+            Some(_) => {
+                let nanos = (until - now) as u128 * 1_000_000_000 / TIMEBASE_FREQUENCY as u128;
+                let sleep = Duration::from_nanos(nanos.min(u64::MAX as u128) as u64);
+                thread::sleep(sleep.min(MAX_SLEEP));
+            }
+            None if until == u64::MAX => (),
+            None => self.skipped += until - now,
+        }
+    }
+
+    /// Catch up with the other harts at tick `time`
+    pub fn sync(&mut self, insns: u64, time: u64) {
+        if self.start.is_none() {
+            self.skipped += time.saturating_sub(self.now(insns));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skips_to_timer() {
+        let mut clock = Clock::new(10);
+        assert_eq!(clock.now(25), 2);
+        clock.wait(25, 100);
+        assert_eq!(clock.now(25), 100);
+        assert_eq!(clock.now(35), 101);
+
+        // no timer set
+        clock.wait(35, u64::MAX);
+        assert_eq!(clock.now(35), 101);
+        clock.sync(35, 50);
+        assert_eq!(clock.now(35), 101);
+        clock.sync(35, 200);
+        assert_eq!(clock.now(35), 200);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::console::ConsoleConfig;
+use crate::machine::Machine;
+use std::fs;
+use std::io;
+
+/*
+ *
+ * Config
+ * ------
+ * Machine description, boot images and console backend for the
+ * risk5 binary. Read from a JSON file, then overridden by command
+ * line flags. Every section and field is optional.
+ *
+ * {
+ *   "machine": {
+ *     "ram": [{ "base": "0x8000_0000", "size": "512M" }],
+ *     "harts": 1,
+ *     "quantum": 1000,
+ *     "threaded": false,
+ *     "time_ratio": 1,
+ *     "realtime": false,
+ *     "isa": "rv64ima",
+ *     "reset_vec": "0x1000",
+ *     "devices": [
+ *       { "type": "clint", "base": "0x200_0000", "size": "0xc000" },
+ *       { "type": "uart", "base": "0x1000_0000", "size": "0x100", "irq": 10 }
+ *     ]
+ *   },
+ *   "boot": { "kernel": "Image", "append": "console=hvc0" },
+ *   "console": { "output": { "file": "console.log" } }
+ * }
+ *
+ */
+
+const DEFAULT_ELF: &str = "assets/bbl";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub machine: Machine,
+    pub boot: BootConfig,
+    pub console: ConsoleConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootConfig {
+    /// ELF entered through the reset vector when there is no kernel
+    pub elf: String,
+    /// Enter the ELF in S-mode with the built-in SBI
+    pub sbi: bool,
+    /// Raw Linux `Image` to boot in S-mode with the built-in SBI
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub append: Option<String>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            elf: DEFAULT_ELF.into(),
+            sbi: false,
+            kernel: None,
+            initrd: None,
+            append: None,
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Config {
+    pub fn load(path: &str) -> io::Result<Config> {
+        let json = fs::read_to_string(path)?;
+        Config::from_json(&json).map_err(|e| invalid(format!("{}: {}", path, e)))
+    }
+
+    pub fn from_json(json: &str) -> io::Result<Config> {
+        serde_json::from_str(json).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        self.machine.validate()?;
+
+        let boot = &self.boot;
+        if boot.kernel.is_none() && (boot.initrd.is_some() || boot.append.is_some()) {
+            return Err(invalid("initrd and append require a kernel".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::ConsoleOutput;
+    use crate::machine::{Device, Region};
+
+    #[test]
+    fn from_json() {
+        let config = Config::from_json(
+            r#"{
+                "machine": {
+                    "ram": [{ "base": "0x8000_0000", "size": "512M" }],
+                    "isa": "rv64ia",
+                    "devices": [
+                        { "type": "clint", "base": "0x200_0000", "size": 49152 },
+                        { "type": "uart", "base": "0x1000_0000", "size": "0x100", "irq": 10 }
+                    ]
+                },
+                "boot": { "kernel": "Image" },
+                "console": { "output": { "file": "console.log" } }
+            }"#,
+        )
+        .expect("config");
+        config.validate().expect("valid");
+
+        let machine = &config.machine;
+        assert_eq!(
+            machine.ram,
+            vec![Region {
+                base: 0x8000_0000,
+                size: 512 << 20
+            }]
+        );
+        assert_eq!(machine.isa(), "rv64ia");
+        assert_eq!(machine.harts, 1);
+        assert_eq!(
+            machine.devices[1],
+            Device::Uart {
+                base: 0x1000_0000,
+                size: 0x100,
+                irq: 10
+            }
+        );
+        assert_eq!(config.boot.kernel.as_deref(), Some("Image"));
+        assert_eq!(config.boot.elf, DEFAULT_ELF);
+        assert_eq!(
+            config.console.output,
+            ConsoleOutput::File("console.log".into())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        assert!(Config::from_json(r#"{ "machine": { "rom": [] } }"#).is_err());
+        assert!(Config::from_json(r#"{ "machine": { "isa": "rv64imafd" } }"#).is_err());
+
+        let overlap = Config::from_json(
+            r#"{ "machine": { "devices": [{ "type": "clint", "base": "0x8000_1000", "size": 16 }] } }"#,
+        )
+        .expect("config");
+        assert!(overlap.validate().is_err());
+
+        let initrd = Config::from_json(r#"{ "boot": { "initrd": "rootfs" } }"#).expect("config");
+        assert!(initrd.validate().is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+
+/*
+ *
+ * Console
+ * -------
+ * Backend for the SBI and legacy ecall console. Output goes to
+ * the configured sink, input is replayed from a fixed script as
+ * stdin is used to toggle the trigger.
+ *
+ */
+
+// typed into the console when the kernel first asks for input
+const DEFAULT_INPUT: &str = "uname -a; cat /proc/cpuinfo; cat /proc/meminfo";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleOutput {
+    Stderr,
+    Stdout,
+    Null,
+    File(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsoleConfig {
+    pub output: ConsoleOutput,
+    /// Typed into the console, followed by a carriage return
+    pub input: String,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        ConsoleConfig {
+            output: ConsoleOutput::Stderr,
+            input: DEFAULT_INPUT.into(),
+        }
+    }
+}
+
+impl ConsoleOutput {
+    /// Parse `stderr`, `stdout`, `null` or `file:PATH`
+    pub fn parse(s: &str) -> Result<ConsoleOutput, String> {
+        match s {
+            "stderr" => Ok(ConsoleOutput::Stderr),
+            "stdout" => Ok(ConsoleOutput::Stdout),
+            "null" => Ok(ConsoleOutput::Null),
+            _ if s.starts_with("file:") => Ok(ConsoleOutput::File(s[5..].into())),
+            _ => Err(format!("unknown console backend '{}'", s)),
+        }
+    }
+}
+
+pub struct Console {
+    out: Box<dyn Write + Send>,
+    input: Vec<u8>,
+    pos: usize,
+}
+
+impl fmt::Debug for Console {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Console {{ pos: {} }}", self.pos)
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Console {
+            out: Box::new(io::stderr()),
+            input: DEFAULT_INPUT.into(),
+            pos: 0,
+        }
+    }
+}
+
+impl Console {
+    pub fn new(config: &ConsoleConfig) -> io::Result<Console> {
+        let out: Box<dyn Write + Send> = match config.output {
+            ConsoleOutput::Stderr => Box::new(io::stderr()),
+            ConsoleOutput::Stdout => Box::new(io::stdout()),
+            ConsoleOutput::Null => Box::new(io::sink()),
+            ConsoleOutput::File(ref path) => Box::new(File::create(path)?),
+        };
+        Ok(Console {
+            out,
+            input: config.input.as_bytes().to_vec(),
+            pos: 0,
+        })
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.out
+            .write_all(bytes)
+            .and_then(|_| self.out.flush())
+            .expect("console write");
+    }
+
+    /// Next input character, a carriage return once the input is
+    /// exhausted and -1 after that.
+    pub fn getchar(&mut self) -> u64 {
+        let pos = self.pos;
+        self.pos += 1;
+
+        if pos == self.input.len() {
+            return 13;
+        }
+        if pos > self.input.len() {
+            return -1i64 as u64;
+        }
+        u64::from(self.input[pos])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripted_input() {
+        let mut console = Console::new(&ConsoleConfig {
+            output: ConsoleOutput::Null,
+            input: "ab".into(),
+        })
+        .expect("console");
+        let chars: Vec<u64> = (0..4).map(|_| console.getchar()).collect();
+        assert_eq!(chars, vec![97, 98, 13, -1i64 as u64]);
+    }
+}
@@ -0,0 +1,469 @@
+use crate::itypes::*;
+use crate::opcodes::{decode, Opcode, OPCODES};
+use crate::regs;
+use elf::types::{Symbol, SHF_EXECINSTR, SHT_NOBITS, STT_FILE, STT_SECTION};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// One instruction as objdump would print it
+#[derive(Debug, PartialEq)]
+pub struct Disassembly {
+    pub mnemonic: String,
+    pub operands: String,
+    /// branch or jump destination, if it is known statically
+    pub target: Option<u64>,
+}
+
+impl Disassembly {
+    fn new<S: Into<String>>(mnemonic: S, operands: String) -> Self {
+        Disassembly {
+            mnemonic: mnemonic.into(),
+            operands,
+            target: None,
+        }
+    }
+
+    fn to(mut self, target: u64) -> Self {
+        self.target = Some(target);
+        self
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // objdump leaves the tab out when there are no operands
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{}\t{}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+fn reg(r: Regi) -> &'static str {
+    regs::name(r)
+}
+
+/// Name of `csr`, or its number if it has none
+pub fn csr_name(csr: u32) -> String {
+    let name = match csr {
+        0x000 => "ustatus",
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0x004 => "uie",
+        0x005 => "utvec",
+        0x040 => "uscratch",
+        0x041 => "uepc",
+        0x042 => "ucause",
+        0x043 => "utval",
+        0x044 => "uip",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0xc03..=0xc1f => return format!("hpmcounter{}", csr - 0xc00),
+        0xc80 => "cycleh",
+        0xc81 => "timeh",
+        0xc82 => "instreth",
+        0xc83..=0xc9f => return format!("hpmcounter{}h", csr - 0xc80),
+        0x100 => "sstatus",
+        0x102 => "sedeleg",
+        0x103 => "sideleg",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0xf11 => "mvendorid",
+        0xf12 => "marchid",
+        0xf13 => "mimpid",
+        0xf14 => "mhartid",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x320 => "mcountinhibit",
+        0x323..=0x33f => return format!("mhpmevent{}", csr - 0x320),
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0x3a0..=0x3a3 => return format!("pmpcfg{}", csr - 0x3a0),
+        0x3b0..=0x3bf => return format!("pmpaddr{}", csr - 0x3b0),
+        0xb00 => "mcycle",
+        0xb02 => "minstret",
+        0xb03..=0xb1f => return format!("mhpmcounter{}", csr - 0xb00),
+        0xb80 => "mcycleh",
+        0xb82 => "minstreth",
+        0xb83..=0xb9f => return format!("mhpmcounter{}h", csr - 0xb80),
+        0x7a0 => "tselect",
+        0x7a1 => "tdata1",
+        0x7a2 => "tdata2",
+        0x7a3 => "tdata3",
+        0x7b0 => "dcsr",
+        0x7b1 => "dpc",
+        0x7b2 => "dscratch",
+        _ => return format!("0x{:x}", csr),
+    };
+    name.to_string()
+}
+
+fn fence_set(bits: u32) -> String {
+    "iorw"
+        .chars()
+        .enumerate()
+        .filter(|&(i, _)| bits & (8 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+fn branch(op: &Opcode, pc: u64, insn: u32) -> Disassembly {
+    let b: Btype = insn.into();
+    let (rs1, rs2) = (b.rs1(), b.rs2());
+    let target = pc.wrapping_add(b.imm() as u64);
+    let (name, ops) = match (op.name, rs1, rs2) {
+        ("beq", _, 0) => ("beqz", format!("{},{:x}", reg(rs1), target)),
+        ("bne", _, 0) => ("bnez", format!("{},{:x}", reg(rs1), target)),
+        ("blt", _, 0) => ("bltz", format!("{},{:x}", reg(rs1), target)),
+        ("blt", 0, _) => ("bgtz", format!("{},{:x}", reg(rs2), target)),
+        ("bge", _, 0) => ("bgez", format!("{},{:x}", reg(rs1), target)),
+        ("bge", 0, _) => ("blez", format!("{},{:x}", reg(rs2), target)),
+        ("bge", _, _) => ("ble", format!("{},{},{:x}", reg(rs2), reg(rs1), target)),
+        ("bgeu", _, _) => ("bleu", format!("{},{},{:x}", reg(rs2), reg(rs1), target)),
+        (name, _, _) => (name, format!("{},{},{:x}", reg(rs1), reg(rs2), target)),
+    };
+    Disassembly::new(name, ops).to(target)
+}
+
+fn jal(pc: u64, insn: u32) -> Disassembly {
+    let j: Jtype = insn.into();
+    let target = pc.wrapping_add(j.imm() as u64);
+    match j.rd() {
+        0 => Disassembly::new("j", format!("{:x}", target)),
+        rd => Disassembly::new("jal", format!("{},{:x}", reg(rd), target)),
+    }
+    .to(target)
+}
+
+fn jalr(insn: u32) -> Disassembly {
+    let i: Itype = insn.into();
+    let (rd, rs1, imm) = (i.rd(), i.rs1(), i.imm());
+    match (rd, rs1, imm) {
+        (0, 1, 0) => Disassembly::new("ret", String::new()),
+        (0, _, 0) => Disassembly::new("jr", reg(rs1).to_string()),
+        (0, _, _) => Disassembly::new("jr", format!("{}({})", imm, reg(rs1))),
+        (1, _, 0) => Disassembly::new("jalr", reg(rs1).to_string()),
+        (1, _, _) => Disassembly::new("jalr", format!("{}({})", imm, reg(rs1))),
+        _ => Disassembly::new("jalr", format!("{},{}({})", reg(rd), imm, reg(rs1))),
+    }
+}
+
+fn csr(op: &Opcode, insn: u32) -> Disassembly {
+    let i: Itype = insn.into();
+    let (rd, rs1, csr) = (i.rd(), i.rs1(), i.immu() as u32);
+    let name = csr_name(csr);
+    // the immediate forms put a 5 bit value where rs1 would be
+    let src = if op.name.ends_with('i') {
+        rs1.to_string()
+    } else {
+        reg(rs1).to_string()
+    };
+    match (op.name, rd, rs1) {
+        ("csrrs", _, 0) => match csr {
+            0xc00 => Disassembly::new("rdcycle", reg(rd).to_string()),
+            0xc01 => Disassembly::new("rdtime", reg(rd).to_string()),
+            0xc02 => Disassembly::new("rdinstret", reg(rd).to_string()),
+            _ => Disassembly::new("csrr", format!("{},{}", reg(rd), name)),
+        },
+        (_, 0, _) => {
+            // csrrw -> csrw, csrrsi -> csrsi, ...
+            let short = format!("csr{}", &op.name[4..]);
+            Disassembly::new(short, format!("{},{}", name, src))
+        }
+        _ => Disassembly::new(op.name, format!("{},{},{}", reg(rd), name, src)),
+    }
+}
+
+fn immediate(op: &Opcode, insn: u32) -> Disassembly {
+    let i: Itype = insn.into();
+    let (rd, rs1, imm) = (i.rd(), i.rs1(), i.imm());
+    match (op.name, rd, rs1, imm) {
+        ("addi", 0, 0, 0) => Disassembly::new("nop", String::new()),
+        ("addi", _, 0, _) => Disassembly::new("li", format!("{},{}", reg(rd), imm)),
+        ("addi", _, _, 0) => Disassembly::new("mv", format!("{},{}", reg(rd), reg(rs1))),
+        ("addiw", _, _, 0) => Disassembly::new("sext.w", format!("{},{}", reg(rd), reg(rs1))),
+        ("xori", _, _, -1) => Disassembly::new("not", format!("{},{}", reg(rd), reg(rs1))),
+        ("sltiu", _, _, 1) => Disassembly::new("seqz", format!("{},{}", reg(rd), reg(rs1))),
+        _ => Disassembly::new(op.name, format!("{},{},{}", reg(rd), reg(rs1), imm)),
+    }
+}
+
+fn register(op: &Opcode, insn: u32) -> Disassembly {
+    let r: Rtype = insn.into();
+    let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+    match (op.name, rs1, rs2) {
+        ("sub", 0, _) => Disassembly::new("neg", format!("{},{}", reg(rd), reg(rs2))),
+        ("subw", 0, _) => Disassembly::new("negw", format!("{},{}", reg(rd), reg(rs2))),
+        ("sltu", 0, _) => Disassembly::new("snez", format!("{},{}", reg(rd), reg(rs2))),
+        ("slt", _, 0) => Disassembly::new("sltz", format!("{},{}", reg(rd), reg(rs1))),
+        ("slt", 0, _) => Disassembly::new("sgtz", format!("{},{}", reg(rd), reg(rs2))),
+        _ => Disassembly::new(op.name, format!("{},{},{}", reg(rd), reg(rs1), reg(rs2))),
+    }
+}
+
+fn atomic(op: &Opcode, insn: u32) -> Disassembly {
+    let r: Rtype = insn.into();
+    let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+    let order = match (insn >> 25) & 0x3 {
+        0 => "",
+        1 => ".rl",
+        2 => ".aq",
+        _ => ".aqrl",
+    };
+    let name = format!("{}{}", op.name, order);
+    if op.name.starts_with("lr.") {
+        Disassembly::new(name, format!("{},({})", reg(rd), reg(rs1)))
+    } else {
+        Disassembly::new(name, format!("{},{},({})", reg(rd), reg(rs2), reg(rs1)))
+    }
+}
+
+/// Disassemble `insn` fetched from `pc` into objdump syntax
+pub fn disasm(pc: u64, insn: u32) -> Disassembly {
+    let op = match decode(insn) {
+        Some(i) => &OPCODES[i],
+        None => return Disassembly::new(".4byte", format!("0x{:x}", insn)),
+    };
+
+    let rd = (insn >> 7) & 0x1f;
+    let rs1 = (insn >> 15) & 0x1f;
+    let rs2 = (insn >> 20) & 0x1f;
+
+    match op.args.join(" ").as_str() {
+        "bimm12hi rs1 rs2 bimm12lo" => branch(op, pc, insn),
+        "rd jimm20" => jal(pc, insn),
+        "rd imm20" => Disassembly::new(op.name, format!("{},0x{:x}", reg(rd), insn >> 12)),
+        "rd rs1 imm12" => match insn & 0x7f {
+            0x03 => {
+                let i: Itype = insn.into();
+                Disassembly::new(op.name, format!("{},{}({})", reg(rd), i.imm(), reg(rs1)))
+            }
+            0x67 => jalr(insn),
+            0x73 => csr(op, insn),
+            _ => immediate(op, insn),
+        },
+        "imm12hi rs1 rs2 imm12lo" => {
+            let s: Stype = insn.into();
+            Disassembly::new(op.name, format!("{},{}({})", reg(rs2), s.imm(), reg(rs1)))
+        }
+        "rd rs1 shamt" | "rd rs1 shamtw" => {
+            let shamt = if op.args[2] == "shamt" { 0x3f } else { 0x1f } & (insn >> 20);
+            Disassembly::new(op.name, format!("{},{},0x{:x}", reg(rd), reg(rs1), shamt))
+        }
+        "rd rs1 rs2" => register(op, insn),
+        "rd rs1 rs2 aqrl" | "rd rs1 aqrl" => atomic(op, insn),
+        "fm pred succ rs1 rd" => {
+            let (pred, succ) = ((insn >> 24) & 0xf, (insn >> 20) & 0xf);
+            if pred == 0xf && succ == 0xf {
+                Disassembly::new(op.name, String::new())
+            } else {
+                Disassembly::new(op.name, format!("{},{}", fence_set(pred), fence_set(succ)))
+            }
+        }
+        "rs1 rs2" => match (rs1, rs2) {
+            (0, 0) => Disassembly::new(op.name, String::new()),
+            (_, 0) => Disassembly::new(op.name, reg(rs1).to_string()),
+            _ => Disassembly::new(op.name, format!("{},{}", reg(rs1), reg(rs2))),
+        },
+        _ => {
+            // anything else just lists its registers
+            let regs: Vec<_> = op
+                .args
+                .iter()
+                .filter_map(|arg| match *arg {
+                    "rd" => Some(reg(rd)),
+                    "rs1" => Some(reg(rs1)),
+                    "rs2" => Some(reg(rs2)),
+                    "rs3" => Some(reg(insn >> 27)),
+                    _ => None,
+                })
+                .collect();
+            Disassembly::new(op.name, regs.join(","))
+        }
+    }
+}
+
+/// Symbols to label addresses with, as objdump does
+pub(crate) struct Symbols(BTreeMap<u64, (String, u64)>);
+
+impl Symbols {
+    fn new(symbols: Vec<Symbol>) -> Self {
+        let mut map = BTreeMap::new();
+        for sym in symbols {
+            if sym.name.is_empty() || sym.symtype == STT_SECTION || sym.symtype == STT_FILE {
+                continue;
+            }
+            map.entry(sym.value).or_insert((sym.name, sym.size));
+        }
+        Symbols(map)
+    }
+
+    /// The symbol table of the ELF at `path`, empty if it is stripped
+    pub(crate) fn load(path: &str) -> io::Result<Symbols> {
+        let file = elf::File::open_path(path).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", path, e))
+        })?;
+        Symbols::of(&file)
+    }
+
+    fn of(file: &elf::File) -> io::Result<Symbols> {
+        let symbols = match file.get_section(".symtab") {
+            Some(symtab) => file
+                .get_symbols(symtab)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?,
+            None => vec![],
+        };
+        Ok(Symbols::new(symbols))
+    }
+
+    fn at(&self, addr: u64) -> Option<&str> {
+        self.0.get(&addr).map(|(name, _)| name.as_str())
+    }
+
+    fn describe(&self, addr: u64) -> Option<String> {
+        let (base, (name, _)) = self.0.range(..=addr).next_back()?;
+        Some(match addr - base {
+            0 => format!("<{}>", name),
+            off => format!("<{}+0x{:x}>", name, off),
+        })
+    }
+
+    /// The sized symbol `addr` falls in, if any
+    pub(crate) fn containing(&self, addr: u64) -> Option<&str> {
+        self.0
+            .range(..=addr)
+            .rev()
+            .find(|&(_, &(_, size))| size > 0)
+            .filter(|&(base, &(_, size))| addr - base < size)
+            .map(|(_, (name, _))| name.as_str())
+    }
+}
+
+/// Disassemble the executable sections of the ELF at `path`
+/// in the same layout as `objdump -d`
+pub fn disasm_elf<W: Write>(path: &str, out: &mut W) -> io::Result<()> {
+    let file = elf::File::open_path(path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {:?}", path, e)))?;
+    let symbols = Symbols::of(&file)?;
+
+    writeln!(out, "\n{}:     file format elf64-littleriscv\n", path)?;
+
+    for section in &file.sections {
+        let shdr = &section.shdr;
+        if shdr.flags.0 & SHF_EXECINSTR.0 == 0 || shdr.shtype == SHT_NOBITS {
+            continue;
+        }
+        writeln!(out, "\nDisassembly of section {}:", shdr.name)?;
+
+        let data = &section.data;
+        let mut offset = 0;
+        while offset < data.len() {
+            let pc = shdr.addr + offset as u64;
+            if let Some(name) = symbols.at(pc) {
+                writeln!(out, "\n{:016x} <{}>:", pc, name)?;
+            }
+
+            if data[offset] & 0x3 != 0x3 || offset + 4 > data.len() {
+                // compressed instructions aren't supported
+                let half = data[offset] as u16 | (*data.get(offset + 1).unwrap_or(&0) as u16) << 8;
+                writeln!(
+                    out,
+                    "{:8x}:\t{:04x}              \t.2byte\t0x{:x}",
+                    pc, half, half
+                )?;
+                offset += 2;
+                continue;
+            }
+
+            let insn = u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ]);
+            let d = disasm(pc, insn);
+            write!(out, "{:8x}:\t{:08x}          \t{}", pc, insn, d)?;
+            if let Some(target) = d.target.and_then(|t| symbols.describe(t)) {
+                write!(out, " {}", target)?;
+            }
+            writeln!(out)?;
+            offset += 4;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // lines of assets/busybox.objdump.bz2, less the `<symbol>` and
+    // `# address` annotations objdump adds
+    const EXPECTED: &[(u64, u32, &str)] = &[
+        (0x101b0, 0xeb010113, "addi\tsp,sp,-336"),
+        (0x101b4, 0x13213823, "sd\ts2,304(sp)"),
+        (0x101b8, 0x00231937, "lui\ts2,0x231"),
+        (0x101c0, 0x0087b783, "ld\ta5,8(a5)"),
+        (0x101dc, 0x02878863, "beq\ta5,s0,1020c"),
+        (0x101e0, 0x00100713, "li\ta4,1"),
+        (0x101e4, 0x1004a7af, "lr.w\ta5,(s1)"),
+        (0x101e8, 0x00079663, "bnez\ta5,101f4"),
+        (0x101ec, 0x1ce4a6af, "sc.w.aq\ta3,a4,(s1)"),
+        (0x101f4, 0x0007879b, "sext.w\ta5,a5"),
+        (0x10200, 0x00154097, "auipc\tra,0x154"),
+        (0x10204, 0xa28080e7, "jalr\t-1496(ra)"),
+        (0x10240, 0xff5ff06f, "j\t10234"),
+        (0x10400, 0xdb1ff0ef, "jal\tra,101b0"),
+        (0x10410, 0x0b430067, "jr\t180(t1)"),
+        (0x10844, 0x00341413, "slli\ts0,s0,0x3"),
+        (0x11754, 0x0047979b, "slliw\ta5,a5,0x4"),
+        (0x11c20, 0x4085551b, "sraiw\ta0,a0,0x8"),
+        (0x102a8, 0x0f50000f, "fence\tiorw,ow"),
+        (0x102d0, 0x00000073, "ecall"),
+        (0x103d4, 0x00100073, "ebreak"),
+        (0x10450, 0x00008067, "ret"),
+        (0x112cec, 0x0ff0000f, "fence"),
+        (0x10358, 0xfff00693, "li\ta3,-1"),
+        (0x74874, 0xffe5c593, "xori\ta1,a1,-2"),
+        (0x16b3a8, 0x08a7b02f, "amoswap.d\tzero,a0,(a5)"),
+        (0x12f3c8, 0xe487b42f, "amomaxu.d.aq\ts0,s0,(a5)"),
+    ];
+
+    #[test]
+    fn objdump_syntax() {
+        for &(pc, insn, expected) in EXPECTED {
+            assert_eq!(disasm(pc, insn).to_string(), expected, "0x{:08x}", insn);
+        }
+    }
+
+    #[test]
+    fn csrs() {
+        assert_eq!(disasm(0, 0xf1402573).to_string(), "csrr\ta0,mhartid");
+        assert_eq!(disasm(0, 0x30529073).to_string(), "csrw\tmtvec,t0");
+        assert_eq!(disasm(0, 0x34011173).to_string(), "csrrw\tsp,mscratch,sp");
+        assert_eq!(disasm(0, 0x30046073).to_string(), "csrsi\tmstatus,8");
+        assert_eq!(disasm(0, 0xc0002f73).to_string(), "rdcycle\tt5");
+        assert_eq!(disasm(0, 0x7c002573).to_string(), "csrr\ta0,0x7c0");
+        assert_eq!(disasm(0, 0x12000073).to_string(), "sfence.vma");
+        assert_eq!(disasm(0, 0x00000000).to_string(), ".4byte\t0x0");
+    }
+}
@@ -1,13 +1,22 @@
+use std::io;
 use std::path::PathBuf;
 
 use elf;
 
-pub fn read_program_segments(filename: &str) -> (u64, Vec<(u64, u64, u64)>) {
+/// file offset, address and size of a loadable segment
+pub type Segment = (u64, u64, u64);
+
+pub fn read_program_segments(filename: &str) -> io::Result<(u64, Vec<Segment>)> {
     let mut r = vec![];
     let path = PathBuf::from(filename);
     let file = match elf::File::open_path(&path) {
         Ok(f) => f,
-        Err(e) => panic!("Error: {:?}", e),
+        Err(e) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {:?}", filename, e),
+            ))
+        }
     };
 
     debug!("ep 0x{:x}", file.ehdr.entry);
@@ -20,5 +29,5 @@ pub fn read_program_segments(filename: &str) -> (u64, Vec<(u64, u64, u64)>) {
             r.push((phdr.offset, phdr.vaddr, phdr.filesz));
         }
     }
-    return (file.ehdr.entry, r);
+    Ok((file.ehdr.entry, r))
 }
@@ -0,0 +1,442 @@
+use std::io;
+
+/*
+ *
+ * Flattened Device Tree
+ * ---------------------
+ * Reads a DTB blob into a tree of nodes and properties so it can
+ * be patched, and writes it back out as a version 17 DTB.
+ *
+ */
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fdt {
+    pub boot_cpuid: u32,
+    pub reserved: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("fdt: {}", msg))
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.into(),
+            properties: vec![],
+            children: vec![],
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.value.as_slice())
+    }
+
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        if let Some(p) = self.properties.iter_mut().find(|p| p.name == name) {
+            p.value = value;
+            return;
+        }
+        self.properties.push(Property {
+            name: name.into(),
+            value,
+        });
+    }
+
+    pub fn set_string(&mut self, name: &str, value: &str) {
+        let mut v = value.as_bytes().to_vec();
+        v.push(0);
+        self.set_property(name, v);
+    }
+
+    pub fn set_u32(&mut self, name: &str, value: u32) {
+        self.set_property(name, value.to_be_bytes().to_vec());
+    }
+
+    pub fn set_u64(&mut self, name: &str, value: u64) {
+        self.set_property(name, value.to_be_bytes().to_vec());
+    }
+
+    pub fn remove_property(&mut self, name: &str) {
+        self.properties.retain(|p| p.name != name);
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    /// Returns the named child, creating it if it does not exist.
+    pub fn child_mut(&mut self, name: &str) -> &mut Node {
+        match self.children.iter().position(|c| c.name == name) {
+            Some(i) => &mut self.children[i],
+            None => {
+                self.children.push(Node::new(name));
+                self.children.last_mut().expect("pushed child")
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| invalid("unexpected end of struct block"))?;
+        self.pos += 4;
+        Ok(be_u32(bytes))
+    }
+
+    fn cstr(&mut self) -> io::Result<String> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| invalid("unterminated node name"))?;
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos = align4(self.pos + len + 1);
+        Ok(s)
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let v = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("property runs past struct block"))?
+            .to_vec();
+        self.pos = align4(self.pos + len);
+        Ok(v)
+    }
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    (u32::from(b[0]) << 24) | (u32::from(b[1]) << 16) | (u32::from(b[2]) << 8) | u32::from(b[3])
+}
+
+fn be_u64(b: &[u8]) -> u64 {
+    (u64::from(be_u32(&b[..4])) << 32) | u64::from(be_u32(&b[4..8]))
+}
+
+impl Default for Fdt {
+    fn default() -> Self {
+        Fdt {
+            boot_cpuid: 0,
+            reserved: vec![],
+            root: Node::new(""),
+        }
+    }
+}
+
+impl Fdt {
+    pub fn from_bytes(blob: &[u8]) -> io::Result<Fdt> {
+        if blob.len() < HEADER_SIZE {
+            return Err(invalid("blob smaller than header"));
+        }
+        let header = |i: usize| be_u32(&blob[i * 4..]);
+        if header(0) != FDT_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let total_size = header(1) as usize;
+        let off_struct = header(2) as usize;
+        let off_strings = header(3) as usize;
+        let off_rsvmap = header(4) as usize;
+        let boot_cpuid = header(7);
+        let size_strings = header(8) as usize;
+        let size_struct = header(9) as usize;
+
+        if total_size > blob.len()
+            || off_struct + size_struct > total_size
+            || off_strings + size_strings > total_size
+        {
+            return Err(invalid("blocks outside of blob"));
+        }
+
+        let mut reserved = vec![];
+        let mut pos = off_rsvmap;
+        loop {
+            let entry = blob
+                .get(pos..pos + 16)
+                .ok_or_else(|| invalid("unterminated reserve map"))?;
+            let (addr, size) = (be_u64(&entry[..8]), be_u64(&entry[8..]));
+            if addr == 0 && size == 0 {
+                break;
+            }
+            reserved.push((addr, size));
+            pos += 16;
+        }
+
+        let strings = &blob[off_strings..off_strings + size_strings];
+        let mut r = Reader {
+            data: &blob[off_struct..off_struct + size_struct],
+            pos: 0,
+        };
+
+        let mut stack: Vec<Node> = vec![];
+        let mut root = None;
+        loop {
+            match r.u32()? {
+                FDT_BEGIN_NODE => stack.push(Node::new(&r.cstr()?)),
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or_else(|| invalid("unbalanced end node"))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = r.u32()? as usize;
+                    let name_off = r.u32()? as usize;
+                    let value = r.bytes(len)?;
+                    let name = strings
+                        .get(name_off..)
+                        .and_then(|s| s.iter().position(|b| *b == 0).map(|n| &s[..n]))
+                        .ok_or_else(|| invalid("bad property name offset"))?;
+                    let node = stack
+                        .last_mut()
+                        .ok_or_else(|| invalid("property outside of node"))?;
+                    node.properties.push(Property {
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        value,
+                    });
+                }
+                FDT_NOP => (),
+                FDT_END => break,
+                token => return Err(invalid(&format!("unknown token 0x{:x}", token))),
+            }
+        }
+
+        let root = root.ok_or_else(|| invalid("no root node"))?;
+        Ok(Fdt {
+            boot_cpuid,
+            reserved,
+            root,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dt_struct = vec![];
+        let mut strings: Vec<u8> = vec![];
+
+        fn push_u32(v: &mut Vec<u8>, n: u32) {
+            v.extend_from_slice(&n.to_be_bytes());
+        }
+
+        fn pad(v: &mut Vec<u8>) {
+            while !v.len().is_multiple_of(4) {
+                v.push(0);
+            }
+        }
+
+        fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+            let mut i = 0;
+            while i < strings.len() {
+                let end = i + strings[i..].iter().position(|b| *b == 0).expect("nul");
+                if &strings[i..end] == name.as_bytes() {
+                    return i as u32;
+                }
+                i = end + 1;
+            }
+            let off = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            off
+        }
+
+        fn write_node(node: &Node, dt_struct: &mut Vec<u8>, strings: &mut Vec<u8>) {
+            push_u32(dt_struct, FDT_BEGIN_NODE);
+            dt_struct.extend_from_slice(node.name.as_bytes());
+            dt_struct.push(0);
+            pad(dt_struct);
+            for p in &node.properties {
+                push_u32(dt_struct, FDT_PROP);
+                push_u32(dt_struct, p.value.len() as u32);
+                let off = string_offset(strings, &p.name);
+                push_u32(dt_struct, off);
+                dt_struct.extend_from_slice(&p.value);
+                pad(dt_struct);
+            }
+            for c in &node.children {
+                write_node(c, dt_struct, strings);
+            }
+            push_u32(dt_struct, FDT_END_NODE);
+        }
+
+        write_node(&self.root, &mut dt_struct, &mut strings);
+        push_u32(&mut dt_struct, FDT_END);
+
+        let off_rsvmap = HEADER_SIZE;
+        let rsvmap_size = (self.reserved.len() + 1) * 16;
+        let off_struct = off_rsvmap + rsvmap_size;
+        let off_strings = off_struct + dt_struct.len();
+        let total_size = off_strings + strings.len();
+
+        let mut out = Vec::with_capacity(total_size);
+        for n in &[
+            FDT_MAGIC,
+            total_size as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            dt_struct.len() as u32,
+        ] {
+            push_u32(&mut out, *n);
+        }
+        for (addr, size) in self.reserved.iter().chain(&[(0, 0)]) {
+            out.extend_from_slice(&addr.to_be_bytes());
+            out.extend_from_slice(&size.to_be_bytes());
+        }
+        out.extend_from_slice(&dt_struct);
+        out.extend_from_slice(&strings);
+        out
+    }
+
+    /// Render as device tree source, guessing how to print each value
+    /// since the blob does not record property types.
+    pub fn to_dts(&self) -> String {
+        let mut out = String::from("/dts-v1/;\n\n");
+        for (addr, size) in &self.reserved {
+            out.push_str(&format!("/memreserve/ 0x{:x} 0x{:x};\n", addr, size));
+        }
+        write_dts_node(&mut out, &self.root, 0);
+        out
+    }
+}
+
+fn dts_value(value: &[u8]) -> String {
+    let printable = |s: &[u8]| !s.is_empty() && s.iter().all(|b| (0x20..0x7f).contains(b));
+    if value.last() == Some(&0) && value[..value.len() - 1].split(|b| *b == 0).all(printable) {
+        let strings: Vec<String> = value[..value.len() - 1]
+            .split(|b| *b == 0)
+            .map(|s| format!("\"{}\"", String::from_utf8_lossy(s)))
+            .collect();
+        return strings.join(", ");
+    }
+    if value.len().is_multiple_of(4) {
+        let cells: Vec<String> = value
+            .chunks(4)
+            .map(|c| format!("0x{:x}", be_u32(c)))
+            .collect();
+        return format!("<{}>", cells.join(" "));
+    }
+    let bytes: Vec<String> = value.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("[{}]", bytes.join(" "))
+}
+
+fn write_dts_node(out: &mut String, node: &Node, depth: usize) {
+    let indent = "\t".repeat(depth);
+    let name = if node.name.is_empty() {
+        "/"
+    } else {
+        &node.name
+    };
+    out.push_str(&format!("{}{} {{\n", indent, name));
+    for p in &node.properties {
+        if p.value.is_empty() {
+            out.push_str(&format!("{}\t{};\n", indent, p.name));
+        } else {
+            out.push_str(&format!(
+                "{}\t{} = {};\n",
+                indent,
+                p.name,
+                dts_value(&p.value)
+            ));
+        }
+    }
+    for c in &node.children {
+        out.push('\n');
+        write_dts_node(out, c, depth + 1);
+    }
+    out.push_str(&format!("{}}};\n", indent));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let dtb = crate::load_dtb();
+        let fdt = Fdt::from_bytes(&dtb).expect("parse dtb");
+        assert!(fdt.root.child("cpus").is_some());
+
+        let reparsed = Fdt::from_bytes(&fdt.to_bytes()).expect("parse written dtb");
+        assert_eq!(fdt, reparsed);
+    }
+
+    #[test]
+    fn patch_chosen() {
+        let mut fdt = Fdt::default();
+        fdt.root
+            .child_mut("chosen")
+            .set_string("bootargs", "console=hvc0");
+        fdt.root
+            .child_mut("chosen")
+            .set_u64("linux,initrd-start", 0x8400_0000);
+
+        let fdt = Fdt::from_bytes(&fdt.to_bytes()).expect("parse");
+        let chosen = fdt.root.child("chosen").expect("chosen");
+        assert_eq!(chosen.property("bootargs"), Some(&b"console=hvc0\0"[..]));
+        assert_eq!(
+            chosen.property("linux,initrd-start"),
+            Some(&[0, 0, 0, 0, 0x84, 0, 0, 0][..])
+        );
+    }
+
+    #[test]
+    fn dts() {
+        let mut fdt = Fdt::default();
+        let cpu = fdt.root.child_mut("cpus").child_mut("cpu@0");
+        cpu.set_string("riscv,isa", "rv64ima");
+        cpu.set_u32("reg", 0);
+        cpu.set_property("interrupt-controller", vec![]);
+        cpu.set_property("compatible", b"a\0b\0".to_vec());
+
+        let dts = fdt.to_dts();
+        assert!(dts.starts_with("/dts-v1/;\n\n/ {\n"));
+        assert!(dts.contains("\t\triscv,isa = \"rv64ima\";\n"));
+        assert!(dts.contains("\t\treg = <0x0>;\n"));
+        assert!(dts.contains("\t\tinterrupt-controller;\n"));
+        assert!(dts.contains("\t\tcompatible = \"a\", \"b\";\n"));
+    }
+}
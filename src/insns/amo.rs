@@ -25,7 +25,7 @@ macro_rules! mem {
 
 pub fn lrw<M: Memory>(p: &mut Processor<M>, i: Rtype) {
     let rs1 = p.regs.get(i.rs1() as usize);
-    let v = mem!(p, read_w, rs1) as u64;
+    let v = mem!(p, load_reserved_w, rs1) as u64;
     let sign_extended = ((v as i64) << 32 >> 32) as u64;
     p.regs.set(i.rd() as usize, sign_extended);
     p.advance_pc();
@@ -33,7 +33,7 @@ pub fn lrw<M: Memory>(p: &mut Processor<M>, i: Rtype) {
 
 pub fn lrd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
     let rs1 = p.regs.get(i.rs1() as usize);
-    let v = mem!(p, read_d, rs1);
+    let v = mem!(p, load_reserved_d, rs1);
     p.regs.set(i.rd() as usize, v);
     p.advance_pc();
 }
@@ -41,41 +41,36 @@ pub fn lrd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
 pub fn scw<M: Memory>(p: &mut Processor<M>, i: Rtype) {
     let rs1 = p.regs.get(i.rs1() as usize);
     let rs2 = p.regs.get(i.rs2() as usize);
-    mem!(p, write_w, rs1, rs2 as u32);
-    p.regs.set(i.rd() as usize, 0);
+    let stored = mem!(p, store_conditional_w, rs1, rs2 as u32);
+    p.regs.set(i.rd() as usize, !stored as u64);
     p.advance_pc();
 }
 
 pub fn scd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
     let rs1 = p.regs.get(i.rs1() as usize);
     let rs2 = p.regs.get(i.rs2() as usize);
-    mem!(p, write_d, rs1, rs2);
-    p.regs.set(i.rd() as usize, 0);
+    let stored = mem!(p, store_conditional_d, rs1, rs2);
+    p.regs.set(i.rd() as usize, !stored as u64);
     p.advance_pc();
 }
 
 pub fn amoswapw<M: Memory>(p: &mut Processor<M>, i: Rtype) {
-    let rs1 = p.regs.geti(i.rs1() as usize);
-    let v = mem!(p, read_w, rs1 as u64);
-
-    let sign_extended_v = ((v as i64) << 32 >> 32) as u64;
-
+    let addr = p.regs.get(i.rs1() as usize);
     let rs2 = p.regs.get(i.rs2() as usize);
 
-    mem!(p, write_w, rs1 as u64, rs2 as u32);
+    let v = mem!(p, fetch_update_w, addr, |_| rs2 as u32);
 
+    let sign_extended_v = ((v as i64) << 32 >> 32) as u64;
     p.regs.set(i.rd() as usize, sign_extended_v);
 
     p.advance_pc();
 }
 
 pub fn amoswapd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
-    let rs1 = p.regs.geti(i.rs1() as usize);
-    let v = mem!(p, read_d, rs1 as u64);
-
+    let addr = p.regs.get(i.rs1() as usize);
     let rs2 = p.regs.get(i.rs2() as usize);
 
-    mem!(p, write_d, rs1 as u64, rs2);
+    let v = mem!(p, fetch_update_d, addr, |_| rs2);
 
     p.regs.set(i.rd() as usize, v);
 
@@ -83,39 +78,24 @@ pub fn amoswapd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
 }
 
 pub fn amoaddw<M: Memory>(p: &mut Processor<M>, i: Rtype) {
-    // load value from addr(rs1)
     let addr = p.regs.get(i.rs1() as usize);
-    let v = mem!(p, read_w, addr);
+    let rs2 = p.regs.get(i.rs2() as usize);
+
+    // load, add and write back in one access
+    let v = mem!(p, fetch_update_w, addr, |v| v.wrapping_add(rs2 as u32));
 
     // sign extend and place in rd
     let sign_extended_v = ((v as i64) << 32 >> 32) as u64;
-    // other value
-    let rs2 = p.regs.geti(i.rs2() as usize);
-
-    // do operation
-    let result = sign_extended_v as i64 + rs2;
-
-    // write result back to addr
-    mem!(p, write_w, addr, result as u32);
-
     p.regs.set(i.rd() as usize, sign_extended_v);
 
     p.advance_pc();
 }
 
 pub fn amoaddd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
-    // load value from addr(rs1)
     let addr = p.regs.get(i.rs1() as usize);
-    let v = mem!(p, read_d, addr);
-
-    // other value
     let rs2 = p.regs.get(i.rs2() as usize);
 
-    // do operation
-    let result = v + rs2;
-
-    // write result back to addr
-    mem!(p, write_d, addr, result);
+    let v = mem!(p, fetch_update_d, addr, |v| v.wrapping_add(rs2));
 
     p.regs.set(i.rd() as usize, v);
 
@@ -123,18 +103,10 @@ pub fn amoaddd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
 }
 
 pub fn amoandd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
-    // load value from addr(rs1)
     let addr = p.regs.get(i.rs1() as usize);
-    let v = mem!(p, read_d, addr);
-
-    // other value
     let rs2 = p.regs.get(i.rs2() as usize);
 
-    // do operation
-    let result = v & rs2;
-
-    // write result back to addr
-    mem!(p, write_d, addr, result);
+    let v = mem!(p, fetch_update_d, addr, |v| v & rs2);
 
     p.regs.set(i.rd() as usize, v);
 
@@ -142,20 +114,52 @@ pub fn amoandd<M: Memory>(p: &mut Processor<M>, i: Rtype) {
 }
 
 pub fn amoord<M: Memory>(p: &mut Processor<M>, i: Rtype) {
-    // load value from addr(rs1)
     let addr = p.regs.get(i.rs1() as usize);
-    let v = mem!(p, read_d, addr);
-
-    // other value
     let rs2 = p.regs.get(i.rs2() as usize);
 
-    // do operation
-    let result = v | rs2;
-
-    // write result back to addr
-    mem!(p, write_d, addr, result);
+    let v = mem!(p, fetch_update_d, addr, |v| v | rs2);
 
     p.regs.set(i.rd() as usize, v);
 
     p.advance_pc();
 }
+
+// An AMO on the word at rs1: memory gets `op` of the word loaded and
+// rs2, rd gets the word loaded, sign extended
+macro_rules! amo_w {
+    ($name:ident, |$v:ident, $rs2:ident| $op:expr) => {
+        pub fn $name<M: Memory>(p: &mut Processor<M>, i: Rtype) {
+            let addr = p.regs.get(i.rs1() as usize);
+            let $rs2 = p.regs.get(i.rs2() as usize) as u32;
+            let v = mem!(p, fetch_update_w, addr, |$v: u32| $op);
+            p.regs.set(i.rd() as usize, v as i32 as u64);
+            p.advance_pc();
+        }
+    };
+}
+
+macro_rules! amo_d {
+    ($name:ident, |$v:ident, $rs2:ident| $op:expr) => {
+        pub fn $name<M: Memory>(p: &mut Processor<M>, i: Rtype) {
+            let addr = p.regs.get(i.rs1() as usize);
+            let $rs2 = p.regs.get(i.rs2() as usize);
+            let v = mem!(p, fetch_update_d, addr, |$v: u64| $op);
+            p.regs.set(i.rd() as usize, v);
+            p.advance_pc();
+        }
+    };
+}
+
+amo_w!(amoxorw, |v, rs2| v ^ rs2);
+amo_w!(amoorw, |v, rs2| v | rs2);
+amo_w!(amoandw, |v, rs2| v & rs2);
+amo_w!(amominw, |v, rs2| (v as i32).min(rs2 as i32) as u32);
+amo_w!(amomaxw, |v, rs2| (v as i32).max(rs2 as i32) as u32);
+amo_w!(amominuw, |v, rs2| v.min(rs2));
+amo_w!(amomaxuw, |v, rs2| v.max(rs2));
+
+amo_d!(amoxord, |v, rs2| v ^ rs2);
+amo_d!(amomind, |v, rs2| (v as i64).min(rs2 as i64) as u64);
+amo_d!(amomaxd, |v, rs2| (v as i64).max(rs2 as i64) as u64);
+amo_d!(amominud, |v, rs2| v.min(rs2));
+amo_d!(amomaxud, |v, rs2| v.max(rs2));
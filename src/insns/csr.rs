@@ -5,7 +5,7 @@ pub trait Op {
 }
 
 pub fn insn<M: Memory, O: Op>(p: &mut Processor<M>, i: Itype) {
-    let old = match p.csrs().get(i.immu() as usize) {
+    let old = match p.get_csr(i.immu() as u32) {
         Ok(v) => v,
         Err(t) => {
             do_trap(p, t.cause, t.value);
@@ -121,12 +121,32 @@ pub fn do_supervisor_trap<M: Memory>(p: &mut Processor<M>, cause: u64, val: u64)
  *
  */
 pub fn do_trap<M: Memory>(p: &mut Processor<M>, cause: u64, val: u64) {
+    // interrupts are delegated through mideleg by the caller
+    let interrupt = cause >> 63 == 1;
+
+    // the instruction raising an exception doesn't retire
+    if !interrupt {
+        p.unretired += 1;
+    }
+    trap(p, cause, val);
+}
+
+/// Trap on an instruction that could not be fetched, which was never
+/// counted in the first place
+pub fn fetch_fault<M: Memory>(p: &mut Processor<M>) {
+    let pc = p.pc();
+    trap(p, 12, pc);
+}
+
+fn trap<M: Memory>(p: &mut Processor<M>, cause: u64, val: u64) {
     let prv = p.csrs().prv();
     let medeleg = p.csrs().medeleg;
 
     debug!("Doing trap prv={} cause=0x{:x} value={:x}", prv, cause, val);
 
-    if prv <= 1 && ((medeleg >> cause) & 0x1) == 1 {
+    let interrupt = cause >> 63 == 1;
+
+    if !interrupt && prv <= 1 && ((medeleg >> cause) & 0x1) == 1 {
         // handle in supervisor mode if in supervisor or user mode
         // or if the bit from cause is set is set in medeleg
 
@@ -213,14 +233,17 @@ pub fn sret<M: Memory>(p: &mut Processor<M>, _: Itype) {
 }
 
 pub fn ecall<M: Memory>(p: &mut Processor<M>, _: Itype) {
+    if p.prv() == 1 && p.sbi_enabled() {
+        crate::sbi::call(p);
+        return;
+    }
+
     let a7 = p.regs.get(17 as usize);
     if p.prv() == 1 && a7 <= 8 {
-        use std::io::{self, Write};
-
         let a0 = p.regs.get(10 as usize);
         if a7 == 1 {
             trace!("putchar ecall 0x{:x} {}", a7, a0 as u8 as char);
-            write!(io::stderr(), "{}", a0 as u8 as char).expect("ecall write");
+            p.putchar(&[a0 as u8]);
         } else if a7 == 2 {
             trace!("getchar ecall 0x{:x} {}", a7, a0 as u8 as char);
             let val = p.getchar();
@@ -250,26 +273,9 @@ pub fn ecall<M: Memory>(p: &mut Processor<M>, _: Itype) {
 }
 
 pub fn wfi<M: Memory>(p: &mut Processor<M>) {
-    use crate::bitfield::Interrupt;
-    let pending = p.pending_interrupts();
-
-    assert!(p.prv() == 1); // only setup for S mode
-
-    if pending.supervisor_timer_interrupt() > 0 {
-        let pc = p.pc();
-        let cause = 0x8000_0000_0000_0005;
-        trace!(
-            "{}: wfi doing: {:?}. cause=0x{:x}",
-            p.insn_counter(),
-            pending,
-            cause
-        );
-        do_supervisor_trap(p, cause, pc);
-    } else {
-        warn!("wfi no pending: {:?}", pending);
-        p.advance_pc();
-        use std::{thread, time};
-        let foo = time::Duration::from_millis(250);
-        thread::sleep(foo);
-    }
+    trace!("wfi pending: {:?}", p.pending_interrupts());
+    p.advance_pc();
+    p.wait_for_interrupt();
+    // the interrupt that ended the wait, if it is enabled
+    p.handle_interrupt();
 }
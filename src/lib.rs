@@ -13,15 +13,30 @@ extern crate lazy_static;
 // }
 
 mod bitfield;
+mod block_cache;
+mod boot;
+mod cli;
+mod clock;
+mod config;
+mod console;
+pub mod disasm;
 mod elf_loader;
+pub mod fdt;
 mod insns;
 mod itypes;
 pub mod logrunner;
+pub mod machine;
 mod matcher;
 mod memory;
 mod mmu;
+mod opcodes;
 mod processor;
 mod regs;
+mod replay;
+mod sbi;
+mod smp;
+#[cfg(test)]
+mod test_util;
 
 pub use crate::insns::*;
 pub(crate) use crate::matcher::{Matcher, Matchers};
@@ -30,8 +45,13 @@ use crate::memory::Memory;
 pub(crate) use crate::mmu::Mmu;
 pub use crate::processor::Processor;
 pub(crate) use crate::regs::Regs;
+pub use crate::config::Config;
+pub use crate::machine::Machine;
+pub use crate::sbi::ResetType;
+pub use crate::smp::{HartState, SharedMemory, System, ThreadedMemory, ThreadedSystem};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, RwLock};
 
 pub fn load_dtb() -> Vec<u8> {
     let mut dtb = vec![];
@@ -41,13 +61,16 @@ pub fn load_dtb() -> Vec<u8> {
 }
 
 pub fn write_reset_vec<M: Memory>(mem: &mut M, entry: u64, dtb: &[u8]) {
+    write_reset_vec_at(mem, machine::RESET_VEC_ADDR, entry, dtb)
+}
+
+pub fn write_reset_vec_at<M: Memory>(mem: &mut M, reset_vec_addr: u64, entry: u64, dtb: &[u8]) {
     //  auipc   t0, 0x0
     //  addi    a1, t0, 32
     //  csrr    a0, mhartid
     //  ld      t0, 24(t0)
     //  jr      t0
 
-    let reset_vec_addr = 0x1000;
     let reset_vec_size = 8;
     mem.write_w(reset_vec_addr, 0x297);
     mem.write_w(
@@ -62,28 +85,26 @@ pub fn write_reset_vec<M: Memory>(mem: &mut M, entry: u64, dtb: &[u8]) {
     mem.write_w(reset_vec_addr + 24, entry as u32);
     mem.write_w(reset_vec_addr + 28, (entry >> 32) as u32);
     for (i, b) in dtb.iter().enumerate() {
-        mem.write_b(reset_vec_addr + 32 + i as u64, *b);
+        mem.write_b(reset_vec_addr + machine::RESET_VEC_DTB_OFFSET + i as u64, *b);
     }
 }
 
 pub fn build_memory() -> BlockMemory {
-    let mut mem = BlockMemory::new(15);
-
-    mem.add_block(0x8000_0000, 2048 * 1024 * 1024);
-
-    // dummy clint
-    mem.add_block(0x200_0000, 0xc000);
-
-    let reset_vec_addr = 0x1000;
-    mem.add_block(reset_vec_addr, 2048);
+    build_memory_with_entry(&Config::default())
+        .expect("build memory")
+        .0
+}
 
-    use std::env;
-    let filename = env::var("BIN").unwrap_or_else(|_| "assets/bbl".into());
+// Returns the memory along with the entry point of the loaded ELF
+fn build_memory_with_entry(config: &Config) -> io::Result<(BlockMemory, u64)> {
+    let machine = &config.machine;
+    let mut mem = machine.build_memory();
 
-    let (entry, sections) = elf_loader::read_program_segments(&filename);
-    let mut elf = File::open(&filename).unwrap();
+    let filename = &config.boot.elf;
+    let (entry, sections) = elf_loader::read_program_segments(filename)?;
+    let mut elf = File::open(filename)?;
     let mut file_bytes = vec![];
-    let _read_file_size = elf.read_to_end(&mut file_bytes).unwrap();
+    let _read_file_size = elf.read_to_end(&mut file_bytes)?;
 
     debug!("Loading ELF");
     for (f_offset, m_offset, size) in sections {
@@ -93,50 +114,172 @@ pub fn build_memory() -> BlockMemory {
         }
     }
 
-    let dtb = load_dtb();
-    write_reset_vec(&mut mem, entry, &dtb);
+    let dtb = machine.dtb();
+    write_reset_vec_at(&mut mem, machine.reset_vec, entry, &dtb);
 
-    mem
+    Ok((mem, entry))
 }
 
-pub fn risk5_main() {
+// Loads a Linux Image, initrd and patched DTB for booting with
+// the built-in SBI
+fn build_linux_memory(config: &Config, kernel: &str) -> io::Result<(BlockMemory, boot::BootInfo)> {
+    let machine = &config.machine;
+    let mut mem = machine.build_memory();
+
+    let image = std::fs::read(kernel)?;
+    let initrd = match config.boot.initrd {
+        Some(ref f) => Some(std::fs::read(f)?),
+        None => None,
+    };
+
+    let ram = machine.main_ram();
+    let info = boot::load_linux(
+        &mut mem,
+        (ram.base, ram.size),
+        &image,
+        initrd.as_deref(),
+        config.boot.append.as_deref(),
+        &machine.dtb(),
+    )?;
+
+    Ok((mem, info))
+}
+
+pub fn risk5_main() -> Result<(), io::Error> {
     pretty_env_logger::init();
     // logrunner::logger::init().unwrap();
 
-    let mut cpu = Processor::new(build_memory());
-    let matchers = &mut build_matchers();
+    use std::env;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = cli::Options::parse(args.iter().cloned())?;
+    if opts.command == cli::Command::Help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    let config = opts.config()?;
+    let machine = &config.machine;
+
+    match opts.command {
+        cli::Command::Dts => {
+            print!("{}", machine.device_tree().to_dts());
+            return Ok(());
+        }
+        cli::Command::Disasm(ref path) => {
+            let stdout = io::stdout();
+            return disasm::disasm_elf(path, &mut stdout.lock());
+        }
+        cli::Command::Lockstep => {
+            let spike = opts.spike.as_deref().unwrap_or(logrunner::DEFAULT_SPIKE);
+            return logrunner::lockstep(&config, spike);
+        }
+        cli::Command::Help => unreachable!("usage is printed before loading the config"),
+        cli::Command::Run => (),
+    }
+
+    let (mem, supervisor) = if let Some(ref kernel) = config.boot.kernel {
+        let (mem, info) = build_linux_memory(&config, kernel)?;
+        (mem, Some((info.entry, info.dtb)))
+    } else {
+        let (mem, entry) = build_memory_with_entry(&config)?;
+
+        // boot the kernel in S-mode using the built-in SBI instead
+        // of going through the reset vector and M-mode firmware
+        let supervisor = if config.boot.sbi {
+            Some((entry, machine.dtb_addr()))
+        } else {
+            None
+        };
+        (mem, supervisor)
+    };
+    let console = Arc::new(Mutex::new(console::Console::new(&config.console)?));
 
     use std::time::SystemTime;
     let start = SystemTime::now();
     let mut mark = SystemTime::now();
 
-    use std::sync::{Arc, RwLock};
-    let trigger = Arc::new(RwLock::new(false));
+    let run_args = replay::run_args(&args);
+    let journal = match (&opts.record, &opts.replay) {
+        (Some(path), _) => Some(replay::Journal::record(path, &run_args)?),
+        (_, Some(path)) => Some(replay::Journal::replay(path, &run_args)?),
+        _ => None,
+    }
+    .map(|journal| Arc::new(Mutex::new(journal)));
+
+    let tracing = opts.trace.is_some() || opts.log_commits;
+    if opts.stats.is_some() && tracing {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--stats and --trace can't be combined",
+        ));
+    }
+    if machine.harts > 1 {
+        if opts.stats.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--stats needs a single hart",
+            ));
+        }
+        if tracing {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--trace and --log-commits need a single hart",
+            ));
+        }
+        if opts.record.is_some() || opts.replay.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--record and --replay need a single hart",
+            ));
+        }
+        return run_system(machine, mem, supervisor, console, &spawn_trigger());
+    }
 
-    {
-        use std::io::stdin;
-        use std::thread::spawn;
-        let trigger = trigger.clone();
-
-        spawn(move || {
-            let mut buf = String::new();
-            loop {
-                stdin().read_line(&mut buf).expect("stdin");
-                let line = buf.trim_end();
-                println!("{}", line);
-
-                if line == "" {
-                    let mut i = trigger.write().expect("write lock");
-                    *i = !*i;
-                    warn!("trigger={}", i);
-                }
+    if tracing {
+        use crate::logrunner::tracer::{trace_run, Format, Recorder, TraceWriter};
+        let out: Box<dyn io::Write> = match opts.trace.as_deref() {
+            Some("-") => Box::new(io::stdout()),
+            Some(path) => Box::new(File::create(path)?),
+            // where spike writes it
+            None => Box::new(io::stderr()),
+        };
+        let format = if opts.log_commits {
+            Format::Commits
+        } else {
+            opts.trace_format
+        };
+        let mut cpu = single_hart(Recorder::new(mem), machine, supervisor, console);
+        if let Some(ref journal) = journal {
+            cpu.set_journal(journal.clone());
+        }
+        let start = (&cpu).into();
+        let mut out = TraceWriter::new(io::BufWriter::new(out), format, &start)?;
+        let traced = trace_run(&mut cpu, &mut build_matchers(), &opts.trace_filter, &mut out)?;
+        out.finish()?;
+        info!("Traced {} instructions", traced);
+        return finish_journal(&journal);
+    }
 
-                buf.clear();
-            }
-        });
+    let matchers = &mut build_matchers();
+    let mut cpu = single_hart(mem, machine, supervisor, console);
+    if let Some(ref journal) = journal {
+        cpu.set_journal(journal.clone());
     }
 
-    const STEP_SIZE: usize = 10_000_000;
+    if let Some(ref path) = opts.stats {
+        let stats = logrunner::stats::record_run(&mut cpu, matchers, opts.stats_count);
+        // a raw kernel Image has no symbols
+        let symbols = match config.boot.kernel {
+            Some(_) => None,
+            None => Some(disasm::Symbols::load(&config.boot.elf)?),
+        };
+        stats.summary(symbols.as_ref(), 20).write_json(path)?;
+        return finish_journal(&journal);
+    }
+
+    // after the runs above, which may write to stdout
+    let trigger = spawn_trigger();
+
+    const STEP_SIZE: u64 = 10_000_000;
 
     let mut counter = 0;
     let mut real_trigger = false;
@@ -150,42 +293,40 @@ pub fn risk5_main() {
         //     break;
         // }
 
-        cpu.step(matchers);
-        // calls[idx] += 1;
-        // let matcher = matchers.remove(idx).expect("used insn");
-        // matchers.push_front(matcher);
-
-        counter += 1;
+        let last = counter;
+        counter += cpu.run(matchers, 1000);
+        if cpu.is_stopped() {
+            warn!("Hart stopped. Reset type {:?}", cpu.reset_type());
+            break;
+        }
         trace!("--- Step {} ---", counter);
 
-        if counter % 1000 == 0 {
-            cpu.handle_interrupt();
-
-            real_trigger = *trigger.read().expect("read lock");
-            cpu.trigger = real_trigger;
-
-            if counter % STEP_SIZE == 0 {
-                // if counter >= 50_000_000 {
-                //     break;
-                // }
-
-                let d = SystemTime::now().duration_since(mark).expect("time");
-                let in_ms = d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000;
-                let in_sec = (in_ms as f32) / 1000f32;
-                let speed = (STEP_SIZE as f32) / in_sec;
-                // warn!(
-                //     "--- Step {}mil --- pc=0x{:x} @ {} MHz",
-                //     counter / 1_000_000,
-                //     cpu.pc(),
-                //     speed / 1_000_000.0
-                // );
-                mark = SystemTime::now();
-
-                // if counter == 180_000_000 {
-                //     error!("too slow");
-                //     panic!("too slow");
-                // }
-            }
+        cpu.handle_interrupt();
+
+        real_trigger = *trigger.read().expect("read lock");
+        cpu.trigger = real_trigger;
+
+        if counter / STEP_SIZE != last / STEP_SIZE {
+            // if counter >= 50_000_000 {
+            //     break;
+            // }
+
+            let d = SystemTime::now().duration_since(mark).expect("time");
+            let in_ms = d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000;
+            let in_sec = (in_ms as f32) / 1000f32;
+            let speed = (STEP_SIZE as f32) / in_sec;
+            // warn!(
+            //     "--- Step {}mil --- pc=0x{:x} @ {} MHz",
+            //     counter / 1_000_000,
+            //     cpu.pc(),
+            //     speed / 1_000_000.0
+            // );
+            mark = SystemTime::now();
+
+            // if counter == 180_000_000 {
+            //     error!("too slow");
+            //     panic!("too slow");
+            // }
         }
 
         // // let _fromhost = cpu.mmu_mut().bare_mut().read_d(0x80009000);
@@ -212,16 +353,128 @@ pub fn risk5_main() {
     // );
 
     // matchers.print();
+
+    finish_journal(&journal)
+}
+
+// Flushes a recording, or fails a replay the run stopped following
+fn finish_journal(journal: &Option<Arc<Mutex<replay::Journal>>>) -> io::Result<()> {
+    match journal {
+        Some(journal) => journal.lock().expect("journal lock").finish(),
+        None => Ok(()),
+    }
+}
+
+// Sets up the only hart of a machine, entering S-mode through the
+// built-in SBI when `supervisor` gives an entry point and DTB
+fn single_hart<M: Memory>(
+    mem: M,
+    machine: &Machine,
+    supervisor: Option<(u64, u64)>,
+    console: Arc<Mutex<console::Console>>,
+) -> Processor<M> {
+    let mut cpu = Processor::new(mem);
+    cpu.set_pc(machine.reset_vec);
+    if let Some((entry, dtb)) = supervisor {
+        cpu.enable_sbi();
+        cpu.boot_supervisor(entry, dtb);
+    }
+    cpu.csrs_mut().misa = machine.misa;
+    cpu.set_clock(machine.clock());
+    cpu.set_console(console);
+    cpu
+}
+
+// Toggles the trigger each time an empty line is read from stdin
+fn spawn_trigger() -> Arc<RwLock<bool>> {
+    use std::io::stdin;
+    use std::thread::spawn;
+
+    let trigger = Arc::new(RwLock::new(false));
+    let t = trigger.clone();
+
+    spawn(move || {
+        let mut buf = String::new();
+        loop {
+            stdin().read_line(&mut buf).expect("stdin");
+            let line = buf.trim_end();
+            println!("{}", line);
+
+            if line == "" {
+                let mut i = t.write().expect("write lock");
+                *i = !*i;
+                warn!("trigger={}", i);
+            }
+
+            buf.clear();
+        }
+    });
+    trigger
+}
+
+// Runs a multi-hart machine until every hart stops or a reset
+fn run_system(
+    machine: &Machine,
+    mem: BlockMemory,
+    supervisor: Option<(u64, u64)>,
+    console: Arc<Mutex<console::Console>>,
+    trigger: &Arc<RwLock<bool>>,
+) -> io::Result<()> {
+    if machine.threaded {
+        let mut system = ThreadedSystem::new(&mem, machine.harts, machine.clint(), machine.quantum);
+        setup_harts(system.harts_mut(), machine, &console);
+        if let Some((entry, dtb)) = supervisor {
+            system.boot_supervisor(entry, dtb);
+        }
+        let reset_type = system.run(trigger);
+        warn!("System stopped. Reset type {:?}", reset_type);
+        return Ok(());
+    }
+
+    let mut system = System::new(mem, machine.harts, machine.clint(), machine.quantum);
+    setup_harts(system.harts_mut(), machine, &console);
+    if let Some((entry, dtb)) = supervisor {
+        system.boot_supervisor(entry, dtb);
+    }
+
+    let matchers = &mut build_matchers();
+    while !system.is_stopped() {
+        system.run_quantum(matchers);
+
+        let real_trigger = *trigger.read().expect("read lock");
+        for hart in system.harts_mut() {
+            hart.trigger = real_trigger;
+        }
+    }
+    warn!("System stopped. Reset type {:?}", system.reset_type());
+
+    Ok(())
+}
+
+fn setup_harts<M>(
+    harts: &mut [Processor<M>],
+    machine: &Machine,
+    console: &Arc<Mutex<console::Console>>,
+) {
+    for hart in harts {
+        hart.set_pc(machine.reset_vec);
+        hart.csrs_mut().misa = machine.misa;
+        hart.set_clock(machine.clock());
+        hart.set_console(console.clone());
+    }
 }
 
 pub fn build_matchers<M: Memory>() -> Matchers<M> {
     macro_rules! wrap {
         ($f:path) => {
             |p, i| {
-                let a = i;
-                let i = i.into();
-                debug!("> exec 0x{:x} 0x{:08x} {} {}", p.pc(), a, stringify!($f), i);
-                $f(p, i)
+                debug!(
+                    "> exec 0x{:x} 0x{:08x} {}",
+                    p.pc(),
+                    i,
+                    disasm::disasm(p.pc(), i)
+                );
+                $f(p, i.into())
             }
         };
     }
@@ -250,409 +503,137 @@ pub fn build_matchers<M: Memory>() -> Matchers<M> {
         };
     }
 
+    // illegal unless misa has extension `$ext`
+    macro_rules! ext {
+        ($ext:expr, $exec:expr) => {
+            |p, i| {
+                let exec: fn(&mut Processor<M>, u32) = $exec;
+                if p.has_extension($ext) {
+                    exec(p, i)
+                } else {
+                    insns::do_trap(p, 2, i as u64)
+                }
+            }
+        };
+    }
+
     use crate::insns::csr;
     use crate::insns::mem;
 
     Matchers::new(vec![
-        Matcher::new(0x707f, 0x63, wrap!(beq)),
-        Matcher::new(0x707f, 0x1063, wrap!(bne)),
-        Matcher::new(0x707f, 0x4063, wrap!(blt)),
-        Matcher::new(0x707f, 0x5063, wrap!(bge)),
-        Matcher::new(0x707f, 0x6063, wrap!(bltu)),
-        Matcher::new(0x707f, 0x7063, wrap!(bgeu)),
-        Matcher::new(0x707f, 0x67, wrap!(jalr)),
-        Matcher::new(0x7f, 0x6f, wrap!(jal)),
-        Matcher::new(0x7f, 0x37, wrap!(lui)),
-        Matcher::new(0x7f, 0x17, wrap!(auipc)),
-        Matcher::new(0x707f, 0x13, wrap!(comp::imm<M, comp::Add>)),
-        Matcher::new(0xfc00707f, 0x1013, wrap!(comp::imm<M, comp::Sll>)),
-        Matcher::new(0x707f, 0x2013, wrap!(comp::imm<M, comp::Slt>)),
-        Matcher::new(0x707f, 0x3013, wrap!(comp::immu<M, comp::Slt>)),
-        Matcher::new(0x707f, 0x4013, wrap!(comp::imm<M, comp::Xor>)),
-        Matcher::new(0xfc00707f, 0x5013, wrap!(comp::immu<M, comp::Srl>)),
-        Matcher::new(0xfc00707f, 0x40005013, wrap!(comp::imm<M, comp::Sra>)),
-        Matcher::new(0x707f, 0x6013, wrap!(comp::imm<M, comp::Or>)),
-        Matcher::new(0x707f, 0x7013, wrap!(comp::imm<M, comp::And>)),
-        Matcher::new(0xfe00707f, 0x33, wrap!(comp::reg<M, comp::Add>)),
-        Matcher::new(0xfe00707f, 0x40000033, wrap!(comp::reg<M, comp::Sub>)),
-        Matcher::new(0xfe00707f, 0x1033, wrap!(comp::reg<M, comp::Sll>)),
-        Matcher::new(0xfe00707f, 0x2033, wrap!(comp::reg<M, comp::Slt>)),
-        Matcher::new(0xfe00707f, 0x3033, wrap!(comp::regu<M, comp::Slt>)),
-        Matcher::new(0xfe00707f, 0x4033, wrap!(comp::reg<M, comp::Xor>)),
-        Matcher::new(0xfe00707f, 0x5033, wrap!(comp::regu<M, comp::Srl>)),
-        Matcher::new(0xfe00707f, 0x40005033, wrap!(comp::reg<M, comp::Sra>)),
-        Matcher::new(0xfe00707f, 0x6033, wrap!(comp::reg<M, comp::Or>)),
-        Matcher::new(0xfe00707f, 0x7033, wrap!(comp::reg<M, comp::And>)),
-        Matcher::new(0x707f, 0x1b, wrap!(comp::immw<M, comp::Add>)),
-        Matcher::new(0xfe00707f, 0x101b, wrap!(comp::immw<M, comp::Sll>)),
-        Matcher::new(0xfe00707f, 0x501b, wrap!(comp::immwu<M, comp::Srl>)),
-        Matcher::new(0xfe00707f, 0x4000501b, wrap!(comp::immw<M, comp::Sra>)),
-        Matcher::new(0xfe00707f, 0x3b, wrap!(comp::regw<M, comp::Add>)),
-        Matcher::new(0xfe00707f, 0x4000003b, wrap!(comp::regw<M, comp::Sub>)),
-        Matcher::new(0xfe00707f, 0x103b, wrap!(comp::regw<M, comp::Sll>)),
-        Matcher::new(0xfe00707f, 0x503b, wrap!(comp::regw<M, comp::Srl>)),
-        Matcher::new(0xfe00707f, 0x4000503b, wrap!(comp::regw<M, comp::Sra>)),
-        Matcher::new(0x707f, 0x3, wrap!(mem::lb)),
-        Matcher::new(0x707f, 0x1003, wrap!(mem::lh)),
-        Matcher::new(0x707f, 0x2003, wrap!(mem::lw)),
-        Matcher::new(0x707f, 0x3003, wrap!(mem::ld)),
-        Matcher::new(0x707f, 0x4003, wrap!(mem::lbu)),
-        Matcher::new(0x707f, 0x5003, wrap!(mem::lhu)),
-        Matcher::new(0x707f, 0x6003, wrap!(mem::lwu)),
-        Matcher::new(0x707f, 0x23, wrap!(mem::sb)),
-        Matcher::new(0x707f, 0x1023, wrap!(mem::sh)),
-        Matcher::new(0x707f, 0x2023, wrap!(mem::sw)),
-        Matcher::new(0x707f, 0x3023, wrap!(mem::sd)),
-        Matcher::new(0x707f, 0xf, |p, _| {
+        Matcher::named("beq", wrap!(beq)),
+        Matcher::named("bne", wrap!(bne)),
+        Matcher::named("blt", wrap!(blt)),
+        Matcher::named("bge", wrap!(bge)),
+        Matcher::named("bltu", wrap!(bltu)),
+        Matcher::named("bgeu", wrap!(bgeu)),
+        Matcher::named("jalr", wrap!(jalr)),
+        Matcher::named("jal", wrap!(jal)),
+        Matcher::named("lui", wrap!(lui)),
+        Matcher::named("auipc", wrap!(auipc)),
+        Matcher::named("addi", wrap!(comp::imm<M, comp::Add>)),
+        Matcher::named("slli", wrap!(comp::imm<M, comp::Sll>)),
+        Matcher::named("slti", wrap!(comp::imm<M, comp::Slt>)),
+        Matcher::named("sltiu", wrap!(comp::immu<M, comp::Slt>)),
+        Matcher::named("xori", wrap!(comp::imm<M, comp::Xor>)),
+        Matcher::named("srli", wrap!(comp::immu<M, comp::Srl>)),
+        Matcher::named("srai", wrap!(comp::imm<M, comp::Sra>)),
+        Matcher::named("ori", wrap!(comp::imm<M, comp::Or>)),
+        Matcher::named("andi", wrap!(comp::imm<M, comp::And>)),
+        Matcher::named("add", wrap!(comp::reg<M, comp::Add>)),
+        Matcher::named("sub", wrap!(comp::reg<M, comp::Sub>)),
+        Matcher::named("sll", wrap!(comp::reg<M, comp::Sll>)),
+        Matcher::named("slt", wrap!(comp::reg<M, comp::Slt>)),
+        Matcher::named("sltu", wrap!(comp::regu<M, comp::Slt>)),
+        Matcher::named("xor", wrap!(comp::reg<M, comp::Xor>)),
+        Matcher::named("srl", wrap!(comp::regu<M, comp::Srl>)),
+        Matcher::named("sra", wrap!(comp::reg<M, comp::Sra>)),
+        Matcher::named("or", wrap!(comp::reg<M, comp::Or>)),
+        Matcher::named("and", wrap!(comp::reg<M, comp::And>)),
+        Matcher::named("addiw", wrap!(comp::immw<M, comp::Add>)),
+        Matcher::named("slliw", wrap!(comp::immw<M, comp::Sll>)),
+        Matcher::named("srliw", wrap!(comp::immwu<M, comp::Srl>)),
+        Matcher::named("sraiw", wrap!(comp::immw<M, comp::Sra>)),
+        Matcher::named("addw", wrap!(comp::regw<M, comp::Add>)),
+        Matcher::named("subw", wrap!(comp::regw<M, comp::Sub>)),
+        Matcher::named("sllw", wrap!(comp::regw<M, comp::Sll>)),
+        Matcher::named("srlw", wrap!(comp::regw<M, comp::Srl>)),
+        Matcher::named("sraw", wrap!(comp::regw<M, comp::Sra>)),
+        Matcher::named("lb", wrap!(mem::lb)),
+        Matcher::named("lh", wrap!(mem::lh)),
+        Matcher::named("lw", wrap!(mem::lw)),
+        Matcher::named("ld", wrap!(mem::ld)),
+        Matcher::named("lbu", wrap!(mem::lbu)),
+        Matcher::named("lhu", wrap!(mem::lhu)),
+        Matcher::named("lwu", wrap!(mem::lwu)),
+        Matcher::named("sb", wrap!(mem::sb)),
+        Matcher::named("sh", wrap!(mem::sh)),
+        Matcher::named("sw", wrap!(mem::sw)),
+        Matcher::named("sd", wrap!(mem::sd)),
+        Matcher::named("fence", |p, _| {
             trace!("Unimplemented insn 'fence' at {:x}", p.pc());
             p.mmu_mut().flush_cache();
+            p.mmu_mut().fence();
             p.advance_pc();
         }),
-        Matcher::new(0x707f, 0x100f, |p, _| {
+        Matcher::named("fence.i", |p, _| {
             trace!("Unimplemented insn 'fence.i' at {:x}", p.pc());
             p.mmu_mut().flush_cache();
+            p.mmu_mut().flush_blocks();
             p.advance_pc();
         }),
-        Matcher::new(0xfe00707f, 0x2000033, wrap!(comp::reg<M, comp::Mul>)),
-        Matcher::new(0xfe00707f, 0x2001033, wrap!(comp::reg<M, comp::Mulh>)),
-        Matcher::new(0xfe00707f, 0x2002033, noimpl!("mulhsu")),
-        Matcher::new(0xfe00707f, 0x2003033, wrap!(comp::regu<M, comp::Mulh>)),
-        Matcher::new(0xfe00707f, 0x2004033, wrap!(comp::reg<M, comp::Div>)),
-        Matcher::new(0xfe00707f, 0x2005033, wrap!(comp::regu<M, comp::Div>)),
-        Matcher::new(0xfe00707f, 0x2006033, wrap!(comp::reg<M, comp::Rem>)),
-        Matcher::new(0xfe00707f, 0x2007033, wrap!(comp::regu<M, comp::Rem>)),
-        Matcher::new(0xfe00707f, 0x200003b, wrap!(comp::regw<M, comp::Mul>)),
-        Matcher::new(0xfe00707f, 0x200403b, wrap!(comp::regw<M, comp::Div>)),
-        Matcher::new(0xfe00707f, 0x200503b, wrap!(comp::reguw<M, comp::Div>)),
-        Matcher::new(0xfe00707f, 0x200603b, wrap!(comp::regw<M, comp::Rem>)),
-        Matcher::new(0xfe00707f, 0x200703b, wrap!(comp::reguw<M, comp::Rem>)),
-        Matcher::new(0xf800707f, 0x202f, wrap!(amoaddw)),
-        Matcher::new(0xf800707f, 0x2000202f, noimpl!("amoxor.w")),
-        Matcher::new(0xf800707f, 0x4000202f, noimpl!("amoor.w")),
-        Matcher::new(0xf800707f, 0x6000202f, noimpl!("amoand.w")),
-        Matcher::new(0xf800707f, 0x8000202f, noimpl!("amomin.w")),
-        Matcher::new(0xf800707f, 0xa000202f, noimpl!("amomax.w")),
-        Matcher::new(0xf800707f, 0xc000202f, noimpl!("amominu.w")),
-        Matcher::new(0xf800707f, 0xe000202f, noimpl!("amomaxu.w")),
-        Matcher::new(0xf800707f, 0x800202f, wrap!(amoswapw)),
-        Matcher::new(0xf9f0707f, 0x1000202f, wrap!(lrw)),
-        Matcher::new(0xf800707f, 0x1800202f, wrap!(scw)),
-        Matcher::new(0xf800707f, 0x302f, wrap!(amoaddd)),
-        Matcher::new(0xf800707f, 0x2000302f, noimpl!("amoxor.d")),
-        Matcher::new(0xf800707f, 0x4000302f, wrap!(amoord)),
-        Matcher::new(0xf800707f, 0x6000302f, wrap!(amoandd)),
-        Matcher::new(0xf800707f, 0x8000302f, noimpl!("amomin.d")),
-        Matcher::new(0xf800707f, 0xa000302f, noimpl!("amomax.d")),
-        Matcher::new(0xf800707f, 0xc000302f, noimpl!("amominu.d")),
-        Matcher::new(0xf800707f, 0xe000302f, noimpl!("amomaxu.d")),
-        Matcher::new(0xf800707f, 0x800302f, wrap!(amoswapd)),
-        Matcher::new(0xf9f0707f, 0x1000302f, wrap!(lrd)),
-        Matcher::new(0xf800707f, 0x1800302f, wrap!(scd)),
-        Matcher::new(0xffffffff, 0x73, wrap!(ecall)),
-        Matcher::new(0xffffffff, 0x100073, noimpl!("ebreak")),
-        Matcher::new(0xffffffff, 0x200073, noimpl!("uret")),
-        Matcher::new(0xffffffff, 0x10200073, wrap!(sret)),
-        Matcher::new(0xffffffff, 0x30200073, wrap!(mret)),
-        Matcher::new(0xffffffff, 0x7b200073, noimpl!("dret")),
-        Matcher::new(0xfe007fff, 0x12000073, |p, _| {
+        Matcher::named("mul", ext!('m', wrap!(comp::reg<M, comp::Mul>))),
+        Matcher::named("mulh", ext!('m', wrap!(comp::reg<M, comp::Mulh>))),
+        Matcher::named("mulhsu", ext!('m', noimpl!("mulhsu"))),
+        Matcher::named("mulhu", ext!('m', wrap!(comp::regu<M, comp::Mulh>))),
+        Matcher::named("div", ext!('m', wrap!(comp::reg<M, comp::Div>))),
+        Matcher::named("divu", ext!('m', wrap!(comp::regu<M, comp::Div>))),
+        Matcher::named("rem", ext!('m', wrap!(comp::reg<M, comp::Rem>))),
+        Matcher::named("remu", ext!('m', wrap!(comp::regu<M, comp::Rem>))),
+        Matcher::named("mulw", ext!('m', wrap!(comp::regw<M, comp::Mul>))),
+        Matcher::named("divw", ext!('m', wrap!(comp::regw<M, comp::Div>))),
+        Matcher::named("divuw", ext!('m', wrap!(comp::reguw<M, comp::Div>))),
+        Matcher::named("remw", ext!('m', wrap!(comp::regw<M, comp::Rem>))),
+        Matcher::named("remuw", ext!('m', wrap!(comp::reguw<M, comp::Rem>))),
+        Matcher::named("amoadd.w", ext!('a', wrap!(amoaddw))),
+        Matcher::named("amoxor.w", ext!('a', wrap!(amoxorw))),
+        Matcher::named("amoor.w", ext!('a', wrap!(amoorw))),
+        Matcher::named("amoand.w", ext!('a', wrap!(amoandw))),
+        Matcher::named("amomin.w", ext!('a', wrap!(amominw))),
+        Matcher::named("amomax.w", ext!('a', wrap!(amomaxw))),
+        Matcher::named("amominu.w", ext!('a', wrap!(amominuw))),
+        Matcher::named("amomaxu.w", ext!('a', wrap!(amomaxuw))),
+        Matcher::named("amoswap.w", ext!('a', wrap!(amoswapw))),
+        Matcher::named("lr.w", ext!('a', wrap!(lrw))),
+        Matcher::named("sc.w", ext!('a', wrap!(scw))),
+        Matcher::named("amoadd.d", ext!('a', wrap!(amoaddd))),
+        Matcher::named("amoxor.d", ext!('a', wrap!(amoxord))),
+        Matcher::named("amoor.d", ext!('a', wrap!(amoord))),
+        Matcher::named("amoand.d", ext!('a', wrap!(amoandd))),
+        Matcher::named("amomin.d", ext!('a', wrap!(amomind))),
+        Matcher::named("amomax.d", ext!('a', wrap!(amomaxd))),
+        Matcher::named("amominu.d", ext!('a', wrap!(amominud))),
+        Matcher::named("amomaxu.d", ext!('a', wrap!(amomaxud))),
+        Matcher::named("amoswap.d", ext!('a', wrap!(amoswapd))),
+        Matcher::named("lr.d", ext!('a', wrap!(lrd))),
+        Matcher::named("sc.d", ext!('a', wrap!(scd))),
+        Matcher::named("ecall", wrap!(ecall)),
+        Matcher::named("ebreak", noimpl!("ebreak")),
+        Matcher::named("uret", noimpl!("uret")),
+        Matcher::named("sret", wrap!(sret)),
+        Matcher::named("mret", wrap!(mret)),
+        Matcher::named("dret", noimpl!("dret")),
+        Matcher::named("sfence.vma", |p, _| {
             trace!("Noop insn 'sfence.vma' at {:x}", p.pc());
             p.mmu_mut().flush_cache();
             p.advance_pc();
         }),
-        Matcher::new(0xffffffff, 0x10500073, wrap_no_arg!(wfi)),
-        Matcher::new(0x707f, 0x1073, wrap!(csr::insn<M, csr::ReadWrite>)),
-        Matcher::new(0x707f, 0x2073, wrap!(csr::insn<M, csr::ReadSet>)),
-        Matcher::new(0x707f, 0x3073, wrap!(csr::insn<M, csr::ReadClear>)),
-        Matcher::new(0x707f, 0x5073, wrap!(csr::insn<M, csr::ReadWriteImm>)),
-        Matcher::new(0x707f, 0x6073, wrap!(csr::insn<M, csr::ReadSetImm>)),
-        Matcher::new(0x707f, 0x7073, wrap!(csr::insn<M, csr::ReadClearImm>)),
-        // Matcher::new(0xfe00007f, 0x53, |p, _| {
-        //     error!("Unimplemented insn 'fadd.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x8000053, |p, _| {
-        //     error!("Unimplemented insn 'fsub.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x10000053, |p, _| {
-        //     error!("Unimplemented insn 'fmul.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x18000053, |p, _| {
-        //     error!("Unimplemented insn 'fdiv.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x20000053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnj.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x20001053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnjn.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x20002053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnjx.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x28000053, |p, _| {
-        //     error!("Unimplemented insn 'fmin.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x28001053, |p, _| {
-        //     error!("Unimplemented insn 'fmax.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x58000053, |p, _| {
-        //     error!("Unimplemented insn 'fsqrt.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x2000053, |p, _| {
-        //     error!("Unimplemented insn 'fadd.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0xa000053, |p, _| {
-        //     error!("Unimplemented insn 'fsub.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x12000053, |p, _| {
-        //     error!("Unimplemented insn 'fmul.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x1a000053, |p, _| {
-        //     error!("Unimplemented insn 'fdiv.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x22000053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnj.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x22001053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnjn.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x22002053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnjx.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x2a000053, |p, _| {
-        //     error!("Unimplemented insn 'fmin.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x2a001053, |p, _| {
-        //     error!("Unimplemented insn 'fmax.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x40100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.s.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x42000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.d.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x5a000053, |p, _| {
-        //     error!("Unimplemented insn 'fsqrt.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x6000053, |p, _| {
-        //     error!("Unimplemented insn 'fadd.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0xe000053, |p, _| {
-        //     error!("Unimplemented insn 'fsub.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x16000053, |p, _| {
-        //     error!("Unimplemented insn 'fmul.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00007f, 0x1e000053, |p, _| {
-        //     error!("Unimplemented insn 'fdiv.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x26000053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnj.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x26001053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnjn.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x26002053, |p, _| {
-        //     error!("Unimplemented insn 'fsgnjx.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x2e000053, |p, _| {
-        //     error!("Unimplemented insn 'fmin.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0x2e001053, |p, _| {
-        //     error!("Unimplemented insn 'fmax.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x40300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.s.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x46000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.q.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x42300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.d.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x46100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.q.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0x5e000053, |p, _| {
-        //     error!("Unimplemented insn 'fsqrt.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa0000053, |p, _| {
-        //     error!("Unimplemented insn 'fle.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa0001053, |p, _| {
-        //     error!("Unimplemented insn 'flt.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa0002053, |p, _| {
-        //     error!("Unimplemented insn 'feq.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa2000053, |p, _| {
-        //     error!("Unimplemented insn 'fle.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa2001053, |p, _| {
-        //     error!("Unimplemented insn 'flt.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa2002053, |p, _| {
-        //     error!("Unimplemented insn 'feq.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa6000053, |p, _| {
-        //     error!("Unimplemented insn 'fle.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa6001053, |p, _| {
-        //     error!("Unimplemented insn 'flt.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfe00707f, 0xa6002053, |p, _| {
-        //     error!("Unimplemented insn 'feq.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc0000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.w.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc0100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.wu.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc0200053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.l.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc0300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.lu.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xe0000053, |p, _| {
-        //     error!("Unimplemented insn 'fmv.x.w' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xe0001053, |p, _| {
-        //     error!("Unimplemented insn 'fclass.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc2000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.w.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc2100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.wu.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc2200053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.l.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc2300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.lu.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xe2000053, |p, _| {
-        //     error!("Unimplemented insn 'fmv.x.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xe2001053, |p, _| {
-        //     error!("Unimplemented insn 'fclass.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc6000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.w.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc6100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.wu.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc6200053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.l.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xc6300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.lu.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xe6000053, |p, _| {
-        //     error!("Unimplemented insn 'fmv.x.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xe6001053, |p, _| {
-        //     error!("Unimplemented insn 'fclass.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd0000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.s.w' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd0100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.s.wu' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd0200053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.s.l' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd0300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.s.lu' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xf0000053, |p, _| {
-        //     error!("Unimplemented insn 'fmv.w.x' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd2000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.d.w' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd2100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.d.wu' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd2200053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.d.l' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd2300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.d.lu' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xf2000053, |p, _| {
-        //     error!("Unimplemented insn 'fmv.d.x' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd6000053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.q.w' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd6100053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.q.wu' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd6200053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.q.l' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0007f, 0xd6300053, |p, _| {
-        //     error!("Unimplemented insn 'fcvt.q.lu' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0xfff0707f, 0xf6000053, |p, _| {
-        //     error!("Unimplemented insn 'fmv.q.x' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x707f, 0x2007, |p, _| {
-        //     error!("Unimplemented insn 'flw' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x707f, 0x3007, |p, _| {
-        //     error!("Unimplemented insn 'fld' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x707f, 0x4007, |p, _| {
-        //     error!("Unimplemented insn 'flq' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x707f, 0x2027, |p, _| {
-        //     error!("Unimplemented insn 'fsw' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x707f, 0x3027, |p, _| {
-        //     error!("Unimplemented insn 'fsd' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x707f, 0x4027, |p, _| {
-        //     error!("Unimplemented insn 'fsq' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x43, |p, _| {
-        //     error!("Unimplemented insn 'fmadd.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x47, |p, _| {
-        //     error!("Unimplemented insn 'fmsub.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x4b, |p, _| {
-        //     error!("Unimplemented insn 'fnmsub.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x4f, |p, _| {
-        //     error!("Unimplemented insn 'fnmadd.s' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x2000043, |p, _| {
-        //     error!("Unimplemented insn 'fmadd.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x2000047, |p, _| {
-        //     error!("Unimplemented insn 'fmsub.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x200004b, |p, _| {
-        //     error!("Unimplemented insn 'fnmsub.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x200004f, |p, _| {
-        //     error!("Unimplemented insn 'fnmadd.d' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x6000043, |p, _| {
-        //     error!("Unimplemented insn 'fmadd.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x6000047, |p, _| {
-        //     error!("Unimplemented insn 'fmsub.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x600004b, |p, _| {
-        //     error!("Unimplemented insn 'fnmsub.q' at {:x}", p.pc())
-        // }),
-        // Matcher::new(0x600007f, 0x600004f, |p, _| {
-        //     error!("Unimplemented insn 'fnmadd.q' at {:x}", p.pc())
-        // }),
+        Matcher::named("wfi", wrap_no_arg!(wfi)),
+        Matcher::named("csrrw", wrap!(csr::insn<M, csr::ReadWrite>)),
+        Matcher::named("csrrs", wrap!(csr::insn<M, csr::ReadSet>)),
+        Matcher::named("csrrc", wrap!(csr::insn<M, csr::ReadClear>)),
+        Matcher::named("csrrwi", wrap!(csr::insn<M, csr::ReadWriteImm>)),
+        Matcher::named("csrrsi", wrap!(csr::insn<M, csr::ReadSetImm>)),
+        Matcher::named("csrrci", wrap!(csr::insn<M, csr::ReadClearImm>)),
     ])
 }
@@ -0,0 +1,197 @@
+use super::super::{Insn, LogTuple, MemoryTrace, State, Transaction};
+use crate::processor::DEFAULT_MISA;
+
+/*
+ *
+ * Legacy records
+ * --------------
+ * Records as version 1 of the trace format wrote them, before
+ * `State` carried every CSR risk5 implements.
+ *
+ */
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub(crate) struct StateV1 {
+    id: u64,
+    pc: u64,
+    prv: u64,
+
+    mstatus: u64,
+    mepc: u64,
+    mtvec: u64,
+    mcause: u64,
+    mscratch: u64,
+    minstret: u64,
+    mie: u64,
+    mip: u64,
+    medeleg: u64,
+    mideleg: u64,
+    mcounteren: u64,
+    scounteren: u64,
+    sepc: u64,
+    stval: u64,
+    sscratch: u64,
+    stvec: u64,
+    satp: u64,
+    scause: u64,
+
+    xregs: [u64; 32],
+}
+
+impl From<StateV1> for State {
+    fn from(s: StateV1) -> State {
+        State {
+            id: s.id,
+            legacy: true,
+            pc: s.pc,
+            prv: s.prv,
+            mstatus: s.mstatus,
+            mepc: s.mepc,
+            mtvec: s.mtvec,
+            mcause: s.mcause,
+            mscratch: s.mscratch,
+            minstret: s.minstret,
+            mie: s.mie,
+            mip: s.mip,
+            medeleg: s.medeleg,
+            mideleg: s.mideleg,
+            mcounteren: s.mcounteren,
+            scounteren: s.scounteren,
+            sepc: s.sepc,
+            stval: s.stval,
+            sscratch: s.sscratch,
+            stvec: s.stvec,
+            satp: s.satp,
+            scause: s.scause,
+            misa: DEFAULT_MISA,
+            mcycle: s.minstret,
+            xregs: s.xregs,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LogTupleV1 {
+    line: usize,
+    state: StateV1,
+    insn: Option<Insn>,
+    store: Option<MemoryTrace>,
+    mems: Vec<MemoryTrace>,
+}
+
+impl From<LogTupleV1> for LogTuple {
+    fn from(t: LogTupleV1) -> LogTuple {
+        LogTuple {
+            line: t.line,
+            state: t.state.into(),
+            insn: t.insn,
+            store: t.store,
+            mems: t.mems,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TransactionV1 {
+    state: StateV1,
+    insn: Option<Insn>,
+    mems: Vec<MemoryTrace>,
+    store: Option<MemoryTrace>,
+    after: StateV1,
+}
+
+impl From<TransactionV1> for Transaction {
+    fn from(t: TransactionV1) -> Transaction {
+        Transaction {
+            state: t.state.into(),
+            insn: t.insn,
+            mems: t.mems,
+            store: t.store,
+            after: t.after.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::LogLine;
+    use super::super::{write_header, TransactionReader};
+    use super::*;
+
+    fn state_v1(pc: u64) -> StateV1 {
+        StateV1 {
+            id: 0,
+            pc,
+            prv: 3,
+            mstatus: 0,
+            mepc: 0,
+            mtvec: 0,
+            mcause: 0,
+            mscratch: 0,
+            minstret: 7,
+            mie: 0,
+            mip: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mcounteren: 0,
+            scounteren: 0,
+            sepc: 0,
+            stval: 0,
+            sscratch: 0,
+            stvec: 0,
+            satp: 0,
+            scause: 0,
+            xregs: [0; 32],
+        }
+    }
+
+    #[test]
+    fn reads_both_versions() {
+        let no_insn: Option<Insn> = None;
+        let no_mems: Vec<MemoryTrace> = vec![];
+        let no_store: Option<MemoryTrace> = None;
+        let old = (
+            state_v1(0x1000),
+            no_insn,
+            no_mems,
+            no_store,
+            state_v1(0x1004),
+        );
+        let old = bincode::serialize(&old).expect("serialize");
+
+        let mut reader = TransactionReader::new(&old[..]).expect("header");
+        let t = reader.next().expect("transaction");
+        assert!(t.state.legacy);
+        assert_eq!(t.after.pc, 0x1004);
+        assert_eq!(t.state.misa, DEFAULT_MISA);
+        assert_eq!(t.state.mcycle, 7);
+        assert!(reader.next().is_none());
+
+        let mut new = vec![];
+        write_header(&mut new).expect("header");
+        let mut t = t;
+        t.state.legacy = false;
+        t.state.mtval = 0x42;
+        bincode::serialize_into(&mut new, &t).expect("serialize");
+        let read = TransactionReader::new(&new[..])
+            .expect("header")
+            .next()
+            .expect("transaction");
+        assert_eq!(read.state, t.state);
+
+        new[6] = 9;
+        assert!(TransactionReader::new(&new[..]).is_err());
+    }
+
+    #[test]
+    fn version_1_state_frame() {
+        // as version 1 wrote a `LogLine::State`
+        let mut frame = 2u32.to_le_bytes().to_vec();
+        frame.extend(bincode::serialize(&state_v1(0x1000)).expect("state"));
+        match bincode::deserialize(&frame).expect("frame") {
+            LogLine::StateV1(state) => assert_eq!(State::from(state).pc, 0x1000),
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+}
@@ -1,6 +1,27 @@
-use super::{json, LogLine, LogTuple};
+use super::container::{ContainerWriter, Header};
+use super::{json, LogLine, LogTuple, Transaction};
+use crate::machine::isa_string;
+use crate::processor::DEFAULT_MISA;
 use bincode;
-use std::io;
+use serde::de::DeserializeOwned;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+mod legacy;
+
+pub(crate) use self::legacy::StateV1;
+use self::legacy::{LogTupleV1, TransactionV1};
+
+/// Leads every tuple or transaction stream risk5 writes, followed by the
+/// format version as a little endian `u16`. Streams without it are
+/// version 1.
+const MAGIC: &[u8; 6] = b"risk5\0";
+pub(crate) const VERSION: u16 = 2;
+
+pub(crate) fn write_header<W: Write>(out: &mut W) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())
+}
 
 pub fn bincodereader() -> Result<(), io::Error> {
     // let i = LogLine::Mark;
@@ -25,17 +46,24 @@ pub fn bincodereader() -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Converts a JSON trace on stdin into a trace container on stdout.
+/// `TRACE_SOURCE` names what produced it, spike by default.
 pub fn convert() -> Result<(), io::Error> {
-    let mut out = io::BufWriter::new(io::stdout());
-
-    for line in json::TupleIterator::new(json::LineIterator::new()) {
+    let out = io::BufWriter::new(io::stdout());
+    let mut tuples = json::TupleIterator::new(json::LineIterator::new()).map(|line| {
         trace!("{:?}", line);
-        let bin = line.to_logtuple();
-        bincode::serialize_into(&mut out, &bin).map_err(|e| match *e {
-            bincode::ErrorKind::Io(e) => e,
-            e => io::Error::new(io::ErrorKind::Other, format!("{}", e)),
-        })?
+        line.to_logtuple()
+    });
+
+    let first = tuples.next().transpose()?;
+    // the ISA as the trace starts out
+    let misa = first.as_ref().map_or(DEFAULT_MISA, |t| t.state.misa);
+    let source = std::env::var("TRACE_SOURCE").unwrap_or_else(|_| "spike".into());
+    let mut out = ContainerWriter::new(out, &Header::new(isa_string(misa), source))?;
+    for tuple in first.into_iter().map(Ok).chain(tuples) {
+        out.push(tuple?)?;
     }
+    out.finish()?;
     Ok(())
 }
 
@@ -126,41 +154,75 @@ impl<T: io::Read> Iterator for LogLineReader<T> {
     }
 }
 
-pub(crate) struct TupleReader {
-    reader: io::BufReader<io::Stdin>,
+/// Reads the records of a stream risk5 wrote, going through `V1` if it
+/// is from version 1
+pub(crate) struct Records<R, T, V1> {
+    reader: io::Chain<Cursor<Vec<u8>>, R>,
+    version: u16,
+    _records: PhantomData<fn() -> (T, V1)>,
 }
 
-impl TupleReader {
-    pub fn new() -> Self {
-        let reader = io::BufReader::new(io::stdin());
-        TupleReader { reader }
+impl<R: Read, T, V1> Records<R, T, V1> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut head = vec![];
+        (&mut reader).take(8).read_to_end(&mut head)?;
+        let version = if head.len() == 8 && head.starts_with(MAGIC) {
+            let version = u16::from_le_bytes([head[6], head[7]]);
+            if version == 0 || version > VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported trace format version {}", version),
+                ));
+            }
+            head.clear();
+            version
+        } else {
+            1
+        };
+
+        Ok(Records {
+            reader: Cursor::new(head).chain(reader),
+            version,
+            _records: PhantomData,
+        })
     }
 }
 
-impl Iterator for TupleReader {
-    type Item = LogTuple;
+impl<R, T, V1> Iterator for Records<R, T, V1>
+where
+    R: Read,
+    T: DeserializeOwned,
+    V1: DeserializeOwned + Into<T>,
+{
+    type Item = T;
 
-    fn next(&mut self) -> Option<LogTuple> {
-        let val = match bincode::deserialize_from(&mut self.reader) {
-            Ok(n) => n,
+    fn next(&mut self) -> Option<T> {
+        let record = if self.version == 1 {
+            bincode::deserialize_from::<_, V1>(&mut self.reader).map(Into::into)
+        } else {
+            bincode::deserialize_from(&mut self.reader)
+        };
+        match record {
+            Ok(record) => Some(record),
             Err(e) => {
-                match *e {
-                    bincode::ErrorKind::Io(ref e) => {
-                        if e.kind() == io::ErrorKind::UnexpectedEof {
-                            return None;
-                        }
+                if let bincode::ErrorKind::Io(ref e) = *e {
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        return None;
                     }
-                    _ => (),
                 }
-                error!("Failed to read log tuple: {}", e);
-                panic!("Failed to read log tuple");
+                error!("Failed to read record: {}", e);
+                panic!("Failed to read record");
             }
-        };
-
-        Some(val)
+        }
     }
 }
 
+/// Tuples written by `convert` before it wrote containers
+pub(crate) type TupleReader<R = io::BufReader<io::Stdin>> = Records<R, LogTuple, LogTupleV1>;
+
+/// Transactions written by `filter` or saved by a failed validation
+pub(crate) type TransactionReader<R> = Records<R, Transaction, TransactionV1>;
+
 pub(crate) struct LineToTupleIterator<I> {
     line_it: I,
 }
@@ -211,6 +273,7 @@ where
                 LogLine::Mark => break,
                 LogLine::Insn(n) => insn = Some(n),
                 LogLine::State(n) => state = Some(n),
+                LogLine::StateV1(n) => state = Some(n.into()),
                 LogLine::Memory(n) => mems.push(n),
                 LogLine::Load(_) => (),
                 LogLine::Store(n) => store = Some(n),
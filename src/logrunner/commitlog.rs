@@ -0,0 +1,677 @@
+use super::pool::validate_parallel;
+use super::stats::trap;
+use super::transaction::TransactionIterator;
+use super::{Insn, LogTuple, MemoryTrace, MemoryTraceKind, Options, State};
+use crate::disasm::{csr_name, disasm};
+use crate::itypes::{FieldImm, FieldRs1, Itype, Stype};
+use crate::memory::ByteMap;
+use crate::opcodes::{decode, OPCODES};
+use crate::Processor;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+
+/*
+ *
+ * Commit log
+ * ----------
+ * Reader and writer for stock spike `--log-commits` output.
+ *
+ * Commit lines only carry what an instruction wrote, so the reader
+ * keeps a running `State` and applies each line to it. Fields the log
+ * never mentions keep risk5's reset values, except for minstret and
+ * mcycle, which count the committed instructions. Trapping
+ * instructions are not committed; run spike with `-l --log-commits`
+ * so their instruction and epc appear and the trap can be filled in
+ * here.
+ *
+ * The writer goes the other way, from a tuple and the state after it.
+ *
+ */
+
+const SSTATUS_MASK: u64 = 0x8000_0003_000d_e762;
+
+#[derive(Debug, PartialEq)]
+enum Line {
+    Commit(Commit),
+    /// `-l` trace of an instruction about to execute
+    Disasm(u64, u32, String),
+    Trap(u64, u64),
+    Tval(u64),
+    Other,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Commit {
+    prv: u64,
+    pc: u64,
+    bits: u32,
+    xregs: Vec<(usize, u64)>,
+    csrs: Vec<(u32, u64)>,
+    loads: Vec<u64>,
+    /// address, value, size in bytes
+    stores: Vec<(u64, u64, usize)>,
+}
+
+fn hex(s: &str) -> Result<u64, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(digits, 16).map_err(|e| format!("bad hex {:?}: {}", s, e))
+}
+
+fn trap_cause(name: &str) -> Result<u64, String> {
+    if let Some(n) = name.strip_prefix("interrupt #") {
+        let n: u64 = n.parse().map_err(|_| format!("bad interrupt {:?}", name))?;
+        return Ok(1 << 63 | n);
+    }
+    if let Some(n) = name.strip_prefix("trap #") {
+        return n.parse().map_err(|_| format!("bad trap {:?}", name));
+    }
+    Ok(match name {
+        "trap_instruction_address_misaligned" => 0,
+        "trap_instruction_access_fault" => 1,
+        "trap_illegal_instruction" => 2,
+        "trap_breakpoint" => 3,
+        "trap_load_address_misaligned" => 4,
+        "trap_load_access_fault" => 5,
+        "trap_store_address_misaligned" => 6,
+        "trap_store_access_fault" => 7,
+        "trap_user_ecall" => 8,
+        "trap_supervisor_ecall" => 9,
+        "trap_machine_ecall" => 11,
+        "trap_instruction_page_fault" => 12,
+        "trap_load_page_fault" => 13,
+        "trap_store_page_fault" => 15,
+        _ => return Err(format!("unknown trap {:?}", name)),
+    })
+}
+
+fn next_hex<'a, I: Iterator<Item = &'a str>>(tokens: &mut I) -> Result<u64, String> {
+    hex(tokens.next().ok_or("missing value")?)
+}
+
+// `x5` or the older `x 5`
+fn reg_number<'a, I>(token: &str, prefix: char, tokens: &mut I) -> Result<Option<usize>, String>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let rest = match token.strip_prefix(prefix) {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    if rest.is_empty() {
+        let n = tokens.next().ok_or("missing register")?;
+        return n
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("bad register {:?}", n));
+    }
+    Ok(rest.parse().ok())
+}
+
+fn parse_commit(prv: &str, rest: &str) -> Result<Commit, String> {
+    let mut tokens = rest.split_whitespace();
+    let mut commit = Commit {
+        prv: prv
+            .parse()
+            .map_err(|_| format!("bad privilege {:?}", prv))?,
+        pc: hex(tokens.next().ok_or("missing pc")?)?,
+        ..Default::default()
+    };
+    let bits = tokens.next().ok_or("missing instruction")?;
+    commit.bits = hex(bits.trim_start_matches('(').trim_end_matches(')'))? as u32;
+
+    let mut tokens = tokens.peekable();
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = next_hex(&mut tokens)?;
+            match tokens.peek() {
+                Some(v) if v.starts_with("0x") => {
+                    let v = tokens.next().unwrap_or_default();
+                    commit.stores.push((addr, hex(v)?, (v.len() - 2) / 2));
+                }
+                _ => commit.loads.push(addr),
+            }
+        } else if let Some(rd) = reg_number(token, 'x', &mut tokens)? {
+            let value = next_hex(&mut tokens)?;
+            if rd != 0 {
+                commit.xregs.push((rd, value));
+            }
+        } else if let Some(csr) = token.strip_prefix('c') {
+            let number = csr.split('_').next().unwrap_or_default();
+            let number = number.parse().map_err(|_| format!("bad csr {:?}", token))?;
+            commit.csrs.push((number, next_hex(&mut tokens)?));
+        } else if reg_number(token, 'f', &mut tokens)?.is_some() {
+            // no F/D in risk5
+            next_hex(&mut tokens)?;
+        } else {
+            debug!("Ignoring commit log field {:?}", token);
+        }
+    }
+    Ok(commit)
+}
+
+fn parse_line(line: &str) -> Result<(usize, Line), String> {
+    let rest = match line.trim_start().strip_prefix("core") {
+        Some(rest) => rest,
+        None => return Ok((0, Line::Other)),
+    };
+    let colon = rest.find(':').ok_or("missing core")?;
+    let core = rest[..colon]
+        .trim()
+        .parse()
+        .map_err(|_| format!("bad core {:?}", &rest[..colon]))?;
+    let rest = rest[colon + 1..].trim();
+
+    let (first, tail) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+    let tail = tail.trim();
+    let parsed = if first.len() == 1 && first.as_bytes()[0].is_ascii_digit() {
+        Line::Commit(parse_commit(first, tail)?)
+    } else if first.starts_with("0x") {
+        let close = tail.find(')').ok_or("missing instruction")?;
+        let bits = hex(tail[..close].trim_start_matches('('))? as u32;
+        Line::Disasm(hex(first)?, bits, tail[close + 1..].trim().to_string())
+    } else if first == "exception" {
+        let comma = tail.find(", epc ").ok_or("missing epc")?;
+        let cause = trap_cause(&tail[..comma])?;
+        Line::Trap(cause, hex(&tail[comma + 6..])?)
+    } else if first == "tval" {
+        Line::Tval(hex(tail)?)
+    } else {
+        Line::Other
+    };
+    Ok((core, parsed))
+}
+
+fn load_size(bits: u32) -> Option<usize> {
+    let name = OPCODES[decode(bits)?].name;
+    match name {
+        "lb" | "lbu" => Some(1),
+        "lh" | "lhu" => Some(2),
+        "lw" | "lwu" => Some(4),
+        "ld" => Some(8),
+        _ if name.ends_with(".w") => Some(4),
+        _ if name.ends_with(".d") => Some(8),
+        _ => None,
+    }
+}
+
+fn bytes(addr: u64, value: u64, size: usize, mems: &mut Vec<MemoryTrace>) {
+    for i in 0..size {
+        mems.push(MemoryTrace {
+            kind: MemoryTraceKind::Uint8,
+            addr: addr + i as u64,
+            value: (value >> (8 * i)) & 0xff,
+        });
+    }
+}
+
+fn insn_size(bits: u32) -> usize {
+    if bits & 3 == 3 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Turns a spike commit log into the `LogTuple`s of one core, the
+/// first one seen unless another is given
+pub(crate) struct CommitLog<R> {
+    lines: Lines<R>,
+    count: usize,
+    core: Option<usize>,
+    state: State,
+    /// last `-l` line, for the description and trapping instructions
+    disasm: Option<Insn>,
+    /// whether the last trap went to S-mode, for its xtval
+    delegated: Option<bool>,
+}
+
+impl<R: BufRead> CommitLog<R> {
+    pub fn new(reader: R, core: Option<usize>) -> Self {
+        let mut state: State = (&Processor::new(ByteMap::default())).into();
+        state.prv = 3;
+        CommitLog {
+            lines: reader.lines(),
+            count: 0,
+            core,
+            state,
+            disasm: None,
+            delegated: None,
+        }
+    }
+
+    fn write_csr(&mut self, csr: u32, value: u64) {
+        let s = &mut self.state;
+        match csr {
+            0x100 => s.mstatus = s.mstatus & !SSTATUS_MASK | value & SSTATUS_MASK,
+            0x104 => s.mie = s.mie & !s.mideleg | value & s.mideleg,
+            0x144 => s.mip = s.mip & !s.mideleg | value & s.mideleg,
+            0x105 => s.stvec = value,
+            0x106 => s.scounteren = value,
+            0x140 => s.sscratch = value,
+            0x141 => s.sepc = value,
+            0x142 => s.scause = value,
+            0x143 => s.stval = value,
+            0x180 => s.satp = value,
+            0x300 => s.mstatus = value,
+            0x302 => s.medeleg = value,
+            0x303 => s.mideleg = value,
+            0x304 => s.mie = value,
+            0x305 => s.mtvec = value,
+            0x306 => s.mcounteren = value,
+            0x340 => s.mscratch = value,
+            0x341 => s.mepc = value,
+            0x342 => s.mcause = value,
+            0x343 => s.mtval = value,
+            0x344 => s.mip = value,
+            0xb00 => s.mcycle = value,
+            0xb02 => s.minstret = value,
+            _ => trace!("Ignoring write to csr 0x{:x}", csr),
+        }
+    }
+
+    // what spike did but didn't log
+    fn take_trap(&mut self, cause: u64, epc: u64) {
+        let s = &mut self.state;
+        let deleg = if cause >> 63 == 1 {
+            s.mideleg
+        } else {
+            s.medeleg
+        };
+        let delegated = s.prv <= 1 && (deleg >> (cause & 63)) & 1 == 1;
+        if delegated {
+            let sie = (s.mstatus >> 1) & 1;
+            s.mstatus = s.mstatus & !(1 << 8 | 1 << 5 | 1 << 1) | s.prv << 8 | sie << 5;
+            s.scause = cause;
+            s.sepc = epc;
+            s.prv = 1;
+        } else {
+            let mie = (s.mstatus >> 3) & 1;
+            s.mstatus = s.mstatus & !(3 << 11 | 1 << 7 | 1 << 3) | s.prv << 11 | mie << 7;
+            s.mcause = cause;
+            s.mepc = epc;
+            s.prv = 3;
+        }
+        self.delegated = Some(delegated);
+    }
+
+    fn commit(&mut self, c: Commit) -> LogTuple {
+        self.state.id += 1;
+        self.state.pc = c.pc;
+        self.state.prv = c.prv;
+        let state = self.state.clone();
+
+        let desc = match self.disasm.take() {
+            Some(ref insn) if insn.pc == c.pc => insn.desc.clone(),
+            _ => disasm(c.pc, c.bits).to_string(),
+        };
+        let mut mems = vec![];
+        bytes(c.pc, c.bits as u64, insn_size(c.bits), &mut mems);
+        if let (Some(&addr), Some(size)) = (c.loads.first(), load_size(c.bits)) {
+            let rd = ((c.bits >> 7) & 0x1f) as usize;
+            if let Some(&(_, value)) = c.xregs.iter().find(|&&(r, _)| r == rd) {
+                bytes(addr, value, size, &mut mems);
+            }
+        }
+        let store = c.stores.last().map(|&(addr, value, size)| MemoryTrace {
+            kind: match size {
+                1 => MemoryTraceKind::Uint8,
+                2 => MemoryTraceKind::Uint16,
+                4 => MemoryTraceKind::Uint32,
+                _ => MemoryTraceKind::Uint64,
+            },
+            addr,
+            value,
+        });
+
+        // xRET leaves privilege to mstatus, which the log may already
+        // show updated
+        match decode(c.bits).map(|i| OPCODES[i].name) {
+            Some("mret") => self.state.prv = (self.state.mstatus >> 11) & 3,
+            Some("sret") => self.state.prv = (self.state.mstatus >> 8) & 1,
+            _ => (),
+        }
+        for &(rd, value) in &c.xregs {
+            self.state.xregs[rd] = value;
+        }
+        // retired, at a CPI of 1 like spike
+        self.state.minstret += 1;
+        self.state.mcycle += 1;
+        for &(csr, value) in &c.csrs {
+            self.write_csr(csr, value);
+        }
+
+        LogTuple {
+            line: self.count,
+            state,
+            insn: Some(Insn {
+                pc: c.pc,
+                bits: c.bits,
+                desc,
+            }),
+            store,
+            mems,
+        }
+    }
+
+    // the instruction that trapped, as far as the log tells
+    fn trap(&mut self, cause: u64, epc: u64) -> LogTuple {
+        self.state.id += 1;
+        self.state.pc = epc;
+        let state = self.state.clone();
+
+        let insn = self.disasm.take().filter(|insn| insn.pc == epc);
+        let mut mems = vec![];
+        if let Some(ref insn) = insn {
+            bytes(insn.pc, insn.bits as u64, insn_size(insn.bits), &mut mems);
+        }
+        self.take_trap(cause, epc);
+
+        LogTuple {
+            line: self.count,
+            state,
+            insn,
+            store: None,
+            mems,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CommitLog<R> {
+    type Item = LogTuple;
+
+    fn next(&mut self) -> Option<LogTuple> {
+        // a line that doesn't read or parse ends the log
+        loop {
+            self.count += 1;
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Reading line {}: {}", self.count, e);
+                    return None;
+                }
+            };
+
+            let (core, parsed) = match parse_line(&line) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Parsing line {}: {}", self.count, e);
+                    error!("{}", line);
+                    return None;
+                }
+            };
+            if parsed == Line::Other || *self.core.get_or_insert(core) != core {
+                continue;
+            }
+            self.state.mhartid = core as u64;
+            trace!("Log {}", line);
+
+            match parsed {
+                Line::Commit(c) => return Some(self.commit(c)),
+                Line::Disasm(pc, bits, desc) => self.disasm = Some(Insn { pc, bits, desc }),
+                Line::Trap(cause, epc) => return Some(self.trap(cause, epc)),
+                Line::Tval(tval) => match self.delegated.take() {
+                    Some(true) => self.state.stval = tval,
+                    Some(false) => self.state.mtval = tval,
+                    None => (),
+                },
+                Line::Other => (),
+            }
+        }
+    }
+}
+
+// The CSRs of `s` as an instruction reads them
+fn read_csr(s: &State, csr: u32) -> Option<u64> {
+    Some(match csr {
+        0x100 => s.mstatus & SSTATUS_MASK,
+        0x102 => s.sedeleg,
+        0x103 => s.sideleg,
+        0x104 => s.mie & s.mideleg,
+        0x105 => s.stvec,
+        0x106 => s.scounteren,
+        0x140 => s.sscratch,
+        0x141 => s.sepc,
+        0x142 => s.scause,
+        0x143 => s.stval,
+        0x144 => s.mip & s.mideleg,
+        0x180 => s.satp,
+        0x300 => s.mstatus,
+        0x301 => s.misa,
+        0x302 => s.medeleg,
+        0x303 => s.mideleg,
+        0x304 => s.mie,
+        0x305 => s.mtvec,
+        0x306 => s.mcounteren,
+        0x340 => s.mscratch,
+        0x341 => s.mepc,
+        0x342 => s.mcause,
+        0x343 => s.mtval,
+        0x344 => s.mip,
+        0xb00 => s.mcycle,
+        0xb02 => s.minstret,
+        0xf14 => s.mhartid,
+        _ => return None,
+    })
+}
+
+// The CSRs an instruction writes, in the order spike lists them. The
+// supervisor views write through to the machine CSR behind them.
+fn written_csrs(name: &str, bits: u32) -> Vec<u32> {
+    let csr = bits >> 20;
+    let rs1 = (bits >> 15) & 0x1f;
+    let csr = match name {
+        "mret" => return vec![0x300],
+        "sret" => 0x100,
+        "csrrw" | "csrrwi" => csr,
+        "csrrs" | "csrrc" | "csrrsi" | "csrrci" if rs1 != 0 => csr,
+        _ => return vec![],
+    };
+    match csr {
+        0x100 => vec![0x100, 0x300],
+        0x104 => vec![0x104, 0x304],
+        0x144 => vec![0x144, 0x344],
+        csr => vec![csr],
+    }
+}
+
+// spike's `0x%0Nx` with N digits for `bits` bits
+fn value(bits: usize, v: u64) -> String {
+    format!("0x{:01$x}", v, bits / 4)
+}
+
+/// Writes the commit line spike's `--log-commits` prints for `t`, going
+/// by the state it left behind in `after`. Like spike, nothing is
+/// written for an instruction that trapped.
+pub(crate) fn write_commit<W: Write>(out: &mut W, t: &LogTuple, after: &State) -> io::Result<()> {
+    let insn = match t.insn {
+        Some(ref insn) => insn,
+        None => return Ok(()),
+    };
+    if trap(&t.state, after).is_some_and(|cause| cause >> 63 == 0) {
+        return Ok(());
+    }
+    let s = &t.state;
+    let name = decode(insn.bits).map(|i| OPCODES[i].name).unwrap_or("");
+
+    let mut line = format!(
+        "core{:4}: {} {} ({})",
+        s.mhartid,
+        s.prv,
+        value(64, insn.pc),
+        value(insn_size(insn.bits) * 8, insn.bits as u64)
+    );
+    let writes_rd = decode(insn.bits).is_some_and(|i| OPCODES[i].args.contains(&"rd"));
+    let rd = ((insn.bits >> 7) & 0x1f) as usize;
+    if writes_rd && rd != 0 && !name.starts_with("fence") {
+        line += &format!(" x{:<2} {}", rd, value(64, after.xregs[rd]));
+    }
+    for csr in written_csrs(name, insn.bits) {
+        if let Some(v) = read_csr(after, csr) {
+            line += &format!(" c{}_{} {}", csr, csr_name(csr), value(64, v));
+        }
+    }
+
+    // virtual addresses, as the instruction computed them
+    let i: Itype = insn.bits.into();
+    let base = s.xregs[i.rs1() as usize];
+    let atomic = name.starts_with("amo") || name.starts_with("lr.") || name.starts_with("sc.");
+    if atomic || load_size(insn.bits).is_some() {
+        let offset = if atomic { 0 } else { i.imm() };
+        if !name.starts_with("sc.") {
+            line += &format!(" mem {}", value(64, base.wrapping_add(offset as u64)));
+        }
+    }
+    if let Some(ref store) = t.store {
+        let st: Stype = insn.bits.into();
+        let offset = if atomic { 0 } else { st.imm() };
+        let bits = match store.kind {
+            MemoryTraceKind::Uint8 | MemoryTraceKind::Int8 => 8,
+            MemoryTraceKind::Uint16 | MemoryTraceKind::Int16 => 16,
+            MemoryTraceKind::Uint32 | MemoryTraceKind::Int32 => 32,
+            _ => 64,
+        };
+        line += &format!(
+            " mem {} {}",
+            value(64, base.wrapping_add(offset as u64)),
+            value(bits, store.value)
+        );
+    }
+    writeln!(out, "{}", line)
+}
+
+/// Validates every instruction of a spike commit log read from stdin
+pub fn validate_commits() -> io::Result<()> {
+    let stdin = io::stdin();
+    let log = CommitLog::new(BufReader::new(stdin.lock()), None);
+    validate_parallel(TransactionIterator::new(log), &Options::from_env())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOG: &str = "\
+core   0: 0x0000000080000000 (0x00000297) auipc   t0, 0x0
+core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+core   0: 3 0x0000000080000004 (0x0202b303) x6  0x00000000deadbeef mem 0x0000000080000020
+core   1: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+core   0: 3 0x0000000080000008 (0x0262b423) mem 0x0000000080000028 0x00000000deadbeef
+core   0: 3 0x000000008000000c (0x30529073) c773_mtvec 0x0000000080000000
+core   0: 0x0000000080000010 (0x00000073) ecall
+core   0: exception trap_machine_ecall, epc 0x0000000080000010
+core   0:           tval 0x0000000000000000
+core   0: 3 0x0000000080000000 (0x00000297) x 5 0x0000000080000000
+";
+
+    #[test]
+    fn parses_commit_lines() {
+        let (core, line) = parse_line(
+            "core   2: 1 0x0000000080000008 (0x0262b423) x7  0x0000000000000001 \
+             c321_sepc 0x0000000000000004 mem 0x0000000080000028 0x000000ef",
+        )
+        .unwrap();
+        assert_eq!(core, 2);
+        assert_eq!(
+            line,
+            Line::Commit(Commit {
+                prv: 1,
+                pc: 0x8000_0008,
+                bits: 0x0262_b423,
+                xregs: vec![(7, 1)],
+                csrs: vec![(0x141, 4)],
+                loads: vec![],
+                stores: vec![(0x8000_0028, 0xef, 4)],
+            })
+        );
+        assert_eq!(parse_line("bbl loader").unwrap().1, Line::Other);
+        assert!(parse_line("core   0: 3 0x80000000 (zz)").is_err());
+
+        // a bad line, parsed or read, ends the log
+        let commit = &b"core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000\n"[..];
+        for bad in &[&b"core   0: 3 0x80000000 (zz)\n"[..], b"core   0: \xff\n"] {
+            let log = [commit, bad, commit].concat();
+            assert_eq!(CommitLog::new(&log[..], None).count(), 1);
+        }
+    }
+
+    fn tuple(pc: u64, bits: u32, store: Option<MemoryTrace>) -> LogTuple {
+        let mut state = State {
+            pc,
+            prv: 3,
+            ..Default::default()
+        };
+        state.xregs[5] = 0x8000_0000;
+        LogTuple {
+            line: 0,
+            state,
+            insn: Some(Insn {
+                pc,
+                bits,
+                desc: String::new(),
+            }),
+            store,
+            mems: vec![],
+        }
+    }
+
+    #[test]
+    fn writes_commit_lines() {
+        let write = |t: &LogTuple, after: &State| {
+            let mut out = vec![];
+            write_commit(&mut out, t, after).expect("write");
+            String::from_utf8(out).expect("utf-8")
+        };
+        let lines: Vec<_> = LOG.lines().map(|l| format!("{}\n", l)).collect();
+
+        let ld = tuple(0x8000_0004, 0x0202_b303, None);
+        let mut after = ld.state.clone();
+        after.xregs[6] = 0xdead_beef;
+        assert_eq!(write(&ld, &after), lines[2]);
+
+        let store = MemoryTrace {
+            kind: MemoryTraceKind::Uint64,
+            addr: 0x8000_0028,
+            value: 0xdead_beef,
+        };
+        let sd = tuple(0x8000_0008, 0x0262_b423, Some(store));
+        assert_eq!(write(&sd, &sd.state), lines[4]);
+
+        let csrw = tuple(0x8000_000c, 0x3052_9073, None);
+        let mut after = csrw.state.clone();
+        after.mtvec = 0x8000_0000;
+        assert_eq!(write(&csrw, &after), lines[5]);
+
+        // spike doesn't commit an instruction that traps
+        let mut ecall = tuple(0x8000_0010, 0x73, None);
+        ecall.state.mtvec = 0x8000_0000;
+        let mut after = ecall.state.clone();
+        after.pc = 0x8000_0000;
+        after.mepc = 0x8000_0010;
+        after.mcause = 11;
+        assert_eq!(write(&ecall, &after), "");
+    }
+
+    #[test]
+    fn replays_commit_log() {
+        let tuples: Vec<_> = CommitLog::new(LOG.as_bytes(), None).collect();
+        assert_eq!(tuples.len(), 6);
+
+        assert_eq!(tuples[0].insn.as_ref().unwrap().desc, "auipc   t0, 0x0");
+        assert_eq!(tuples[1].state.xregs[5], 0x8000_0000);
+        // instruction and loaded bytes
+        assert_eq!(tuples[1].mems.len(), 4 + 8);
+        assert_eq!(tuples[2].store.as_ref().unwrap().value, 0xdead_beef);
+
+        let ecall = &tuples[4];
+        assert_eq!(ecall.insn.as_ref().unwrap().bits, 0x73);
+        assert_eq!(ecall.state.mtvec, 0x8000_0000);
+        assert_eq!(tuples[5].state.mepc, 0x8000_0010);
+        assert_eq!(tuples[5].state.mcause, 11);
+
+        // risk5 ends up where spike said it would
+        let matchers = &mut crate::build_matchers::<ByteMap>();
+        for t in TransactionIterator::new(tuples.into_iter()) {
+            t.validate(matchers).expect("transaction");
+        }
+    }
+}
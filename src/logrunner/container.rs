@@ -0,0 +1,450 @@
+use super::bincode::{LogLineReader, VERSION};
+use super::commitlog::CommitLog;
+use super::json::{LineIterator, TupleIterator};
+use super::LogTuple;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/*
+ *
+ * Trace container
+ * ---------------
+ * A header, compressed chunks of `LogTuple`s and an index of the
+ * chunks by instruction number and PC, so tools can jump straight to
+ * a window of a long trace:
+ *
+ * "risk5trc" | u32 header size | Header
+ * (u64 chunk size | deflated Vec<LogTuple>)* | u64 0
+ * Vec<ChunkInfo> | u64 index offset | "risk5idx"
+ *
+ * Sizes and offsets are little endian, everything else is bincode.
+ * The chunks can also be read in order from a pipe, without the
+ * index.
+ *
+ */
+
+const MAGIC: &[u8; 8] = b"risk5trc";
+const INDEX_MAGIC: &[u8; 8] = b"risk5idx";
+
+/// Tuples per chunk, the smallest unit read
+const CHUNK: usize = 16384;
+
+/// What a trace holds and where it came from
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Header {
+    /// format version of the records
+    pub version: u16,
+    /// e.g. rv64ima
+    pub isa: String,
+    /// what produced the trace, e.g. spike
+    pub source: String,
+}
+
+impl Header {
+    pub fn new(isa: String, source: String) -> Header {
+        Header {
+            version: VERSION,
+            isa,
+            source,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChunkInfo {
+    /// of the chunk's size
+    offset: u64,
+    /// instruction number of the first tuple
+    first: u64,
+    count: u64,
+    min_pc: u64,
+    max_pc: u64,
+}
+
+pub(crate) fn to_io(e: bincode::ErrorKind) -> io::Error {
+    match e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Header> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a trace container"));
+    }
+    let mut size = [0; 4];
+    reader.read_exact(&mut size)?;
+    let mut header = vec![0; u32::from_le_bytes(size) as usize];
+    reader.read_exact(&mut header)?;
+    let header: Header = bincode::deserialize(&header).map_err(|e| to_io(*e))?;
+    if header.version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported trace version {}", header.version),
+        ));
+    }
+    Ok(header)
+}
+
+// `None` at the end of the chunks
+fn read_chunk<R: Read>(reader: &mut R) -> io::Result<Option<Vec<LogTuple>>> {
+    let size = read_u64(reader)?;
+    if size == 0 {
+        return Ok(None);
+    }
+    let chunk = DeflateDecoder::new(reader.take(size));
+    bincode::deserialize_from(chunk)
+        .map(Some)
+        .map_err(|e| to_io(*e))
+}
+
+/// The tuples of a trace in any format: a container, JSON records, a
+/// spike commit log or a bincode stream, going by how it starts
+pub(crate) fn tuples<R>(mut reader: R) -> io::Result<Box<dyn Iterator<Item = LogTuple>>>
+where
+    R: Read + 'static,
+{
+    let mut head = vec![];
+    (&mut reader)
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut head)?;
+    let is_container = head == MAGIC;
+    let is_json = head.starts_with(b"{");
+    let is_commit_log = head.starts_with(b"core");
+    let reader = io::Cursor::new(head).chain(reader);
+    if is_json {
+        let lines = LineIterator::from_reader(io::BufReader::new(reader));
+        // a record that doesn't convert ends the trace
+        return Ok(Box::new(TupleIterator::new(lines).map_while(|t| {
+            t.to_logtuple()
+                .map_err(|e| error!("Converting JSON record: {}", e))
+                .ok()
+        })));
+    }
+    if is_commit_log {
+        return Ok(Box::new(CommitLog::new(io::BufReader::new(reader), None)));
+    }
+    if !is_container {
+        return Ok(Box::new(LogLineReader::new(reader).to_tuple()));
+    }
+
+    let stream = ContainerStream::new(reader)?;
+    let header = stream.header();
+    info!("Reading {} trace from {}", header.isa, header.source);
+    Ok(Box::new(stream))
+}
+
+/// Writes a container, keeping one chunk in memory
+pub(crate) struct ContainerWriter<W: Write> {
+    out: W,
+    offset: u64,
+    count: u64,
+    chunk: Vec<LogTuple>,
+    index: Vec<ChunkInfo>,
+}
+
+impl<W: Write> ContainerWriter<W> {
+    pub fn new(mut out: W, header: &Header) -> io::Result<Self> {
+        let header = bincode::serialize(header).map_err(|e| to_io(*e))?;
+        out.write_all(MAGIC)?;
+        out.write_all(&(header.len() as u32).to_le_bytes())?;
+        out.write_all(&header)?;
+        Ok(ContainerWriter {
+            out,
+            offset: (MAGIC.len() + 4 + header.len()) as u64,
+            count: 0,
+            chunk: Vec::with_capacity(CHUNK),
+            index: vec![],
+        })
+    }
+
+    pub fn push(&mut self, tuple: LogTuple) -> io::Result<()> {
+        self.chunk.push(tuple);
+        if self.chunk.len() == CHUNK {
+            self.write_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let mut z = DeflateEncoder::new(vec![], Compression::default());
+        bincode::serialize_into(&mut z, &self.chunk).map_err(|e| to_io(*e))?;
+        let data = z.finish()?;
+
+        let pcs = self.chunk.iter().map(|t| t.state.pc);
+        self.index.push(ChunkInfo {
+            offset: self.offset,
+            first: self.count,
+            count: self.chunk.len() as u64,
+            min_pc: pcs.clone().min().unwrap_or(0),
+            max_pc: pcs.max().unwrap_or(0),
+        });
+        self.out.write_all(&(data.len() as u64).to_le_bytes())?;
+        self.out.write_all(&data)?;
+        self.offset += 8 + data.len() as u64;
+        self.count += self.chunk.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Writes the last chunk and the index
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.out.write_all(&0u64.to_le_bytes())?;
+        let index = self.offset + 8;
+        bincode::serialize_into(&mut self.out, &self.index).map_err(|e| to_io(*e))?;
+        self.out.write_all(&index.to_le_bytes())?;
+        self.out.write_all(INDEX_MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Random access to a container through its index
+pub(crate) struct Container<R> {
+    reader: R,
+    header: Header,
+    index: Vec<ChunkInfo>,
+}
+
+impl<R: Read + Seek> Container<R> {
+    pub fn open(mut reader: R) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = read_header(&mut reader)?;
+
+        reader.seek(SeekFrom::End(-16))?;
+        let offset = read_u64(&mut reader)?;
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(invalid("trace has no index, was it cut short?"));
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        let index = bincode::deserialize_from(&mut reader).map_err(|e| to_io(*e))?;
+
+        Ok(Container {
+            reader,
+            header,
+            index,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Number of tuples in the trace
+    pub fn len(&self) -> u64 {
+        self.index.last().map_or(0, |c| c.first + c.count)
+    }
+
+    // the chunk holding instruction `n`
+    fn chunk_of(&self, n: u64) -> Option<usize> {
+        let i = self.index.partition_point(|c| c.first + c.count <= n);
+        Some(i).filter(|&i| i < self.index.len())
+    }
+
+    fn read(&mut self, chunk: usize) -> io::Result<Vec<LogTuple>> {
+        self.reader
+            .seek(SeekFrom::Start(self.index[chunk].offset))?;
+        read_chunk(&mut self.reader)?.ok_or_else(|| invalid("index points past the chunks"))
+    }
+
+    /// Tuples `start..start + count`, reading only the chunks they are in
+    pub fn window(&mut self, start: u64, count: u64) -> Window<'_, R> {
+        let end = start.saturating_add(count).min(self.len());
+        Window {
+            container: self,
+            next: start,
+            end,
+            chunk: vec![],
+            first: 0,
+        }
+    }
+
+    /// Instruction numbers of every tuple at `pc`. Only chunks whose PC
+    /// range covers it are read.
+    pub fn find_pc(&mut self, pc: u64) -> io::Result<Vec<u64>> {
+        let chunks: Vec<_> = (0..self.index.len())
+            .filter(|&i| self.index[i].min_pc <= pc && pc <= self.index[i].max_pc)
+            .collect();
+        let mut found = vec![];
+        for i in chunks {
+            let first = self.index[i].first;
+            let tuples = self.read(i)?;
+            found.extend((first..).zip(&tuples).filter_map(|(n, t)| {
+                if t.state.pc == pc {
+                    Some(n)
+                } else {
+                    None
+                }
+            }));
+        }
+        Ok(found)
+    }
+}
+
+/// Tuples of a window of a `Container`
+pub(crate) struct Window<'c, R> {
+    container: &'c mut Container<R>,
+    next: u64,
+    end: u64,
+    chunk: Vec<LogTuple>,
+    /// instruction number of `chunk[0]`
+    first: u64,
+}
+
+impl<'c, R: Read + Seek> Iterator for Window<'c, R> {
+    type Item = LogTuple;
+
+    fn next(&mut self) -> Option<LogTuple> {
+        if self.next >= self.end {
+            return None;
+        }
+        if self.next >= self.first + self.chunk.len() as u64 {
+            let i = self.container.chunk_of(self.next)?;
+            self.first = self.container.index[i].first;
+            self.chunk = match self.container.read(i) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!("Failed to read trace chunk {}: {}", i, e);
+                    panic!("Failed to read trace chunk");
+                }
+            };
+        }
+        let tuple = self.chunk[(self.next - self.first) as usize].clone();
+        self.next += 1;
+        Some(tuple)
+    }
+}
+
+/// Reads a container front to back, as from a pipe
+pub(crate) struct ContainerStream<R> {
+    reader: R,
+    header: Header,
+    chunk: std::vec::IntoIter<LogTuple>,
+    done: bool,
+}
+
+impl<R: Read> ContainerStream<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let header = read_header(&mut reader)?;
+        Ok(ContainerStream {
+            reader,
+            header,
+            chunk: vec![].into_iter(),
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+impl<R: Read> Iterator for ContainerStream<R> {
+    type Item = LogTuple;
+
+    fn next(&mut self) -> Option<LogTuple> {
+        loop {
+            if let Some(tuple) = self.chunk.next() {
+                return Some(tuple);
+            }
+            if self.done {
+                return None;
+            }
+            match read_chunk(&mut self.reader) {
+                Ok(Some(chunk)) => self.chunk = chunk.into_iter(),
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    error!("Failed to read trace chunk: {}", e);
+                    panic!("Failed to read trace chunk");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::State;
+    use super::*;
+    use std::io::Cursor;
+
+    fn tuple(n: u64) -> LogTuple {
+        LogTuple {
+            line: n as usize,
+            state: State {
+                id: n,
+                pc: 0x8000_0000 + 4 * (n % 10_000),
+                ..Default::default()
+            },
+            insn: None,
+            store: None,
+            mems: vec![],
+        }
+    }
+
+    #[test]
+    fn reads_windows() {
+        let header = Header::new("rv64ima".into(), "test".into());
+        let mut out = ContainerWriter::new(vec![], &header).expect("header");
+        for n in 0..40_000 {
+            out.push(tuple(n)).expect("push");
+        }
+        let data = out.finish().expect("finish");
+
+        let mut trace = Container::open(Cursor::new(&data[..])).expect("open");
+        assert_eq!(trace.header(), &header);
+        assert_eq!(trace.len(), 40_000);
+        // across the first chunk boundary
+        let ids: Vec<_> = trace.window(16_383, 3).map(|t| t.state.id).collect();
+        assert_eq!(ids, [16_383, 16_384, 16_385]);
+        assert_eq!(trace.window(39_999, 10).count(), 1);
+        assert_eq!(
+            trace.find_pc(0x8000_0000 + 4 * 1234).expect("find"),
+            [1234, 11_234, 21_234, 31_234]
+        );
+
+        let stream = ContainerStream::new(&data[..]).expect("stream");
+        assert_eq!(stream.header().source, "test");
+        assert!(stream.map(|t| t.state.id).eq(0..40_000));
+
+        let cut = &data[..data.len() - 1];
+        assert!(Container::open(Cursor::new(cut)).is_err());
+        assert_eq!(tuples(Cursor::new(data)).expect("tuples").count(), 40_000);
+
+        let log = "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000\n";
+        let pcs: Vec<_> = tuples(log.as_bytes())
+            .expect("tuples")
+            .map(|t| t.state.pc)
+            .collect();
+        assert_eq!(pcs, [0x8000_0000]);
+
+        // only the current version is read
+        let old = Header {
+            version: VERSION - 1,
+            ..header
+        };
+        let out = ContainerWriter::new(vec![], &old).expect("header");
+        let data = out.finish().expect("finish");
+        assert!(ContainerStream::new(&data[..]).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use super::bincode::write_header;
+use super::container::{tuples, Container};
+use super::transaction::TransactionIterator;
+use super::Transaction;
+use crate::machine::{parse_count, parse_number, parse_prv, parse_range};
+use crate::opcodes::{decode, OPCODES};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/*
+ *
+ * Filter
+ * ------
+ * Picks transactions out of a trace and splits them into gzipped
+ * transaction logs by opcode class, for `validate-stream`.
+ *
+ */
+
+/// Major opcodes logs are split by. Everything else goes to `other`.
+const CLASSES: &[(&str, u32)] = &[
+    ("load", 0b000_0011),
+    ("store", 0b010_0011),
+    ("comp", 0b011_0011),
+    ("imm", 0b001_0011),
+    ("immw", 0b001_1011),
+    ("branch", 0b110_0011),
+    ("wide", 0b011_1011),
+    ("system", 0b111_0011),
+    ("amo", 0b010_1111),
+];
+
+fn class(t: &Transaction) -> usize {
+    let major = t.insn.as_ref().map(|insn| insn.bits & 0x7f);
+    CLASSES
+        .iter()
+        .position(|&(_, mtch)| Some(mtch) == major)
+        .unwrap_or(CLASSES.len())
+}
+
+const USAGE: &str = "usage: filter [options] [TRACE]
+Reads the trace container TRACE, or a spike stream on stdin, and writes
+the transactions passing every filter to one log per opcode class.
+  --from N         skip to instruction N, e.g. 180M for 180 million
+  --from-pc PC     skip to where TRACE first reaches PC
+  --count N        stop after N instructions
+  --pc LO:HI       only pcs from LO to HI, inclusive
+  --prv P          only privilege P: u, s, m or 0, 1, 3
+  --mask M         only insns whose bits & M equal --match
+  --match V
+  --mnemonic NAME  only NAME, e.g. addi; may be repeated
+  --trap           only transactions that trap
+  --no-trap        only transactions that don't
+  --out DIR        where logs go, assets/transactions-logs by default
+  --rotate N       transactions per log file, 250000 by default
+  --sample N       keep N random transactions per class
+  --seed N         seed for --sample";
+
+fn usage_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", msg, USAGE))
+}
+
+/// Command line options of `filter`
+#[derive(Debug)]
+pub(crate) struct Filter {
+    trace: Option<String>,
+    from: u64,
+    from_pc: Option<u64>,
+    count: Option<u64>,
+    pc: Option<(u64, u64)>,
+    prv: Option<u64>,
+    mask: u32,
+    mtch: u32,
+    mnemonics: Vec<String>,
+    trap: Option<bool>,
+    out: PathBuf,
+    rotate: usize,
+    sample: Option<usize>,
+    seed: u64,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            trace: None,
+            from: 0,
+            from_pc: None,
+            count: None,
+            pc: None,
+            prv: None,
+            mask: 0,
+            mtch: 0,
+            mnemonics: vec![],
+            trap: None,
+            out: "assets/transactions-logs".into(),
+            rotate: 250_000,
+            sample: None,
+            seed: 1,
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> io::Result<Filter> {
+        let mut f = Filter::default();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| usage_error(&format!("missing value for {}", arg)))
+            };
+            let bad_value = |e: String| usage_error(&format!("{}: {}", arg, e));
+            let number = |v: String| parse_number(&v).map_err(bad_value);
+            let count = |v: String| parse_count(&v).map_err(bad_value);
+            match arg.as_str() {
+                "--from" => f.from = count(value()?)?,
+                "--from-pc" => f.from_pc = Some(number(value()?)?),
+                "--count" => f.count = Some(count(value()?)?),
+                "--pc" => f.pc = Some(parse_range(&value()?).map_err(bad_value)?),
+                "--prv" => f.prv = Some(parse_prv(&value()?).map_err(bad_value)?),
+                "--mask" => f.mask = number(value()?)? as u32,
+                "--match" => f.mtch = number(value()?)? as u32,
+                "--mnemonic" => {
+                    let name = value()?;
+                    if !OPCODES.iter().any(|op| op.name == name) {
+                        return Err(bad_value(format!("unknown mnemonic '{}'", name)));
+                    }
+                    f.mnemonics.push(name);
+                }
+                "--trap" => f.trap = Some(true),
+                "--no-trap" => f.trap = Some(false),
+                "--out" => f.out = value()?.into(),
+                "--rotate" => f.rotate = number(value()?)?.max(1) as usize,
+                "--sample" => f.sample = Some(number(value()?)? as usize),
+                "--seed" => f.seed = number(value()?)?,
+                "-h" | "--help" => return Err(usage_error("")),
+                a if a.starts_with('-') => {
+                    return Err(usage_error(&format!("unknown argument {}", a)))
+                }
+                _ if f.trace.is_none() => f.trace = Some(arg),
+                _ => return Err(usage_error("more than one trace given")),
+            }
+        }
+        if f.from_pc.is_some() && f.trace.is_none() {
+            return Err(usage_error("--from-pc needs a trace container"));
+        }
+        Ok(f)
+    }
+
+    /// Whether `t` passes every filter but the instruction range
+    fn matches(&self, t: &Transaction) -> bool {
+        let pc = t.state.pc;
+        if self.pc.is_some_and(|(lo, hi)| pc < lo || pc > hi)
+            || self.prv.is_some_and(|prv| prv != t.state.prv)
+            || self.trap.is_some_and(|trap| trap != t.trapped())
+        {
+            return false;
+        }
+        let bits = match t.insn {
+            Some(ref insn) => insn.bits,
+            // nothing to match the instruction filters against
+            None => return self.mask == 0 && self.mnemonics.is_empty(),
+        };
+        if bits & self.mask != self.mtch {
+            return false;
+        }
+        self.mnemonics.is_empty()
+            || decode(bits).is_some_and(|i| self.mnemonics.iter().any(|m| m == OPCODES[i].name))
+    }
+}
+
+/// Gzipped logs of one class, rotated every `rotate` transactions
+struct LogFile {
+    dir: PathBuf,
+    name: &'static str,
+    rotate: usize,
+    idx: usize,
+    count: usize,
+    total: usize,
+    f: Option<GzEncoder<BufWriter<File>>>,
+}
+
+impl LogFile {
+    fn new(dir: &Path, name: &'static str, rotate: usize) -> Self {
+        LogFile {
+            dir: dir.to_path_buf(),
+            name,
+            rotate,
+            idx: 0,
+            count: 0,
+            total: 0,
+            f: None,
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(f) = self.f.take() {
+            f.finish()?;
+            self.idx += 1;
+        }
+        let ord = if self.idx == 0 {
+            String::new()
+        } else {
+            format!(".{}", self.idx)
+        };
+        let path = self.dir.join(format!("{}{}.trans.log.gz", self.name, ord));
+        let file = File::create(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let mut f = GzEncoder::new(BufWriter::new(file), Compression::default());
+        write_header(&mut f)?;
+        self.f = Some(f);
+        self.count = 0;
+        Ok(())
+    }
+
+    fn store(&mut self, t: &Transaction) -> io::Result<()> {
+        if self.f.is_none() || self.count == self.rotate {
+            self.open()?;
+        }
+        let f = self.f.as_mut().expect("open log");
+        bincode::serialize_into(f, t).map_err(|e| match *e {
+            bincode::ErrorKind::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, format!("{}", e)),
+        })?;
+        self.count += 1;
+        self.total += 1;
+        Ok(())
+    }
+
+    /// Closes the last file, returning how many transactions were logged
+    fn finish(self) -> io::Result<usize> {
+        if let Some(f) = self.f {
+            f.finish()?;
+        }
+        Ok(self.total)
+    }
+}
+
+// xorshift64*, plenty for picking samples
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+}
+
+/// Reservoir of `n` transactions picked uniformly from those offered
+struct Sample {
+    n: usize,
+    seen: u64,
+    kept: Vec<(u64, Transaction)>,
+}
+
+impl Sample {
+    fn offer(&mut self, index: u64, t: Transaction, rng: &mut Rng) {
+        self.seen += 1;
+        if self.kept.len() < self.n {
+            self.kept.push((index, t));
+        } else {
+            let i = rng.below(self.seen) as usize;
+            if i < self.n {
+                self.kept[i] = (index, t);
+            }
+        }
+    }
+}
+
+fn run(f: &Filter) -> io::Result<()> {
+    let mut container;
+    let transactions: Box<dyn Iterator<Item = Transaction>> = match f.trace {
+        Some(ref path) => {
+            let file = File::open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            container = Container::open(io::BufReader::new(file))?;
+            let header = container.header();
+            info!("{}: {} trace from {}", path, header.isa, header.source);
+            let from = match f.from_pc {
+                Some(pc) => *container.find_pc(pc)?.first().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("the trace never reaches 0x{:x}", pc),
+                    )
+                })?,
+                None => f.from,
+            };
+            // the tuple after the window closes its last transaction
+            let count = f.count.map_or(u64::MAX, |n| n + 1);
+            Box::new(TransactionIterator::new(container.window(from, count)))
+        }
+        None => {
+            let reader = tuples(io::BufReader::new(io::stdin()))?;
+            let transactions = TransactionIterator::new(reader).skip(f.from as usize);
+            match f.count {
+                Some(n) => Box::new(transactions.take(n as usize)),
+                None => Box::new(transactions),
+            }
+        }
+    };
+
+    std::fs::create_dir_all(&f.out)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", f.out.display(), e)))?;
+    let names = CLASSES.iter().map(|&(name, _)| name).chain(Some("other"));
+    let mut logs: Vec<_> = names.map(|n| LogFile::new(&f.out, n, f.rotate)).collect();
+    let mut samples: Option<Vec<_>> = f.sample.map(|n| {
+        (0..logs.len())
+            .map(|_| Sample {
+                n,
+                seen: 0,
+                kept: vec![],
+            })
+            .collect()
+    });
+    let mut rng = Rng::new(f.seed);
+
+    for (i, t) in (0..).zip(transactions) {
+        if !f.matches(&t) {
+            continue;
+        }
+        let c = class(&t);
+        match samples {
+            Some(ref mut samples) => samples[c].offer(i, t, &mut rng),
+            None => logs[c].store(&t)?,
+        }
+    }
+
+    for (c, sample) in samples.into_iter().flatten().enumerate() {
+        let mut kept = sample.kept;
+        kept.sort_by_key(|&(i, _)| i);
+        for (_, t) in &kept {
+            logs[c].store(t)?;
+        }
+    }
+    for log in logs {
+        let name = log.name;
+        let total = log.finish()?;
+        if total > 0 {
+            println!("{:>9} {}", total, name);
+        }
+    }
+    Ok(())
+}
+
+/// Runs `filter` with the process arguments
+pub fn filter() -> io::Result<()> {
+    run(&Filter::parse(std::env::args().skip(1))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Insn, State};
+    use super::*;
+
+    fn parse(args: &[&str]) -> io::Result<Filter> {
+        Filter::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    fn transaction(pc: u64, bits: u32, prv: u64) -> Transaction {
+        let state = State {
+            pc,
+            prv,
+            mtvec: 0x8000_0100,
+            ..Default::default()
+        };
+        let mut after = state.clone();
+        after.pc += 4;
+        Transaction {
+            state,
+            insn: Some(Insn {
+                pc,
+                bits,
+                desc: String::new(),
+            }),
+            mems: vec![],
+            store: None,
+            after,
+        }
+    }
+
+    #[test]
+    fn matches_filters() {
+        let addi = transaction(0x8000_0000, 0x0010_0293, 3);
+        let ld = transaction(0x8000_1000, 0x0003_3283, 1);
+        let mut ecall = transaction(0x8000_2000, 0x0000_0073, 1);
+        ecall.after.pc = 0x8000_0100;
+        ecall.after.prv = 3;
+        ecall.after.mepc = 0x8000_2000;
+        assert_eq!(class(&ld), 0);
+        assert_eq!(class(&addi), 3);
+        assert!(ecall.trapped() && !ld.trapped());
+
+        let f = parse(&["--pc", "0x80000000:0x80001fff", "--prv", "s"]).expect("filter");
+        assert!(f.matches(&ld));
+        assert!(!f.matches(&addi));
+        assert!(!f.matches(&ecall));
+
+        let f = parse(&["--mnemonic", "addi", "--mnemonic", "ld"]).expect("filter");
+        assert!(f.matches(&addi) && f.matches(&ld) && !f.matches(&ecall));
+        let f = parse(&["--mask", "0x7f", "--match", "0x73", "--trap"]).expect("filter");
+        assert!(f.matches(&ecall) && !f.matches(&addi));
+        let f = parse(&["--no-trap", "trace.bin"]).expect("filter");
+        assert_eq!(f.trace.as_deref(), Some("trace.bin"));
+        assert!(!f.matches(&ecall));
+
+        assert!(parse(&["--mnemonic", "frob"]).is_err());
+        assert!(parse(&["--prv", "h"]).is_err());
+        assert!(parse(&["--from-pc", "0x80000000"]).is_err());
+    }
+
+    #[test]
+    fn samples_and_rotates() {
+        let mut rng = Rng::new(7);
+        let mut sample = Sample {
+            n: 3,
+            seen: 0,
+            kept: vec![],
+        };
+        for i in 0..100 {
+            sample.offer(i, transaction(0x8000_0000 + 4 * i, 0x13, 3), &mut rng);
+        }
+        assert_eq!(sample.seen, 100);
+        assert_eq!(sample.kept.len(), 3);
+        assert!(sample.kept.iter().any(|&(i, _)| i >= 3));
+
+        let dir = std::env::temp_dir().join(format!("risk5-filter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("dir");
+        let mut log = LogFile::new(&dir, "imm", 2);
+        for (_, t) in &sample.kept {
+            log.store(t).expect("store");
+        }
+        assert_eq!(log.finish().expect("finish"), 3);
+        assert!(dir.join("imm.trans.log.gz").exists());
+        assert!(dir.join("imm.1.trans.log.gz").exists());
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+}
@@ -1,4 +1,6 @@
 use super::*;
+use crate::processor::DEFAULT_MISA;
+use std::convert::{TryFrom, TryInto};
 
 mod lineiterator;
 mod tupleiterator;
@@ -42,6 +44,9 @@ pub(crate) struct JsonInsn {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct JsonState {
+    /// absent before version 2, which added the CSRs from `misa` on
+    #[serde(default)]
+    version: Option<u16>,
     id: u64,
     pc: String,
     prv: String,
@@ -66,6 +71,19 @@ pub(crate) struct JsonState {
     satp: String,
     scause: String,
 
+    #[serde(default)]
+    misa: Option<String>,
+    #[serde(default)]
+    mhartid: Option<String>,
+    #[serde(default)]
+    mcycle: Option<String>,
+    #[serde(default)]
+    sedeleg: Option<String>,
+    #[serde(default)]
+    sideleg: Option<String>,
+    #[serde(default)]
+    sip: Option<String>,
+
     xregs: Vec<String>,
 }
 
@@ -78,77 +96,326 @@ pub(crate) struct JsonMemory {
 }
 
 impl JsonLogTuple {
-    pub fn to_logtuple(self) -> LogTuple {
-        LogTuple {
+    pub fn to_logtuple(self) -> io::Result<LogTuple> {
+        Ok(LogTuple {
             line: self.line,
-            state: self.state.into(),
-            insn: self.insn.map(|i| i.into()),
-            store: self.store.map(|n| n.into()),
-            mems: self.mems.into_iter().map(|n| n.into()).collect(),
-        }
+            state: self.state.try_into()?,
+            insn: self.insn.map(TryInto::try_into).transpose()?,
+            store: self.store.map(TryInto::try_into).transpose()?,
+            mems: self
+                .mems
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<io::Result<_>>()?,
+        })
     }
 }
 
-impl Into<Insn> for JsonInsn {
-    fn into(self) -> Insn {
-        Insn {
-            pc: string_to_u64(&self.pc),
-            bits: string_to_u32(&self.bits),
-            desc: self.desc,
-        }
+impl TryFrom<JsonInsn> for Insn {
+    type Error = io::Error;
+
+    fn try_from(insn: JsonInsn) -> io::Result<Insn> {
+        Ok(Insn {
+            pc: string_to_u64(&insn.pc)?,
+            bits: string_to_u32(&insn.bits)?,
+            desc: insn.desc,
+        })
     }
 }
 
-impl Into<State> for JsonState {
-    fn into(self) -> State {
+impl TryFrom<JsonState> for State {
+    type Error = io::Error;
+
+    fn try_from(state: JsonState) -> io::Result<State> {
+        if state.xregs.len() != 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("state has {} xregs", state.xregs.len()),
+            ));
+        }
         let mut xregs = [0; 32];
-        for (i, v) in self.xregs.iter().enumerate() {
-            xregs[i] = string_to_u64(v);
+        for (i, v) in state.xregs.iter().enumerate() {
+            xregs[i] = string_to_u64(v)?;
         }
 
-        State {
-            id: self.id,
-            pc: string_to_u64(&self.pc),
-            prv: string_to_u64(&self.prv),
-            mstatus: string_to_u64(&self.mstatus),
-            mscratch: string_to_u64(&self.mscratch),
-            mtvec: string_to_u64(&self.mtvec),
-            mcause: string_to_u64(&self.mcause),
-            mepc: string_to_u64(&self.mepc),
-            minstret: string_to_u64(&self.minstret),
-            mie: string_to_u64(&self.mie),
-            mip: string_to_u64(&self.mip),
-            medeleg: string_to_u64(&self.medeleg),
-            mideleg: string_to_u64(&self.mideleg),
-            mcounteren: string_to_u64(&self.mcounteren),
-            scounteren: string_to_u64(&self.scounteren),
-            sepc: string_to_u64(&self.sepc),
-            stval: string_to_u64(&self.stval),
-            sscratch: string_to_u64(&self.sscratch),
-            stvec: string_to_u64(&self.stvec),
-            satp: string_to_u64(&self.satp),
-            scause: string_to_u64(&self.scause),
-            xregs: xregs,
+        let legacy = match state.version.unwrap_or(1) {
+            1 => true,
+            2 => false,
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported state version {}", v),
+                ))
+            }
+        };
+        // left at reset values in version 1
+        let added = |name, v: &Option<String>, reset| match v {
+            Some(v) => string_to_u64(v),
+            None if legacy => Ok(reset),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("version 2 state without {}", name),
+            )),
+        };
+        let minstret = string_to_u64(&state.minstret)?;
+
+        Ok(State {
+            id: state.id,
+            legacy,
+            pc: string_to_u64(&state.pc)?,
+            prv: string_to_u64(&state.prv)?,
+            mstatus: string_to_u64(&state.mstatus)?,
+            mscratch: string_to_u64(&state.mscratch)?,
+            mtvec: string_to_u64(&state.mtvec)?,
+            mcause: string_to_u64(&state.mcause)?,
+            mepc: string_to_u64(&state.mepc)?,
+            minstret,
+            mie: string_to_u64(&state.mie)?,
+            mip: string_to_u64(&state.mip)?,
+            medeleg: string_to_u64(&state.medeleg)?,
+            mideleg: string_to_u64(&state.mideleg)?,
+            mcounteren: string_to_u64(&state.mcounteren)?,
+            scounteren: string_to_u64(&state.scounteren)?,
+            sepc: string_to_u64(&state.sepc)?,
+            stval: string_to_u64(&state.stval)?,
+            sscratch: string_to_u64(&state.sscratch)?,
+            stvec: string_to_u64(&state.stvec)?,
+            satp: string_to_u64(&state.satp)?,
+            scause: string_to_u64(&state.scause)?,
+            mtval: string_to_u64(&state.mtval)?,
+            misa: added("misa", &state.misa, DEFAULT_MISA)?,
+            mhartid: added("mhartid", &state.mhartid, 0)?,
+            mcycle: added("mcycle", &state.mcycle, minstret)?,
+            sedeleg: added("sedeleg", &state.sedeleg, 0)?,
+            sideleg: added("sideleg", &state.sideleg, 0)?,
+            sip: added("sip", &state.sip, 0)?,
+            xregs,
+        })
+    }
+}
+
+impl TryFrom<JsonMemory> for MemoryTrace {
+    type Error = io::Error;
+
+    fn try_from(mem: JsonMemory) -> io::Result<MemoryTrace> {
+        use MemoryTraceKind::*;
+        let kind = match mem.kind.as_str() {
+            "uint8" => Uint8,
+            "uint16" => Uint16,
+            "uint32" => Uint32,
+            "uint64" => Uint64,
+            "int8" => Int8,
+            "int16" => Int16,
+            "int32" => Int32,
+            "int64" => Int64,
+            "read" => Read,
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown memory type {:?}", kind),
+                ))
+            }
+        };
+        Ok(MemoryTrace {
+            kind,
+            addr: string_to_u64(&mem.addr)?,
+            value: string_to_u64(&mem.value)?,
+        })
+    }
+}
+
+impl JsonInsn {
+    pub(crate) fn new(insn: &Insn, core: usize) -> JsonInsn {
+        JsonInsn {
+            core,
+            pc: hex(insn.pc),
+            bits: hex(u64::from(insn.bits)),
+            desc: insn.desc.clone(),
         }
     }
 }
 
-impl Into<MemoryTrace> for JsonMemory {
-    fn into(self) -> MemoryTrace {
-        unimplemented!("Removed for bincode");
-        // let JsonMemory { kind, addr, value } = self;
-        // MemoryTrace {
-        //     kind: kind,
-        //     addr: string_to_u64(&addr),
-        //     value: string_to_u64(&value),
-        // }
+impl From<&State> for JsonState {
+    /// Always version 2, even for a state read from a version 1 trace
+    fn from(s: &State) -> JsonState {
+        JsonState {
+            version: Some(2),
+            id: s.id,
+            pc: hex(s.pc),
+            prv: hex(s.prv),
+            mstatus: hex(s.mstatus),
+            mepc: hex(s.mepc),
+            mtval: hex(s.mtval),
+            mscratch: hex(s.mscratch),
+            mtvec: hex(s.mtvec),
+            mcause: hex(s.mcause),
+            minstret: hex(s.minstret),
+            mie: hex(s.mie),
+            mip: hex(s.mip),
+            medeleg: hex(s.medeleg),
+            mideleg: hex(s.mideleg),
+            mcounteren: hex(s.mcounteren),
+            scounteren: hex(s.scounteren),
+            sepc: hex(s.sepc),
+            stval: hex(s.stval),
+            sscratch: hex(s.sscratch),
+            stvec: hex(s.stvec),
+            satp: hex(s.satp),
+            scause: hex(s.scause),
+            misa: Some(hex(s.misa)),
+            mhartid: Some(hex(s.mhartid)),
+            mcycle: Some(hex(s.mcycle)),
+            sedeleg: Some(hex(s.sedeleg)),
+            sideleg: Some(hex(s.sideleg)),
+            sip: Some(hex(s.sip)),
+            xregs: s.xregs.iter().map(|&x| hex(x)).collect(),
+        }
+    }
+}
+
+impl From<&MemoryTrace> for JsonMemory {
+    fn from(mem: &MemoryTrace) -> JsonMemory {
+        use MemoryTraceKind::*;
+        let kind = match mem.kind {
+            Uint8 => "uint8",
+            Uint16 => "uint16",
+            Uint32 => "uint32",
+            Uint64 => "uint64",
+            Int8 => "int8",
+            Int16 => "int16",
+            Int32 => "int32",
+            Int64 => "int64",
+            Read => "read",
+        };
+        JsonMemory {
+            kind: kind.into(),
+            addr: hex(mem.addr),
+            value: hex(mem.value),
+        }
     }
 }
 
-fn string_to_u64(s: &String) -> u64 {
-    u64::from_str_radix(&s[2..], 16).expect("hex parse")
+fn hex(v: u64) -> String {
+    format!("0x{:x}", v)
 }
 
-fn string_to_u32(s: &String) -> u32 {
-    u32::from_str_radix(&s[2..], 16).expect("hex parse")
+fn string_to_u64(s: &str) -> io::Result<u64> {
+    s.strip_prefix("0x")
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad hex value {:?}", s)))
+}
+
+fn string_to_u32(s: &str) -> io::Result<u32> {
+    let v = string_to_u64(s)?;
+    if v > u32::MAX as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not fit 32 bits", s),
+        ));
+    }
+    Ok(v as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STATE: &str = r#"{"kind": "state", "id": 1, "pc": "0x80000000", "prv": "0x3",
+        "mstatus": "0x0", "mepc": "0x0", "mtval": "0x0", "mscratch": "0x0", "mtvec": "0x0",
+        "mcause": "0x0", "minstret": "0x0", "mie": "0x0", "mip": "0x0", "medeleg": "0x0",
+        "mideleg": "0x0", "mcounteren": "0x0", "scounteren": "0x0", "sepc": "0x0",
+        "stval": "0x0", "sscratch": "0x0", "stvec": "0x0", "satp": "0x0", "scause": "0x0",
+        "xregs": ["0x0", "0x0", "0x0", "0x0", "0x0", "0x2a", "0x0", "0x0", "0x0", "0x0",
+        "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0",
+        "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0", "0x0"]}"#;
+
+    fn lines(text: &str) -> LineIterator<&[u8]> {
+        LineIterator::from_reader(text.as_bytes())
+    }
+
+    #[test]
+    fn converts_memory_records() {
+        let state = STATE.replace('\n', " ");
+        // the reader starts from the first full tuple
+        let text = [
+            &state,
+            r#"{"kind": "mark"}"#,
+            &state,
+            r#"{"kind": "insn", "core": 0, "pc": "0x80000000", "bits": "0x00553023", "desc": "sd t0, 0(a0)"}"#,
+            r#"{"kind": "mem", "type": "uint8", "addr": "0x80001000", "value": "0xff"}"#,
+            r#"{"kind": "store", "type": "uint64", "addr": "0x80001000", "value": "0x2a"}"#,
+            r#"{"kind": "mark"}"#,
+        ]
+        .join("\n");
+
+        let tuple = TupleIterator::new(lines(&text)).next().expect("tuple");
+        let tuple = tuple.to_logtuple().expect("convert");
+        assert_eq!(tuple.state.xregs[5], 0x2a);
+        assert_eq!(tuple.insn.expect("insn").bits, 0x0055_3023);
+        assert_eq!(tuple.mems[0].addr, 0x8000_1000);
+        assert_eq!(tuple.mems[0].value, 0xff);
+        let store = tuple.store.expect("store");
+        assert!(matches!(store.kind, MemoryTraceKind::Uint64));
+        assert_eq!(store.value, 0x2a);
+    }
+
+    #[test]
+    fn reports_bad_values() {
+        let mem = |kind: &str, value: &str| JsonMemory {
+            kind: kind.into(),
+            addr: "0x0".into(),
+            value: value.into(),
+        };
+        assert!(MemoryTrace::try_from(mem("int32", "0x1")).is_ok());
+        let e = MemoryTrace::try_from(mem("uint8", "12")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(MemoryTrace::try_from(mem("uint8", "0xzz")).is_err());
+        assert!(MemoryTrace::try_from(mem("float", "0x1")).is_err());
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let json: JsonState = serde_json::from_str(STATE).expect("json");
+        let mut state = State::try_from(json).expect("state");
+        state.legacy = false;
+        state.mcycle = 0x10;
+        let text = serde_json::to_string(&LogLine::State((&state).into())).expect("write");
+        let json: JsonState = serde_json::from_str(&text).expect("json");
+        assert_eq!(State::try_from(json).expect("state"), state);
+
+        let mem = MemoryTrace {
+            kind: MemoryTraceKind::Int16,
+            addr: 0x8000_0002,
+            value: 0xffff,
+        };
+        let read = MemoryTrace::try_from(JsonMemory::from(&mem)).expect("memory");
+        assert_eq!(
+            (read.kind, read.addr, read.value),
+            (mem.kind, mem.addr, mem.value)
+        );
+    }
+
+    #[test]
+    fn reads_state_versions() {
+        let state = |text: &str| -> io::Result<State> {
+            let json: JsonState = serde_json::from_str(text).expect("json");
+            json.try_into()
+        };
+        let old = state(STATE).expect("version 1");
+        assert!(old.legacy);
+        assert_eq!(old.misa, DEFAULT_MISA);
+
+        let v2 = STATE.replace(r#""kind": "state","#, r#""kind": "state", "version": 2,"#);
+        let e = state(&v2).unwrap_err();
+        assert_eq!(e.to_string(), "version 2 state without misa");
+        let v2 = v2.replace(
+            r#""scause": "0x0","#,
+            r#""scause": "0x0", "misa": "0x8000000000141105", "mhartid": "0x1",
+            "mcycle": "0x9", "sedeleg": "0x0", "sideleg": "0x0", "sip": "0x2","#,
+        );
+        let new = state(&v2).expect("version 2");
+        assert!(!new.legacy);
+        assert_eq!(new.mhartid, 1);
+        assert_eq!(new.sip, 2);
+    }
 }
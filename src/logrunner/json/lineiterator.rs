@@ -1,15 +1,20 @@
 use super::*;
 use std::io::BufRead;
 
-pub(crate) struct LineIterator {
+pub(crate) struct LineIterator<R = BufReader<io::Stdin>> {
     pub(crate) count: usize,
     pub(crate) had_first_state: bool,
-    pub(crate) lines: Lines<BufReader<io::Stdin>>,
+    pub(crate) lines: Lines<R>,
 }
 
 impl LineIterator {
     pub fn new() -> Self {
-        let reader = BufReader::new(io::stdin());
+        Self::from_reader(BufReader::new(io::stdin()))
+    }
+}
+
+impl<R: BufRead> LineIterator<R> {
+    pub fn from_reader(reader: R) -> Self {
         LineIterator {
             count: 0,
             had_first_state: false,
@@ -18,7 +23,7 @@ impl LineIterator {
     }
 }
 
-impl Iterator for LineIterator {
+impl<R: BufRead> Iterator for LineIterator<R> {
     type Item = (usize, LogLine, String);
 
     fn next(&mut self) -> Option<(usize, LogLine, String)> {
@@ -0,0 +1,258 @@
+use super::container::tuples;
+use super::{Insn, State};
+use crate::config::Config;
+use crate::console::Console;
+use crate::disasm::disasm;
+use crate::matcher::Matchers;
+use crate::memory::Memory;
+use crate::{build_matchers, regs, Processor};
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// Spike used when no other is given
+pub const DEFAULT_SPIKE: &str = "assets/compliance/bin/spike";
+
+/// The first point where risk5 and the reference disagree
+#[derive(Debug)]
+pub struct Divergence {
+    /// instructions both agreed on
+    pub step: u64,
+    /// the instruction that led to the difference
+    pub insn: Option<Insn>,
+    pub before: Option<State>,
+    pub expected: State,
+    pub actual: State,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged from spike after {} instructions", self.step)?;
+        match self.insn {
+            Some(ref insn) => writeln!(f, "Insn:   {} ({})", insn, disasm(insn.pc, insn.bits))?,
+            None => writeln!(f, "Insn:   None")?,
+        }
+        if let Some(ref before) = self.before {
+            writeln!(f, "Before: {}", before)?;
+        }
+        writeln!(f, "Spike:  {}", self.expected)?;
+        write!(f, "risk5:  {}", self.actual)?;
+        for (i, (expected, actual)) in self
+            .expected
+            .xregs
+            .iter()
+            .zip(self.actual.xregs.iter())
+            .enumerate()
+        {
+            if expected != actual {
+                write!(
+                    f,
+                    "\n{:>4}:   spike=0x{:016x} risk5=0x{:016x}",
+                    regs::REG_NAMES[i],
+                    expected,
+                    actual
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Steps `cpu` once for every state of the reference, checking
+/// the whole state before each step. Returns the number of
+/// instructions executed if the reference runs out first.
+pub(crate) fn compare<M, I>(
+    cpu: &mut Processor<M>,
+    matchers: &mut Matchers<M>,
+    reference: I,
+) -> Result<u64, Box<Divergence>>
+where
+    M: Memory,
+    I: Iterator<Item = (State, Option<Insn>)>,
+{
+    let mut before: Option<State> = None;
+    let mut insn: Option<Insn> = None;
+    let mut step = 0;
+
+    for (state, next) in reference {
+        if Some(&state) == before.as_ref() {
+            debug!("Last state and this state are the same");
+            continue;
+        }
+
+        if !state.validate(&*cpu, before.clone()) {
+            return Err(Box::new(Divergence {
+                step,
+                insn,
+                before,
+                expected: state,
+                actual: (&*cpu).into(),
+            }));
+        }
+
+        if cpu.is_stopped() {
+            break;
+        }
+        cpu.step(matchers);
+        cpu.handle_interrupt();
+
+        step += 1;
+        before = Some(state);
+        insn = next;
+    }
+
+    Ok(step)
+}
+
+fn spike_command(spike: &str) -> Command {
+    let mut command = Command::new(spike);
+    // the bundled spike keeps its libraries next to bin/
+    if let Some(lib) = Path::new(spike).parent().and_then(Path::parent) {
+        let lib = lib.join("lib");
+        if lib.is_dir() {
+            command.env("LD_LIBRARY_PATH", lib);
+        }
+    }
+    command
+}
+
+// Stock spike logs commits to stderr when asked, where the bundled one
+// writes JSON records to stdout. Its usage tells them apart.
+fn logs_commits(spike: &str) -> io::Result<bool> {
+    let usage = spike_command(spike)
+        .arg("-h")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", spike, e)))?;
+    let has = |out: &[u8]| out.windows(13).any(|w| w == b"--log-commits");
+    Ok(has(&usage.stdout) || has(&usage.stderr))
+}
+
+/// Starts `spike` on the configured ELF, returning it with the stream
+/// its trace comes out on
+fn spawn_spike(spike: &str, config: &Config) -> io::Result<(Child, Box<dyn Read>)> {
+    let machine = &config.machine;
+    let mut command = spike_command(spike);
+    if logs_commits(spike)? {
+        command
+            .args(["-l", "--log-commits"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+    } else {
+        command.stdout(Stdio::piped());
+    }
+
+    let mut child = command
+        .arg(format!("--isa={}", machine.isa()))
+        .arg(format!("-m{}", machine.main_ram().size >> 20))
+        .arg(&config.boot.elf)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", spike, e)))?;
+    let trace: Box<dyn Read> = match (child.stdout.take(), child.stderr.take()) {
+        (Some(stdout), _) => Box::new(stdout),
+        (_, Some(stderr)) => Box::new(stderr),
+        _ => unreachable!("spike output is piped"),
+    };
+    Ok((child, trace))
+}
+
+/// Runs the configured ELF on risk5 and `spike` side by side and
+/// stops at the first instruction after which their states differ
+pub fn lockstep(config: &Config, spike: &str) -> io::Result<()> {
+    let machine = &config.machine;
+    if machine.harts != 1 || config.boot.kernel.is_some() || config.boot.sbi {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "lockstep needs a single hart booting an ELF through the reset vector",
+        ));
+    }
+
+    let (mut child, trace) = spawn_spike(spike, config)?;
+    // JSON records or a commit log, going by how the trace starts
+    let reference = tuples(trace)?.map(|t| (t.state, t.insn));
+
+    let (mem, _) = crate::build_memory_with_entry(config)?;
+    let matchers = &mut build_matchers();
+    let mut cpu = Processor::new(mem);
+    cpu.set_pc(machine.reset_vec);
+    cpu.csrs_mut().misa = machine.misa;
+    cpu.set_console(Arc::new(Mutex::new(Console::new(&config.console)?)));
+
+    match compare(&mut cpu, matchers, reference) {
+        Ok(steps) => {
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} failed after {} instructions: {}", spike, steps, status),
+                ));
+            }
+            warn!("risk5 and spike agreed on {} instructions", steps);
+            Ok(())
+        }
+        Err(divergence) => {
+            // spike would otherwise keep going
+            let _ = child.kill();
+            child.wait()?;
+            for line in divergence.to_string().lines() {
+                error!("{}", line);
+            }
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("diverged from spike after {} instructions", divergence.step),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{machine, PROGRAM};
+
+    // what a reference that agrees with risk5 would report
+    fn reference(steps: usize) -> Vec<(State, Option<Insn>)> {
+        let matchers = &mut build_matchers();
+        let mut cpu = machine(PROGRAM);
+        (0..steps)
+            .map(|_| {
+                let state: State = (&cpu).into();
+                let pc = cpu.pc();
+                let bits = cpu.mmu_mut().read_w(pc).expect("fetch");
+                let insn = Insn {
+                    pc,
+                    bits,
+                    desc: disasm(pc, bits).to_string(),
+                };
+                cpu.step(matchers);
+                (state, Some(insn))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stops_at_first_divergence() {
+        let matchers = &mut build_matchers();
+
+        let mut cpu = machine(PROGRAM);
+        assert_eq!(
+            compare(&mut cpu, matchers, reference(15).into_iter()).ok(),
+            Some(15)
+        );
+
+        // the second add leaves a different result behind
+        let mut bad = reference(15);
+        for (state, _) in &mut bad[6..] {
+            state.xregs[5] += 1;
+        }
+        let mut cpu = machine(PROGRAM);
+        let d = compare(&mut cpu, matchers, bad.into_iter()).expect_err("divergence");
+        assert_eq!(d.step, 6);
+        assert_eq!(d.insn.as_ref().expect("insn").pc, 0x8000_0008);
+        assert_eq!(d.expected.xregs[5], d.actual.xregs[5] + 1);
+        assert!(d.to_string().contains("t0:"));
+    }
+}
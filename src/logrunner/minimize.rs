@@ -0,0 +1,326 @@
+use super::transaction::read_one;
+use super::{Failure, MemoryTrace, MemoryTraceKind, Transaction};
+use crate::matcher::Matchers;
+use crate::memory::ByteMap;
+use crate::{build_matchers, regs};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+/*
+ *
+ * Minimize
+ * --------
+ * Shrinks a failing `Transaction` and turns it into a regression
+ * test. Memory bytes and state the instruction leaves alone are
+ * dropped a chunk at a time, and a change is kept only if the
+ * transaction still fails with exactly the same differences.
+ *
+ */
+
+fn same(a: &Failure, b: &Failure) -> bool {
+    a.fields == b.fields && a.store == b.store && a.page_fault == b.page_fault
+}
+
+// the last value written to each address, as the replay sees it
+fn memory(t: &Transaction) -> Vec<(u64, u8)> {
+    let bytes: BTreeMap<u64, u8> = t.mems.iter().map(|m| (m.addr, m.value as u8)).collect();
+    bytes.into_iter().collect()
+}
+
+fn with_memory(t: &Transaction, bytes: &[(u64, u8)]) -> Transaction {
+    let mut t = t.clone();
+    t.mems = bytes
+        .iter()
+        .map(|&(addr, value)| MemoryTrace {
+            kind: MemoryTraceKind::Uint8,
+            addr,
+            value: value as u64,
+        })
+        .collect();
+    t
+}
+
+/// The smallest transaction found that fails like `t`, or `None` if
+/// `t` doesn't fail at all
+pub(crate) fn minimize(t: &Transaction, matchers: &mut Matchers<ByteMap>) -> Option<Transaction> {
+    let failure = t.validate(matchers).err()?;
+    let mut fails = |candidate: &Transaction| {
+        // some shrunk states hit unimplemented corners of risk5
+        let result = panic::catch_unwind(AssertUnwindSafe(|| candidate.validate(matchers)));
+        match result {
+            Ok(Err(f)) => same(&f, &failure),
+            _ => false,
+        }
+    };
+
+    // the instruction itself always stays
+    let pc = t.state.pc;
+    let (fetch, mut bytes): (Vec<_>, Vec<_>) = memory(t)
+        .into_iter()
+        .partition(|&(addr, _)| addr >= pc && addr < pc + 4);
+    let shrunk = |bytes: &[(u64, u8)]| with_memory(t, &[&fetch[..], bytes].concat());
+
+    // halve the chunks until single bytes are tried
+    let mut chunk = (bytes.len() / 2).max(1);
+    loop {
+        let mut i = 0;
+        while i < bytes.len() {
+            let mut candidate = bytes.clone();
+            candidate.drain(i..(i + chunk).min(bytes.len()));
+            if fails(&shrunk(&candidate)) {
+                bytes = candidate;
+            } else {
+                i += chunk;
+            }
+        }
+        if chunk == 1 {
+            break;
+        }
+        chunk /= 2;
+    }
+    let mut t = shrunk(&bytes);
+
+    // registers and csrs the instruction didn't change
+    for i in 1..32 {
+        if t.state.xregs[i] != 0 && t.state.xregs[i] == t.after.xregs[i] {
+            let mut candidate = t.clone();
+            candidate.state.xregs[i] = 0;
+            candidate.after.xregs[i] = 0;
+            if fails(&candidate) {
+                t = candidate;
+            }
+        }
+    }
+    macro_rules! clear {
+        ($($id:ident),+) => {$(
+            if t.state.$id != 0 && t.state.$id == t.after.$id {
+                let mut candidate = t.clone();
+                candidate.state.$id = 0;
+                candidate.after.$id = 0;
+                if fails(&candidate) {
+                    t = candidate;
+                }
+            }
+        )+};
+    }
+    clear!(
+        mstatus, mepc, mtvec, mcause, mscratch, mie, mip, medeleg, mideleg, mcounteren, scounteren,
+        sepc, stval, sscratch, stvec, satp, scause, mtval, misa, mhartid, sedeleg, sideleg, sip
+    );
+
+    // the counters only count from the state before
+    let mut candidate = t.clone();
+    for (before, after) in [
+        (&mut candidate.state.minstret, &mut candidate.after.minstret),
+        (&mut candidate.state.mcycle, &mut candidate.after.mcycle),
+    ] {
+        *after = after.wrapping_sub(*before);
+        *before = 0;
+    }
+    if fails(&candidate) {
+        t = candidate;
+    }
+
+    Some(t)
+}
+
+fn state_literal(out: &mut String, name: &str, s: &super::State) {
+    writeln!(out, "    let {} = State {{", name).unwrap();
+    if s.legacy {
+        writeln!(out, "        legacy: true,").unwrap();
+    }
+    macro_rules! field {
+        ($($id:ident),+) => {$(
+            if s.$id != 0 {
+                writeln!(out, "        {}: 0x{:x},", stringify!($id), s.$id).unwrap();
+            }
+        )+};
+    }
+    field!(
+        pc, prv, mstatus, mepc, mtvec, mcause, mscratch, minstret, mie, mip, medeleg, mideleg,
+        mcounteren, scounteren, sepc, stval, sscratch, stvec, satp, scause, mtval, misa, mhartid,
+        mcycle, sedeleg, sideleg, sip
+    );
+    let regs: Vec<_> = (1..32)
+        .filter(|&i| s.xregs[i] != 0)
+        .map(|i| format!("({}, 0x{:x})", i, s.xregs[i]))
+        .collect();
+    if !regs.is_empty() {
+        writeln!(out, "        xregs: xregs(&[{}]),", regs.join(", ")).unwrap();
+    }
+    writeln!(out, "        ..Default::default()\n    }};").unwrap();
+}
+
+// FNV-1a of the state and memory `t` starts from, which tells tests of
+// the same instruction apart
+fn start_hash(t: &Transaction) -> u32 {
+    let start = bincode::serialize(&(&t.state, memory(t))).expect("serialize");
+    start.iter().fold(0x811c_9dc5, |h, &b| {
+        (h ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// A `#[test]` for `regressions.rs` that replays `t` and expects its
+/// `after` state
+pub(crate) fn regression_test(t: &Transaction, opcode: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "#[test]").unwrap();
+    writeln!(
+        out,
+        "fn {}_{:x}_{:08x}() {{",
+        opcode.replace('.', "_"),
+        t.state.pc,
+        start_hash(t)
+    )
+    .unwrap();
+    if let Some(ref insn) = t.insn {
+        let desc: Vec<_> = insn.desc.split_whitespace().collect();
+        writeln!(out, "    // {} (0x{:08x})", desc.join(" "), insn.bits).unwrap();
+    }
+    let changed: Vec<_> = (1..32)
+        .filter(|&i| t.state.xregs[i] != t.after.xregs[i])
+        .map(|i| regs::REG_NAMES[i])
+        .collect();
+    if !changed.is_empty() {
+        writeln!(out, "    // writes {}", changed.join(", ")).unwrap();
+    }
+
+    state_literal(&mut out, "before", &t.state);
+    writeln!(out, "    let memory = &[").unwrap();
+    for (addr, value) in memory(t) {
+        writeln!(out, "        (0x{:x}, 0x{:02x}),", addr, value).unwrap();
+    }
+    writeln!(out, "    ];").unwrap();
+    match t.store {
+        Some(ref store) => writeln!(
+            out,
+            "    let store = Some(MemoryTrace {{\n        kind: MemoryTraceKind::{:?},\n        addr: 0x{:x},\n        value: 0x{:x},\n    }});",
+            store.kind, store.addr, store.value
+        )
+        .unwrap(),
+        None => writeln!(out, "    let store = None;").unwrap(),
+    }
+    state_literal(&mut out, "expected", &t.after);
+    writeln!(out, "    check(before, memory, store, expected);").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Reads a failed transaction from stdin and prints a minimized
+/// regression test for it
+pub fn minimize_failed() -> io::Result<()> {
+    let matchers = &mut build_matchers::<ByteMap>();
+    let t = read_one(io::BufReader::new(io::stdin()))?;
+    let opcode = match t.validate(matchers) {
+        Ok(()) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "transaction doesn't fail",
+            ))
+        }
+        Err(failure) => failure.opcode,
+    };
+
+    // every replay of missing memory would be logged
+    let level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+    let small = minimize(&t, matchers);
+    panic::set_hook(hook);
+    log::set_max_level(level);
+
+    let small = small.expect("still fails");
+    warn!(
+        "Minimized memory from {} to {} bytes",
+        memory(&t).len(),
+        memory(&small).len()
+    );
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out)?;
+    write!(out, "{}", regression_test(&small, opcode))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::State;
+    use super::*;
+    use crate::memory::Memory;
+    use crate::Processor;
+
+    // ld t0, 0(t1); the reference loaded one more than memory holds
+    fn failing() -> Transaction {
+        let mut mems = vec![];
+        let mut mem = ByteMap::default();
+        let mut byte = |addr, value: u8| {
+            mem.write_b(addr, value);
+            mems.push(MemoryTrace {
+                kind: MemoryTraceKind::Uint8,
+                addr,
+                value: value as u64,
+            });
+        };
+        for (i, b) in 0x0003_3283u32.to_le_bytes().iter().enumerate() {
+            byte(0x8000_0000 + i as u64, *b);
+        }
+        for i in 0..8 {
+            byte(0x8000_1000 + i, 0x10 + i as u8);
+        }
+        // never read
+        for i in 0..64 {
+            byte(0x8000_2000 + i, 0xaa);
+        }
+
+        let mut cpu = Processor::new(mem);
+        cpu.set_pc(0x8000_0000);
+        let mut state: State = (&cpu).into();
+        state.xregs[6] = 0x8000_1000;
+        state.xregs[7] = 0x1234;
+        state.mscratch = 0x55;
+        let mut after = state.clone();
+        after.pc += 4;
+        after.minstret += 1;
+        after.mcycle += 1;
+        after.xregs[5] = 0x1716_1514_1312_1111;
+
+        Transaction {
+            state,
+            insn: None,
+            mems,
+            store: None,
+            after,
+        }
+    }
+
+    #[test]
+    fn shrinks_failing_load() {
+        let matchers = &mut build_matchers::<ByteMap>();
+        let t = failing();
+        let small = minimize(&t, matchers).expect("fails");
+
+        // the instruction and the bytes loaded stay, the rest goes
+        assert_eq!(memory(&small).len(), 4 + 8);
+        assert_eq!(small.state.xregs[6], 0x8000_1000);
+        assert_eq!(small.state.xregs[7], 0);
+        assert_eq!(small.state.mscratch, 0);
+        let failure = small.validate(matchers).expect_err("still fails");
+        assert_eq!(failure.fields[0].field, "t0");
+
+        let test = regression_test(&small, "ld");
+        assert!(test.starts_with("#[test]\nfn ld_80000000_"));
+        // another start at the same pc gets another name
+        let mut other = small.clone();
+        other.state.xregs[7] = 1;
+        let name = |test: &str| test.lines().nth(1).map(String::from);
+        assert_ne!(name(&test), name(&regression_test(&other, "ld")));
+        assert!(test.contains("xregs: xregs(&[(6, 0x80001000)]),"));
+        assert!(test.contains("(0x80001000, 0x10)"));
+
+        let mut passing = small.clone();
+        passing.after.xregs[5] = 0x1716_1514_1312_1110;
+        assert!(minimize(&passing, matchers).is_none());
+    }
+}
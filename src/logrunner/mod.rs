@@ -5,17 +5,38 @@ use serde_json;
 use std::default::Default;
 use std::io::BufReader;
 use std::io::Lines;
+use std::ops::Range;
 use std::{fmt, io};
 
 mod bincode;
+mod commitlog;
+mod container;
+mod filter;
 pub(crate) mod json;
+mod lockstep;
 pub(crate) mod logger;
+mod minimize;
+mod pool;
+#[cfg(test)]
+mod regressions;
+mod report;
 mod run;
+pub(crate) mod stats;
+mod tracediff;
+pub(crate) mod tracer;
 pub mod transaction;
 
 pub use self::bincode::bincodereader;
 pub use self::bincode::convert;
+pub use self::commitlog::validate_commits;
+pub use self::container::Header;
+pub use self::filter::filter;
+pub use self::lockstep::{lockstep, Divergence, DEFAULT_SPIKE};
+pub use self::minimize::minimize_failed;
+pub use self::report::{Failure, FieldDiff, Options, Report, StoreDiff};
 pub use self::run::run;
+pub use self::stats::stats;
+pub use self::tracediff::tracediff;
 pub(crate) use transaction::Transaction;
 
 pub(crate) fn format_diff<T: fmt::Binary + fmt::LowerHex>(expected: T, actual: T) -> String {
@@ -32,14 +53,19 @@ pub(crate) fn format_diff<T: fmt::Binary + fmt::LowerHex>(expected: T, actual: T
     )
 }
 
+// bincode tags variants by their position, and version 1 traces have
+// their states at position 2, where `State` used to be. Keep the order
+// and add variants at the end.
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) enum LogLine {
     Mark,
     Insn(Insn),
-    State(State),
+    /// a state from a version 1 trace
+    StateV1(bincode::StateV1),
     Load(MemoryTrace),
     Store(MemoryTrace),
     Memory(MemoryTrace),
+    State(State),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -63,9 +89,13 @@ pub struct RestorableState<'s, M> {
     pub memory: M,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+/// Architectural state of a hart between two instructions. risk5 has no
+/// F or D extension, so there is no FP state to carry.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
 pub struct State {
     pub(crate) id: u64,
+    /// read from a version 1 trace, which lacks the CSRs from `mtval` on
+    pub(crate) legacy: bool,
     pub(crate) pc: u64,
     pub(crate) prv: u64,
 
@@ -88,6 +118,14 @@ pub struct State {
     pub(crate) satp: u64,
     pub(crate) scause: u64,
 
+    pub(crate) mtval: u64,
+    pub(crate) misa: u64,
+    pub(crate) mhartid: u64,
+    pub(crate) mcycle: u64,
+    pub(crate) sedeleg: u64,
+    pub(crate) sideleg: u64,
+    pub(crate) sip: u64,
+
     pub(crate) xregs: [u64; 32],
 }
 
@@ -117,7 +155,7 @@ pub struct MemoryTrace {
     value: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum MemoryTraceKind {
     Uint8,
     Uint16,
@@ -130,6 +168,9 @@ pub enum MemoryTraceKind {
     Read,
 }
 
+/// Where the test programs' HTIF lives, which risk5 doesn't model
+pub(crate) const HTIF: Range<u64> = 0x8000_9000..0x8000_9016;
+
 trait ToMemory {
     fn to_memory<M: Memory + Default>(&self) -> M;
 }
@@ -145,63 +186,47 @@ impl ToMemory for Vec<MemoryTrace> {
 }
 
 impl MemoryTrace {
-    fn validate<M>(&self, m: &mut Mmu<M>) -> bool
+    /// Reads the stored value back through `m`
+    fn check<M>(&self, m: &mut Mmu<M>) -> Result<(), StoreDiff>
     where
         M: Memory,
     {
         trace!("Checking store {}", self);
-        if self.addr >= 0x8000_9000 && self.addr < 0x8000_9016 {
+        if HTIF.contains(&self.addr) {
             trace!("Ignoring write to HTIF: {}", self);
-            return true;
-        }
-
-        macro_rules! fail_on {
-            ($name:expr, $expected:expr, $actual:expr) => {
-                let actual = if let Ok(a) = $actual {
-                    a
-                } else {
-                    warn!("MMU returning nothing");
-                    // TODO store won't happen if we get a store page-fault
-                    // return false;
-                    return true;
-                };
-                if $expected != actual {
-                    error!(
-                        "Fail check on {}.\n{}",
-                        $name,
-                        format_diff($expected, actual)
-                    );
-                    // TODO store won't happen if we get a store page-fault
-                    // return false;
-                    return true;
-                }
-                return true;
-            };
+            return Ok(());
         }
 
         use MemoryTraceKind::*;
-        match &self.kind {
-            Uint8 => {
-                let val = m.read_b(self.addr);
-                fail_on!("store", self.value as u8, val);
-            }
-            Uint16 => {
-                let val = m.read_h(self.addr);
-                fail_on!("store", self.value as u16, val);
-            }
-            Uint32 => {
-                let val = m.read_w(self.addr);
-                fail_on!("store", self.value as u32, val);
-            }
-            Uint64 => {
-                let val = m.read_d(self.addr);
-                fail_on!("store", self.value, val);
-            }
-            n => unimplemented!("check store type {:?}", n),
+        let (expected, actual) = match &self.kind {
+            Uint8 | Int8 => (self.value as u8 as u64, m.read_b(self.addr).map(u64::from)),
+            Uint16 | Int16 => (self.value as u16 as u64, m.read_h(self.addr).map(u64::from)),
+            Uint32 | Int32 => (self.value as u32 as u64, m.read_w(self.addr).map(u64::from)),
+            Uint64 | Int64 => (self.value, m.read_d(self.addr)),
+            // a read has no width to check a store with
+            Read => (self.value, Err(())),
+        };
+
+        match actual {
+            Ok(actual) if actual == expected => Ok(()),
+            actual => Err(StoreDiff {
+                kind: self.kind.clone(),
+                addr: self.addr,
+                expected,
+                actual: actual.ok(),
+            }),
         }
     }
 }
 
+impl LogTuple {
+    /// Whether this only carries the state a filtered trace stopped at,
+    /// which is no instruction to run
+    pub(crate) fn is_gap(&self) -> bool {
+        self.insn.is_none() && self.mems.is_empty() && self.store.is_none()
+    }
+}
+
 impl fmt::Display for Insn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "pc=0x{:x} bits=0x{:x} {}", self.pc, self.bits, self.desc)
@@ -224,98 +249,52 @@ impl State {
         S: Into<State>,
         T: Into<State>,
     {
-        let other = other.into();
         let before = before.map(|b| b.into());
+        let diffs = self.diff(&other.into(), before.as_ref());
+        for diff in &diffs {
+            error!("{}", diff);
+        }
+        diffs.is_empty()
+    }
+
+    /// Fields where `actual` differs from this expected state
+    pub(crate) fn diff(&self, actual: &State, before: Option<&State>) -> Vec<FieldDiff> {
+        let mut diffs = vec![];
+        let mut check = |field, expected: u64, actual: u64, before: Option<u64>| {
+            if expected != actual {
+                diffs.push(FieldDiff {
+                    field,
+                    expected,
+                    actual,
+                    unexpected_change: before == Some(expected),
+                });
+            }
+        };
 
         macro_rules! valid {
-            ($id:ident) => {{
-                let actual = other.$id;
-                let expected = self.$id;
-                if actual != expected {
-                    let expected_unchanged = if let Some(before) = before.as_ref() {
-                        if expected == before.$id {
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    };
-                    error!(
-                        "Fail check{} on {}.\n{}",
-                        match expected_unchanged {
-                            false => "",
-                            true => " (unexpected change)",
-                        },
-                        stringify!($id),
-                        format_diff(expected, actual)
-                    );
-                    false
-                } else {
-                    true
-                }
-            }};
-            ($id:ident, $($next:ident),+) => {{
-                valid!($id) & valid!($($next),+)
-            }};
+            ($($id:ident),+) => {
+                $(check(stringify!($id), self.$id, actual.$id, before.map(|b| b.$id));)+
+            };
         }
 
-        // TODO add mip mcounteren
-        // TODO add supervisor csrs
-        let mut valid = valid!(
-            pc, prv, mepc, mtvec, mcause, mscratch, mie, medeleg, mideleg, sepc, stval, sscratch,
-            stvec, satp, scause
+        valid!(
+            pc, prv, mepc, mtvec, mcause, mscratch, mie, mip, medeleg, mideleg, mcounteren,
+            scounteren, sepc, stval, sscratch, stvec, satp, scause, mstatus, minstret
         );
-
-        if self.mstatus != other.mstatus {
-            use crate::bitfield::Mstatus;
-            let mstatus_expected: Mstatus = self.mstatus.into();
-            let mstatus_actual: Mstatus = other.mstatus.into();
-            let unexpected_change = if let Some(before) = before.as_ref() {
-                if self.mstatus == before.mstatus {
-                    true
-                } else {
-                    false
-                }
-            } else {
-                false
-            };
-            error!(
-                "Fail mstatus check{}\n{}\nWas:      {:?}\nExpected: {:?}{}",
-                match unexpected_change {
-                    true => " (unexpected change)",
-                    false => "",
-                },
-                format_diff(self.mstatus, other.mstatus),
-                mstatus_actual,
-                mstatus_expected,
-                if let Some(before) = before.as_ref() {
-                    format!("\nBefore:   {:?}", Into::<Mstatus>::into(before.mstatus))
-                } else {
-                    String::new()
-                },
-            );
-            valid = false;
+        if !self.legacy {
+            valid!(mtval, misa, mhartid, mcycle, sedeleg, sideleg, sip);
         }
 
-        for (i, reg) in self.xregs.iter().enumerate() {
-            let expected = *reg;
-            let actual = other.xregs[i];
-            if expected != actual {
-                if let Some(before) = before.as_ref() {
-                    if expected == before.xregs[i] {
-                        error!("Expected was unchanged. Before was 0x{:x}", before.xregs[i]);
-                    }
-                }
-
-                let msg = format!("Fail reg check on 0x{:02x} ({})\nWas:      0x{:016x} {:064b} \nExpected: 0x{:016x} {:064b}",
-                    i, regs::REG_NAMES[i], actual, actual, reg, reg);
-                error!("{}", msg);
-                valid = false;
-            }
+        for (i, name) in regs::REG_NAMES.iter().enumerate() {
+            check(
+                name,
+                self.xregs[i],
+                actual.xregs[i],
+                before.map(|b| b.xregs[i]),
+            );
         }
 
-        valid
+        diffs
     }
 }
 
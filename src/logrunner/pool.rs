@@ -0,0 +1,274 @@
+use super::report::{create_dir, failed_path};
+use super::{Failure, Options, Report, Transaction};
+use crate::build_matchers;
+use crate::memory::ByteMap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+/// Transactions handed to a worker at a time
+const BATCH: usize = 4096;
+
+struct Batch {
+    /// stream index of the first transaction
+    start: u64,
+    transactions: Vec<Transaction>,
+}
+
+type Failed = (Box<Failure>, Transaction);
+
+fn worker(
+    jobs: Arc<Mutex<Receiver<Batch>>>,
+    failed: Arc<AtomicU64>,
+    keep_going: bool,
+) -> Vec<Failed> {
+    let matchers = &mut build_matchers::<ByteMap>();
+    let mut failures = vec![];
+    loop {
+        let batch = match jobs.lock().expect("jobs").recv() {
+            Ok(batch) => batch,
+            Err(_) => return failures,
+        };
+        // only failures before the earliest known one matter
+        if batch.start > failed.load(Ordering::Relaxed) {
+            continue;
+        }
+        for (t, i) in batch.transactions.into_iter().zip(batch.start..) {
+            // a panic fails the transaction, not the whole run
+            let result = panic::catch_unwind(AssertUnwindSafe(|| t.validate(matchers)))
+                .unwrap_or_else(|e| Err(t.panicked(e)));
+            if let Err(mut failure) = result {
+                failure.index = i;
+                failures.push((failure, t));
+                if !keep_going {
+                    failed.fetch_min(i, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Validates `transactions` on worker threads, each with its own
+/// matchers. Failures are reported in stream order; without
+/// `keep_going` only the first is, once everything before it has been
+/// checked.
+pub(crate) fn validate_parallel<I>(transactions: I, options: &Options) -> io::Result<u64>
+where
+    I: Iterator<Item = Transaction>,
+{
+    validate_batches(transactions, options, BATCH)
+}
+
+fn validate_batches<I>(transactions: I, options: &Options, batch: usize) -> io::Result<u64>
+where
+    I: Iterator<Item = Transaction>,
+{
+    let threads = options.threads;
+    let mark = SystemTime::now();
+    let failed = Arc::new(AtomicU64::new(u64::MAX));
+    let (send, jobs) = sync_channel::<Batch>(threads * 2);
+    let jobs = Arc::new(Mutex::new(jobs));
+    let (done, results) = channel();
+
+    for _ in 0..threads {
+        let jobs = jobs.clone();
+        let failed = failed.clone();
+        let done = done.clone();
+        let keep_going = options.keep_going;
+        thread::Builder::new()
+            .name("validate".into())
+            .spawn(move || done.send(worker(jobs, failed, keep_going)))?;
+    }
+    drop(done);
+
+    let mut count = 0;
+    let mut transactions = transactions.peekable();
+    while transactions.peek().is_some() && count <= failed.load(Ordering::Relaxed) {
+        let chunk: Vec<_> = transactions.by_ref().take(batch).collect();
+        let start = count;
+        count += chunk.len() as u64;
+        let job = Batch {
+            start,
+            transactions: chunk,
+        };
+        if send.send(job).is_err() {
+            break;
+        }
+    }
+    drop(send);
+
+    let mut workers = 0;
+    let mut failures = vec![];
+    for mut f in results {
+        workers += 1;
+        failures.append(&mut f);
+    }
+    if workers != threads {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "validation worker panicked",
+        ));
+    }
+    failures.sort_by_key(|(failure, _)| failure.index);
+    if !options.keep_going {
+        failures.truncate(1);
+    }
+
+    let d = SystemTime::now().duration_since(mark).expect("time");
+    let speed = count as f64 / d.as_secs_f64();
+    println!(
+        "Validated {} transactions @ {:.0} per/sec on {} threads",
+        count, speed, threads
+    );
+
+    if let Some(ref dir) = options.out_dir {
+        create_dir(dir)?;
+        for (failure, t) in &failures {
+            t.save_to(&failed_path(dir, failure.index))?;
+        }
+    }
+    for (failure, _) in &failures {
+        for line in failure.to_string().lines() {
+            error!("{}", line);
+        }
+    }
+    let first = failures.first().map(|(failure, _)| failure.index);
+    let failed = failures.len();
+    if let Some(ref path) = options.report {
+        let failures = failures.into_iter().map(|(failure, _)| *failure).collect();
+        Report::new(count, failures).write_to(path)?;
+    }
+
+    match first {
+        None => Ok(count),
+        Some(i) if failed == 1 => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("transaction {} failed", i),
+        )),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} of {} transactions failed", failed, count),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Insn, MemoryTrace, MemoryTraceKind, State};
+    use super::*;
+    use crate::memory::Memory;
+    use crate::test_util::PROGRAM;
+    use crate::Processor;
+
+    fn transactions(n: usize) -> Vec<Transaction> {
+        let matchers = &mut build_matchers::<ByteMap>();
+        let mut mem = ByteMap::default();
+        let mut mems = vec![];
+        for (i, insn) in PROGRAM.iter().enumerate() {
+            for b in 0..4 {
+                let addr = 0x8000_0000 + 4 * i as u64 + b;
+                let value = (insn >> (8 * b)) & 0xff;
+                mem.write_b(addr, value as u8);
+                mems.push(MemoryTrace {
+                    kind: MemoryTraceKind::Uint8,
+                    addr,
+                    value: value as u64,
+                });
+            }
+        }
+        let mut cpu = Processor::new(mem);
+        cpu.set_pc(0x8000_0000);
+
+        (0..n)
+            .map(|_| {
+                let state: State = (&cpu).into();
+                let bits = PROGRAM[(cpu.pc() as usize - 0x8000_0000) / 4];
+                cpu.step(matchers);
+                Transaction {
+                    insn: Some(Insn {
+                        pc: state.pc,
+                        bits,
+                        desc: String::new(),
+                    }),
+                    state,
+                    mems: mems.clone(),
+                    store: None,
+                    after: (&cpu).into(),
+                }
+            })
+            .collect()
+    }
+
+    fn options(keep_going: bool) -> Options {
+        Options {
+            threads: 3,
+            keep_going,
+            out_dir: None,
+            report: None,
+        }
+    }
+
+    fn bad() -> Vec<Transaction> {
+        let mut bad = transactions(40);
+        bad[9].after.xregs[5] ^= 1;
+        bad[20].store = Some(MemoryTrace {
+            kind: MemoryTraceKind::Uint32,
+            addr: 0x8000_0000,
+            value: 0,
+        });
+        bad[33].after.xregs[5] ^= 1;
+        // ebreak isn't implemented and panics
+        let pc = bad[25].state.pc;
+        for (i, b) in 0x0010_0073u32.to_le_bytes().iter().enumerate() {
+            bad[25].mems.push(MemoryTrace {
+                kind: MemoryTraceKind::Uint8,
+                addr: pc + i as u64,
+                value: *b as u64,
+            });
+        }
+        bad
+    }
+
+    #[test]
+    fn reports_first_failure() {
+        let ok = validate_batches(transactions(40).into_iter(), &options(false), 4);
+        assert_eq!(ok.ok(), Some(40));
+
+        // the earlier failure wins whichever worker finds it first
+        let e = validate_batches(bad().into_iter(), &options(false), 4).expect_err("failure");
+        assert_eq!(e.to_string(), "transaction 9 failed");
+
+        let dir = std::env::temp_dir().join(format!("risk5-pool-{}", std::process::id()));
+        let mut keep_going = options(true);
+        keep_going.out_dir = Some(dir.clone());
+        keep_going.report = Some(dir.join("report.json"));
+        let e = validate_batches(bad().into_iter(), &keep_going, 4).expect_err("failures");
+        assert_eq!(e.to_string(), "4 of 40 transactions failed");
+        assert!(failed_path(&dir, 20).exists());
+
+        let report = std::fs::read_to_string(dir.join("report.json")).expect("report");
+        let report: serde_json::Value = serde_json::from_str(&report).expect("json");
+        assert_eq!(report["failed"], 4);
+        let addi = &report["by_opcode"]["addi"][0];
+        assert_eq!(addi["index"], 9);
+        assert_eq!(addi["fields"][0]["field"], "t0");
+        // both spinning on `j .`
+        let jal = &report["by_opcode"]["jal"];
+        assert_eq!(jal[0]["store"]["actual"], 0x0010_0293);
+        assert_eq!(jal[1]["index"], 25);
+        assert_eq!(jal[1]["panic"], "no insn impl");
+        assert_eq!(jal[2]["index"], 33);
+
+        keep_going.report = Some(dir.join("report.html"));
+        validate_batches(bad().into_iter(), &keep_going, 4).expect_err("failures");
+        let html = std::fs::read_to_string(dir.join("report.html")).expect("report");
+        assert!(html.contains("<h2>addi (1)</h2>"));
+
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+}
@@ -0,0 +1,76 @@
+use super::{MemoryTrace, MemoryTraceKind, RestorableState, State};
+use crate::memory::{ByteMap, Memory};
+use crate::{build_matchers, Processor};
+
+/*
+ *
+ * Regressions
+ * -----------
+ * Regression tests, mostly appended by `make minimize-failed`. Each
+ * one restores the state before a single instruction and expects the
+ * state, and any store, the reference ended up with.
+ *
+ */
+
+fn xregs(regs: &[(usize, u64)]) -> [u64; 32] {
+    let mut xregs = [0; 32];
+    for &(i, v) in regs {
+        xregs[i] = v;
+    }
+    xregs
+}
+
+fn check(before: State, memory: &'static [(u64, u8)], store: Option<MemoryTrace>, expected: State) {
+    let matchers = &mut build_matchers::<ByteMap>();
+    let mut mem = ByteMap::default();
+    for &(addr, value) in memory {
+        mem.write_b(addr, value);
+    }
+    let state = &before;
+    let mut cpu: Processor<ByteMap> = RestorableState { state, memory: mem }.into();
+    cpu.step(matchers);
+
+    let actual: State = (&cpu).into();
+    let mut diffs: Vec<_> = expected
+        .diff(&actual, Some(&before))
+        .iter()
+        .map(|d| d.to_string())
+        .collect();
+    if let Some(Err(diff)) = store.map(|s| s.check(cpu.mmu_mut())) {
+        diffs.push(diff.to_string());
+    }
+    assert!(diffs.is_empty(), "\n{}", diffs.join("\n"));
+}
+
+#[test]
+fn sd_80000000() {
+    // sd t0,8(t1) (0x00533423)
+    let before = State {
+        pc: 0x80000000,
+        prv: 0x3,
+        mstatus: 0xa00000000,
+        xregs: xregs(&[(5, 0x1716151413121110), (6, 0x80001000)]),
+        ..Default::default()
+    };
+    let memory = &[
+        (0x80000000, 0x23),
+        (0x80000001, 0x34),
+        (0x80000002, 0x53),
+        (0x80000003, 0x00),
+    ];
+    let store = Some(MemoryTrace {
+        kind: MemoryTraceKind::Uint64,
+        addr: 0x80001008,
+        value: 0x1716151413121110,
+    });
+    let expected = State {
+        pc: 0x80000004,
+        prv: 0x3,
+        mstatus: 0xa00000000,
+        minstret: 0x1,
+        mcycle: 0x1,
+        xregs: xregs(&[(5, 0x1716151413121110), (6, 0x80001000)]),
+        ..Default::default()
+    };
+    check(before, memory, store, expected);
+}
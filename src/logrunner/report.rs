@@ -0,0 +1,247 @@
+use super::{format_diff, Insn, MemoryTraceKind, State};
+use crate::bitfield::Mstatus;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// How a validation run behaves, from `VALIDATE_*` environment variables
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// `VALIDATE_THREADS`, one per host CPU by default
+    pub threads: usize,
+    /// `VALIDATE_KEEP_GOING`: check everything instead of stopping at the
+    /// first failure
+    pub keep_going: bool,
+    /// `VALIDATE_OUT`: directory for failing transactions, which are not
+    /// kept otherwise
+    pub out_dir: Option<PathBuf>,
+    /// `VALIDATE_REPORT`: report file, HTML if it ends in `.html` and
+    /// JSON otherwise
+    pub report: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn from_env() -> Options {
+        let var = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Options {
+            threads: var("VALIDATE_THREADS")
+                .and_then(|n| n.parse().ok())
+                .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+                .unwrap_or(1)
+                .max(1),
+            keep_going: var("VALIDATE_KEEP_GOING").is_some_and(|v| v != "0"),
+            out_dir: var("VALIDATE_OUT").map(PathBuf::from),
+            report: var("VALIDATE_REPORT").map(PathBuf::from),
+        }
+    }
+}
+
+/// One state field that ended up different
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub expected: u64,
+    pub actual: u64,
+    /// the reference left this field alone
+    pub unexpected_change: bool,
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Fail check{} on {}.\n{}",
+            if self.unexpected_change {
+                " (unexpected change)"
+            } else {
+                ""
+            },
+            self.field,
+            format_diff(self.expected, self.actual)
+        )?;
+        if self.field == "mstatus" {
+            let expected: Mstatus = self.expected.into();
+            let actual: Mstatus = self.actual.into();
+            write!(f, "\nWas:      {:?}\nExpected: {:?}", actual, expected)?;
+        }
+        Ok(())
+    }
+}
+
+/// A store that didn't leave the expected value behind
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoreDiff {
+    pub kind: MemoryTraceKind,
+    pub addr: u64,
+    pub expected: u64,
+    /// `None` if reading it back faulted or the trace has a read
+    /// where the store belongs
+    pub actual: Option<u64>,
+}
+
+impl fmt::Display for StoreDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.actual {
+            None if self.kind == MemoryTraceKind::Read => write!(
+                f,
+                "Fail check on store at 0x{:x}: the trace has a read instead",
+                self.addr
+            ),
+            Some(actual) => write!(
+                f,
+                "Fail check on store {:?} at 0x{:x}.\n{}",
+                self.kind,
+                self.addr,
+                format_diff(self.expected, actual)
+            ),
+            None => write!(
+                f,
+                "Fail check on store {:?} at 0x{:x}: reading it back faulted",
+                self.kind, self.addr
+            ),
+        }
+    }
+}
+
+/// Everything known about a transaction that failed validation
+#[derive(Serialize, Debug, Clone)]
+pub struct Failure {
+    /// position in the transaction stream
+    pub index: u64,
+    pub opcode: &'static str,
+    pub insn: Option<Insn>,
+    pub before: State,
+    pub expected: State,
+    pub actual: State,
+    pub fields: Vec<FieldDiff>,
+    pub store: Option<StoreDiff>,
+    /// page fault cause risk5 trapped with where the reference didn't
+    pub page_fault: Option<u64>,
+    /// what risk5 panicked with, which leaves nothing else to compare
+    pub panic: Option<String>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Transaction {} failed ({})", self.index, self.opcode)?;
+        match self.insn {
+            Some(ref insn) => writeln!(f, "Insn:   {}", insn)?,
+            None => writeln!(f, "Insn:   None")?,
+        }
+        if let Some(cause) = self.page_fault {
+            writeln!(f, "Page fault: cause {}", cause)?;
+        }
+        if let Some(ref panic) = self.panic {
+            writeln!(f, "Panicked: {}", panic)?;
+        }
+        for diff in &self.fields {
+            writeln!(f, "{}", diff)?;
+        }
+        if let Some(ref store) = self.store {
+            writeln!(f, "{}", store)?;
+        }
+        writeln!(f, "Before: {}", self.before)?;
+        write!(f, "After:  {}", self.expected)
+    }
+}
+
+/// Failures of a validation run, grouped by opcode
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub total: u64,
+    pub failed: usize,
+    pub by_opcode: BTreeMap<&'static str, Vec<Failure>>,
+}
+
+impl Report {
+    pub fn new(total: u64, failures: Vec<Failure>) -> Report {
+        let mut report = Report {
+            total,
+            failed: failures.len(),
+            ..Default::default()
+        };
+        for failure in failures {
+            report
+                .by_opcode
+                .entry(failure.opcode)
+                .or_default()
+                .push(failure);
+        }
+        report
+    }
+
+    /// Writes the report as HTML or JSON, going by the extension
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if path.extension().is_some_and(|e| e == "html") {
+            self.write_html(&mut out)?;
+        } else {
+            serde_json::to_writer_pretty(&mut out, self)?;
+        }
+        out.flush()
+    }
+
+    fn write_html<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">")?;
+        writeln!(out, "<title>risk5 validation</title>")?;
+        writeln!(
+            out,
+            "<style>td {{ vertical-align: top; font-family: monospace; white-space: pre; }}</style>"
+        )?;
+        writeln!(out, "</head><body>")?;
+        writeln!(
+            out,
+            "<h1>{} of {} transactions failed</h1>",
+            self.failed, self.total
+        )?;
+        for (opcode, failures) in &self.by_opcode {
+            writeln!(out, "<h2>{} ({})</h2>", escape(opcode), failures.len())?;
+            writeln!(
+                out,
+                "<table border=\"1\"><tr><th>#</th><th>insn</th><th>differences</th></tr>"
+            )?;
+            for failure in failures {
+                let insn = failure
+                    .insn
+                    .as_ref()
+                    .map_or_else(|| "None".to_string(), |i| i.to_string());
+                let mut diffs: Vec<_> = failure.fields.iter().map(|d| d.to_string()).collect();
+                diffs.extend(failure.store.iter().map(|s| s.to_string()));
+                if let Some(cause) = failure.page_fault {
+                    diffs.push(format!("page fault: cause {}", cause));
+                }
+                if let Some(ref panic) = failure.panic {
+                    diffs.push(format!("panicked: {}", panic));
+                }
+                writeln!(
+                    out,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    failure.index,
+                    escape(&insn),
+                    escape(&diffs.join("\n"))
+                )?;
+            }
+            writeln!(out, "</table>")?;
+        }
+        writeln!(out, "</body></html>")
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Where a failing transaction is saved in `dir`
+pub fn failed_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("failed-{}.bincode", index))
+}
+
+pub(crate) fn create_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))
+}
@@ -0,0 +1,516 @@
+use super::container::tuples;
+use super::transaction::TransactionIterator;
+use super::State;
+use crate::disasm::Symbols;
+use crate::machine::{parse_count, parse_number};
+use crate::matcher::Matchers;
+use crate::memory::Memory;
+use crate::opcodes::{decode, OPCODES};
+use crate::Processor;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/*
+ *
+ * Stats
+ * -----
+ * Instruction mix, privilege split, traps, hot code and memory access
+ * sizes of a recorded trace or a live run.
+ *
+ */
+
+const EXCEPTIONS: &[&str] = &[
+    "instruction_address_misaligned",
+    "instruction_access_fault",
+    "illegal_instruction",
+    "breakpoint",
+    "load_address_misaligned",
+    "load_access_fault",
+    "store_address_misaligned",
+    "store_access_fault",
+    "user_ecall",
+    "supervisor_ecall",
+    "hypervisor_ecall",
+    "machine_ecall",
+    "instruction_page_fault",
+    "load_page_fault",
+    "reserved",
+    "store_page_fault",
+];
+
+const INTERRUPTS: &[&str] = &[
+    "user_software",
+    "supervisor_software",
+    "hypervisor_software",
+    "machine_software",
+    "user_timer",
+    "supervisor_timer",
+    "hypervisor_timer",
+    "machine_timer",
+    "user_external",
+    "supervisor_external",
+    "hypervisor_external",
+    "machine_external",
+];
+
+const PRIVILEGES: [&str; 4] = ["u", "s", "h", "m"];
+
+fn cause_name(cause: u64) -> String {
+    let code = (cause & !(1 << 63)) as usize;
+    let names = if cause >> 63 == 1 {
+        INTERRUPTS
+    } else {
+        EXCEPTIONS
+    };
+    match names.get(code) {
+        Some(name) => name.to_string(),
+        None => format!("cause_{}", code),
+    }
+}
+
+/// Extension `name` belongs to
+fn extension(name: &str) -> &'static str {
+    if name.starts_with("amo") || name.starts_with("lr.") || name.starts_with("sc.") {
+        "A"
+    } else if ["mul", "div", "rem"].iter().any(|p| name.starts_with(p)) {
+        "M"
+    } else if name.starts_with("csrr") {
+        "Zicsr"
+    } else if name == "fence.i" {
+        "Zifencei"
+    } else if ["uret", "sret", "mret", "dret", "wfi", "sfence.vma"].contains(&name) {
+        "priv"
+    } else {
+        "I"
+    }
+}
+
+/// Bytes the instruction called `name` reads or writes
+fn access_size(name: &str) -> Option<u64> {
+    Some(match name {
+        "lb" | "lbu" | "sb" => 1,
+        "lh" | "lhu" | "sh" => 2,
+        "lw" | "lwu" | "sw" => 4,
+        "ld" | "sd" => 8,
+        _ if extension(name) == "A" && name.ends_with(".w") => 4,
+        _ if extension(name) == "A" => 8,
+        _ => return None,
+    })
+}
+
+/// The cause of the trap taken between `s` and `a`, going by where the
+/// hart ended up
+pub(crate) fn trap(s: &State, a: &State) -> Option<u64> {
+    let entered = |tvec: u64, cause: u64, epc: u64| {
+        let base = tvec & !3;
+        let interrupt = cause >> 63 == 1;
+        let vectored = tvec & 3 == 1 && interrupt && a.pc == base + 4 * (cause & 0x3f);
+        (a.pc == base || vectored) && (epc == s.pc || interrupt)
+    };
+    if a.prv == 3 && entered(a.mtvec, a.mcause, a.mepc) {
+        Some(a.mcause)
+    } else if a.prv == 1 && entered(a.stvec, a.scause, a.sepc) {
+        Some(a.scause)
+    } else {
+        None
+    }
+}
+
+/// Counts gathered one instruction at a time
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    instructions: u64,
+    unknown: u64,
+    mnemonics: HashMap<&'static str, u64>,
+    privileges: [u64; 4],
+    traps: HashMap<u64, u64>,
+    access_sizes: BTreeMap<u64, u64>,
+    pcs: HashMap<u64, u64>,
+}
+
+impl Stats {
+    /// Counts the instruction `bits` that took the hart from `before` to
+    /// `after`
+    pub(crate) fn record(&mut self, before: &State, bits: Option<u32>, after: &State) {
+        self.instructions += 1;
+        self.privileges[before.prv as usize & 3] += 1;
+        *self.pcs.entry(before.pc).or_default() += 1;
+
+        let trapped = trap(before, after);
+        if let Some(cause) = trapped {
+            *self.traps.entry(cause).or_default() += 1;
+        }
+        match bits.and_then(decode).map(|op| OPCODES[op].name) {
+            Some(name) => {
+                *self.mnemonics.entry(name).or_default() += 1;
+                // a faulting access never happened
+                if let Some(size) = access_size(name).filter(|_| trapped.is_none()) {
+                    *self.access_sizes.entry(size).or_default() += 1;
+                }
+            }
+            None => self.unknown += 1,
+        }
+    }
+
+    /// The `top` hottest pcs and functions along with everything else
+    pub(crate) fn summary(&self, symbols: Option<&Symbols>, top: usize) -> Summary {
+        let mut extensions = BTreeMap::new();
+        for (name, count) in &self.mnemonics {
+            *extensions.entry(extension(name)).or_default() += count;
+        }
+        let privileges = (0..4)
+            .filter(|&p| self.privileges[p] > 0)
+            .map(|p| (PRIVILEGES[p], self.privileges[p]))
+            .collect();
+        let mut traps = BTreeMap::new();
+        let mut interrupts = BTreeMap::new();
+        for (&cause, &count) in &self.traps {
+            let counts = if cause >> 63 == 1 {
+                &mut interrupts
+            } else {
+                &mut traps
+            };
+            *counts.entry(cause_name(cause)).or_default() += count;
+        }
+
+        let symbol = |pc| symbols.and_then(|s| s.containing(pc));
+        let mut pcs: Vec<_> = self.pcs.iter().map(|(&pc, &count)| (count, pc)).collect();
+        pcs.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        pcs.truncate(top);
+        let mut functions: HashMap<&str, u64> = HashMap::new();
+        if symbols.is_some() {
+            for (&pc, &count) in &self.pcs {
+                *functions.entry(symbol(pc).unwrap_or("?")).or_default() += count;
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        functions.truncate(top);
+
+        Summary {
+            instructions: self.instructions,
+            unknown: self.unknown,
+            mnemonics: self.mnemonics.iter().map(|(&n, &c)| (n, c)).collect(),
+            extensions,
+            privileges,
+            traps,
+            interrupts,
+            access_sizes: self.access_sizes.clone(),
+            hot_pcs: pcs
+                .into_iter()
+                .map(|(count, pc)| HotPc {
+                    pc,
+                    count,
+                    symbol: symbol(pc).map(String::from),
+                })
+                .collect(),
+            hot_functions: functions
+                .into_iter()
+                .map(|(name, count)| HotFunction {
+                    name: name.to_string(),
+                    count,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct HotPc {
+    pc: u64,
+    count: u64,
+    /// function `pc` is in
+    symbol: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct HotFunction {
+    /// `?` for code outside every symbol
+    name: String,
+    count: u64,
+}
+
+/// What `stats` reports, in the shape written as JSON
+#[derive(Serialize, Debug)]
+pub(crate) struct Summary {
+    instructions: u64,
+    /// instructions that didn't decode
+    unknown: u64,
+    mnemonics: BTreeMap<&'static str, u64>,
+    extensions: BTreeMap<&'static str, u64>,
+    /// instructions executed in each privilege mode
+    privileges: BTreeMap<&'static str, u64>,
+    traps: BTreeMap<String, u64>,
+    interrupts: BTreeMap<String, u64>,
+    /// loads, stores and AMOs by bytes accessed
+    access_sizes: BTreeMap<u64, u64>,
+    hot_pcs: Vec<HotPc>,
+    hot_functions: Vec<HotFunction>,
+}
+
+impl Summary {
+    /// Writes the summary as JSON to `path`, or stdout for `-`
+    pub(crate) fn write_json(&self, path: &str) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out: Box<dyn Write> = match path {
+            "-" => Box::new(stdout.lock()),
+            _ => {
+                Box::new(BufWriter::new(File::create(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{}: {}", path, e))
+                })?))
+            }
+        };
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.instructions.max(1) as f64;
+        let percent = |n: u64| 100.0 * n as f64 / total;
+        let sorted = |counts: &BTreeMap<&'static str, u64>| {
+            let mut counts: Vec<_> = counts.iter().map(|(&n, &c)| (n, c)).collect();
+            counts.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+            counts
+        };
+
+        writeln!(
+            f,
+            "{} instructions, {} unknown",
+            self.instructions, self.unknown
+        )?;
+        writeln!(f, "\nPrivilege:")?;
+        for (prv, n) in &self.privileges {
+            writeln!(f, "{:>12} {:6.2}% {}", n, percent(*n), prv)?;
+        }
+        writeln!(f, "\nExtensions:")?;
+        for (ext, n) in sorted(&self.extensions) {
+            writeln!(f, "{:>12} {:6.2}% {}", n, percent(n), ext)?;
+        }
+        writeln!(f, "\nMnemonics:")?;
+        for (name, n) in sorted(&self.mnemonics) {
+            writeln!(f, "{:>12} {:6.2}% {}", n, percent(n), name)?;
+        }
+        for (title, counts) in &[("Traps", &self.traps), ("Interrupts", &self.interrupts)] {
+            if !counts.is_empty() {
+                writeln!(f, "\n{}:", title)?;
+                for (cause, n) in counts.iter() {
+                    writeln!(f, "{:>12} {}", n, cause)?;
+                }
+            }
+        }
+        writeln!(f, "\nMemory accesses:")?;
+        for (size, n) in &self.access_sizes {
+            writeln!(f, "{:>12} {} bytes", n, size)?;
+        }
+        writeln!(f, "\nHottest pcs:")?;
+        for hot in &self.hot_pcs {
+            write!(
+                f,
+                "{:>12} {:6.2}% 0x{:x}",
+                hot.count,
+                percent(hot.count),
+                hot.pc
+            )?;
+            match hot.symbol {
+                Some(ref symbol) => writeln!(f, " <{}>", symbol)?,
+                None => writeln!(f)?,
+            }
+        }
+        if !self.hot_functions.is_empty() {
+            writeln!(f, "\nHottest functions:")?;
+        }
+        for hot in &self.hot_functions {
+            writeln!(
+                f,
+                "{:>12} {:6.2}% {}",
+                hot.count,
+                percent(hot.count),
+                hot.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Steps `cpu` one instruction at a time until it stops or `count`
+/// instructions retire, counting each
+pub(crate) fn record_run<M: Memory>(
+    cpu: &mut Processor<M>,
+    matchers: &mut Matchers<M>,
+    count: Option<u64>,
+) -> Stats {
+    let mut stats = Stats::default();
+    let mut before: State = (&*cpu).into();
+    while !cpu.is_stopped() && count.is_none_or(|n| stats.instructions < n) {
+        let bits = cpu.mmu_mut().read_insn(before.pc).ok();
+        cpu.step(matchers);
+        let after: State = (&*cpu).into();
+        stats.record(&before, bits, &after);
+        before = after;
+    }
+    stats
+}
+
+const USAGE: &str = "usage: stats [options] [TRACE]
+Reads TRACE in any trace format, or stdin, and reports the instruction
+mix, privilege split, traps, hottest code and memory access sizes.
+  --elf FILE   ELF to name hot functions from
+  --from N     skip to instruction N
+  --count N    stop after N instructions
+  --top N      hot pcs and functions to list, 20 by default
+  --json FILE  write JSON to FILE, or to stdout for -";
+
+fn usage_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", msg, USAGE))
+}
+
+/// Command line options of `stats`
+#[derive(Debug)]
+struct Options {
+    trace: Option<String>,
+    elf: Option<String>,
+    from: u64,
+    count: Option<u64>,
+    top: usize,
+    json: Option<String>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> io::Result<Options> {
+        let mut opts = Options {
+            trace: None,
+            elf: None,
+            from: 0,
+            count: None,
+            top: 20,
+            json: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| usage_error(&format!("missing value for {}", arg)))
+            };
+            let bad_value = |e: String| usage_error(&format!("{}: {}", arg, e));
+            let number = |v: String| parse_number(&v).map_err(bad_value);
+            let count = |v: String| parse_count(&v).map_err(bad_value);
+            match arg.as_str() {
+                "--elf" => opts.elf = Some(value()?),
+                "--from" => opts.from = count(value()?)?,
+                "--count" => opts.count = Some(count(value()?)?),
+                "--top" => opts.top = number(value()?)? as usize,
+                "--json" => opts.json = Some(value()?),
+                "-h" | "--help" => return Err(usage_error("")),
+                _ if arg.starts_with('-') => {
+                    return Err(usage_error(&format!("unknown argument {}", arg)))
+                }
+                _ if opts.trace.is_none() => opts.trace = Some(arg),
+                _ => return Err(usage_error("only one trace at a time")),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Runs `stats` with the process arguments
+pub fn stats() -> io::Result<()> {
+    let opts = Options::parse(std::env::args().skip(1))?;
+    let symbols = opts.elf.as_deref().map(Symbols::load).transpose()?;
+    let reader = match opts.trace {
+        Some(ref path) => {
+            let file = File::open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            tuples(io::BufReader::new(file))?
+        }
+        None => tuples(io::BufReader::new(io::stdin()))?,
+    };
+
+    let transactions = TransactionIterator::new(reader).skip(opts.from as usize);
+    let mut stats = Stats::default();
+    for t in transactions.take(opts.count.map_or(usize::MAX, |n| n as usize)) {
+        let bits = t.insn.as_ref().map(|insn| insn.bits);
+        stats.record(&t.state, bits, &t.after);
+    }
+
+    let summary = stats.summary(symbols.as_ref(), opts.top);
+    match opts.json {
+        Some(ref path) if path == "-" => summary.write_json(path),
+        Some(ref path) => {
+            print!("{}", summary);
+            summary.write_json(path)
+        }
+        None => {
+            print!("{}", summary);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(pc: u64, prv: u64) -> State {
+        State {
+            pc,
+            prv,
+            mtvec: 0x8000_0100,
+            stvec: 0x8000_0201,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let mut stats = Stats::default();
+        let addi = 0x0010_0293;
+        let ld = 0x0003_3283;
+        stats.record(&state(0x1000, 0), Some(addi), &state(0x1004, 0));
+        stats.record(&state(0x1004, 0), Some(addi), &state(0x1008, 0));
+        stats.record(&state(0x1008, 0), Some(ld), &state(0x100c, 0));
+        stats.record(&state(0x100c, 0), Some(0xffff_ffff), &state(0x1010, 0));
+
+        // a user ecall delegated to S
+        let mut after = state(0x8000_0200, 1);
+        after.scause = 8;
+        after.sepc = 0x1010;
+        stats.record(&state(0x1010, 0), Some(0x0000_0073), &after);
+
+        // a timer interrupt, vectored in S
+        let mut after = state(0x8000_0214, 1);
+        after.scause = 1 << 63 | 5;
+        after.sepc = 0x8000_0300;
+        stats.record(&state(0x8000_0200, 1), Some(addi), &after);
+
+        // a load page fault to M doesn't count as an access
+        let mut after = state(0x8000_0100, 3);
+        after.mcause = 13;
+        after.mepc = 0x8000_0214;
+        stats.record(&state(0x8000_0214, 1), Some(ld), &after);
+
+        let summary = stats.summary(None, 2);
+        assert_eq!(summary.instructions, 7);
+        assert_eq!(summary.unknown, 1);
+        assert_eq!(summary.mnemonics["addi"], 3);
+        assert_eq!(summary.extensions["I"], 6);
+        assert_eq!(summary.privileges["u"], 5);
+        assert_eq!(summary.privileges["s"], 2);
+        assert_eq!(summary.traps["user_ecall"], 1);
+        assert_eq!(summary.traps["load_page_fault"], 1);
+        assert_eq!(summary.interrupts["supervisor_timer"], 1);
+        assert_eq!(summary.access_sizes.get(&8), Some(&1));
+        assert_eq!(summary.hot_pcs.len(), 2);
+        assert_eq!(summary.hot_pcs[0].pc, 0x1000);
+        assert!(summary.hot_functions.is_empty());
+
+        let json = serde_json::to_string(&summary).expect("json");
+        assert!(json.contains("\"access_sizes\":{\"8\":1}"));
+        assert!(summary.to_string().contains("supervisor_timer"));
+
+        assert!(Options::parse(["--top", "lots"].iter().map(|s| s.to_string())).is_err());
+    }
+}
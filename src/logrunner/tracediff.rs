@@ -0,0 +1,542 @@
+use super::container::tuples;
+use super::{FieldDiff, LogTuple, MemoryTrace, HTIF};
+use crate::disasm::disasm;
+use crate::machine::{parse_count, parse_number, parse_range};
+use crate::regs::REG_NAMES;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+/*
+ *
+ * Tracediff
+ * ---------
+ * Where two traces of the same program part ways, such as two builds
+ * of risk5 or risk5 and spike.
+ *
+ */
+
+/// State fields `State::diff` reports, besides the registers
+const FIELDS: &[&str] = &[
+    "pc",
+    "prv",
+    "mepc",
+    "mtvec",
+    "mcause",
+    "mscratch",
+    "mie",
+    "mip",
+    "medeleg",
+    "mideleg",
+    "mcounteren",
+    "scounteren",
+    "sepc",
+    "stval",
+    "sscratch",
+    "stvec",
+    "satp",
+    "scause",
+    "mstatus",
+    "mtval",
+    "misa",
+    "mhartid",
+    "sedeleg",
+    "sideleg",
+    "sip",
+];
+
+/// cycle, time, instret, mcycle and minstret
+const COUNTERS: &[u32] = &[0xc00, 0xc01, 0xc02, 0xb00, 0xb02];
+
+/// Differences that don't count
+#[derive(Debug, Default)]
+struct Ignore {
+    fields: HashSet<&'static str>,
+    /// what reading the counter CSRs returns
+    counters: bool,
+    /// loads and stores here
+    memory: Vec<Range<u64>>,
+}
+
+impl Ignore {
+    fn add(&mut self, entry: &str) -> Result<(), String> {
+        match entry {
+            "" => (),
+            "timer" => {
+                self.counters = true;
+                self.fields.extend(&["mip", "sip"]);
+            }
+            "htif" => self.memory.push(HTIF),
+            _ => match entry.strip_prefix("mem:") {
+                Some(range) => {
+                    let (lo, hi) = parse_range(range)?;
+                    self.memory.push(lo..hi.saturating_add(1));
+                }
+                None => {
+                    let reg = entry
+                        .strip_prefix('x')
+                        .and_then(|n| n.parse::<usize>().ok());
+                    let field = match reg {
+                        Some(n) => REG_NAMES.get(n).copied(),
+                        None => FIELDS
+                            .iter()
+                            .chain(REG_NAMES)
+                            .find(|&&f| f == entry)
+                            .copied(),
+                    };
+                    let field = field.ok_or_else(|| format!("unknown field '{}'", entry))?;
+                    self.fields.insert(field);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn memory(&self, addr: u64) -> bool {
+        self.memory.iter().any(|r| r.contains(&addr))
+    }
+
+    /// The register the instruction of `t` filled with a value that
+    /// doesn't count
+    fn rd(&self, t: &LogTuple) -> Option<&'static str> {
+        let bits = t.insn.as_ref()?.bits;
+        let rs1 = t.state.xregs[(bits >> 15 & 0x1f) as usize];
+        let ignored = match bits & 0x7f {
+            // csrr* other than ecall and friends
+            0x73 => self.counters && bits >> 12 & 3 != 0 && COUNTERS.contains(&(bits >> 20)),
+            // loads
+            0x03 => self.memory(rs1.wrapping_add((bits as i32 >> 20) as u64)),
+            // AMOs
+            0x2f => self.memory(rs1),
+            _ => false,
+        };
+        if ignored {
+            Some(REG_NAMES[(bits >> 7 & 0x1f) as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Whether the stores of A and B differ in a way that counts
+    fn stores(&self, a: &Option<MemoryTrace>, b: &Option<MemoryTrace>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) if a.addr == b.addr && a.value == b.value => false,
+            (None, None) => false,
+            _ => !a.iter().chain(b).any(|s| self.memory(s.addr)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Align {
+    /// the nth instruction of A against the nth of B
+    Count,
+    /// skip ahead in one trace when the pcs differ
+    Pc,
+}
+
+const USAGE: &str = "usage: tracediff [options] A B
+Compares the traces A and B, each in any trace format or - for stdin,
+and reports where fields start to differ.
+  --align MODE     count, the default, or pc to skip ahead in one trace
+                   to get back in step after control flow differs
+  --window N       instructions to look ahead for --align pc, 10000 by
+                   default
+  --max N          stop after N divergences, 10 by default
+  --context N      instructions of A to show before each, 5 by default
+  --ignore LIST    comma separated differences to ignore; may be repeated
+                   timer      counter CSR reads, mip and sip
+                   htif       loads and stores to the HTIF
+                   mem:LO:HI  loads and stores from LO to HI, inclusive
+                   FIELD      a state field or register, e.g. mcause or a0
+                   timer,htif by default
+  --strict         don't ignore timer and htif";
+
+fn usage_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", msg, USAGE))
+}
+
+/// Command line options of `tracediff`
+#[derive(Debug)]
+struct Options {
+    traces: Vec<String>,
+    align: Align,
+    window: u64,
+    max: usize,
+    context: usize,
+    ignore: Ignore,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> io::Result<Options> {
+        let mut opts = Options {
+            traces: vec![],
+            align: Align::Count,
+            window: 10_000,
+            max: 10,
+            context: 5,
+            ignore: Ignore::default(),
+        };
+        let mut ignore = vec![];
+        let mut strict = false;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| usage_error(&format!("missing value for {}", arg)))
+            };
+            let bad_value = |e: String| usage_error(&format!("{}: {}", arg, e));
+            let number = |v: String| parse_number(&v).map_err(bad_value);
+            match arg.as_str() {
+                "--align" => {
+                    opts.align = match value()?.as_str() {
+                        "count" => Align::Count,
+                        "pc" => Align::Pc,
+                        a => return Err(bad_value(format!("unknown alignment '{}'", a))),
+                    }
+                }
+                "--window" => opts.window = parse_count(&value()?).map_err(bad_value)?,
+                "--max" => opts.max = number(value()?)? as usize,
+                "--context" => opts.context = number(value()?)? as usize,
+                "--ignore" => ignore.extend(value()?.split(',').map(String::from)),
+                "--strict" => strict = true,
+                "-h" | "--help" => return Err(usage_error("")),
+                "-" => opts.traces.push(arg),
+                _ if arg.starts_with('-') => {
+                    return Err(usage_error(&format!("unknown argument {}", arg)))
+                }
+                _ => opts.traces.push(arg),
+            }
+        }
+        if !strict {
+            ignore.extend(vec!["timer".to_string(), "htif".to_string()]);
+        }
+        for entry in &ignore {
+            opts.ignore
+                .add(entry)
+                .map_err(|e| usage_error(&format!("--ignore: {}", e)))?;
+        }
+        if opts.traces.len() != 2 {
+            return Err(usage_error("expected two traces"));
+        }
+        Ok(opts)
+    }
+}
+
+/// A trace that can be looked ahead in
+struct Stream {
+    tuples: Box<dyn Iterator<Item = LogTuple>>,
+    ahead: VecDeque<LogTuple>,
+    /// tuples taken so far
+    taken: u64,
+}
+
+impl Stream {
+    fn new(tuples: Box<dyn Iterator<Item = LogTuple>>) -> Self {
+        Stream {
+            tuples,
+            ahead: VecDeque::new(),
+            taken: 0,
+        }
+    }
+
+    fn peek(&mut self, n: u64) -> Option<&LogTuple> {
+        while self.ahead.len() as u64 <= n {
+            let t = self.tuples.next()?;
+            self.ahead.push_back(t);
+        }
+        self.ahead.get(n as usize)
+    }
+
+    fn pc(&mut self, n: u64) -> Option<u64> {
+        self.peek(n).map(|t| t.state.pc)
+    }
+}
+
+impl Iterator for Stream {
+    type Item = LogTuple;
+
+    fn next(&mut self) -> Option<LogTuple> {
+        let t = self.ahead.pop_front().or_else(|| self.tuples.next())?;
+        self.taken += 1;
+        Some(t)
+    }
+}
+
+/// Instructions to skip in A and B to get them to the same pc
+fn resync(a: &mut Stream, b: &mut Stream, window: u64) -> Option<(u64, u64)> {
+    let (pc_a, pc_b) = (a.pc(0)?, b.pc(0)?);
+    for n in 1..=window {
+        let (next_a, next_b) = (a.pc(n), b.pc(n));
+        if next_a == Some(pc_b) {
+            return Some((n, 0));
+        }
+        if next_b == Some(pc_a) {
+            return Some((0, n));
+        }
+        if next_a.is_none() && next_b.is_none() {
+            break;
+        }
+    }
+    None
+}
+
+/// Where fields of the two traces started to differ
+#[derive(Debug)]
+struct Divergence {
+    /// positions in A and B of the first state that differs
+    a: u64,
+    b: u64,
+    /// what A executed up to here, the instruction responsible last
+    context: Vec<LogTuple>,
+    fields: Vec<FieldDiff>,
+    /// stores of A and B by the instruction responsible
+    stores: Option<(Option<MemoryTrace>, Option<MemoryTrace>)>,
+    /// instructions of A and B skipped to get back to the same pc
+    skipped: (u64, u64),
+}
+
+fn describe(t: &LogTuple) -> String {
+    match t.insn {
+        Some(ref insn) => format!("0x{:016x} {}", insn.pc, disasm(insn.pc, insn.bits)),
+        None => format!("0x{:016x} ?", t.state.pc),
+    }
+}
+
+fn describe_store(store: &Option<MemoryTrace>) -> String {
+    match store {
+        Some(s) => format!("0x{:x} at 0x{:x}", s.value, s.addr),
+        None => "none".into(),
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "A#{} and B#{} differ", self.a, self.b)?;
+        match self.skipped {
+            (0, 0) => writeln!(f)?,
+            (a, b) => writeln!(f, " after skipping {} of A and {} of B", a, b)?,
+        }
+        let first = self.a - self.context.len() as u64;
+        for (i, t) in (first..).zip(&self.context) {
+            writeln!(f, "  A#{:<10} {}", i, describe(t))?;
+        }
+        for diff in &self.fields {
+            writeln!(
+                f,
+                "{:>12}: A=0x{:016x} B=0x{:016x}",
+                diff.field, diff.expected, diff.actual
+            )?;
+        }
+        if let Some((ref a, ref b)) = self.stores {
+            writeln!(
+                f,
+                "{:>12}: A={} B={}",
+                "store",
+                describe_store(a),
+                describe_store(b)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Walks A and B together and returns the first `opts.max` divergences.
+/// A field that keeps differing is only reported where it starts to.
+fn diff_traces(a: &mut Stream, b: &mut Stream, opts: &Options) -> Vec<Divergence> {
+    let mut divergences = vec![];
+    let mut history: VecDeque<LogTuple> = VecDeque::new();
+    let mut prev: Option<(LogTuple, LogTuple)> = None;
+    let mut differing: HashSet<&'static str> = HashSet::new();
+    let mut skipped = (0, 0);
+
+    while divergences.len() < opts.max {
+        let (pc_a, pc_b) = match (a.pc(0), b.pc(0)) {
+            (Some(pc_a), Some(pc_b)) => (pc_a, pc_b),
+            _ => break,
+        };
+        if opts.align == Align::Pc && pc_a != pc_b {
+            let (skip_a, skip_b) = match resync(a, b, opts.window) {
+                Some(skip) => skip,
+                None => {
+                    warn!("No common pc within {} instructions", opts.window);
+                    break;
+                }
+            };
+            history.extend(a.take(skip_a as usize));
+            b.take(skip_b as usize).for_each(drop);
+            skipped = (skip_a, skip_b);
+            prev = None;
+        }
+        let (ta, tb) = match (a.next(), b.next()) {
+            (Some(ta), Some(tb)) => (ta, tb),
+            _ => break,
+        };
+
+        let mut fields = ta.state.diff(&tb.state, None);
+        let now: HashSet<_> = fields.iter().map(|d| d.field).collect();
+        let rd = prev.as_ref().and_then(|(p, _)| opts.ignore.rd(p));
+        fields.retain(|d| {
+            !differing.contains(d.field)
+                && !opts.ignore.fields.contains(d.field)
+                && Some(d.field) != rd
+        });
+        differing = now;
+        let stores = prev
+            .as_ref()
+            .filter(|(pa, pb)| opts.ignore.stores(&pa.store, &pb.store))
+            .map(|(pa, pb)| (pa.store.clone(), pb.store.clone()));
+
+        if !fields.is_empty() || stores.is_some() || skipped != (0, 0) {
+            while history.len() > opts.context {
+                history.pop_front();
+            }
+            divergences.push(Divergence {
+                a: a.taken - 1,
+                b: b.taken - 1,
+                context: history.iter().cloned().collect(),
+                fields,
+                stores,
+                skipped,
+            });
+        }
+        skipped = (0, 0);
+
+        history.push_back(ta.clone());
+        while history.len() > opts.context {
+            history.pop_front();
+        }
+        prev = Some((ta, tb));
+    }
+    divergences
+}
+
+fn open(path: &str) -> io::Result<Stream> {
+    let tuples = match path {
+        "-" => tuples(io::BufReader::new(io::stdin()))?,
+        _ => {
+            let file = File::open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            tuples(io::BufReader::new(file))?
+        }
+    };
+    Ok(Stream::new(tuples))
+}
+
+/// Runs `tracediff` with the process arguments
+pub fn tracediff() -> io::Result<()> {
+    let opts = Options::parse(std::env::args().skip(1))?;
+    let (a, b) = (&mut open(&opts.traces[0])?, &mut open(&opts.traces[1])?);
+    let divergences = diff_traces(a, b, &opts);
+    for d in &divergences {
+        println!("{}", d);
+    }
+
+    let (rest_a, rest_b) = (a.peek(0).is_some(), b.peek(0).is_some());
+    println!(
+        "{} divergences in {} instructions of A and {} of B",
+        divergences.len(),
+        a.taken,
+        b.taken
+    );
+    if divergences.len() < opts.max && rest_a != rest_b {
+        println!("{} ended first", if rest_a { "B" } else { "A" });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Insn, MemoryTraceKind, State};
+    use super::*;
+
+    fn tuple(pc: u64, bits: u32, regs: &[(usize, u64)]) -> LogTuple {
+        let mut state = State {
+            pc,
+            prv: 3,
+            ..Default::default()
+        };
+        for &(i, v) in regs {
+            state.xregs[i] = v;
+        }
+        LogTuple {
+            line: 0,
+            state,
+            insn: Some(Insn {
+                pc,
+                bits,
+                desc: String::new(),
+            }),
+            store: None,
+            mems: vec![],
+        }
+    }
+
+    fn stream(tuples: Vec<LogTuple>) -> Stream {
+        Stream::new(Box::new(tuples.into_iter()))
+    }
+
+    fn parse(args: &[&str]) -> io::Result<Options> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    const ADDI: u32 = 0x0010_0293; // addi t0, zero, 1
+    const RDTIME: u32 = 0xc010_2373; // rdtime t1
+
+    #[test]
+    fn finds_divergences() {
+        let a = vec![
+            tuple(0x1000, RDTIME, &[]),
+            tuple(0x1004, ADDI, &[(6, 100)]),
+            tuple(0x1008, ADDI, &[(5, 1), (6, 100)]),
+            tuple(0x100c, ADDI, &[(5, 1), (6, 100)]),
+        ];
+        let mut b = a.clone();
+        // a different time is fine, a different t0 isn't
+        for t in &mut b[1..] {
+            t.state.xregs[6] = 200;
+        }
+        b[2].state.xregs[5] = 2;
+        b[3].state.xregs[5] = 2;
+        b[2].store = Some(MemoryTrace {
+            kind: MemoryTraceKind::Uint64,
+            addr: 0x8000_9000,
+            value: 1,
+        });
+
+        let opts = parse(&["--context", "1", "a", "b"]).expect("options");
+        let found = diff_traces(&mut stream(a.clone()), &mut stream(b.clone()), &opts);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].a, found[0].b), (2, 2));
+        assert_eq!(found[0].fields.len(), 1);
+        assert_eq!(found[0].fields[0].field, "t0");
+        assert_eq!(found[0].context.len(), 1);
+        assert!(found[0].to_string().contains("t0: A=0x0000000000000001"));
+
+        let opts = parse(&["--strict", "--ignore", "t0", "a", "b"]).expect("options");
+        let found = diff_traces(&mut stream(a.clone()), &mut stream(b), &opts);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].fields[0].field, "t1");
+        assert!(found[1].stores.is_some());
+
+        // B takes an interrupt before 0x1008
+        let mut b = a.clone();
+        b.insert(2, tuple(0x8000_0000, ADDI, &[(6, 100)]));
+        b.insert(3, tuple(0x8000_0004, ADDI, &[(6, 100)]));
+        let opts = parse(&["--align", "pc", "a", "b"]).expect("options");
+        let found = diff_traces(&mut stream(a.clone()), &mut stream(b.clone()), &opts);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].a, found[0].b), (2, 4));
+        assert_eq!(found[0].skipped, (0, 2));
+
+        let opts = parse(&["a", "b"]).expect("options");
+        let found = diff_traces(&mut stream(a), &mut stream(b), &opts);
+        assert_eq!(found[0].fields[0].field, "pc");
+
+        assert!(parse(&["a"]).is_err());
+        assert!(parse(&["--ignore", "mem:0x1000", "a", "b"]).is_err());
+        assert!(parse(&["--ignore", "x33", "a", "b"]).is_err());
+        assert!(parse(&["--ignore", "x5,mcause,mem:0:0xfff", "a", "b"]).is_ok());
+    }
+}
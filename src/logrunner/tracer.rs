@@ -0,0 +1,404 @@
+use super::commitlog::write_commit;
+use super::container::{to_io, ContainerWriter, Header};
+use super::{json, Insn, LogLine, LogTuple, MemoryTrace, MemoryTraceKind, State};
+use crate::disasm::disasm;
+use crate::machine::isa_string;
+use crate::{Matchers, Memory, Processor};
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+/*
+ *
+ * Tracer
+ * ------
+ * Traces of risk5's own runs, in the formats spike traces come in, so
+ * `filter`, `stats` and `tracediff` work on them too, and as the
+ * commit log spike writes for tools of its own.
+ *
+ */
+
+/// Memory recording what each instruction reads and stores, as a
+/// tuple carries it
+pub(crate) struct Recorder<M> {
+    mem: M,
+    mems: Vec<MemoryTrace>,
+    // bytes the instruction has read or written so far
+    seen: HashSet<u64>,
+    store: Option<MemoryTrace>,
+}
+
+impl<M> Recorder<M> {
+    pub fn new(mem: M) -> Self {
+        Recorder {
+            mem,
+            mems: vec![],
+            seen: HashSet::new(),
+            store: None,
+        }
+    }
+
+    /// Start recording the next instruction
+    fn clear(&mut self) {
+        self.mems.clear();
+        self.seen.clear();
+        self.store = None;
+    }
+
+    /// The bytes read since `clear`, each with the value it had before,
+    /// and the last store
+    fn take(&mut self) -> (Vec<MemoryTrace>, Option<MemoryTrace>) {
+        (std::mem::take(&mut self.mems), self.store.take())
+    }
+
+    fn read(&mut self, offset: u64, value: u64, size: u64) {
+        for i in 0..size {
+            // a byte read after a store has its stored value
+            if self.seen.insert(offset + i) {
+                self.mems.push(MemoryTrace {
+                    kind: MemoryTraceKind::Uint8,
+                    addr: offset + i,
+                    value: (value >> (i * 8)) & 0xff,
+                });
+            }
+        }
+    }
+
+    fn wrote(&mut self, offset: u64, value: u64, size: u64, kind: MemoryTraceKind) {
+        self.seen.extend(offset..offset + size);
+        self.store = Some(MemoryTrace {
+            kind,
+            addr: offset,
+            value,
+        });
+    }
+}
+
+macro_rules! recorded_access {
+    ($read:ident, $write:ident, $t:ty, $size:expr, $kind:ident) => {
+        fn $read(&mut self, offset: u64) -> $t {
+            let value = self.mem.$read(offset);
+            self.read(offset, value as u64, $size);
+            value
+        }
+
+        fn $write(&mut self, offset: u64, value: $t) {
+            self.mem.$write(offset, value);
+            self.wrote(offset, value as u64, $size, MemoryTraceKind::$kind);
+        }
+    };
+}
+
+impl<M: Memory> Memory for Recorder<M> {
+    recorded_access!(read_b, write_b, u8, 1, Uint8);
+    recorded_access!(read_h, write_h, u16, 2, Uint16);
+    recorded_access!(read_w, write_w, u32, 4, Uint32);
+    recorded_access!(read_d, write_d, u64, 8, Uint64);
+
+    fn reserve(&mut self, offset: u64) {
+        self.mem.reserve(offset)
+    }
+
+    fn take_reservation(&mut self, offset: u64) -> bool {
+        self.mem.take_reservation(offset)
+    }
+
+    fn fence(&mut self) {
+        self.mem.fence()
+    }
+
+    fn is_ram(&mut self, offset: u64, len: u64) -> bool {
+        self.mem.is_ram(offset, len)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    #[default]
+    Bincode,
+    Container,
+    /// spike's `--log-commits` lines
+    Commits,
+}
+
+impl Format {
+    /// Parse `json`, `bincode`, `container` or `commits`
+    pub fn parse(s: &str) -> Result<Format, String> {
+        match s {
+            "json" => Ok(Format::Json),
+            "bincode" => Ok(Format::Bincode),
+            "container" => Ok(Format::Container),
+            "commits" => Ok(Format::Commits),
+            _ => Err(format!("unknown trace format '{}'", s)),
+        }
+    }
+}
+
+/// Which instructions of a run go into its trace
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TraceFilter {
+    /// first instruction to trace
+    pub from: u64,
+    /// instructions to trace before stopping the run
+    pub count: Option<u64>,
+    /// inclusive pc range
+    pub pc: Option<(u64, u64)>,
+    pub prv: Option<u64>,
+}
+
+impl TraceFilter {
+    fn picks(&self, n: u64, state: &State) -> bool {
+        n >= self.from
+            && self
+                .pc
+                .is_none_or(|(lo, hi)| state.pc >= lo && state.pc <= hi)
+            && self.prv.is_none_or(|prv| state.prv == prv)
+    }
+}
+
+/// Writes tuples in the format the trace readers sniff
+pub(crate) enum TraceWriter<W: Write> {
+    Json(W),
+    Bincode(W),
+    Container(ContainerWriter<W>),
+    /// a commit line needs the state after the instruction, which the
+    /// next tuple brings
+    Commits(W, Option<LogTuple>),
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// `start` is the state the run starts in, whose ISA goes into the
+    /// header of a container
+    pub fn new(mut out: W, format: Format, start: &State) -> io::Result<Self> {
+        // the readers skip everything up to the first tuple, which is a
+        // state and mark for JSON and two marks for bincode
+        Ok(match format {
+            Format::Json => {
+                json_line(&mut out, &json::LogLine::State(start.into()))?;
+                json_line(&mut out, &json::LogLine::Mark)?;
+                TraceWriter::Json(out)
+            }
+            Format::Bincode => {
+                write_frames(&mut out, &[LogLine::Mark, LogLine::Mark])?;
+                TraceWriter::Bincode(out)
+            }
+            Format::Container => {
+                let header = Header::new(isa_string(start.misa), "risk5".into());
+                TraceWriter::Container(ContainerWriter::new(out, &header)?)
+            }
+            Format::Commits => TraceWriter::Commits(out, None),
+        })
+    }
+
+    pub fn push(&mut self, tuple: LogTuple) -> io::Result<()> {
+        match self {
+            TraceWriter::Json(out) => {
+                json_line(out, &json::LogLine::State((&tuple.state).into()))?;
+                if let Some(ref insn) = tuple.insn {
+                    let core = tuple.state.mhartid as usize;
+                    json_line(out, &json::LogLine::Insn(json::JsonInsn::new(insn, core)))?;
+                }
+                for mem in &tuple.mems {
+                    json_line(out, &json::LogLine::Memory(mem.into()))?;
+                }
+                if let Some(ref store) = tuple.store {
+                    json_line(out, &json::LogLine::Store(store.into()))?;
+                }
+                json_line(out, &json::LogLine::Mark)
+            }
+            TraceWriter::Bincode(out) => {
+                let mut lines = vec![LogLine::State(tuple.state)];
+                lines.extend(tuple.insn.map(LogLine::Insn));
+                lines.extend(tuple.mems.into_iter().map(LogLine::Memory));
+                lines.extend(tuple.store.map(LogLine::Store));
+                lines.push(LogLine::Mark);
+                write_frames(out, &lines)
+            }
+            TraceWriter::Container(out) => out.push(tuple),
+            TraceWriter::Commits(out, last) => match last.replace(tuple) {
+                Some(before) if !before.is_gap() => {
+                    let after = &last.as_ref().expect("tuple").state;
+                    write_commit(out, &before, after)
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            TraceWriter::Json(mut out)
+            | TraceWriter::Bincode(mut out)
+            | TraceWriter::Commits(mut out, _) => {
+                out.flush()?;
+                Ok(out)
+            }
+            TraceWriter::Container(out) => out.finish(),
+        }
+    }
+}
+
+fn json_line<W: Write>(out: &mut W, line: &json::LogLine) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, line)?;
+    writeln!(out)
+}
+
+// Bincode lines go in frames led by their length as a little endian
+// `u16`, which a tuple may need more than one of
+fn write_frames<W: Write>(out: &mut W, lines: &[LogLine]) -> io::Result<()> {
+    let mut frame = vec![];
+    for line in lines {
+        let bytes = bincode::serialize(line).map_err(|e| to_io(*e))?;
+        if frame.len() + bytes.len() > u16::MAX as usize {
+            write_frame(out, &frame)?;
+            frame.clear();
+        }
+        frame.extend(bytes);
+    }
+    write_frame(out, &frame)
+}
+
+fn write_frame<W: Write>(out: &mut W, frame: &[u8]) -> io::Result<()> {
+    out.write_all(&(frame.len() as u16).to_le_bytes())?;
+    out.write_all(frame)
+}
+
+/// Steps `cpu` one instruction at a time until it stops or `filter` has
+/// picked its count, writing the instructions it picks to `out`. A
+/// stretch that isn't picked leaves a gap tuple with the state the last
+/// picked instruction ended in. Returns how many were traced.
+pub(crate) fn trace_run<M: Memory, W: Write>(
+    cpu: &mut Processor<Recorder<M>>,
+    matchers: &mut Matchers<Recorder<M>>,
+    filter: &TraceFilter,
+    out: &mut TraceWriter<W>,
+) -> io::Result<u64> {
+    let gap = |state| LogTuple {
+        line: 0,
+        state,
+        insn: None,
+        store: None,
+        mems: vec![],
+    };
+
+    let mut traced = 0;
+    let mut tracing = false;
+    let mut n = 0;
+    while !cpu.is_stopped() && filter.count.is_none_or(|count| traced < count) {
+        let mut state: State = (&*cpu).into();
+        state.id = n;
+
+        cpu.mmu_mut().mem_mut().clear();
+        let bits = cpu.mmu_mut().read_insn(state.pc).ok();
+        cpu.step(matchers);
+        let (mems, store) = cpu.mmu_mut().mem_mut().take();
+
+        let picked = filter.picks(n, &state);
+        if picked {
+            let insn = bits.map(|bits| Insn {
+                pc: state.pc,
+                bits,
+                desc: disasm(state.pc, bits).to_string(),
+            });
+            out.push(LogTuple {
+                line: n as usize,
+                state,
+                insn,
+                store,
+                mems,
+            })?;
+            traced += 1;
+        } else if tracing {
+            out.push(gap(state))?;
+        }
+        tracing = picked;
+        n += 1;
+    }
+
+    if tracing {
+        let mut state: State = (&*cpu).into();
+        state.id = n;
+        out.push(gap(state))?;
+    }
+    Ok(traced)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::container::tuples;
+    use super::super::transaction::TransactionIterator;
+    use super::*;
+    use crate::build_matchers;
+    use crate::memory::{BlockMemory, ByteMap};
+
+    const PROGRAM: &[u32] = &[
+        0x0000_0297, // auipc t0, 0
+        0x1002_8293, // addi  t0, t0, 256
+        0x0640_0313, // li    t1, 100
+        // loop:
+        0x0062_b023, // sd    t1, 0(t0)
+        0x0002_be03, // ld    t3, 0(t0)
+        0x01c3_83b3, // add   t2, t2, t3
+        0x0072_9423, // sh    t2, 8(t0)
+        0x0082_cf03, // lbu   t5, 8(t0)
+        0xfff3_0313, // addi  t1, t1, -1
+        0xfe03_14e3, // bnez  t1, loop
+        0x0000_006f, // j     .
+    ];
+
+    fn trace(format: Format, filter: &TraceFilter) -> Vec<LogTuple> {
+        let mut mem = BlockMemory::new(0);
+        mem.add_block(0x8000_0000, 0x2000);
+        for (i, insn) in PROGRAM.iter().enumerate() {
+            mem.write_w(0x8000_0000 + 4 * i as u64, *insn);
+        }
+        let mut cpu = Processor::new(Recorder::new(mem));
+        cpu.set_pc(0x8000_0000);
+
+        let matchers = &mut build_matchers();
+        let mut out = TraceWriter::new(vec![], format, &(&cpu).into()).expect("writer");
+        trace_run(&mut cpu, matchers, filter, &mut out).expect("trace");
+        let trace = out.finish().expect("finish");
+        tuples(io::Cursor::new(trace)).expect("tuples").collect()
+    }
+
+    #[test]
+    fn replays() {
+        let all = TraceFilter {
+            count: Some(30),
+            ..Default::default()
+        };
+        for format in [Format::Json, Format::Bincode, Format::Container] {
+            let t = trace(format, &all);
+            assert_eq!(t.len(), 31, "{:?}", format);
+            assert_eq!(t[3].state.id, 3);
+            let sd = &t[3];
+            assert_eq!(sd.insn.as_ref().expect("insn").bits, 0x0062_b023);
+            assert_eq!(sd.store.as_ref().expect("store").value, 100);
+            // the fetched instruction, but nothing the store wrote
+            assert_eq!(sd.mems.len(), 4);
+            assert_eq!(t[4].mems.len(), 12);
+            assert!(t[30].is_gap());
+        }
+
+        // every traced instruction replays against the next state
+        let matchers = &mut build_matchers::<ByteMap>();
+        let loads = TraceFilter {
+            from: 2,
+            count: Some(12),
+            pc: Some((0x8000_0010, 0x8000_001c)),
+            ..Default::default()
+        };
+        let t = trace(Format::Bincode, &loads);
+        assert_eq!(t.iter().filter(|t| t.is_gap()).count(), 3);
+        let transactions: Vec<_> = TransactionIterator::new(t.into_iter()).collect();
+        assert_eq!(transactions.len(), 12);
+        for t in &transactions {
+            let pc = t.insn.as_ref().expect("insn").pc;
+            assert!((0x8000_0010..=0x8000_001c).contains(&pc));
+            if let Err(failure) = t.validate(matchers) {
+                panic!("{}", failure);
+            }
+        }
+    }
+}
@@ -1,175 +1,45 @@
-use super::bincode::TupleReader;
-use super::{Insn, LogTuple, MemoryTrace, RestorableState, State, ToMemory};
+use super::bincode::{write_header, TransactionReader, TupleReader};
+use super::container::tuples;
+use super::pool::validate_parallel;
+use super::{Failure, Insn, LogTuple, MemoryTrace, Options, RestorableState, State, ToMemory};
+use crate::opcodes::{decode, OPCODES};
 use crate::{build_matchers, matcher::Matchers, memory::ByteMap, Processor};
-use std::io;
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
 
 pub fn single() -> Result<(), io::Error> {
     let matchers = &mut build_matchers::<ByteMap>();
-    let mut reader = io::BufReader::new(io::stdin());
-    let t: Transaction = bincode::deserialize_from(&mut reader).expect("read transaction");
-    t.validate(matchers);
-    Ok(())
-}
-
-pub fn validatestream() -> Result<(), io::Error> {
-    let matchers = &mut build_matchers::<ByteMap>();
-    let mut reader = io::BufReader::new(io::stdin());
-
-    use std::time::SystemTime;
-    let mark = SystemTime::now();
-    let mut count = 0;
-
-    loop {
-        let t: Transaction = match bincode::deserialize_from(&mut reader) {
-            Ok(t) => t,
-            Err(e) => {
-                if let bincode::ErrorKind::Io(ref e) = *e {
-                    if e.kind() == io::ErrorKind::UnexpectedEof {
-                        break;
-                    }
-                }
-                error!("Failed to read trans: {}", e);
-                panic!("Failed to read trans");
-            }
-        };
-        t.validate(matchers);
-        count += 1;
-    }
-
-    let d = SystemTime::now().duration_since(mark).expect("time");
-    let in_ms = d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000;
-    let in_sec = (in_ms as f32) / 1000f32;
-    let speed = (count as f32) / in_sec;
-    println!("Validated {} transactions @ {} per/sec", count, speed);
-
-    Ok(())
-}
-
-use flate2::write::GzEncoder;
-use flate2::Compression;
-
-struct TransLogFile {
-    name: String,
-    count: usize,
-    idx: usize,
-    mask: u32,
-    mtch: u32,
-    skip: bool,
-    f: Option<GzEncoder<io::BufWriter<std::fs::File>>>,
-}
-
-impl TransLogFile {
-    fn new(mask: u32, mtch: u32, name: &str) -> Self {
-        let mut t = TransLogFile {
-            name: name.into(),
-            count: 0,
-            idx: 0,
-            mask,
-            mtch,
-            skip: false,
-            f: None,
-        };
-        t.open_file();
-        t
-    }
-    fn open_file(&mut self) {
-        let ord = if self.idx == 0 {
-            String::new()
-        } else {
-            format!(".{}", self.idx)
-        };
-        self.f = Some(GzEncoder::new(
-            io::BufWriter::new(
-                std::fs::File::create(format!(
-                    "assets/transactions-logs/{}{}.trans.log.gz",
-                    self.name, ord
-                ))
-                .expect("create trans.log"),
-            ),
-            Compression::default(),
-        ));
-    }
-    fn rotate_file(&mut self) {
-        self.f.take();
-        self.idx += 1;
-        self.count = 0;
-        self.open_file();
-    }
-    fn store(&mut self, t: &Transaction) -> bool {
-        if let Some(insn) = &t.insn {
-            if insn.bits & self.mask == self.mtch {
-                if self.skip {
-                    return true;
-                }
-                bincode::serialize_into(self.f.as_mut().expect("f"), &t).expect("serialize trans");
-                self.count += 1;
-                if self.count >= 250_000 {
-                    self.rotate_file();
-                }
-                return true;
-            }
+    let t = read_one(io::BufReader::new(io::stdin()))?;
+    match t.validate(matchers) {
+        Ok(()) => {
+            info!("ok");
+            Ok(())
         }
-        false
-    }
-}
-
-pub fn filter() -> Result<(), io::Error> {
-    let stdin = io::stdin();
-    let handle = stdin.lock();
-    let reader = super::bincode::LogLineReader::new(io::BufReader::new(handle)).to_tuple();
-
-    let mut logs = vec![
-        TransLogFile::new(0x7f, 0b000_0011, "load"),
-        TransLogFile::new(0x7f, 0b010_0011, "store"),
-        TransLogFile::new(0x7f, 0b011_0011, "comp"),
-        TransLogFile::new(0x7f, 0b001_0011, "imm"),
-        TransLogFile::new(0x7f, 0b001_1011, "immw"),
-        TransLogFile::new(0x7f, 0b110_0011, "branch"),
-        TransLogFile::new(0x7f, 0b011_1011, "wide"),
-        TransLogFile::new(0x7f, 0b111_0011, "system"),
-        TransLogFile::new(0x7f, 0b010_1111, "amo"),
-    ];
-
-    let mut other = TransLogFile::new(0, 0, "other");
-
-    let mut stored = false;
-    for t in TransactionIterator::new(reader)
-        .skip(180_000_000)
-        .take(10_000_000)
-    {
-        stored = false;
-
-        for log in &mut logs {
-            if log.store(&t) {
-                stored = true;
-                break;
+        Err(failure) => {
+            for line in failure.to_string().lines() {
+                error!("{}", line);
             }
-        }
-
-        if !stored {
-            other.store(&t);
+            Err(io::Error::new(io::ErrorKind::Other, "transaction failed"))
         }
     }
+}
 
-    println!("\n\n{}\n", logs[1].count);
-
+pub fn validatestream() -> Result<(), io::Error> {
+    let transactions = TransactionReader::new(io::BufReader::new(io::stdin()))?;
+    validate_parallel(transactions, &Options::from_env())?;
     Ok(())
 }
 
 pub fn stream() -> Result<(), io::Error> {
-    let matchers = &mut build_matchers::<ByteMap>();
+    let reader = tuples(io::BufReader::new(io::stdin()))?;
 
-    let stdin = io::stdin();
-    let handle = stdin.lock();
-    let reader = super::bincode::LogLineReader::new(io::BufReader::new(handle)).to_tuple();
-
-    for t in TransactionIterator::new(reader) {
-        t.validate(matchers);
-    }
+    validate_parallel(TransactionIterator::new(reader), &Options::from_env())?;
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct Transaction {
     pub(crate) state: State,
     pub(crate) insn: Option<Insn>,
@@ -179,61 +49,116 @@ pub(crate) struct Transaction {
 }
 
 impl Transaction {
-    pub fn validate(&self, matchers: &mut Matchers<ByteMap>) {
+    /// Steps a CPU restored from `state` and compares it with `after`
+    pub fn validate(&self, matchers: &mut Matchers<ByteMap>) -> Result<(), Box<Failure>> {
         let mut cpu = {
             let memory = self.mems.to_memory();
             let state = &self.state;
             let mut cpu: Processor<ByteMap> = RestorableState { state, memory }.into();
+            #[cfg(feature = "jit")]
+            cpu.step_native(matchers);
+            #[cfg(not(feature = "jit"))]
             cpu.step(matchers);
             cpu
         };
 
-        let mut fail = if !self.after.validate(&cpu, Some(self.state.clone())) {
-            error!("cpu state transaction fail");
-            true
-        } else {
-            false
+        let actual: State = (&cpu).into();
+        let fields = self.after.diff(&actual, Some(&self.state));
+        let store = match self.store {
+            Some(ref store) => store.check(cpu.mmu_mut()).err(),
+            None => None,
         };
-
-        if let Some(ref store) = self.store {
-            trace!("Validating store");
-            if !store.validate(cpu.mmu_mut()) {
-                error!("mem store transaction fail. Expected {}", store);
-                fail = true;
-            }
+        if fields.is_empty() && store.is_none() {
+            return Ok(());
         }
 
-        if fail {
-            self.save_to("failed.bincode");
-            // error!("transaction failed\n{:?}", self);
-            if let Some(ref insn) = self.insn {
-                error!("Insn: {}", insn.desc);
-            } else {
-                error!("Insn: None");
-            }
-            error!("Before {}", self.state);
-            error!("After  {}", self.after);
-            panic!("transaction failed");
+        let mut failure = self.failure(actual);
+        failure.page_fault = self.page_fault(&failure.actual);
+        failure.fields = fields;
+        failure.store = store;
+        Err(failure)
+    }
+
+    /// The failure of a validation that panicked with `panic`
+    pub(crate) fn panicked(&self, panic: Box<dyn Any + Send>) -> Box<Failure> {
+        let panic = match panic.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(panic) => panic.downcast_ref::<&str>().map_or("", |m| m).to_string(),
+        };
+        let mut failure = self.failure(self.state.clone());
+        failure.panic = Some(panic);
+        failure
+    }
+
+    // a failure with no differences yet
+    fn failure(&self, actual: State) -> Box<Failure> {
+        let opcode = match self.insn {
+            Some(ref insn) => decode(insn.bits).map_or("unknown", |i| OPCODES[i].name),
+            None => "none",
+        };
+        Box::new(Failure {
+            index: 0,
+            opcode,
+            insn: self.insn.clone(),
+            before: self.state.clone(),
+            expected: self.after.clone(),
+            actual,
+            fields: vec![],
+            store: None,
+            page_fault: None,
+            panic: None,
+        })
+    }
+
+    /// Whether the instruction took an exception, going by where the
+    /// reference ended up
+    pub(crate) fn trapped(&self) -> bool {
+        let (s, a) = (&self.state, &self.after);
+        (a.prv == 3 && a.pc == s.mtvec & !3 && a.mepc == s.pc)
+            || (a.prv == 1 && a.pc == s.stvec & !3 && a.sepc == s.pc)
+    }
+
+    // a page fault taken by risk5 alone
+    fn page_fault(&self, actual: &State) -> Option<u64> {
+        let (cause, expected) = if actual.mcause != self.state.mcause {
+            (actual.mcause, self.after.mcause)
+        } else if actual.scause != self.state.scause {
+            (actual.scause, self.after.scause)
         } else {
-            info!("ok");
+            return None;
+        };
+        match cause {
+            12 | 13 | 15 if cause != expected => Some(cause),
+            _ => None,
         }
     }
 
-    fn save_to(&self, filename: &str) {
-        use bincode;
-        use std::fs::File;
-        let mut out = File::create(filename).expect("create save file");
-        bincode::serialize_into(&mut out, self).expect("save_to");
+    /// Saves the transaction where `validate-single` can read it back
+    pub(crate) fn save_to(&self, path: &Path) -> io::Result<()> {
+        let mut out = io::BufWriter::new(File::create(path)?);
+        write_header(&mut out)?;
+        bincode::serialize_into(&mut out, self).map_err(|e| match *e {
+            bincode::ErrorKind::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, format!("{}", e)),
+        })?;
+        out.flush()
     }
 }
 
+/// The first transaction of a stream, as saved by `save_to`
+pub(crate) fn read_one<R: io::Read>(reader: R) -> io::Result<Transaction> {
+    TransactionReader::new(reader)?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no transaction to read"))
+}
+
 pub(crate) struct TransactionIterator<I = TupleReader> {
     last_tuple: LogTuple,
     it: I,
 }
 
 impl<T: Iterator<Item = LogTuple>> TransactionIterator<T> {
-    fn new(mut it: T) -> Self {
+    pub(crate) fn new(mut it: T) -> Self {
         let last_tuple = it.next().expect("no transaction data");
         TransactionIterator { last_tuple, it }
     }
@@ -241,7 +166,7 @@ impl<T: Iterator<Item = LogTuple>> TransactionIterator<T> {
 
 impl Default for TransactionIterator {
     fn default() -> Self {
-        let mut it = TupleReader::new();
+        let mut it = TupleReader::new(io::BufReader::new(io::stdin())).expect("read tuples");
         let last_tuple = it.next().expect("no transaction data");
         TransactionIterator { last_tuple, it }
     }
@@ -254,16 +179,16 @@ where
     type Item = Transaction;
 
     fn next(&mut self) -> Option<Transaction> {
-        let tuple = if let Some(t) = self.it.next() {
-            t
-        } else {
-            return None;
+        let (this_tuple, after) = loop {
+            let tuple = self.it.next()?;
+            let after = tuple.state.clone();
+            let this_tuple = std::mem::replace(&mut self.last_tuple, tuple);
+            // a filtered trace resumes with a new state after a gap
+            if !this_tuple.is_gap() {
+                break (this_tuple, after);
+            }
         };
 
-        let after = tuple.state.clone();
-        let this_tuple = self.last_tuple.clone();
-        self.last_tuple = tuple.clone();
-
         // all values of the next run
         // clone state is our after
         let LogTuple {
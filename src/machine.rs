@@ -0,0 +1,647 @@
+use crate::clock::Clock;
+use crate::fdt::{Fdt, Node};
+use crate::memory::BlockMemory;
+use serde::{Deserialize, Deserializer};
+use std::io;
+
+/*
+ *
+ * Machine
+ * -------
+ * Describes the board: RAM, harts and the devices on the bus.
+ * Memory is laid out and the device tree generated from this
+ * so they can not drift apart.
+ *
+ */
+
+pub(crate) const RESET_VEC_ADDR: u64 = 0x1000;
+// the reset ROM grows in steps of this to fit the DTB
+const RESET_VEC_SIZE: u64 = 2048;
+
+// offset of the DTB from the start of the reset vector
+pub(crate) const RESET_VEC_DTB_OFFSET: u64 = 32;
+
+pub(crate) const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+const CPU_FREQUENCY: u32 = 1_000_000_000;
+const BOOTARGS: &str = "console=hvc0 loglevel=8";
+
+// canonical order of single letter extensions in an ISA string
+const ISA_ORDER: &str = "imafdqlcbjtpvnh";
+
+// extensions the decoder implements, S and U are implied
+const ISA_SUPPORTED: &str = "ima";
+const MISA_S: u64 = 1 << (b's' - b'a');
+const MISA_U: u64 = 1 << (b'u' - b'a');
+const MISA_RV64: u64 = 2 << 62;
+
+// legacy SBI hart masks are a single doubleword
+const MAX_HARTS: usize = 64;
+
+// Interrupt causes as wired to the hart local interrupt controller
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Device {
+    Clint {
+        #[serde(deserialize_with = "number")]
+        base: u64,
+        #[serde(deserialize_with = "number")]
+        size: u64,
+    },
+    Plic {
+        #[serde(deserialize_with = "number")]
+        base: u64,
+        #[serde(deserialize_with = "number")]
+        size: u64,
+        ndev: u32,
+    },
+    Uart {
+        #[serde(deserialize_with = "number")]
+        base: u64,
+        #[serde(deserialize_with = "number")]
+        size: u64,
+        irq: u32,
+    },
+    Virtio {
+        #[serde(deserialize_with = "number")]
+        base: u64,
+        #[serde(deserialize_with = "number")]
+        size: u64,
+        irq: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    #[serde(deserialize_with = "number")]
+    pub base: u64,
+    #[serde(deserialize_with = "number")]
+    pub size: u64,
+}
+
+/// Board description, usually read from the `machine` section of
+/// a config file. Missing fields take their default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Machine {
+    /// RAM regions, the kernel is loaded into the first
+    pub ram: Vec<Region>,
+    pub harts: usize,
+    /// Instructions each hart runs before the next gets a turn
+    #[serde(deserialize_with = "number")]
+    pub quantum: u64,
+    /// Run each hart on its own host thread instead of round-robin.
+    /// Faster, but runs are no longer reproducible.
+    pub threaded: bool,
+    /// Retired instructions per timebase tick
+    #[serde(deserialize_with = "number")]
+    pub time_ratio: u64,
+    /// Follow the host clock instead of counting instructions, for
+    /// interactive use. Runs are no longer reproducible.
+    pub realtime: bool,
+    /// Reported in misa and the device tree. Instructions of the M
+    /// and A extensions are illegal when it leaves them out.
+    #[serde(rename = "isa", deserialize_with = "isa")]
+    pub misa: u64,
+    #[serde(deserialize_with = "number")]
+    pub reset_vec: u64,
+    pub bootargs: String,
+    pub devices: Vec<Device>,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine {
+            ram: vec![Region {
+                base: 0x8000_0000,
+                size: 2048 * 1024 * 1024,
+            }],
+            harts: 1,
+            quantum: crate::smp::DEFAULT_QUANTUM,
+            threaded: false,
+            time_ratio: 1,
+            realtime: false,
+            misa: crate::processor::DEFAULT_MISA,
+            reset_vec: RESET_VEC_ADDR,
+            bootargs: BOOTARGS.into(),
+            devices: vec![Device::Clint {
+                base: 0x200_0000,
+                size: 0xc000,
+            }],
+        }
+    }
+}
+
+/// Parse a number as written in a config file or on the command line:
+/// decimal or 0x hex, `_` separators and an optional K, M or G suffix.
+/// The suffixes are binary, as for sizes.
+pub fn parse_number(s: &str) -> Result<u64, String> {
+    parse_scaled(s, 1 << 10)
+}
+
+/// Parse an instruction count like `parse_number`, but with decimal
+/// suffixes: `180M` is 180 million instructions.
+pub(crate) fn parse_count(s: &str) -> Result<u64, String> {
+    parse_scaled(s, 1000)
+}
+
+fn parse_scaled(s: &str, unit: u64) -> Result<u64, String> {
+    let s: String = s.trim().chars().filter(|c| *c != '_').collect();
+    let (digits, exp) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 2),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 3),
+        _ => (&s[..], 0),
+    };
+    let n = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse()
+    };
+    n.ok()
+        .and_then(|n| n.checked_mul(unit.pow(exp)))
+        .ok_or_else(|| format!("invalid number '{}'", s))
+}
+
+/// Parse an inclusive range such as `0x80000000:0x80001fff`.
+pub(crate) fn parse_range(s: &str) -> Result<(u64, u64), String> {
+    let (lo, hi) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected LO:HI, not '{}'", s))?;
+    Ok((parse_number(lo)?, parse_number(hi)?))
+}
+
+/// Parse a privilege level: u, s, m or their numbers.
+pub(crate) fn parse_prv(s: &str) -> Result<u64, String> {
+    match s {
+        "u" | "0" => Ok(0),
+        "s" | "1" => Ok(1),
+        "m" | "3" => Ok(3),
+        p => Err(format!("unknown privilege '{}'", p)),
+    }
+}
+
+/// Parse an ISA string such as `rv64ima` into a misa value.
+pub fn parse_isa(s: &str) -> Result<u64, String> {
+    let s = s.to_lowercase();
+    if !s.starts_with("rv64") {
+        return Err(format!("ISA '{}' is not rv64", s));
+    }
+    let mut misa = MISA_RV64 | MISA_S | MISA_U;
+    for c in s[4..].chars() {
+        if !ISA_SUPPORTED.contains(c) {
+            return Err(format!("extension '{}' in ISA '{}' is not supported", c, s));
+        }
+        misa |= 1 << (c as u8 - b'a');
+    }
+    if misa & (1 << (b'i' - b'a')) == 0 {
+        return Err(format!("ISA '{}' is missing the base 'i'", s));
+    }
+    Ok(misa)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Int(u64),
+    Str(String),
+}
+
+fn number<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    match Number::deserialize(d)? {
+        Number::Int(n) => Ok(n),
+        Number::Str(s) => parse_number(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+/// The ISA string `misa` describes, e.g. rv64ima
+pub(crate) fn isa_string(misa: u64) -> String {
+    let xlen = match misa >> 62 {
+        1 => 32,
+        3 => 128,
+        _ => 64,
+    };
+    let exts: String = ISA_ORDER
+        .chars()
+        .filter(|c| (misa >> (*c as u8 - b'a')) & 1 == 1)
+        .collect();
+    format!("rv{}{}", xlen, exts)
+}
+
+fn isa<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    parse_isa(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+}
+
+impl Device {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Device::Clint { .. } => "clint",
+            Device::Plic { .. } => "plic",
+            Device::Uart { .. } => "uart",
+            Device::Virtio { .. } => "virtio",
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        match self {
+            Device::Clint { base, .. }
+            | Device::Plic { base, .. }
+            | Device::Uart { base, .. }
+            | Device::Virtio { base, .. } => *base,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Device::Clint { size, .. }
+            | Device::Plic { size, .. }
+            | Device::Uart { size, .. }
+            | Device::Virtio { size, .. } => *size,
+        }
+    }
+}
+
+fn reg(base: u64, size: u64) -> Vec<u8> {
+    cells(&[
+        (base >> 32) as u32,
+        base as u32,
+        (size >> 32) as u32,
+        size as u32,
+    ])
+}
+
+fn cells(cells: &[u32]) -> Vec<u8> {
+    cells
+        .iter()
+        .flat_map(|c| c.to_be_bytes().to_vec())
+        .collect()
+}
+
+fn strings(strings: &[&str]) -> Vec<u8> {
+    let mut v = vec![];
+    for s in strings {
+        v.extend_from_slice(s.as_bytes());
+        v.push(0);
+    }
+    v
+}
+
+impl Machine {
+    /// Check the layout makes sense before building anything from it
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+
+        if self.quantum == 0 {
+            return invalid("quantum must be at least one instruction".into());
+        }
+        if self.time_ratio == 0 {
+            return invalid("time ratio must be at least one instruction".into());
+        }
+        if self.ram.is_empty() {
+            return invalid("machine has no RAM".into());
+        }
+        if self.harts == 0 || self.harts > MAX_HARTS {
+            return invalid(format!(
+                "{} harts requested, only up to {} supported",
+                self.harts, MAX_HARTS
+            ));
+        }
+
+        let mut regions: Vec<(String, u64, u64)> = vec![];
+        for r in &self.ram {
+            regions.push(("ram".into(), r.base, r.size));
+        }
+        for d in &self.devices {
+            regions.push((d.name().into(), d.base(), d.size()));
+        }
+        regions.push(("reset vector".into(), self.reset_vec, self.reset_vec_size()));
+
+        for (i, (name, base, size)) in regions.iter().enumerate() {
+            if *size == 0 || base.checked_add(*size).is_none() {
+                return invalid(format!(
+                    "{} at 0x{:x} has a bad size 0x{:x}",
+                    name, base, size
+                ));
+            }
+            for (other, obase, osize) in &regions[i + 1..] {
+                if *base < obase + osize && *obase < base + size {
+                    return invalid(format!(
+                        "{} at 0x{:x} overlaps {} at 0x{:x}",
+                        name, base, other, obase
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Timebase for each hart
+    pub fn clock(&self) -> Clock {
+        if self.realtime {
+            Clock::realtime()
+        } else {
+            Clock::new(self.time_ratio)
+        }
+    }
+
+    pub fn clint(&self) -> Option<u64> {
+        self.devices.iter().find_map(|d| match d {
+            Device::Clint { base, .. } => Some(*base),
+            _ => None,
+        })
+    }
+
+    /// The region the kernel and DTB are loaded into
+    pub fn main_ram(&self) -> &Region {
+        &self.ram[0]
+    }
+
+    /// Size of the reset ROM, enough for the boot code and the DTB
+    /// after it
+    pub fn reset_vec_size(&self) -> u64 {
+        let used = RESET_VEC_DTB_OFFSET + self.dtb().len() as u64;
+        used.next_multiple_of(RESET_VEC_SIZE)
+    }
+
+    pub fn dtb_addr(&self) -> u64 {
+        self.reset_vec + RESET_VEC_DTB_OFFSET
+    }
+
+    /// ISA string as found in `riscv,isa`, e.g. rv64ima
+    pub fn isa(&self) -> String {
+        isa_string(self.misa)
+    }
+
+    /// Only Sv39 paging is implemented by the MMU
+    pub fn mmu_type(&self) -> &'static str {
+        "riscv,sv39"
+    }
+
+    pub fn build_memory(&self) -> BlockMemory {
+        let mut mem = BlockMemory::new(15);
+        for r in &self.ram {
+            mem.add_block(r.base, r.size);
+        }
+        for device in &self.devices {
+            // devices are plain memory until they get a model
+            mem.add_block(device.base(), device.size());
+        }
+        mem.add_block(self.reset_vec, self.reset_vec_size());
+        mem
+    }
+
+    // phandle of the interrupt controller for each hart
+    fn intc_phandle(hart: usize) -> u32 {
+        hart as u32 + 1
+    }
+
+    fn plic_phandle(&self) -> u32 {
+        self.harts as u32 + 1
+    }
+
+    // route a device's irq to the PLIC, unless there is none to route
+    // it to
+    fn set_irq(&self, node: &mut Node, irq: u32) {
+        let plic = self
+            .devices
+            .iter()
+            .any(|d| matches!(d, Device::Plic { .. }));
+        if plic {
+            node.set_u32("interrupt-parent", self.plic_phandle());
+            node.set_u32("interrupts", irq);
+        }
+    }
+
+    // interrupts-extended for each hart's interrupt controller
+    fn hart_interrupts(&self, irqs: &[u32]) -> Vec<u8> {
+        let mut v = vec![];
+        for hart in 0..self.harts {
+            for irq in irqs {
+                v.push(Self::intc_phandle(hart));
+                v.push(*irq);
+            }
+        }
+        cells(&v)
+    }
+
+    fn cpus(&self) -> Node {
+        let mut cpus = Node::new("cpus");
+        cpus.set_u32("#address-cells", 1);
+        cpus.set_u32("#size-cells", 0);
+        cpus.set_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+
+        for hart in 0..self.harts {
+            let mut cpu = Node::new(&format!("cpu@{}", hart));
+            cpu.set_string("device_type", "cpu");
+            cpu.set_u32("reg", hart as u32);
+            cpu.set_string("status", "okay");
+            cpu.set_string("compatible", "riscv");
+            cpu.set_string("riscv,isa", &self.isa());
+            cpu.set_string("mmu-type", self.mmu_type());
+            cpu.set_u32("clock-frequency", CPU_FREQUENCY);
+
+            let intc = cpu.child_mut("interrupt-controller");
+            intc.set_u32("#interrupt-cells", 1);
+            intc.set_property("interrupt-controller", vec![]);
+            intc.set_string("compatible", "riscv,cpu-intc");
+            intc.set_u32("phandle", Self::intc_phandle(hart));
+
+            cpus.children.push(cpu);
+        }
+        cpus
+    }
+
+    fn device_node(&self, device: &Device) -> Node {
+        let (base, size) = (device.base(), device.size());
+        let mut node = match device {
+            Device::Clint { .. } => {
+                let mut node = Node::new(&format!("clint@{:x}", base));
+                node.set_string("compatible", "riscv,clint0");
+                node.set_property(
+                    "interrupts-extended",
+                    self.hart_interrupts(&[IRQ_M_SOFT, IRQ_M_TIMER]),
+                );
+                node
+            }
+            Device::Plic { ndev, .. } => {
+                let mut node = Node::new(&format!("plic@{:x}", base));
+                node.set_property("compatible", strings(&["sifive,plic-1.0.0", "riscv,plic0"]));
+                node.set_u32("#interrupt-cells", 1);
+                node.set_property("interrupt-controller", vec![]);
+                node.set_property(
+                    "interrupts-extended",
+                    self.hart_interrupts(&[IRQ_M_EXT, IRQ_S_EXT]),
+                );
+                node.set_u32("riscv,ndev", *ndev);
+                node.set_u32("phandle", self.plic_phandle());
+                node
+            }
+            Device::Uart { irq, .. } => {
+                let mut node = Node::new(&format!("uart@{:x}", base));
+                node.set_string("compatible", "ns16550a");
+                node.set_u32("clock-frequency", 3_686_400);
+                self.set_irq(&mut node, *irq);
+                node
+            }
+            Device::Virtio { irq, .. } => {
+                let mut node = Node::new(&format!("virtio_mmio@{:x}", base));
+                node.set_string("compatible", "virtio,mmio");
+                self.set_irq(&mut node, *irq);
+                node
+            }
+        };
+        node.set_property("reg", reg(base, size));
+        node
+    }
+
+    /// Generate the device tree for this machine
+    pub fn device_tree(&self) -> Fdt {
+        let mut fdt = Fdt::default();
+        let root = &mut fdt.root;
+        root.set_u32("#address-cells", 2);
+        root.set_u32("#size-cells", 2);
+        root.set_string("compatible", "risk5,bare-dev");
+        root.set_string("model", "risk5,bare");
+
+        root.children.push(self.cpus());
+
+        for r in &self.ram {
+            let mut memory = Node::new(&format!("memory@{:x}", r.base));
+            memory.set_string("device_type", "memory");
+            memory.set_property("reg", reg(r.base, r.size));
+            root.children.push(memory);
+        }
+
+        let mut soc = Node::new("soc");
+        soc.set_u32("#address-cells", 2);
+        soc.set_u32("#size-cells", 2);
+        soc.set_property("compatible", strings(&["risk5,bare-soc", "simple-bus"]));
+        soc.set_property("ranges", vec![]);
+        for device in &self.devices {
+            soc.children.push(self.device_node(device));
+        }
+        root.children.push(soc);
+
+        let chosen = root.child_mut("chosen");
+        chosen.set_string("bootargs", &self.bootargs);
+
+        fdt
+    }
+
+    pub fn dtb(&self) -> Vec<u8> {
+        self.device_tree().to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::Memory;
+
+    #[test]
+    fn number_suffixes() {
+        assert_eq!(parse_number("512M"), Ok(512 << 20));
+        assert_eq!(parse_number("0x1_000"), Ok(0x1000));
+        assert_eq!(parse_count("180M"), Ok(180_000_000));
+        assert_eq!(parse_count("2k"), Ok(2000));
+        assert!(parse_count("1M1").is_err());
+    }
+
+    #[test]
+    fn isa_from_misa() {
+        let machine = Machine::default();
+        assert_eq!(machine.isa(), "rv64ima");
+
+        let machine = Machine {
+            misa: 0x8000_0000_0014_112d,
+            ..Machine::default()
+        };
+        assert_eq!(machine.isa(), "rv64imafdc");
+    }
+
+    #[test]
+    fn device_tree() {
+        let mut machine = Machine {
+            harts: 2,
+            devices: vec![
+                Device::Clint {
+                    base: 0x200_0000,
+                    size: 0xc000,
+                },
+                Device::Uart {
+                    base: 0x1000_0000,
+                    size: 0x100,
+                    irq: 10,
+                },
+            ],
+            ..Machine::default()
+        };
+        let fdt = Fdt::from_bytes(&machine.dtb()).expect("parse generated dtb");
+
+        let cpus = fdt.root.child("cpus").expect("cpus");
+        assert_eq!(cpus.children.len(), 2);
+        let cpu = cpus.child("cpu@1").expect("cpu@1");
+        assert_eq!(cpu.property("riscv,isa"), Some(&b"rv64ima\0"[..]));
+        assert_eq!(cpu.property("mmu-type"), Some(&b"riscv,sv39\0"[..]));
+
+        let memory = fdt.root.child("memory@80000000").expect("memory");
+        assert_eq!(
+            memory.property("reg"),
+            Some(&[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x80, 0, 0, 0][..])
+        );
+
+        let soc = fdt.root.child("soc").expect("soc");
+        let clint = soc.child("clint@2000000").expect("clint");
+        assert_eq!(
+            clint.property("interrupts-extended"),
+            Some(&cells(&[1, 3, 1, 7, 2, 3, 2, 7])[..])
+        );
+        // no PLIC to take the uart's interrupt
+        let uart = soc.child("uart@10000000").expect("uart");
+        assert_eq!(uart.property("interrupt-parent"), None);
+        assert_eq!(uart.property("interrupts"), None);
+
+        machine.devices.push(Device::Plic {
+            base: 0xc00_0000,
+            size: 0x400_0000,
+            ndev: 31,
+        });
+        let fdt = Fdt::from_bytes(&machine.dtb()).expect("parse generated dtb");
+        let soc = fdt.root.child("soc").expect("soc");
+        let uart = soc.child("uart@10000000").expect("uart");
+        assert_eq!(uart.property("interrupt-parent"), Some(&cells(&[3])[..]));
+        assert_eq!(uart.property("interrupts"), Some(&cells(&[10])[..]));
+    }
+
+    #[test]
+    fn boots_many_harts() {
+        let machine = Machine {
+            harts: 8,
+            ..Machine::default()
+        };
+        machine.validate().expect("valid");
+        let dtb = machine.dtb();
+        assert!(dtb.len() as u64 > RESET_VEC_SIZE);
+
+        let mut mem = machine.build_memory();
+        let entry = machine.main_ram().base;
+        mem.write_w(entry, 0x0000_006f); // j .
+        crate::write_reset_vec_at(&mut mem, machine.reset_vec, entry, &dtb);
+
+        let mut sys = crate::System::new(mem, machine.harts, machine.clint(), 10);
+        for hart in sys.harts_mut() {
+            hart.set_pc(machine.reset_vec);
+        }
+        sys.run_quantum(&mut crate::build_matchers());
+        for (i, hart) in sys.harts_mut().iter_mut().enumerate() {
+            assert_eq!(hart.pc(), entry);
+            assert_eq!(hart.get_reg(10), i as u64);
+            assert_eq!(hart.get_reg(11), machine.dtb_addr());
+        }
+    }
+}
@@ -1,8 +1,10 @@
+mod atomic;
 mod block;
 mod bytemap;
 mod fake;
 // mod sv39;
 
+pub(crate) use self::atomic::AtomicMemory;
 pub(crate) use self::block::BlockMemory;
 pub(crate) use self::bytemap::ByteMap;
 #[cfg(test)]
@@ -60,4 +62,73 @@ pub trait Memory {
         n |= (self.read_b(offset + 7) as u64) << 56;
         n
     }
+
+    /// Record a load-reserved of `offset` by the accessing hart.
+    fn reserve(&mut self, _offset: u64) {}
+
+    /// Load `offset` and reserve it for a later store-conditional.
+    fn load_reserved_w(&mut self, offset: u64) -> u32 {
+        self.reserve(offset);
+        self.read_w(offset)
+    }
+
+    fn load_reserved_d(&mut self, offset: u64) -> u64 {
+        self.reserve(offset);
+        self.read_d(offset)
+    }
+
+    /// Check and clear the reservation for a store-conditional to
+    /// `offset`. Memory that is not shared never loses a reservation.
+    fn take_reservation(&mut self, _offset: u64) -> bool {
+        true
+    }
+
+    /// Store `value` if the reservation on `offset` is still held.
+    fn store_conditional_w(&mut self, offset: u64, value: u32) -> bool {
+        let held = self.take_reservation(offset);
+        if held {
+            self.write_w(offset, value);
+        }
+        held
+    }
+
+    fn store_conditional_d(&mut self, offset: u64, value: u64) -> bool {
+        let held = self.take_reservation(offset);
+        if held {
+            self.write_d(offset, value);
+        }
+        held
+    }
+
+    /// Replace the word at `offset` with `f` of it and return the old
+    /// value, as one indivisible access for AMOs.
+    fn fetch_update_w<F: Fn(u32) -> u32>(&mut self, offset: u64, f: F) -> u32 {
+        let v = self.read_w(offset);
+        self.write_w(offset, f(v));
+        v
+    }
+
+    fn fetch_update_d<F: Fn(u64) -> u64>(&mut self, offset: u64, f: F) -> u64 {
+        let v = self.read_d(offset);
+        self.write_d(offset, f(v));
+        v
+    }
+
+    /// Order earlier accesses of the hart before later ones. Only
+    /// memory shared between host threads has anything to do.
+    fn fence(&mut self) {}
+
+    /// Whether the `len` bytes at `offset` are plain memory, which can
+    /// be read without side effects and without falling off the end
+    /// of what is mapped. Memory that does not know its layout says
+    /// they are.
+    fn is_ram(&mut self, _offset: u64, _len: u64) -> bool {
+        true
+    }
+
+    /// Host address of the 4 KiB page starting at `offset` when it is
+    /// plain memory that translated code may access directly.
+    fn host_page(&mut self, _offset: u64) -> Option<*mut u8> {
+        None
+    }
 }
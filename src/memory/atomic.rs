@@ -0,0 +1,217 @@
+use super::BlockMemory;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+ *
+ * Atomic memory
+ * -------------
+ * Physical memory shared by harts running on separate host threads.
+ * Every block is held as atomic doublewords and all accesses go
+ * through `&self`.
+ *
+ * Plain loads and stores are relaxed. AMOs and store-conditionals
+ * are sequentially consistent, and a FENCE is a host SeqCst fence,
+ * which is at least as strong as RVWMO asks for. Accesses that cross
+ * a doubleword are split into bytes and are not single-copy atomic;
+ * neither are misaligned AMOs.
+ *
+ */
+
+struct Block {
+    // aligned down to a doubleword
+    start: u64,
+    end: u64,
+    words: Vec<AtomicU64>,
+}
+
+pub struct AtomicMemory {
+    blocks: Vec<Block>,
+}
+
+impl fmt::Debug for AtomicMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AtomicMemory")
+    }
+}
+
+fn mask(size: u64) -> u64 {
+    if size == 8 {
+        u64::MAX
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+impl From<&BlockMemory> for AtomicMemory {
+    fn from(mem: &BlockMemory) -> Self {
+        let blocks = mem
+            .blocks()
+            .map(|(start, data)| {
+                let aligned = start & !0x7;
+                let end = (start + data.len() as u64 + 0x7) & !0x7;
+                let mut words = vec![0u64; ((end - aligned) / 8) as usize];
+                for (i, b) in data.iter().enumerate() {
+                    let at = (start - aligned) as usize + i;
+                    words[at / 8] |= (*b as u64) << ((at % 8) * 8);
+                }
+                Block {
+                    start: aligned,
+                    end,
+                    words: words.into_iter().map(AtomicU64::new).collect(),
+                }
+            })
+            .collect();
+        AtomicMemory { blocks }
+    }
+}
+
+impl AtomicMemory {
+    // doubleword holding `offset` and the shift of `offset` in it
+    fn word(&self, offset: u64) -> (&AtomicU64, u64) {
+        for block in &self.blocks {
+            if offset >= block.start && offset < block.end {
+                let i = ((offset - block.start) / 8) as usize;
+                return (&block.words[i], (offset & 0x7) * 8);
+            }
+        }
+        panic!("Unable to find memory block for 0x{:x}", offset);
+    }
+
+    /// Whether the `len` bytes at `offset` fall in one block
+    pub fn contains(&self, offset: u64, len: u64) -> bool {
+        let end = match offset.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        len == 0
+            || self
+                .blocks
+                .iter()
+                .any(|b| offset >= b.start && end <= b.end)
+    }
+
+    fn crosses_word(offset: u64, size: u64) -> bool {
+        (offset & 0x7) + size > 8
+    }
+
+    pub fn load(&self, offset: u64, size: u64) -> u64 {
+        if Self::crosses_word(offset, size) {
+            return (0..size).fold(0, |v, i| v | (self.load(offset + i, 1) << (i * 8)));
+        }
+        let (word, shift) = self.word(offset);
+        (word.load(Ordering::Relaxed) >> shift) & mask(size)
+    }
+
+    pub fn store(&self, offset: u64, size: u64, value: u64) {
+        if Self::crosses_word(offset, size) {
+            for i in 0..size {
+                self.store(offset + i, 1, value >> (i * 8));
+            }
+            return;
+        }
+        let (word, shift) = self.word(offset);
+        if size == 8 {
+            return word.store(value, Ordering::Relaxed);
+        }
+        let m = mask(size) << shift;
+        word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |w| {
+            Some((w & !m) | ((value << shift) & m))
+        })
+        .expect("store");
+    }
+
+    /// Replace `size` bytes at `offset` with `f` of them in one
+    /// indivisible access, returning the old value
+    pub fn fetch_update<F: Fn(u64) -> u64>(&self, offset: u64, size: u64, f: F) -> u64 {
+        if Self::crosses_word(offset, size) {
+            let v = self.load(offset, size);
+            self.store(offset, size, f(v));
+            return v;
+        }
+        let (word, shift) = self.word(offset);
+        let m = mask(size);
+        let old = word
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |w| {
+                let v = f((w >> shift) & m);
+                Some((w & !(m << shift)) | ((v & m) << shift))
+            })
+            .expect("fetch update");
+        (old >> shift) & m
+    }
+
+    /// Store `new` if `size` bytes at `offset` still hold `current`
+    pub fn compare_exchange(&self, offset: u64, size: u64, current: u64, new: u64) -> bool {
+        if Self::crosses_word(offset, size) {
+            let held = self.load(offset, size) == current & mask(size);
+            if held {
+                self.store(offset, size, new);
+            }
+            return held;
+        }
+        let (word, shift) = self.word(offset);
+        let m = mask(size);
+        word.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |w| {
+            if (w >> shift) & m == current & m {
+                Some((w & !(m << shift)) | ((new & m) << shift))
+            } else {
+                None
+            }
+        })
+        .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn rw() {
+        let mut block = BlockMemory::new(0);
+        block.add_block(0x1004, 0x14);
+        block.write_w(0x1008, 0x0403_0201);
+        let m = AtomicMemory::from(&block);
+
+        assert_eq!(m.load(0x1008, 4), 0x0403_0201);
+        assert_eq!(m.load(0x1009, 1), 0x02);
+
+        // crossing into the next doubleword
+        m.store(0x100e, 4, 0xaabb_ccdd);
+        assert_eq!(m.load(0x100e, 4), 0xaabb_ccdd);
+        assert_eq!(m.load(0x1010, 2), 0xaabb);
+        assert_eq!(m.load(0x1008, 4), 0x0403_0201);
+
+        assert_eq!(m.fetch_update(0x1008, 2, |v| v + 1), 0x0201);
+        assert_eq!(m.load(0x1008, 4), 0x0403_0202);
+        assert!(!m.compare_exchange(0x1008, 4, 0, 7));
+        assert!(m.compare_exchange(0x1008, 4, 0x0403_0202, 7));
+        assert_eq!(m.load(0x1008, 8), 0xccdd_0000_0000_0007);
+    }
+
+    #[test]
+    fn concurrent_amos() {
+        let mut block = BlockMemory::new(0);
+        block.add_block(0x8000_0000, 0x10);
+        let m = Arc::new(AtomicMemory::from(&block));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        m.fetch_update(0x8000_0004, 4, |v| v + 1);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("join");
+        }
+        assert_eq!(m.load(0x8000_0004, 4), 40_000);
+        assert_eq!(m.load(0x8000_0000, 4), 0);
+    }
+}
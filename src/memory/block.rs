@@ -45,6 +45,11 @@ impl BlockMemory {
         });
     }
 
+    /// Start address and contents of every block
+    pub fn blocks(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.blocks.iter().map(|b| (b.start, &b.data[..]))
+    }
+
     fn get_block(&self, i: usize) -> &Block {
         unsafe { self.blocks.get_unchecked(i) }
     }
@@ -62,12 +67,11 @@ impl BlockMemory {
         //     error!("serial 0x{:x}", offset);
         // }
         for (i, block) in self.blocks.iter().enumerate() {
-            if offset >= block.start {
-                assert!(offset < block.end);
+            if offset >= block.start && offset < block.end {
                 return i;
             }
         }
-        panic!("Unable to find memory block");
+        panic!("Unable to find memory block for 0x{:x}", offset);
     }
 }
 
@@ -184,6 +188,26 @@ impl Memory for BlockMemory {
         // self.blocks[block].1[offset + 6] = (value >> 48) as u8;
         // self.blocks[block].1[offset + 7] = (value >> 56) as u8;
     }
+
+    fn is_ram(&mut self, offset: u64, len: u64) -> bool {
+        let end = match offset.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        len == 0
+            || self
+                .blocks
+                .iter()
+                .any(|b| offset >= b.start && end <= b.end)
+    }
+
+    fn host_page(&mut self, offset: u64) -> Option<*mut u8> {
+        let block = self
+            .blocks
+            .iter_mut()
+            .find(|b| offset >= b.start && offset + 0x1000 <= b.end)?;
+        Some(unsafe { block.data.as_mut_ptr().add((offset - block.start) as usize) })
+    }
 }
 
 #[cfg(test)]
@@ -7,22 +7,9 @@ pub(crate) struct ByteMap {
 }
 
 impl ByteMap {
-    pub fn with_persistent(mut self, p: Vec<(u64, u8)>) -> Self {
-        self.persistent = p;
-        self
-    }
-
-    pub fn into_data(self) -> Vec<(u64, u8)> {
-        self.data
-    }
-
     pub fn _set_data(&mut self, data: Vec<(u64, u8)>) {
         self.data = data;
     }
-
-    pub fn clear(&mut self) {
-        self.data.clear();
-    }
 }
 
 impl Default for ByteMap {
@@ -56,4 +43,14 @@ impl Memory for ByteMap {
         trace!("Storing 0x{:x}: 0x{:x}", offset, value);
         self.data.push((offset, value))
     }
+
+    // only the bytes it was given
+    fn is_ram(&mut self, offset: u64, len: u64) -> bool {
+        (offset..offset.saturating_add(len)).all(|a| {
+            self.data
+                .iter()
+                .chain(&self.persistent)
+                .any(|(addr, _)| *addr == a)
+        })
+    }
 }
@@ -1,5 +1,6 @@
 use crate::bitfield::{Mstatus, PageTableEntry, PhysicalAddress, VirtualAddress};
-use crate::Memory;
+use crate::block_cache::{ends_block, BlockCache, Decoded, MAX_BLOCK_INSNS, PAGE_SHIFT};
+use crate::{Matchers, Memory};
 use std::fmt;
 
 const INSN_CACHE_SIZE: usize = 10_000;
@@ -13,6 +14,11 @@ pub(crate) struct Mmu<M> {
     ppn: u64,
     cache: Vec<(u64, u64)>,
     insn_cache: [(u64, u32); INSN_CACHE_SIZE],
+    blocks: BlockCache<M>,
+    // last page stored to that held no blocks
+    data_page: u64,
+    // bumped whenever cached translations must be dropped
+    generation: u64,
     hit: u64,
     miss: u64,
 }
@@ -32,6 +38,9 @@ impl<M> Mmu<M> {
             ppn: 0,
             cache: new_cache(),
             insn_cache: [(0, 0); INSN_CACHE_SIZE],
+            blocks: BlockCache::new(),
+            data_page: u64::MAX,
+            generation: 0,
             hit: 0,
             miss: 0,
         }
@@ -47,6 +56,44 @@ impl<M> Mmu<M> {
 
     pub fn flush_cache(&mut self) {
         self.insn_cache = [(0, 0); INSN_CACHE_SIZE];
+        self.generation += 1;
+    }
+
+    /// Changes whenever translations, privilege or cached blocks
+    /// change, so anything derived from them is stale
+    #[cfg(feature = "jit")]
+    pub(crate) fn generation(&self) -> (u64, u64) {
+        (self.generation, self.blocks.generation())
+    }
+
+    /// Changes when block indices are reused
+    #[cfg(feature = "jit")]
+    pub(crate) fn block_epoch(&self) -> u64 {
+        self.blocks.epoch()
+    }
+
+    /// Drop every predecoded block, for fence.i
+    pub fn flush_blocks(&mut self) {
+        self.blocks.flush();
+        self.data_page = u64::MAX;
+    }
+
+    #[inline(always)]
+    pub(crate) fn block_insn(&self, block: usize, i: usize) -> Option<Decoded<M>> {
+        self.blocks.insn(block, i)
+    }
+
+    // a store to a page holding blocks drops them
+    #[inline(always)]
+    fn note_store(&mut self, addr: u64) {
+        let page = addr >> PAGE_SHIFT;
+        if page == self.data_page {
+            return;
+        }
+        if self.blocks.is_code_page(page) {
+            self.blocks.invalidate_page(page);
+        }
+        self.data_page = page;
     }
 
     pub fn set_prv(&mut self, prv: u64, mstatus: &Mstatus) {
@@ -123,6 +170,7 @@ macro_rules! mem {
         //         $val
         //     );
         // }
+        $self.note_store(addr);
         Ok($self.mem.$func(addr, $val))
     }};
 }
@@ -301,6 +349,72 @@ impl<M: Memory> Mmu<M> {
         Ok(val)
     }
 
+    /// Difference between the host and guest address of the page
+    /// holding `vaddr` if it is plain memory that may be accessed
+    /// directly. Stores never get direct access to pages with blocks.
+    #[cfg(feature = "jit")]
+    pub(crate) fn host_addend(&mut self, vaddr: u64, store: bool) -> Option<u64> {
+        let op = if store {
+            MemoryOp::Store
+        } else {
+            MemoryOp::Load
+        };
+        let addr = self.translate(vaddr, op, self.prv).ok()?;
+        let page = addr >> PAGE_SHIFT;
+        if store && self.blocks.is_code_page(page) {
+            return None;
+        }
+        let host = self.mem.host_page(page << PAGE_SHIFT)?;
+        Some((host as u64).wrapping_sub(vaddr & !0xfff))
+    }
+
+    /// Block of predecoded instructions starting at `pc`, decoding
+    /// it on a miss. None if the first instruction has no matcher.
+    pub(crate) fn fetch_block(
+        &mut self,
+        pc: u64,
+        matchers: &mut Matchers<M>,
+    ) -> Result<Option<usize>, ()> {
+        let addr = match self.translate(pc, MemoryOp::Fetch, self.insn_prv) {
+            Ok(a) => a,
+            Err(_) => {
+                debug!("Page-fault on fetch");
+                return Err(());
+            }
+        };
+        if let Some(block) = self.blocks.find(addr) {
+            return Ok(Some(block));
+        }
+
+        let mut insns = vec![];
+        let mut a = addr;
+        loop {
+            // past the first instruction the guest has not fetched
+            // anything yet, so only read ahead in RAM
+            if a != addr && !self.mem.is_ram(a, 4) {
+                break;
+            }
+            let insn = self.mem.read_w(a);
+            match matchers.lookup(insn) {
+                Some(matcher) => insns.push(Decoded::new(insn, matcher)),
+                None => break,
+            }
+            a += 4;
+            let page_end = a >> PAGE_SHIFT != addr >> PAGE_SHIFT;
+            if ends_block(insn) || page_end || insns.len() == MAX_BLOCK_INSNS {
+                break;
+            }
+        }
+        if insns.is_empty() {
+            return Ok(None);
+        }
+        trace!("Decoded block at 0x{:x} of {} insns", addr, insns.len());
+        if addr >> PAGE_SHIFT == self.data_page {
+            self.data_page = u64::MAX;
+        }
+        Ok(Some(self.blocks.insert(addr, insns)))
+    }
+
     #[inline(never)]
     pub fn read_b(&mut self, offset: u64) -> Result<u8, ()> {
         mem!(self, read_b, self.prv, offset)
@@ -340,6 +454,47 @@ impl<M: Memory> Mmu<M> {
     pub fn write_d(&mut self, offset: u64, value: u64) -> Result<(), ()> {
         mem!(self, write_d, self.prv, offset, value)
     }
+
+    pub fn load_reserved_w(&mut self, offset: u64) -> Result<u32, ()> {
+        mem!(self, load_reserved_w, self.prv, offset)
+    }
+
+    pub fn load_reserved_d(&mut self, offset: u64) -> Result<u64, ()> {
+        mem!(self, load_reserved_d, self.prv, offset)
+    }
+
+    pub fn store_conditional_w(&mut self, offset: u64, value: u32) -> Result<bool, ()> {
+        mem!(self, store_conditional_w, self.prv, offset, value)
+    }
+
+    pub fn store_conditional_d(&mut self, offset: u64, value: u64) -> Result<bool, ()> {
+        mem!(self, store_conditional_d, self.prv, offset, value)
+    }
+
+    pub fn fetch_update_w<F: Fn(u32) -> u32>(&mut self, offset: u64, f: F) -> Result<u32, ()> {
+        mem!(self, fetch_update_w, self.prv, offset, f)
+    }
+
+    pub fn fetch_update_d<F: Fn(u64) -> u64>(&mut self, offset: u64, f: F) -> Result<u64, ()> {
+        mem!(self, fetch_update_d, self.prv, offset, f)
+    }
+
+    pub fn fence(&mut self) {
+        self.mem.fence();
+    }
+
+    /// Whether the physical `[addr, addr + len)` is RAM, for firmware
+    /// handed an address by the guest
+    pub fn is_ram(&mut self, addr: u64, len: u64) -> bool {
+        self.mem.is_ram(addr, len)
+    }
+
+    /// Store a byte at physical `addr` on behalf of firmware. Blocks
+    /// decoded from the page are dropped as for any store.
+    pub fn write_phys_b(&mut self, addr: u64, value: u8) {
+        self.note_store(addr);
+        self.mem.write_b(addr, value);
+    }
 }
 
 impl<M> fmt::Debug for Mmu<M> {
@@ -351,10 +506,27 @@ impl<M> fmt::Debug for Mmu<M> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::memory::{FakeMemory, FakeMemoryItem};
+    use crate::memory::{BlockMemory, FakeMemory, FakeMemoryItem};
+
+    #[test]
+    fn blocks_stop_at_end_of_ram() {
+        let mut mem = BlockMemory::new(0);
+        // RAM ends two instructions into the page
+        mem.add_block(0x8000_0000, 8);
+        mem.write_w(0x8000_0000, 0x0010_8093); // addi ra,ra,1
+        mem.write_w(0x8000_0004, 0x0010_8093);
+        let mut mmu = Mmu::new(mem);
+        let block = mmu
+            .fetch_block(0x8000_0000, &mut crate::build_matchers())
+            .expect("fetch")
+            .expect("block");
+        assert!(mmu.block_insn(block, 1).is_some());
+        assert!(mmu.block_insn(block, 2).is_none());
+    }
 
     #[test]
     fn first_linux_page_translation() {
+        // kernel mappings without the U bit, so translated for S-mode
         let mut mmu = Mmu::new({
             let mut mem = FakeMemory::new();
             mem.push_read(FakeMemoryItem::Double(0x8021c000, 0x200800cf));
@@ -363,7 +535,8 @@ mod test {
         });
         mmu.set_page_mode(0, 0x8021d);
         assert_eq!(
-            mmu.translate(0xffffffe0000000c0, 0).expect("ok"),
+            mmu.translate(0xffffffe0000000c0, MemoryOp::Load, 1)
+                .expect("ok"),
             0x802000c0
         );
 
@@ -375,7 +548,8 @@ mod test {
         });
         mmu.set_page_mode(0, 0x80707);
         assert_eq!(
-            mmu.translate(0xffffffe000464440, 0).expect("ok"),
+            mmu.translate(0xffffffe000464440, MemoryOp::Load, 1)
+                .expect("ok"),
             0x80664440
         );
 
@@ -388,7 +562,9 @@ mod test {
         mmu.set_page_mode(0, 0x80707);
 
         let expected = 0x80202df8;
-        let actual = mmu.translate(0xffffffe000002df8, 0).expect("ok");
+        let actual = mmu
+            .translate(0xffffffe000002df8, MemoryOp::Load, 1)
+            .expect("ok");
 
         trace!("Actual   0x{:16x}", actual);
         trace!("Expected 0x{:16x}", expected);
@@ -415,6 +591,9 @@ impl<'a, M> Into<Mmu<M>> for RestorableState<'a, M> {
             ppn: satp.ppn() as u64,
             cache: vec![(0, 0)],
             insn_cache: [(0, 0); INSN_CACHE_SIZE],
+            blocks: BlockCache::new(),
+            data_page: u64::MAX,
+            generation: 0,
             hit: 0,
             miss: 0,
         };
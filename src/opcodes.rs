@@ -0,0 +1,51 @@
+/// An instruction encoding from `assets/opcodes`
+#[derive(Debug)]
+pub struct Opcode {
+    pub name: &'static str,
+    pub mask: u32,
+    pub mtch: u32,
+    /// operand fields, in the order they are listed
+    pub args: &'static [&'static str],
+}
+
+impl Opcode {
+    pub fn by_name(name: &str) -> Option<usize> {
+        OPCODES.iter().position(|op| op.name == name)
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/opcodes.rs"));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn linear(insn: u32) -> Option<usize> {
+        OPCODES.iter().position(|op| insn & op.mask == op.mtch)
+    }
+
+    #[test]
+    fn decode_matches_linear_scan() {
+        for (i, op) in OPCODES.iter().enumerate() {
+            assert_eq!(decode(op.mtch), Some(i), "{}", op.name);
+            // operand bits don't change the instruction
+            assert_eq!(decode(op.mtch | !op.mask), Some(i), "{}", op.name);
+        }
+
+        let mut x = 0x1234_5678u32;
+        for _ in 0..100_000 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            assert_eq!(decode(x), linear(x), "0x{:08x}", x);
+        }
+    }
+
+    #[test]
+    fn names() {
+        let beq = Opcode::by_name("beq").unwrap();
+        assert_eq!(OPCODES[beq].mask, 0x707f);
+        assert_eq!(OPCODES[beq].mtch, 0x63);
+        assert_eq!(Opcode::by_name("fadd.s"), None);
+    }
+}
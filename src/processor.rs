@@ -1,10 +1,24 @@
 use crate::bitfield::{Interrupt, Mstatus};
+use crate::clock::Clock;
+use crate::console::Console;
 use crate::matcher::{Matcher, Matchers};
+use crate::replay::{Journal, Source};
+use crate::sbi::ResetType;
+use crate::smp::{HartState, Smp};
 use crate::Mmu;
 use crate::{Memory, Regs};
 use csrs::{Csrs, PostSetOp, SetMemMode};
+use std::sync::{Arc, Mutex};
 
 mod csrs;
+#[cfg(feature = "jit")]
+mod jit;
+
+// translated blocks are x86-64 machine code, run in place
+#[cfg(all(feature = "jit", not(target_arch = "x86_64")))]
+compile_error!("the jit feature needs an x86-64 host");
+
+pub(crate) use self::csrs::DEFAULT_MISA;
 
 #[derive(Debug)]
 pub struct Processor<M> {
@@ -14,8 +28,21 @@ pub struct Processor<M> {
     mmu: Mmu<M>,
     pub(crate) trigger: bool,
     insn_counter: u64,
+    /// of the counted instructions, those that raised an exception
+    /// instead of retiring
+    pub(crate) unretired: u64,
+    clock: Clock,
     timer: u64,
-    ecall_counter: usize,
+    console: Arc<Mutex<Console>>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    sbi: bool,
+    stopped: bool,
+    /// in wfi, waiting for the scheduler to deliver an interrupt
+    waiting: bool,
+    reset_type: Option<ResetType>,
+    smp: Option<Smp>,
+    #[cfg(feature = "jit")]
+    jit: Option<Box<jit::Jit<M>>>,
 }
 
 impl<M> Processor<M> {
@@ -27,31 +54,200 @@ impl<M> Processor<M> {
             mmu: Mmu::new(mem),
             trigger: false,
             insn_counter: 0,
+            unretired: 0,
+            clock: Clock::default(),
             timer: u64::max_value(),
-            ecall_counter: 0,
+            console: Default::default(),
+            journal: None,
+            sbi: false,
+            stopped: false,
+            waiting: false,
+            reset_type: None,
+            smp: None,
+            #[cfg(feature = "jit")]
+            jit: None,
         }
     }
 
-    pub fn getchar(&mut self) -> u64 {
-        let counter = self.ecall_counter;
-        self.ecall_counter += 1;
+    /// Handle S-mode ecalls with the built-in SBI instead of
+    /// trapping to M-mode firmware.
+    pub fn enable_sbi(&mut self) {
+        self.sbi = true;
+    }
+
+    pub fn sbi_enabled(&self) -> bool {
+        self.sbi
+    }
+
+    /// Start executing a kernel at `entry` in S-mode as M-mode
+    /// firmware would. Traps and interrupts are delegated to S-mode.
+    /// As HSM requires, translation is off and S-mode interrupts are
+    /// disabled, whatever the hart did before it stopped.
+    pub fn boot_supervisor(&mut self, entry: u64, dtb: u64) {
+        // satp, bare mode also drops cached translations
+        self.set_csr(0x180, 0);
+        self.csrs.mstatus.set_supervisor_interrupt_enabled(0);
+
+        let hartid = self.hartid();
+        self.regs.set(10usize, hartid);
+        self.regs.set(11usize, dtb);
+
+        // all exceptions except ecalls from S and M mode
+        self.csrs.medeleg = 0xb1ff;
+        // supervisor software, timer and external
+        self.csrs.mideleg = 0x222;
+        self.csrs.mcounteren = 0x7;
+
+        self.set_prv(1);
+        self.set_pc(entry);
+    }
+
+    /// Become hart `hartid` of a multi-hart system
+    pub fn join_smp(&mut self, hartid: u64, smp: Smp) {
+        self.csrs.mhartid = hartid;
+        self.smp = Some(smp);
+    }
+
+    pub fn smp(&self) -> Option<&Smp> {
+        self.smp.as_ref()
+    }
+
+    pub fn hartid(&self) -> u64 {
+        self.csrs.mhartid
+    }
+
+    pub fn hart_count(&self) -> u64 {
+        self.smp
+            .as_ref()
+            .map(|s| s.lock().harts() as u64)
+            .unwrap_or(1)
+    }
+
+    pub fn stop(&mut self) {
+        debug!("Stopping hart {}", self.hartid());
+        self.stopped = true;
+        if let Some(ref smp) = self.smp {
+            smp.lock()
+                .set_state(self.hartid() as usize, HartState::Stopped);
+        }
+    }
+
+    /// Resume a hart stopped through HSM
+    pub fn restart(&mut self) {
+        self.stopped = false;
+        self.waiting = false;
+    }
+
+    /// Bring the clock of a newly started hart in line with the others
+    pub fn sync_clock(&mut self, time: u64) {
+        self.clock.sync(self.insn_counter, time);
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// The timebase, as read through the `time` CSR
+    pub fn time(&self) -> u64 {
+        self.clock.now(self.insn_counter)
+    }
 
-        let cmd = "uname -a; cat /proc/cpuinfo; cat /proc/meminfo";
-        let n: Vec<_> = cmd.chars().map(|i| i as u64).collect();
+    /// Supervisor timer deadline, `u64::MAX` when none is set
+    pub fn timer(&self) -> u64 {
+        self.timer
+    }
 
-        if counter == n.len() {
-            return 13;
+    /// Idle in wfi until an interrupt is pending. A lone hart waits
+    /// for its timer here, harts of a multi-hart machine are left for
+    /// the scheduler to wake.
+    pub fn wait_for_interrupt(&mut self) {
+        if self.pending_interrupts().val() != 0 {
+            return;
+        }
+        match self.smp {
+            Some(ref smp) => {
+                self.waiting = true;
+                smp.lock().set_waiting(self.hartid() as usize, true);
+            }
+            None => self.idle_until(self.timer),
         }
-        if counter > n.len() {
-            return -1 as isize as u64;
+    }
+
+    /// Let time pass until tick `when` or the supervisor timer,
+    /// whichever comes first
+    pub fn idle_until(&mut self, when: u64) {
+        self.clock.wait(self.insn_counter, when.min(self.timer));
+        self.check_clock();
+    }
+
+    /// Whether the hart still waits in wfi, waking it once an
+    /// interrupt is pending
+    pub fn is_waiting(&mut self) -> bool {
+        if self.waiting {
+            self.check_clock();
+            if self.pending_interrupts().val() != 0 {
+                self.waiting = false;
+                if let Some(ref smp) = self.smp {
+                    smp.lock().set_waiting(self.hartid() as usize, false);
+                }
+            }
+        }
+        self.waiting
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn system_reset(&mut self, reset_type: ResetType) {
+        info!("System reset requested: {:?}", reset_type);
+        self.reset_type = Some(reset_type);
+        self.stop();
+    }
+
+    pub fn reset_type(&self) -> Option<ResetType> {
+        self.reset_type
+    }
+
+    /// Console shared by every hart of the machine
+    pub fn set_console(&mut self, console: Arc<Mutex<Console>>) {
+        self.console = console;
+    }
+
+    /// Journal that records or replays the console, timer and `time`
+    /// CSR inputs of the run
+    pub fn set_journal(&mut self, journal: Arc<Mutex<Journal>>) {
+        self.journal = Some(journal);
+    }
+
+    pub fn getchar(&mut self) -> u64 {
+        let console = &self.console;
+        let live = || console.lock().expect("console lock").getchar();
+        match self.journal {
+            Some(ref journal) => journal.lock().expect("journal lock").input(
+                self.insn_counter,
+                Source::Console,
+                live,
+            ),
+            None => live(),
         }
-        n[counter]
+    }
+
+    pub fn putchar(&mut self, bytes: &[u8]) {
+        self.console.lock().expect("console lock").write(bytes);
     }
 
     pub fn insn_counter(&self) -> u64 {
         self.insn_counter
     }
 
+    /// Instructions retired, which like spike doesn't count those that
+    /// raised an exception. Cycles are the same, as for a hart with a
+    /// CPI of 1.
+    pub fn retired(&self) -> u64 {
+        self.insn_counter - self.unretired
+    }
+
     pub fn prv(&self) -> u64 {
         self.csrs.prv()
     }
@@ -76,6 +272,23 @@ impl<M> Processor<M> {
         self.regs.get(i as usize)
     }
 
+    pub fn get_csr(&self, i: u32) -> Result<u64, crate::insns::Trap> {
+        match i {
+            // cycle and instret count retired insns, time is the
+            // clock's and an input guests read to tell the time
+            0xc01 => Ok(match self.journal {
+                Some(ref journal) => journal.lock().expect("journal lock").input(
+                    self.insn_counter,
+                    Source::Time,
+                    || self.time(),
+                ),
+                None => self.time(),
+            }),
+            0xc00..=0xc02 => Ok(self.retired()),
+            i => self.csrs.get(i as usize),
+        }
+    }
+
     pub fn set_csr(&mut self, i: u32, val: u64) {
         match self.csrs.set(i as usize, val) {
             PostSetOp::None => (),
@@ -92,6 +305,11 @@ impl<M> Processor<M> {
         &mut self.csrs
     }
 
+    /// Whether misa has the extension with letter `ext`, e.g. 'm'
+    pub fn has_extension(&self, ext: char) -> bool {
+        (self.csrs.misa >> (ext as u8 - b'a')) & 1 == 1
+    }
+
     pub(crate) fn mmu(&self) -> &Mmu<M> {
         &self.mmu
     }
@@ -108,17 +326,33 @@ impl<M> Processor<M> {
         (self.csrs.mip.val() & self.csrs.mie.val()).into()
     }
 
+    /// Set the supervisor timer to fire `delta` ticks from now
     pub fn set_timer(&mut self, delta: u64) {
-        self.timer = self.insn_counter + delta;
+        self.timer = self.time().saturating_add(delta);
         debug!("Setting timer for {} ({})", delta, self.timer);
-        if self.prv() != 1 {
-            // TODO need different timers for different prv?
-            unimplemented!("set timer for {}", self.prv());
+    }
+
+    /// Set the supervisor timer to fire at the absolute time `when`
+    /// and clear any pending timer interrupt.
+    pub fn set_timer_at(&mut self, when: u64) {
+        debug!("Setting timer at {}", when);
+        self.timer = when;
+        self.csrs.mip.set_supervisor_timer_interrupt(0);
+        if let Some(ref smp) = self.smp {
+            smp.lock().set_timer(self.hartid() as usize, when);
         }
     }
 
     pub fn check_clock(&mut self) {
-        if self.insn_counter > self.timer {
+        let due = self.time() >= self.timer;
+        let due = match self.journal {
+            Some(ref journal) => journal
+                .lock()
+                .expect("journal lock")
+                .timer(self.insn_counter, due),
+            None => due,
+        };
+        if due {
             self.timer = u64::max_value();
             self.csrs.mip.set_supervisor_timer_interrupt(1);
             // error!("mip {:?}", self.csrs.mip);
@@ -128,15 +362,45 @@ impl<M> Processor<M> {
         }
     }
 
+    /// Take the highest priority pending and enabled interrupt
     pub fn handle_interrupt(&mut self)
     where
         M: Memory,
     {
-        // let interrupts = self.pending_interrupts();
-        // if interrupts.val() > 0 {
-        //     warn!("should handle {:?}", interrupts);
-        //     crate::insns::do_trap(self, 0x8000_0000_0000_0007, self.pc);
-        // }
+        // MEI, MSI, MTI, SEI, SSI, STI
+        const PRIORITY: [u64; 6] = [11, 3, 7, 9, 1, 5];
+
+        let pending = self.csrs.mip.val() & self.csrs.mie.val();
+        if pending == 0 {
+            return;
+        }
+
+        let prv = self.prv();
+        let mstatus = &self.csrs.mstatus;
+        let m_enabled = prv < 3 || mstatus.machine_interrupt_enabled() == 1;
+        let s_enabled = prv < 1 || (prv == 1 && mstatus.supervisor_interrupt_enabled() == 1);
+
+        let m_pending = pending & !self.csrs.mideleg;
+        let s_pending = pending & self.csrs.mideleg;
+        let (irqs, supervisor) = if m_enabled && m_pending != 0 {
+            (m_pending, false)
+        } else if s_enabled && s_pending != 0 {
+            (s_pending, true)
+        } else {
+            return;
+        };
+
+        let irq = PRIORITY
+            .iter()
+            .find(|i| (irqs >> *i) & 1 == 1)
+            .expect("pending irq");
+        let cause = (1 << 63) | irq;
+        trace!("hart {} taking interrupt 0x{:x}", self.hartid(), cause);
+        if supervisor {
+            crate::insns::do_supervisor_trap(self, cause, 0);
+        } else {
+            crate::insns::do_trap(self, cause, 0);
+        }
     }
 
     fn execute(&mut self, insn: u32, matcher: &Matcher<M>)
@@ -154,7 +418,7 @@ impl<M> Processor<M> {
         let insn = match self.mmu.read_insn(self.pc) {
             Ok(insn) => insn,
             Err(()) => {
-                crate::insns::do_trap(self, 12, self.pc);
+                crate::insns::fetch_fault(self);
                 return;
             }
         };
@@ -185,6 +449,59 @@ impl<M> Processor<M> {
         // panic!("Unmatched instruction");
     }
 
+    /// Run up to `budget` instructions a block at a time and return
+    /// how many retired. Stops early once the hart stops or waits.
+    pub fn run(&mut self, matchers: &mut Matchers<M>, budget: u64) -> u64
+    where
+        M: Memory,
+    {
+        let start = self.insn_counter;
+        while self.insn_counter - start < budget && !self.stopped && !self.waiting {
+            let left = budget - (self.insn_counter - start);
+            self.run_block(matchers, left);
+        }
+        self.insn_counter - start
+    }
+
+    #[allow(unused_variables)]
+    fn run_block(&mut self, matchers: &mut Matchers<M>, budget: u64)
+    where
+        M: Memory,
+    {
+        let block = match self.mmu.fetch_block(self.pc, matchers) {
+            Ok(Some(block)) => block,
+            // let step report the illegal instruction
+            Ok(None) => return self.step(matchers),
+            Err(()) => return crate::insns::fetch_fault(self),
+        };
+
+        #[cfg(feature = "jit")]
+        {
+            if self.run_native(block, budget) {
+                return;
+            }
+        }
+
+        let ticks = self.insn_counter / 5000;
+        let mut pc = self.pc;
+        let mut i = 0;
+        while let Some(d) = self.mmu.block_insn(block, i) {
+            trace!("0x{:x} inst 0x{:x}", self.pc, d.insn);
+            (d.exec)(self, d.insn);
+            self.insn_counter += 1;
+            pc += 4;
+            i += 1;
+            if self.pc != pc || self.stopped || self.waiting {
+                break;
+            }
+        }
+
+        if self.insn_counter / 5000 != ticks {
+            self.check_clock();
+            self.handle_interrupt();
+        }
+    }
+
     pub fn advance_pc(&mut self) {
         self.pc += 4;
     }
@@ -217,9 +534,20 @@ impl<'a, M> Into<Processor<M>> for RestorableState<'a, M> {
             regs: state.xregs.into(),
             mmu: RestorableState { state, memory }.into(),
             trigger: false,
-            timer: 0,        // TODO: store timer in state
-            insn_counter: 0, // TODO: store insn_counter in state
-            ecall_counter: 0,
+            // the timer is outside the hart's state
+            timer: u64::MAX,
+            insn_counter: state.minstret,
+            unretired: 0,
+            clock: Clock::default(),
+            console: Default::default(),
+            journal: None,
+            sbi: false,
+            stopped: false,
+            waiting: false,
+            reset_type: None,
+            smp: None,
+            #[cfg(feature = "jit")]
+            jit: None,
         }
     }
 }
@@ -229,6 +557,7 @@ impl<M> Into<State> for &Processor<M> {
     fn into(self) -> State {
         State {
             id: 0,
+            legacy: false,
             pc: self.pc,
             prv: self.csrs.prv(),
             mstatus: self.csrs.mstatus.val(),
@@ -236,7 +565,7 @@ impl<M> Into<State> for &Processor<M> {
             mtvec: self.csrs.mtvec,
             mcause: self.csrs.mcause,
             mscratch: self.csrs.mscratch,
-            minstret: 0, // self.csrs.minstret,
+            minstret: self.retired(),
             mie: (&self.csrs.mie).into(),
             mip: (&self.csrs.mip).into(),
             medeleg: self.csrs.medeleg,
@@ -249,7 +578,108 @@ impl<M> Into<State> for &Processor<M> {
             stvec: self.csrs.stvec,
             satp: (&self.csrs.satp).into(),
             scause: self.csrs.scause,
+            mtval: self.csrs.mtval,
+            misa: self.csrs.misa,
+            mhartid: self.csrs.mhartid,
+            mcycle: self.retired(),
+            sedeleg: self.csrs.sedeleg,
+            sideleg: self.csrs.sideleg,
+            sip: self.csrs.sip,
             xregs: (&self.regs).into(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::BlockMemory;
+
+    // funct5, .w or .d, memory before, rs2, memory after
+    const AMOS: &[(u32, bool, u64, u64, u64)] = &[
+        (0b00100, true, 0xffff_fff0, 0x0f, 0xffff_ffff),
+        (0b01000, true, 0xffff_fff0, 0x0f, 0xffff_ffff),
+        (0b01100, true, 0xffff_fff0, 0x1f, 0x10),
+        (0b10000, true, 0xffff_fff0, 5, 0xffff_fff0),
+        (0b10100, true, 0xffff_fff0, 5, 5),
+        (0b11000, true, 0xffff_fff0, 5, 5),
+        (0b11100, true, 0xffff_fff0, 5, 0xffff_fff0),
+        (0b00100, false, 0xf0, 0xff, 0x0f),
+        (0b10000, false, u64::MAX, 5, u64::MAX),
+        (0b10100, false, u64::MAX, 5, 5),
+        (0b11000, false, u64::MAX, 5, 5),
+        (0b11100, false, u64::MAX, 5, u64::MAX),
+    ];
+
+    #[test]
+    fn amos() {
+        let matchers = &mut crate::build_matchers();
+        for &(funct5, word, before, rs2, after) in AMOS {
+            // amo t2, t1, (t0)
+            let funct3 = if word { 2 } else { 3 };
+            let insn = funct5 << 27 | 6 << 20 | 5 << 15 | funct3 << 12 | 7 << 7 | 0x2f;
+            let mut mem = BlockMemory::new(0);
+            mem.add_block(0x8000_0000, 0x1000);
+            mem.write_w(0x8000_0000, insn);
+            mem.write_d(0x8000_0800, before);
+            let mut p = Processor::new(mem);
+            p.set_pc(0x8000_0000);
+            p.regs.set(5usize, 0x8000_0800);
+            p.regs.set(6usize, rs2);
+            p.step(matchers);
+
+            let loaded = if word { before as i32 as u64 } else { before };
+            assert_eq!(p.get_reg(7), loaded, "insn 0x{:08x}", insn);
+            let stored = if word {
+                p.mmu_mut().read_w(0x8000_0800).map(u64::from)
+            } else {
+                p.mmu_mut().read_d(0x8000_0800)
+            };
+            assert_eq!(stored, Ok(after), "insn 0x{:08x}", insn);
+        }
+    }
+
+    #[test]
+    fn disabled_extensions() {
+        let matchers = &mut crate::build_matchers();
+        let program = [
+            0x0263_0eb3, // mul      t4, t1, t1
+            0x0062_b2af, // amoadd.d t0, t1, (t0)
+        ];
+        for (i, insn) in program.iter().enumerate() {
+            let mut mem = BlockMemory::new(0);
+            mem.add_block(0x8000_0000, 0x1000);
+            mem.write_w(0x8000_0000, *insn);
+            let mut p = Processor::new(mem);
+            p.set_pc(0x8000_0000);
+            p.set_csr(0x305, 0x8000_0100);
+            p.regs.set(5usize, 0x8000_0800);
+            p.regs.set(6usize, 3);
+
+            // rv64i with neither M nor A
+            p.csrs_mut().misa = DEFAULT_MISA & !(1 << 12 | 1);
+            p.step(matchers);
+            assert_eq!(p.pc(), 0x8000_0100, "insn {}", i);
+            assert_eq!(p.csrs().mcause, 2);
+            assert_eq!(p.get_reg(29), 0);
+            assert_eq!(p.get_reg(5), 0x8000_0800);
+            // counted, but not retired
+            assert_eq!((p.insn_counter(), p.retired()), (1, 0));
+
+            // translated code falls back to the same handlers
+            #[cfg(feature = "jit")]
+            {
+                p.set_pc(0x8000_0000);
+                p.step_native(matchers);
+                assert_eq!(p.pc(), 0x8000_0100, "insn {}", i);
+                assert_eq!((p.insn_counter(), p.retired()), (2, 0));
+            }
+
+            p.set_pc(0x8000_0000);
+            p.csrs_mut().misa = DEFAULT_MISA;
+            p.step(matchers);
+            assert_eq!(p.pc(), 0x8000_0004, "insn {}", i);
+            assert_eq!(p.get_csr(0xc02).ok(), Some(1));
+        }
+    }
+}
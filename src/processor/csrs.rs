@@ -4,6 +4,7 @@ use std::fmt;
 
 pub struct Csrs {
     prv: u64,
+    pub(crate) mhartid: u64,
 
     pub(crate) mstatus: Mstatus,
     pub(crate) medeleg: u64,
@@ -42,6 +43,9 @@ pub struct SetMemMode {
     pub ppn: u64,
 }
 
+// RV64 with A, I, M, S and U
+pub(crate) const DEFAULT_MISA: u64 = 0x8000000000141101;
+
 // Supervisor
 
 // Supervisor Trap Setup
@@ -99,7 +103,8 @@ impl Csrs {
             mtval: 0,
             mcause: 0,
             mscratch: 0,
-            misa: 0x8000000000141101,
+            mhartid: 0,
+            misa: DEFAULT_MISA,
             mcounteren: 0,
             mie: 0.into(),
             mip: 0.into(),
@@ -188,7 +193,7 @@ impl Csrs {
         let i = i.into();
         trace!("Getting CSR 0x{:x} with prv {}", i, self.prv);
         Ok(match i {
-            MHARTID => 0,
+            MHARTID => self.mhartid,
 
             MSTATUS => self.mstatus.val(),
             MISA => self.misa,
@@ -256,22 +261,23 @@ impl Into<Csrs> for &State {
             mideleg: self.mideleg,
             mtvec: self.mtvec,
             mepc: self.mepc,
-            mtval: 0, // self.mtval,
+            mtval: self.mtval,
             mcause: self.mcause,
             mscratch: self.mscratch,
-            misa: 0x8000000000141101,
+            mhartid: self.mhartid,
+            misa: self.misa,
             mcounteren: self.mcounteren,
             mie: self.mie.into(),
             mip: self.mip.into(),
-            sedeleg: 0, // self.sedeleg,
-            sideleg: 0, // self.sideleg,
+            sedeleg: self.sedeleg,
+            sideleg: self.sideleg,
             stvec: self.stvec,
             scounteren: self.scounteren,
             sscratch: self.sscratch,
             sepc: self.sepc,
             scause: self.scause,
             stval: self.stval,
-            sip: 0, // self.sip,
+            sip: self.sip,
             satp: self.satp.into(),
         }
     }
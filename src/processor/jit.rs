@@ -0,0 +1,442 @@
+use super::Processor;
+use crate::block_cache::Decoded;
+use crate::matcher::Matchers;
+use crate::mmu::Mmu;
+use crate::Memory;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::{fmt, mem};
+
+mod asm;
+mod code;
+mod translate;
+
+use self::code::CodeBuffer;
+
+/*
+ *
+ * JIT
+ * ---
+ * Blocks from the block cache that have run `HOT_RUNS` times are
+ * translated to x86-64. Guest registers stay in `Regs` and are loaded
+ * and stored around every instruction, so the interpreter and
+ * translated code can hand over at any instruction boundary.
+ *
+ * Translated code runs with a `Context`:
+ * - a softmmu TLB of host addends for loads and stores, filled from
+ *   the slow path. A hit is a compare and an add. Pages holding
+ *   blocks never get a store entry, so every store into code still
+ *   goes through `Mmu` and drops the blocks.
+ * - a jump cache from guest pc to translated code. At the end of a
+ *   block the next one is looked up there and jumped to directly, so
+ *   hot loops never leave native code until the budget runs out.
+ *
+ * Anything else, CSR accesses, AMOs, divides, traps and so on, calls
+ * back into the interpreter's handler for that instruction. When the
+ * handler leaves the straight line path, stops the hart or changes
+ * translations or blocks, translated code returns to `run` which
+ * picks up from wherever the handler left the hart.
+ *
+ * Translated code is tied to the guest pc it was translated at, as
+ * that is baked into auipc, jal and branches. Both caches are dropped
+ * whenever `Mmu::generation` changes.
+ *
+ */
+
+const HOT_RUNS: u32 = 32;
+const CODE_SIZE: usize = 32 << 20;
+const TLB_SIZE: usize = 256;
+const JUMP_CACHE_SIZE: usize = 1024;
+// no tag has the low bits set, so this never matches
+const INVALID: u64 = u64::MAX;
+
+// offsets into `Context` used by translated code
+const CTX_REGS: i32 = 0;
+const CTX_PC: i32 = 8;
+const CTX_EXECUTED: i32 = 16;
+const CTX_BUDGET: i32 = 24;
+const CTX_TLB: i32 = 48;
+const CTX_JUMPS: i32 = CTX_TLB + (TLB_SIZE * 32) as i32;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    // page of the entry, for loads and stores
+    read: u64,
+    write: u64,
+    // host address minus guest address
+    addend: u64,
+    _pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct JumpEntry {
+    pc: u64,
+    code: *const u8,
+}
+
+#[repr(C)]
+struct Context<M> {
+    regs: *mut u64,
+    pc: *mut u64,
+    // instructions retired since entry
+    executed: u64,
+    // return to `run` at the first block end after this many
+    budget: u64,
+    processor: *mut Processor<M>,
+    start: u64,
+    tlb: [TlbEntry; TLB_SIZE],
+    jumps: [JumpEntry; JUMP_CACHE_SIZE],
+    // a panic in a handler, which can't unwind through translated
+    // code and is resumed once it returned
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<M> Context<M> {
+    fn flush(&mut self) {
+        for e in self.tlb.iter_mut() {
+            e.read = INVALID;
+            e.write = INVALID;
+        }
+        for e in self.jumps.iter_mut() {
+            e.pc = INVALID;
+        }
+    }
+}
+
+fn tlb_index(vaddr: u64) -> usize {
+    (vaddr >> 12) as usize & (TLB_SIZE - 1)
+}
+
+fn jump_index(pc: u64) -> usize {
+    (pc >> 2) as usize & (JUMP_CACHE_SIZE - 1)
+}
+
+#[derive(Clone, Copy)]
+struct Native {
+    // with prologue, for calls from `run`
+    entry: *const u8,
+    // for jumps from other translated blocks
+    body: *const u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    pc: u64,
+    runs: u32,
+    native: Option<Native>,
+}
+
+pub(crate) struct Jit<M> {
+    ctx: Box<Context<M>>,
+    code: CodeBuffer,
+    // by block index
+    slots: Vec<Slot>,
+    epoch: u64,
+    generation: (u64, u64),
+}
+
+// the raw pointers only ever point into the hart that owns the JIT
+unsafe impl<M: Send> Send for Jit<M> {}
+
+impl<M> fmt::Debug for Jit<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Jit")
+    }
+}
+
+impl<M: Memory> Jit<M> {
+    pub fn new() -> Self {
+        let mut ctx = Box::new(Context {
+            regs: std::ptr::null_mut(),
+            pc: std::ptr::null_mut(),
+            executed: 0,
+            budget: 0,
+            processor: std::ptr::null_mut(),
+            start: 0,
+            tlb: [TlbEntry {
+                read: INVALID,
+                write: INVALID,
+                addend: 0,
+                _pad: 0,
+            }; TLB_SIZE],
+            jumps: [JumpEntry {
+                pc: INVALID,
+                code: std::ptr::null(),
+            }; JUMP_CACHE_SIZE],
+            panic: None,
+        });
+        ctx.flush();
+        Jit {
+            ctx,
+            code: CodeBuffer::new(CODE_SIZE).expect("jit code buffer"),
+            slots: vec![],
+            epoch: 0,
+            generation: (0, 0),
+        }
+    }
+
+    fn reset(&mut self) {
+        debug!("Dropping all translated code");
+        self.code.clear();
+        self.slots.clear();
+        self.ctx.flush();
+    }
+
+    fn install(&mut self, pc: u64, insns: &[Decoded<M>], misa: u64) -> Native {
+        let (code, body) = translate::block(pc, insns, misa);
+        let entry = match self.code.push(code.code()) {
+            Some(entry) => entry,
+            None => {
+                self.reset();
+                self.code.push(code.code()).expect("block fits")
+            }
+        };
+        Native {
+            entry,
+            body: entry.wrapping_add(body),
+        }
+    }
+
+    // Translated code for `block` at `pc` once it is hot
+    fn native(&mut self, block: usize, pc: u64, mmu: &Mmu<M>, misa: u64) -> Option<Native> {
+        if mmu.block_epoch() != self.epoch {
+            self.epoch = mmu.block_epoch();
+            self.reset();
+        }
+        if mmu.generation() != self.generation {
+            self.generation = mmu.generation();
+            self.ctx.flush();
+        }
+
+        if block >= self.slots.len() {
+            self.slots.resize(block + 1, Slot::default());
+        }
+        let slot = &mut self.slots[block];
+        if slot.pc != pc {
+            *slot = Slot {
+                pc,
+                ..Slot::default()
+            };
+        }
+        if slot.native.is_none() {
+            slot.runs += 1;
+            if slot.runs < HOT_RUNS {
+                return None;
+            }
+            let insns: Vec<_> = (0..).map_while(|i| mmu.block_insn(block, i)).collect();
+            trace!("Translating block at 0x{:x} of {} insns", pc, insns.len());
+            let native = self.install(pc, &insns, misa);
+            // install may have reset the slots
+            if block >= self.slots.len() {
+                self.slots.resize(block + 1, Slot::default());
+            }
+            self.slots[block] = Slot {
+                pc,
+                runs: HOT_RUNS,
+                native: Some(native),
+            };
+        }
+
+        let native = self.slots[block].native?;
+        self.ctx.jumps[jump_index(pc)] = JumpEntry {
+            pc,
+            code: native.body,
+        };
+        Some(native)
+    }
+}
+
+impl<M: Memory> Processor<M> {
+    /// Run `block` and whatever it chains to as native code, for at
+    /// most about `budget` instructions. False if it is not hot yet.
+    pub(super) fn run_native(&mut self, block: usize, budget: u64) -> bool {
+        let jit = self.jit.get_or_insert_with(|| Box::new(Jit::new()));
+        let native = match jit.native(block, self.pc, &self.mmu, self.csrs.misa) {
+            Some(native) => native,
+            None => return false,
+        };
+        let ticks = self.insn_counter / 5000;
+        let to_tick = 5000 - self.insn_counter % 5000;
+        self.enter(native, budget.min(to_tick));
+        if self.insn_counter / 5000 != ticks {
+            self.check_clock();
+            self.handle_interrupt();
+        }
+        true
+    }
+
+    /// Translate and run the single instruction at pc, for checking
+    /// translations against the interpreter
+    pub fn step_native(&mut self, matchers: &mut Matchers<M>) {
+        let insn = match self.mmu.read_insn(self.pc) {
+            Ok(insn) => insn,
+            Err(()) => return crate::insns::fetch_fault(self),
+        };
+        let matcher = matchers.find_for(insn);
+        let d = Decoded::new(insn, matcher);
+        let pc = self.pc;
+        let jit = self.jit.get_or_insert_with(|| Box::new(Jit::new()));
+        let native = jit.install(pc, &[d], self.csrs.misa);
+        self.enter(native, 1);
+    }
+
+    fn enter(&mut self, native: Native, budget: u64) {
+        let start = self.insn_counter;
+        let p: *mut Processor<M> = self;
+        let jit = self.jit.as_mut().expect("jit");
+        let ctx: *mut Context<M> = &mut *jit.ctx;
+        unsafe {
+            (*ctx).regs = (*p).regs.as_mut_ptr();
+            (*ctx).pc = &mut (*p).pc;
+            (*ctx).processor = p;
+            (*ctx).start = start;
+            (*ctx).executed = 0;
+            (*ctx).budget = budget;
+            let f: unsafe extern "C" fn(*mut Context<M>) = mem::transmute(native.entry);
+            f(ctx);
+            (*p).insn_counter = start + (*ctx).executed;
+            if let Some(panic) = (*ctx).panic.take() {
+                panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+// Run one instruction with its interpreter handler on behalf of
+// translated code. Non-zero when translated code must return.
+unsafe extern "C" fn interpret<M: Memory>(ctx: *mut Context<M>, insn: u32, exec: usize) -> u64 {
+    let ctx = &mut *ctx;
+    let p = &mut *ctx.processor;
+    let exec: fn(&mut Processor<M>, u32) = mem::transmute(exec);
+
+    p.insn_counter = ctx.start + ctx.executed;
+    let pc = p.pc;
+    let generation = p.mmu.generation();
+    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| exec(p, insn))) {
+        ctx.panic = Some(panic);
+        return 1;
+    }
+    (p.pc != pc.wrapping_add(4) || p.stopped || p.waiting || p.mmu.generation() != generation)
+        as u64
+}
+
+// Slow path of a load or store: run it through the interpreter and
+// fill the TLB for next time
+unsafe extern "C" fn access<M: Memory>(
+    ctx: *mut Context<M>,
+    insn: u32,
+    exec: usize,
+    vaddr: u64,
+) -> u64 {
+    if interpret(ctx, insn, exec) != 0 {
+        return 1;
+    }
+
+    let ctx = &mut *ctx;
+    let store = insn & 0x7f == 0x23;
+    if let Some(addend) = (*ctx.processor).mmu.host_addend(vaddr, store) {
+        let page = vaddr & !0xfff;
+        let e = &mut ctx.tlb[tlb_index(vaddr)];
+        // both tags share the addend
+        if e.addend != addend {
+            e.read = INVALID;
+            e.write = INVALID;
+        }
+        e.addend = addend;
+        if store {
+            e.write = page;
+        } else {
+            e.read = page;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build_matchers;
+    use crate::memory::BlockMemory;
+    use crate::test_util::machine;
+
+    #[test]
+    fn context_layout() {
+        let mut jit: Jit<BlockMemory> = Jit::new();
+        let ctx = &mut *jit.ctx;
+        let base = ctx as *mut _ as usize;
+        assert_eq!(&mut ctx.regs as *mut _ as usize - base, CTX_REGS as usize);
+        assert_eq!(&mut ctx.pc as *mut _ as usize - base, CTX_PC as usize);
+        assert_eq!(
+            &mut ctx.executed as *mut _ as usize - base,
+            CTX_EXECUTED as usize
+        );
+        assert_eq!(
+            &mut ctx.budget as *mut _ as usize - base,
+            CTX_BUDGET as usize
+        );
+        assert_eq!(&mut ctx.tlb as *mut _ as usize - base, CTX_TLB as usize);
+        assert_eq!(&mut ctx.jumps as *mut _ as usize - base, CTX_JUMPS as usize);
+    }
+
+    // A loop over most translated instruction kinds, with a divide and
+    // a CSR read going through the interpreter
+    const PROGRAM: &[u32] = &[
+        0x0000_0297, // auipc t0, 0
+        0x1002_8293, // addi  t0, t0, 256
+        0x0640_0313, // li    t1, 100
+        0x0000_0393, // li    t2, 0
+        // loop:
+        0x0062_b023, // sd    t1, 0(t0)
+        0x0002_be03, // ld    t3, 0(t0)
+        0x01c3_83b3, // add   t2, t2, t3
+        0x0263_0eb3, // mul   t4, t1, t1
+        0x41d3_83b3, // sub   t2, t2, t4
+        0x0033_9393, // slli  t2, t2, 3
+        0x4033_d393, // srai  t2, t2, 3
+        0x5553_c393, // xori  t2, t2, 0x555
+        0x0063_83bb, // addw  t2, t2, t1
+        0x01f3_7f13, // andi  t5, t1, 31
+        0x41e3_df3b, // sraw  t5, t2, t5
+        0x01e3_83b3, // add   t2, t2, t5
+        0x0262_cfb3, // div   t6, t0, t1
+        0xc000_2f73, // rdcycle t5
+        0x01e3_83b3, // add   t2, t2, t5
+        0x0072_9423, // sh    t2, 8(t0)
+        0x0082_cf03, // lbu   t5, 8(t0)
+        0x0063_bf33, // sltu  t5, t2, t1
+        0x01e3_83b3, // add   t2, t2, t5
+        0xfff3_0313, // addi  t1, t1, -1
+        0xfa03_18e3, // bnez  t1, loop
+        0x0000_006f, // j     .
+    ];
+
+    #[test]
+    fn matches_interpreter() {
+        let matchers = &mut build_matchers();
+
+        let mut translated = machine(PROGRAM);
+        while translated.pc() != 0x8000_0064 {
+            translated.run(matchers, 1);
+        }
+        assert!(translated.jit.is_some());
+
+        // blocks run past the final branch into `j .`, so catch up by count
+        let mut interpreted = machine(PROGRAM);
+        while interpreted.insn_counter() < translated.insn_counter() {
+            interpreted.step(matchers);
+        }
+        assert_eq!(translated.pc(), interpreted.pc());
+
+        for r in 0..32 {
+            assert_eq!(translated.get_reg(r), interpreted.get_reg(r), "x{}", r);
+        }
+        // rdcycle saw the same count
+        assert_eq!(translated.insn_counter(), interpreted.insn_counter());
+        assert_eq!(
+            translated.mmu_mut().read_d(0x8000_0100),
+            interpreted.mmu_mut().read_d(0x8000_0100)
+        );
+    }
+}
@@ -0,0 +1,340 @@
+/*
+ *
+ * x86-64 assembler
+ * ----------------
+ * Only the handful of encodings the translator needs. Memory operands
+ * are always `[base + disp32]`, which keeps the ModRM handling to one
+ * place at the cost of a few bytes per access.
+ *
+ */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsi = 6,
+    Rdi = 7,
+    R12 = 12,
+    R13 = 13,
+}
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+}
+
+/// Two-operand integer operations sharing the classic encoding scheme
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// Condition codes as used by jcc and setcc
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Cond {
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    Be = 0x6,
+    L = 0xc,
+    Ge = 0xd,
+}
+
+/// Width of a memory access
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Width {
+    B,
+    H,
+    W,
+    D,
+}
+
+impl Width {
+    pub fn bytes(self) -> u64 {
+        match self {
+            Width::B => 1,
+            Width::H => 2,
+            Width::W => 4,
+            Width::D => 8,
+        }
+    }
+}
+
+/// Position of a rel32 waiting for its target
+#[must_use]
+pub(crate) struct Label(usize);
+
+#[derive(Default)]
+pub(crate) struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.code.push(b);
+    }
+
+    fn bytes(&mut self, bs: &[u8]) {
+        self.code.extend_from_slice(bs);
+    }
+
+    fn imm32(&mut self, v: i32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    // REX prefix, left out when it would carry no information
+    fn rex(&mut self, w: bool, reg: u8, base: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3 & 1) << 2 | (base >> 3 & 1);
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: Reg) {
+        self.byte(0xc0 | (reg & 7) << 3 | rm.low());
+    }
+
+    fn modrm_mem(&mut self, reg: u8, base: Reg, disp: i32) {
+        self.byte(0x80 | (reg & 7) << 3 | base.low());
+        // rsp and r12 as a base need a SIB byte
+        if base.low() == 4 {
+            self.byte(0x24);
+        }
+        self.imm32(disp);
+    }
+
+    /// `op dst, [base + disp]` for 64 or 32 bit operands
+    fn op_load(&mut self, w: bool, opcode: &[u8], dst: Reg, base: Reg, disp: i32) {
+        self.rex(w, dst as u8, base as u8);
+        self.bytes(opcode);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    pub fn mov_load(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.op_load(true, &[0x8b], dst, base, disp);
+    }
+
+    pub fn mov_load32(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.op_load(false, &[0x8b], dst, base, disp);
+    }
+
+    pub fn mov_store(&mut self, base: Reg, disp: i32, src: Reg) {
+        self.op_load(true, &[0x89], src, base, disp);
+    }
+
+    pub fn mov_reg(&mut self, dst: Reg, src: Reg) {
+        self.rex(true, src as u8, dst as u8);
+        self.byte(0x89);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// Sign-extended 32 bit immediate
+    pub fn mov_imm32(&mut self, dst: Reg, imm: i32) {
+        self.rex(true, 0, dst as u8);
+        self.byte(0xc7);
+        self.modrm_reg(0, dst);
+        self.imm32(imm);
+    }
+
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        if imm as i64 == imm as i32 as i64 {
+            return self.mov_imm32(dst, imm as i32);
+        }
+        self.rex(true, 0, dst as u8);
+        self.byte(0xb8 + dst.low());
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `op dst, [base + disp]`
+    pub fn alu_load(&mut self, w: bool, op: Alu, dst: Reg, base: Reg, disp: i32) {
+        self.op_load(w, &[(op as u8) << 3 | 0x3], dst, base, disp);
+    }
+
+    /// 64 bit `op dst, src`
+    pub fn alu_reg(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.rex(true, src as u8, dst as u8);
+        self.byte((op as u8) << 3 | 0x1);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    pub fn alu_imm(&mut self, w: bool, op: Alu, dst: Reg, imm: i32) {
+        self.rex(w, 0, dst as u8);
+        self.byte(0x81);
+        self.modrm_reg(op as u8, dst);
+        self.imm32(imm);
+    }
+
+    /// `cmp [base + disp], src`
+    pub fn cmp_mem(&mut self, base: Reg, disp: i32, src: Reg) {
+        self.op_load(true, &[0x39], src, base, disp);
+    }
+
+    /// `add qword [base + disp], imm`
+    pub fn add_mem_imm(&mut self, base: Reg, disp: i32, imm: i32) {
+        self.rex(true, 0, base as u8);
+        self.byte(0x81);
+        self.modrm_mem(0, base, disp);
+        self.imm32(imm);
+    }
+
+    pub fn imul_load(&mut self, w: bool, dst: Reg, base: Reg, disp: i32) {
+        self.op_load(w, &[0x0f, 0xaf], dst, base, disp);
+    }
+
+    pub fn shift_imm(&mut self, w: bool, op: Shift, dst: Reg, amount: u8) {
+        self.rex(w, 0, dst as u8);
+        self.byte(0xc1);
+        self.modrm_reg(op as u8, dst);
+        self.byte(amount);
+    }
+
+    /// Shift by cl
+    pub fn shift_cl(&mut self, w: bool, op: Shift, dst: Reg) {
+        self.rex(w, 0, dst as u8);
+        self.byte(0xd3);
+        self.modrm_reg(op as u8, dst);
+    }
+
+    /// `movsxd dst, src32`
+    pub fn sign_extend32(&mut self, dst: Reg, src: Reg) {
+        self.rex(true, dst as u8, src as u8);
+        self.byte(0x63);
+        self.modrm_reg(dst as u8, src);
+    }
+
+    /// `setcc al; movzx eax, al`
+    pub fn set_rax(&mut self, cond: Cond) {
+        self.bytes(&[0x0f, 0x90 | cond as u8, 0xc0]);
+        self.bytes(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    pub fn test(&mut self, a: Reg, b: Reg) {
+        self.rex(true, b as u8, a as u8);
+        self.byte(0x85);
+        self.modrm_reg(b as u8, a);
+    }
+
+    /// Load `width` bytes from `[base]`, extending to 64 bits
+    pub fn load(&mut self, dst: Reg, base: Reg, width: Width, signed: bool) {
+        match (width, signed) {
+            (Width::B, false) => self.op_load(false, &[0x0f, 0xb6], dst, base, 0),
+            (Width::B, true) => self.op_load(true, &[0x0f, 0xbe], dst, base, 0),
+            (Width::H, false) => self.op_load(false, &[0x0f, 0xb7], dst, base, 0),
+            (Width::H, true) => self.op_load(true, &[0x0f, 0xbf], dst, base, 0),
+            (Width::W, false) => self.op_load(false, &[0x8b], dst, base, 0),
+            (Width::W, true) => self.op_load(true, &[0x63], dst, base, 0),
+            (Width::D, _) => self.op_load(true, &[0x8b], dst, base, 0),
+        }
+    }
+
+    /// Store the low `width` bytes of `src`, one of rax, rcx or rdx
+    pub fn store(&mut self, base: Reg, src: Reg, width: Width) {
+        debug_assert!((src as u8) < 4);
+        match width {
+            Width::B => self.op_load(false, &[0x88], src, base, 0),
+            Width::H => {
+                self.byte(0x66);
+                self.op_load(false, &[0x89], src, base, 0)
+            }
+            Width::W => self.op_load(false, &[0x89], src, base, 0),
+            Width::D => self.op_load(true, &[0x89], src, base, 0),
+        }
+    }
+
+    pub fn push(&mut self, r: Reg) {
+        self.rex(false, 0, r as u8);
+        self.byte(0x50 + r.low());
+    }
+
+    pub fn pop(&mut self, r: Reg) {
+        self.rex(false, 0, r as u8);
+        self.byte(0x58 + r.low());
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xc3);
+    }
+
+    pub fn call(&mut self, target: Reg) {
+        self.rex(false, 0, target as u8);
+        self.byte(0xff);
+        self.modrm_reg(2, target);
+    }
+
+    /// `jmp qword [base + disp]`
+    pub fn jmp_mem(&mut self, base: Reg, disp: i32) {
+        self.rex(false, 0, base as u8);
+        self.byte(0xff);
+        self.modrm_mem(4, base, disp);
+    }
+
+    pub fn jcc(&mut self, cond: Cond) -> Label {
+        self.bytes(&[0x0f, 0x80 | cond as u8]);
+        self.imm32(0);
+        Label(self.code.len() - 4)
+    }
+
+    pub fn jmp(&mut self) -> Label {
+        self.byte(0xe9);
+        self.imm32(0);
+        Label(self.code.len() - 4)
+    }
+
+    /// Point `label` at the current position
+    pub fn bind(&mut self, label: Label) {
+        let rel = (self.code.len() - (label.0 + 4)) as i32;
+        self.code[label.0..label.0 + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodings() {
+        let mut a = Assembler::default();
+        a.mov_load(Reg::Rax, Reg::R12, 8);
+        assert_eq!(a.code(), &[0x49, 0x8b, 0x84, 0x24, 8, 0, 0, 0]);
+
+        let mut a = Assembler::default();
+        a.alu_imm(true, Alu::Add, Reg::Rax, -1);
+        assert_eq!(a.code(), &[0x48, 0x81, 0xc0, 0xff, 0xff, 0xff, 0xff]);
+
+        let mut a = Assembler::default();
+        a.push(Reg::R13);
+        a.pop(Reg::Rbx);
+        a.store(Reg::Rax, Reg::Rdx, Width::H);
+        assert_eq!(a.code(), &[0x41, 0x55, 0x5b, 0x66, 0x89, 0x90, 0, 0, 0, 0]);
+
+        let mut a = Assembler::default();
+        let l = a.jmp();
+        a.ret();
+        a.bind(l);
+        assert_eq!(a.code(), &[0xe9, 1, 0, 0, 0, 0xc3]);
+    }
+}
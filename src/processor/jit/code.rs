@@ -0,0 +1,63 @@
+use std::io;
+use std::ptr;
+
+/// Executable memory that translated blocks are bump allocated from.
+/// Nothing is freed on its own; when it fills up everything goes.
+pub(crate) struct CodeBuffer {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl CodeBuffer {
+    pub fn new(size: usize) -> io::Result<Self> {
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(CodeBuffer {
+            base: base as *mut u8,
+            size,
+            used: 0,
+        })
+    }
+
+    /// Copy `code` in and return where it went, none when full
+    pub fn push(&mut self, code: &[u8]) -> Option<*const u8> {
+        // keep entry points 16 byte aligned
+        let start = (self.used + 15) & !15;
+        if start + code.len() > self.size {
+            return None;
+        }
+        unsafe {
+            let dst = self.base.add(start);
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+            self.used = start + code.len();
+            Some(dst)
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+// only the owning hart writes to or runs from the buffer
+unsafe impl Send for CodeBuffer {}
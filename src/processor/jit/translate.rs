@@ -0,0 +1,499 @@
+use super::asm::{Alu, Assembler, Cond, Label, Reg, Shift, Width};
+use super::{access, interpret};
+use super::{CTX_BUDGET, CTX_EXECUTED, CTX_JUMPS, CTX_PC, CTX_REGS, CTX_TLB};
+use super::{JUMP_CACHE_SIZE, TLB_SIZE};
+use crate::block_cache::Decoded;
+use crate::Memory;
+
+/*
+ *
+ * Register use in translated code
+ * -------------------------------
+ * rbx  the `Context`
+ * r12  guest registers
+ * rax, rcx, rdx, rsi, rdi  scratch and helper arguments
+ *
+ * r13 is only saved to keep the stack 16 byte aligned for calls into
+ * the helpers.
+ *
+ */
+
+// where the next pc comes from at the end of a block
+enum Next {
+    Static(u64),
+    // in rax
+    Dynamic,
+}
+
+struct Translator<M> {
+    asm: Assembler,
+    // exits to the epilogue
+    exits: Vec<Label>,
+    // instructions already added to `executed` on the straight path
+    retired: u64,
+    interpret: usize,
+    access: usize,
+    // multiplies are only translated with the M extension, otherwise
+    // their handlers raise illegal-instruction
+    mul: bool,
+    _memory: std::marker::PhantomData<M>,
+}
+
+fn reg(r: u32) -> i32 {
+    r as i32 * 8
+}
+
+fn bits(insn: u32, hi: u32, lo: u32) -> u32 {
+    (insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn imm_i(insn: u32) -> i32 {
+    insn as i32 >> 20
+}
+
+fn imm_s(insn: u32) -> i32 {
+    (insn as i32 >> 25) << 5 | bits(insn, 11, 7) as i32
+}
+
+fn imm_b(insn: u32) -> i64 {
+    let imm = (insn as i32 >> 31) << 12
+        | (bits(insn, 7, 7) << 11) as i32
+        | (bits(insn, 30, 25) << 5) as i32
+        | (bits(insn, 11, 8) << 1) as i32;
+    imm as i64
+}
+
+fn imm_j(insn: u32) -> i64 {
+    let imm = (insn as i32 >> 31) << 20
+        | (bits(insn, 19, 12) << 12) as i32
+        | (bits(insn, 20, 20) << 11) as i32
+        | (bits(insn, 30, 21) << 1) as i32;
+    imm as i64
+}
+
+/// Translate a block starting at guest `pc`. Returns the code and the
+/// offset of the body, the entry point for chained jumps. `misa` is
+/// the hart's.
+pub(super) fn block<M: Memory>(pc: u64, insns: &[Decoded<M>], misa: u64) -> (Assembler, usize) {
+    let mut t = Translator::<M> {
+        asm: Assembler::default(),
+        exits: vec![],
+        retired: 0,
+        interpret: interpret::<M> as *const () as usize,
+        access: access::<M> as *const () as usize,
+        mul: (misa >> (b'm' - b'a')) & 1 == 1,
+        _memory: std::marker::PhantomData,
+    };
+
+    t.asm.push(Reg::Rbx);
+    t.asm.push(Reg::R12);
+    t.asm.push(Reg::R13);
+    t.asm.mov_reg(Reg::Rbx, Reg::Rdi);
+    let body = t.asm.len();
+    t.asm.mov_load(Reg::R12, Reg::Rbx, CTX_REGS);
+
+    let mut ended = false;
+    for (i, d) in insns.iter().enumerate() {
+        let insn_pc = pc + 4 * i as u64;
+        ended = t.insn(i as u64, insn_pc, d);
+    }
+    if !ended {
+        let next = pc + 4 * insns.len() as u64;
+        t.exit(Next::Static(next), insns.len() as u64);
+    }
+
+    for label in std::mem::take(&mut t.exits) {
+        t.asm.bind(label);
+    }
+    t.asm.pop(Reg::R13);
+    t.asm.pop(Reg::R12);
+    t.asm.pop(Reg::Rbx);
+    t.asm.ret();
+
+    (t.asm, body)
+}
+
+impl<M: Memory> Translator<M> {
+    // Translate instruction `i` of the block. True if it ends the block.
+    fn insn(&mut self, i: u64, pc: u64, d: &Decoded<M>) -> bool {
+        let insn = d.insn;
+        let rd = bits(insn, 11, 7);
+        let rs1 = bits(insn, 19, 15);
+        let rs2 = bits(insn, 24, 20);
+        let funct3 = bits(insn, 14, 12);
+        let funct7 = bits(insn, 31, 25);
+
+        match insn & 0x7f {
+            0x37 => self.write(rd, |a| a.mov_imm32(Reg::Rax, (insn & 0xffff_f000) as i32)),
+            0x17 => {
+                let v = pc.wrapping_add((insn & 0xffff_f000) as i32 as u64);
+                self.write(rd, |a| a.mov_imm(Reg::Rax, v))
+            }
+            0x13 => return self.op_imm(i, pc, d, rd, rs1, funct3),
+            0x1b => return self.op_imm32(i, pc, d, rd, rs1, funct3, funct7),
+            0x33 => return self.op(i, pc, d, rd, rs1, rs2, funct3, funct7),
+            0x3b => return self.op32(i, pc, d, rd, rs1, rs2, funct3, funct7),
+            0x03 if funct3 != 7 => self.load(i, pc, d, rd, rs1, funct3),
+            0x23 if funct3 < 4 => self.store(i, pc, d, rs1, rs2, funct3),
+            0x63 if funct3 != 2 && funct3 != 3 => self.branch(i, pc, insn, rs1, rs2, funct3),
+            0x6f => {
+                self.write(rd, |a| a.mov_imm(Reg::Rax, pc + 4));
+                let target = pc.wrapping_add(imm_j(insn) as u64);
+                self.exit(Next::Static(target), i + 1);
+                return true;
+            }
+            0x67 if funct3 == 0 => {
+                self.asm.mov_load(Reg::Rax, Reg::R12, reg(rs1));
+                self.asm.alu_imm(true, Alu::Add, Reg::Rax, imm_i(insn));
+                self.asm.alu_imm(true, Alu::And, Reg::Rax, -2);
+                if rd != 0 {
+                    self.asm.mov_imm(Reg::Rcx, pc + 4);
+                    self.asm.mov_store(Reg::R12, reg(rd), Reg::Rcx);
+                }
+                self.exit(Next::Dynamic, i + 1);
+                return true;
+            }
+            _ => return self.fallback(i, pc, d),
+        }
+        false
+    }
+
+    // Compute a value into rax and write it to rd. Writes to x0 are
+    // dropped before anything is computed.
+    fn write<F: FnOnce(&mut Assembler)>(&mut self, rd: u32, f: F) {
+        if rd == 0 {
+            return;
+        }
+        f(&mut self.asm);
+        self.asm.mov_store(Reg::R12, reg(rd), Reg::Rax);
+    }
+
+    fn op_imm(&mut self, i: u64, pc: u64, d: &Decoded<M>, rd: u32, rs1: u32, funct3: u32) -> bool {
+        let insn = d.insn;
+        let imm = imm_i(insn);
+        let shamt = bits(insn, 25, 20) as u8;
+        let alu = match funct3 {
+            0 => Some(Alu::Add),
+            4 => Some(Alu::Xor),
+            6 => Some(Alu::Or),
+            7 => Some(Alu::And),
+            _ => None,
+        };
+        let a = |asm: &mut Assembler| asm.mov_load(Reg::Rax, Reg::R12, reg(rs1));
+        match (funct3, insn >> 26) {
+            (0, _) | (4, _) | (6, _) | (7, _) => {
+                let alu = alu.expect("alu op");
+                self.write(rd, |asm| {
+                    a(asm);
+                    asm.alu_imm(true, alu, Reg::Rax, imm);
+                })
+            }
+            (2, _) | (3, _) => {
+                let cond = if funct3 == 2 { Cond::L } else { Cond::B };
+                self.write(rd, |asm| {
+                    a(asm);
+                    asm.alu_imm(true, Alu::Cmp, Reg::Rax, imm);
+                    asm.set_rax(cond);
+                })
+            }
+            (1, 0) | (5, 0) | (5, 0x10) => {
+                let shift = match (funct3, insn >> 26) {
+                    (1, _) => Shift::Shl,
+                    (_, 0) => Shift::Shr,
+                    _ => Shift::Sar,
+                };
+                self.write(rd, |asm| {
+                    a(asm);
+                    asm.shift_imm(true, shift, Reg::Rax, shamt);
+                })
+            }
+            _ => return self.fallback(i, pc, d),
+        }
+        false
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn op_imm32(
+        &mut self,
+        i: u64,
+        pc: u64,
+        d: &Decoded<M>,
+        rd: u32,
+        rs1: u32,
+        funct3: u32,
+        funct7: u32,
+    ) -> bool {
+        let insn = d.insn;
+        let shamt = bits(insn, 24, 20) as u8;
+        let shift = match (funct3, funct7) {
+            (0, _) => None,
+            (1, 0) => Some(Shift::Shl),
+            (5, 0) => Some(Shift::Shr),
+            (5, 0x20) => Some(Shift::Sar),
+            _ => return self.fallback(i, pc, d),
+        };
+        self.write(rd, |asm| {
+            asm.mov_load32(Reg::Rax, Reg::R12, reg(rs1));
+            match shift {
+                None => asm.alu_imm(false, Alu::Add, Reg::Rax, imm_i(insn)),
+                Some(shift) => asm.shift_imm(false, shift, Reg::Rax, shamt),
+            }
+            asm.sign_extend32(Reg::Rax, Reg::Rax);
+        });
+        false
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn op(
+        &mut self,
+        i: u64,
+        pc: u64,
+        d: &Decoded<M>,
+        rd: u32,
+        rs1: u32,
+        rs2: u32,
+        funct3: u32,
+        funct7: u32,
+    ) -> bool {
+        let (a, b) = (reg(rs1), reg(rs2));
+        let alu = |alu: Alu| {
+            move |asm: &mut Assembler| {
+                asm.mov_load(Reg::Rax, Reg::R12, a);
+                asm.alu_load(true, alu, Reg::Rax, Reg::R12, b);
+            }
+        };
+        let set = |cond: Cond| {
+            move |asm: &mut Assembler| {
+                asm.mov_load(Reg::Rax, Reg::R12, a);
+                asm.alu_load(true, Alu::Cmp, Reg::Rax, Reg::R12, b);
+                asm.set_rax(cond);
+            }
+        };
+        let shift = |shift: Shift| {
+            move |asm: &mut Assembler| {
+                asm.mov_load(Reg::Rcx, Reg::R12, b);
+                asm.mov_load(Reg::Rax, Reg::R12, a);
+                asm.shift_cl(true, shift, Reg::Rax);
+            }
+        };
+        match (funct7, funct3) {
+            (0, 0) => self.write(rd, alu(Alu::Add)),
+            (0x20, 0) => self.write(rd, alu(Alu::Sub)),
+            (0, 1) => self.write(rd, shift(Shift::Shl)),
+            (0, 2) => self.write(rd, set(Cond::L)),
+            (0, 3) => self.write(rd, set(Cond::B)),
+            (0, 4) => self.write(rd, alu(Alu::Xor)),
+            (0, 5) => self.write(rd, shift(Shift::Shr)),
+            (0x20, 5) => self.write(rd, shift(Shift::Sar)),
+            (0, 6) => self.write(rd, alu(Alu::Or)),
+            (0, 7) => self.write(rd, alu(Alu::And)),
+            (1, 0) if self.mul => self.write(rd, |asm| {
+                asm.mov_load(Reg::Rax, Reg::R12, a);
+                asm.imul_load(true, Reg::Rax, Reg::R12, b);
+            }),
+            _ => return self.fallback(i, pc, d),
+        }
+        false
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn op32(
+        &mut self,
+        i: u64,
+        pc: u64,
+        d: &Decoded<M>,
+        rd: u32,
+        rs1: u32,
+        rs2: u32,
+        funct3: u32,
+        funct7: u32,
+    ) -> bool {
+        let (a, b) = (reg(rs1), reg(rs2));
+        match (funct7, funct3) {
+            (0, 0) | (0x20, 0) | (1, 0) if funct7 != 1 || self.mul => {
+                self.write(rd, |asm| {
+                    asm.mov_load32(Reg::Rax, Reg::R12, a);
+                    match funct7 {
+                        0 => asm.alu_load(false, Alu::Add, Reg::Rax, Reg::R12, b),
+                        1 => asm.imul_load(false, Reg::Rax, Reg::R12, b),
+                        _ => asm.alu_load(false, Alu::Sub, Reg::Rax, Reg::R12, b),
+                    }
+                    asm.sign_extend32(Reg::Rax, Reg::Rax);
+                });
+            }
+            (0, 1) | (0, 5) | (0x20, 5) => {
+                let shift = match (funct7, funct3) {
+                    (0, 1) => Shift::Shl,
+                    (0, _) => Shift::Shr,
+                    _ => Shift::Sar,
+                };
+                self.write(rd, |asm| {
+                    asm.mov_load(Reg::Rcx, Reg::R12, b);
+                    asm.mov_load32(Reg::Rax, Reg::R12, a);
+                    asm.shift_cl(false, shift, Reg::Rax);
+                    asm.sign_extend32(Reg::Rax, Reg::Rax);
+                });
+            }
+            _ => return self.fallback(i, pc, d),
+        }
+        false
+    }
+
+    // Guest address into rax, then look it up in the TLB. Falls
+    // through on a hit with the host address in rax. Returns the label
+    // of the miss path.
+    fn tlb_lookup(&mut self, rs1: u32, imm: i32, width: Width, store: bool) -> Label {
+        let tag = if store { 8 } else { 0 };
+        let a = &mut self.asm;
+        a.mov_load(Reg::Rax, Reg::R12, reg(rs1));
+        if imm != 0 {
+            a.alu_imm(true, Alu::Add, Reg::Rax, imm);
+        }
+        a.mov_reg(Reg::Rcx, Reg::Rax);
+        a.shift_imm(true, Shift::Shr, Reg::Rcx, 12);
+        a.alu_imm(false, Alu::And, Reg::Rcx, TLB_SIZE as i32 - 1);
+        a.shift_imm(false, Shift::Shl, Reg::Rcx, 5);
+        a.alu_reg(Alu::Add, Reg::Rcx, Reg::Rbx);
+        // misaligned addresses keep low bits and miss
+        a.mov_reg(Reg::Rdx, Reg::Rax);
+        a.alu_imm(true, Alu::And, Reg::Rdx, -4096 | (width.bytes() as i32 - 1));
+        a.cmp_mem(Reg::Rcx, CTX_TLB + tag, Reg::Rdx);
+        let miss = a.jcc(Cond::Ne);
+        a.alu_load(true, Alu::Add, Reg::Rax, Reg::Rcx, CTX_TLB + 16);
+        miss
+    }
+
+    // Miss path of a load or store, with the guest address in rax
+    fn access_slow(&mut self, i: u64, pc: u64, d: &Decoded<M>, miss: Label, done: Label) {
+        self.asm.bind(miss);
+        let pending = (i - self.retired) as i32;
+        if pending != 0 {
+            self.asm.add_mem_imm(Reg::Rbx, CTX_EXECUTED, pending);
+        }
+        self.set_pc(pc);
+        self.asm.mov_reg(Reg::Rcx, Reg::Rax);
+        self.call(self.access, d);
+        if pending != 0 {
+            self.asm.add_mem_imm(Reg::Rbx, CTX_EXECUTED, -pending);
+        }
+        self.asm.bind(done);
+    }
+
+    fn load(&mut self, i: u64, pc: u64, d: &Decoded<M>, rd: u32, rs1: u32, funct3: u32) {
+        let (width, signed) = match funct3 {
+            0 => (Width::B, true),
+            1 => (Width::H, true),
+            2 => (Width::W, true),
+            3 => (Width::D, true),
+            4 => (Width::B, false),
+            5 => (Width::H, false),
+            _ => (Width::W, false),
+        };
+        let miss = self.tlb_lookup(rs1, imm_i(d.insn), width, false);
+        self.asm.load(Reg::Rax, Reg::Rax, width, signed);
+        if rd != 0 {
+            self.asm.mov_store(Reg::R12, reg(rd), Reg::Rax);
+        }
+        let done = self.asm.jmp();
+        self.access_slow(i, pc, d, miss, done);
+    }
+
+    fn store(&mut self, i: u64, pc: u64, d: &Decoded<M>, rs1: u32, rs2: u32, funct3: u32) {
+        let width = match funct3 {
+            0 => Width::B,
+            1 => Width::H,
+            2 => Width::W,
+            _ => Width::D,
+        };
+        let miss = self.tlb_lookup(rs1, imm_s(d.insn), width, true);
+        self.asm.mov_load(Reg::Rdx, Reg::R12, reg(rs2));
+        self.asm.store(Reg::Rax, Reg::Rdx, width);
+        let done = self.asm.jmp();
+        self.access_slow(i, pc, d, miss, done);
+    }
+
+    fn branch(&mut self, i: u64, pc: u64, insn: u32, rs1: u32, rs2: u32, funct3: u32) {
+        // jump over the exit when the branch is not taken
+        let not_taken = match funct3 {
+            0 => Cond::Ne,
+            1 => Cond::E,
+            4 => Cond::Ge,
+            5 => Cond::L,
+            6 => Cond::Ae,
+            _ => Cond::B,
+        };
+        self.asm.mov_load(Reg::Rax, Reg::R12, reg(rs1));
+        self.asm
+            .alu_load(true, Alu::Cmp, Reg::Rax, Reg::R12, reg(rs2));
+        let skip = self.asm.jcc(not_taken);
+        let target = pc.wrapping_add(imm_b(insn) as u64);
+        self.exit(Next::Static(target), i + 1);
+        self.asm.bind(skip);
+    }
+
+    // Run the instruction with its interpreter handler
+    fn fallback(&mut self, i: u64, pc: u64, d: &Decoded<M>) -> bool {
+        let pending = (i - self.retired) as i32;
+        if pending != 0 {
+            self.asm.add_mem_imm(Reg::Rbx, CTX_EXECUTED, pending);
+        }
+        self.retired = i;
+        self.set_pc(pc);
+        self.call(self.interpret, d);
+        false
+    }
+
+    fn set_pc(&mut self, pc: u64) {
+        self.asm.mov_load(Reg::Rcx, Reg::Rbx, CTX_PC);
+        self.asm.mov_imm(Reg::Rdx, pc);
+        self.asm.mov_store(Reg::Rcx, 0, Reg::Rdx);
+    }
+
+    // Call a helper for `d` and leave the block if it says so. The
+    // instruction has retired either way.
+    fn call(&mut self, helper: usize, d: &Decoded<M>) {
+        let a = &mut self.asm;
+        a.mov_reg(Reg::Rdi, Reg::Rbx);
+        a.mov_imm(Reg::Rsi, d.insn as u64);
+        a.mov_imm(Reg::Rdx, d.exec as usize as u64);
+        a.mov_imm(Reg::Rax, helper as u64);
+        a.call(Reg::Rax);
+        a.test(Reg::Rax, Reg::Rax);
+        let stay = a.jcc(Cond::E);
+        a.add_mem_imm(Reg::Rbx, CTX_EXECUTED, 1);
+        let leave = a.jmp();
+        a.bind(stay);
+        self.exits.push(leave);
+    }
+
+    // Leave the block for `next` with `count` of its instructions
+    // retired, chaining to the next block while there is budget
+    fn exit(&mut self, next: Next, count: u64) {
+        let a = &mut self.asm;
+        if let Next::Static(pc) = next {
+            a.mov_imm(Reg::Rax, pc);
+        }
+        a.mov_load(Reg::Rcx, Reg::Rbx, CTX_PC);
+        a.mov_store(Reg::Rcx, 0, Reg::Rax);
+        let pending = (count - self.retired) as i32;
+        if pending != 0 {
+            a.add_mem_imm(Reg::Rbx, CTX_EXECUTED, pending);
+        }
+
+        a.mov_load(Reg::Rcx, Reg::Rbx, CTX_EXECUTED);
+        a.cmp_mem(Reg::Rbx, CTX_BUDGET, Reg::Rcx);
+        let spent = a.jcc(Cond::Be);
+        self.exits.push(spent);
+
+        let a = &mut self.asm;
+        a.mov_reg(Reg::Rcx, Reg::Rax);
+        a.shift_imm(true, Shift::Shr, Reg::Rcx, 2);
+        a.alu_imm(false, Alu::And, Reg::Rcx, JUMP_CACHE_SIZE as i32 - 1);
+        a.shift_imm(false, Shift::Shl, Reg::Rcx, 4);
+        a.alu_reg(Alu::Add, Reg::Rcx, Reg::Rbx);
+        a.cmp_mem(Reg::Rcx, CTX_JUMPS, Reg::Rax);
+        let miss = a.jcc(Cond::Ne);
+        self.exits.push(miss);
+        self.asm.jmp_mem(Reg::Rcx, CTX_JUMPS + 8);
+    }
+}
@@ -1,7 +1,7 @@
 pub(crate) static REG_NAMES: &'static [&str] = &[
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
-    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "sA", "sB", "t3", "t4", "t5",
-    "t6",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
 ];
 
 pub(crate) fn name(i: u32) -> &'static str {
@@ -45,6 +45,12 @@ impl Regs {
     pub fn seti<T: Into<usize>>(&mut self, i: T, v: i64) {
         self.set(i, v as u64)
     }
+
+    /// For translated code, which must leave x0 alone
+    #[cfg(feature = "jit")]
+    pub fn as_mut_ptr(&mut self) -> *mut u64 {
+        self.regs.as_mut_ptr()
+    }
 }
 
 impl Into<Vec<u64>> for &Regs {
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+
+/*
+ *
+ * Replay
+ * ------
+ * Journal of the inputs a run takes from outside the machine: console
+ * bytes, timer interrupts and reads of the `time` CSR. Recording
+ * writes each one with the instruction count it arrived at. Replaying
+ * hands them back at the same counts instead of asking the live
+ * source, so the journal and the command line it was recorded with
+ * repeat the run exactly.
+ *
+ * The journal is JSON lines, led by a header:
+ *
+ * {"version":1,"args":["--kernel","Image"]}
+ * {"at":1042311,"source":"time","value":1042311}
+ * {"at":1050000,"source":"timer","value":1}
+ *
+ */
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Console,
+    Timer,
+    Time,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    version: u32,
+    args: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Input {
+    at: u64,
+    source: Source,
+    value: u64,
+}
+
+#[derive(Debug)]
+enum Mode {
+    Record(LineWriter<File>),
+    Replay(VecDeque<Input>),
+    /// the replay stopped matching the run, which went on with the
+    /// live sources
+    Diverged,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    mode: Mode,
+    /// first divergence or failed write
+    error: Option<String>,
+}
+
+/// The command line without `--record` or `--replay`, which a replay
+/// has to repeat
+pub(crate) fn run_args(args: &[String]) -> Vec<String> {
+    let mut run = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" | "--replay" => {
+                args.next();
+            }
+            _ => run.push(arg.clone()),
+        }
+    }
+    run
+}
+
+impl Journal {
+    /// Record to `path`, noting the command line `args` in the header
+    pub fn record<P: AsRef<Path>>(path: P, args: &[String]) -> io::Result<Journal> {
+        // a line at a time, so a run that panics leaves the inputs up
+        // to the panic behind
+        let mut out = LineWriter::new(File::create(path)?);
+        let header = Header {
+            version: VERSION,
+            args: args.to_vec(),
+        };
+        serde_json::to_writer(&mut out, &header)?;
+        writeln!(out)?;
+        Ok(Journal {
+            mode: Mode::Record(out),
+            error: None,
+        })
+    }
+
+    /// Replay the journal at `path`, warning when it was recorded with
+    /// other `args`
+    pub fn replay<P: AsRef<Path>>(path: P, args: &[String]) -> io::Result<Journal> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err(invalid("empty journal".into())),
+        };
+        if header.version != VERSION {
+            return Err(invalid(format!(
+                "unsupported journal version {}",
+                header.version
+            )));
+        }
+        if header.args != args {
+            warn!("Journal was recorded with: {}", header.args.join(" "));
+        }
+
+        let inputs = lines
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<io::Result<_>>()?;
+        Ok(Journal {
+            mode: Mode::Replay(inputs),
+            error: None,
+        })
+    }
+
+    /// What `source` gives at instruction `at`, from the journal when
+    /// replaying and from `live` otherwise
+    pub fn input<F: FnOnce() -> u64>(&mut self, at: u64, source: Source, live: F) -> u64 {
+        let next = match self.mode {
+            Mode::Record(_) => {
+                let value = live();
+                self.write(Input { at, source, value });
+                return value;
+            }
+            Mode::Replay(ref mut inputs) => match inputs.front() {
+                Some(i) if i.at == at && i.source == source => {
+                    return inputs.pop_front().map_or(0, |i| i.value);
+                }
+                next => next.map(|i| format!("{:?} at {}", i.source, i.at)),
+            },
+            Mode::Diverged => return live(),
+        };
+        self.diverge(format!(
+            "replay diverged at instruction {}: {:?} input, but the journal has {}",
+            at,
+            source,
+            next.as_deref().unwrap_or("nothing left")
+        ));
+        live()
+    }
+
+    /// Whether the timer fires at instruction `at`. Replaying fires it
+    /// at the first check from the count in the journal on, whatever
+    /// `live` says.
+    pub fn timer(&mut self, at: u64, live: bool) -> bool {
+        match self.mode {
+            Mode::Record(_) => {
+                if live {
+                    self.write(Input {
+                        at,
+                        source: Source::Timer,
+                        value: 1,
+                    });
+                }
+                live
+            }
+            Mode::Replay(ref mut inputs) => match inputs.front() {
+                Some(i) if i.source == Source::Timer && i.at <= at => {
+                    inputs.pop_front();
+                    true
+                }
+                _ => false,
+            },
+            Mode::Diverged => live,
+        }
+    }
+
+    /// Fails if the replay diverged or has inputs left over, or if
+    /// recording failed
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(ref e) = self.error {
+            return Err(io::Error::new(io::ErrorKind::Other, e.clone()));
+        }
+        match self.mode {
+            Mode::Record(ref mut out) => out.flush(),
+            Mode::Replay(ref inputs) if !inputs.is_empty() => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "replay ended with {} inputs left, the next at instruction {}",
+                    inputs.len(),
+                    inputs[0].at
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&mut self, input: Input) {
+        if let Mode::Record(ref mut out) = self.mode {
+            let written = serde_json::to_writer(&mut *out, &input)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(out));
+            if let Err(e) = written {
+                self.fail(format!("writing journal: {}", e));
+            }
+        }
+    }
+
+    fn diverge(&mut self, msg: String) {
+        self.mode = Mode::Diverged;
+        self.fail(msg);
+    }
+
+    fn fail(&mut self, msg: String) {
+        if self.error.is_none() {
+            error!("{}", msg);
+            self.error = Some(msg);
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replays_inputs() {
+        let path = std::env::temp_dir().join(format!("risk5-replay-{}", std::process::id()));
+        let args = vec!["--elf".to_string(), "test.elf".into()];
+        assert_eq!(
+            run_args(&[
+                "--record".into(),
+                "j".into(),
+                args[0].clone(),
+                args[1].clone()
+            ]),
+            args
+        );
+
+        let mut j = Journal::record(&path, &args).expect("record");
+        assert_eq!(j.input(10, Source::Console, || 97), 97);
+        assert!(!j.timer(5000, false));
+        assert!(j.timer(10_000, true));
+        assert_eq!(j.input(10_001, Source::Time, || 10_001), 10_001);
+        j.finish().expect("finish");
+
+        // the journal wins over the live sources
+        let mut j = Journal::replay(&path, &args).expect("replay");
+        assert_eq!(j.input(10, Source::Console, || 0), 97);
+        assert!(!j.timer(5000, true));
+        assert!(j.timer(10_003, false));
+        assert_eq!(j.input(10_001, Source::Time, || 0), 10_001);
+        j.finish().expect("finish");
+
+        let mut j = Journal::replay(&path, &args).expect("replay");
+        assert_eq!(j.input(11, Source::Console, || 98), 98);
+        let e = j.finish().expect_err("diverged");
+        assert!(e.to_string().contains("instruction 11"), "{}", e);
+
+        let mut j = Journal::replay(&path, &args).expect("replay");
+        j.input(10, Source::Console, || 0);
+        assert!(j.finish().is_err());
+
+        std::fs::remove_file(&path).expect("remove");
+    }
+}
@@ -0,0 +1,500 @@
+use crate::smp::HartState;
+use crate::{Memory, Processor};
+
+/*
+ *
+ * SBI
+ * ---
+ * Built-in implementation of the RISC-V Supervisor Binary Interface
+ * (v1.0) so S-mode kernels can run without bbl/OpenSBI in M-mode.
+ *
+ * a7 = extension id, a6 = function id, a0..a5 = args.
+ * Returns error in a0 and value in a1. Legacy (v0.1) calls
+ * only return a0.
+ *
+ */
+
+const SPEC_VERSION: u64 = 1 << 24; // v1.0
+const IMPL_ID: u64 = 0x7269_736b; // "risk"
+const IMPL_VERSION: u64 = 1;
+
+// Extension IDs
+const EXT_LEGACY_END: u64 = 0x0f;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x0048_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+const EXT_DBCN: u64 = 0x4442_434e;
+
+// Legacy Extension IDs
+const LEGACY_SET_TIMER: u64 = 0;
+const LEGACY_CONSOLE_PUTCHAR: u64 = 1;
+const LEGACY_CONSOLE_GETCHAR: u64 = 2;
+const LEGACY_CLEAR_IPI: u64 = 3;
+const LEGACY_SEND_IPI: u64 = 4;
+const LEGACY_REMOTE_FENCE_I: u64 = 5;
+const LEGACY_REMOTE_SFENCE_VMA: u64 = 6;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 7;
+const LEGACY_SHUTDOWN: u64 = 8;
+
+// Error codes
+const SUCCESS: i64 = 0;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// Argument registers
+const A0: usize = 10;
+const A1: usize = 11;
+const A6: usize = 16;
+const A7: usize = 17;
+
+// HSM hart states
+const HSM_STARTED: u64 = 0;
+const HSM_STOPPED: u64 = 1;
+const HSM_START_PENDING: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetType {
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+
+struct SbiRet {
+    error: i64,
+    value: u64,
+}
+
+impl SbiRet {
+    fn ok(value: u64) -> Self {
+        SbiRet {
+            error: SUCCESS,
+            value,
+        }
+    }
+
+    fn err(error: i64) -> Self {
+        SbiRet { error, value: 0 }
+    }
+}
+
+/// Handle an `ecall` from S-mode. The ecall is always consumed
+/// and the pc advanced; errors are reported to the caller in a0.
+pub fn call<M: Memory>(p: &mut Processor<M>) {
+    let eid = p.regs.get(A7);
+    let fid = p.regs.get(A6);
+
+    trace!("sbi call eid=0x{:x} fid=0x{:x}", eid, fid);
+
+    if eid <= EXT_LEGACY_END {
+        let ret = legacy(p, eid);
+        if let Some(ret) = ret {
+            p.regs.set(A0, ret as u64);
+        }
+        p.advance_pc();
+        return;
+    }
+
+    let ret = match eid {
+        EXT_BASE => base(p, fid),
+        EXT_TIME => time(p, fid),
+        EXT_IPI => ipi(p, fid),
+        EXT_RFENCE => rfence(p, fid),
+        EXT_HSM => hsm(p, fid),
+        EXT_SRST => srst(p, fid),
+        EXT_DBCN => dbcn(p, fid),
+        _ => {
+            debug!("sbi unsupported extension 0x{:x}", eid);
+            SbiRet::err(ERR_NOT_SUPPORTED)
+        }
+    };
+
+    p.regs.set(A0, ret.error as u64);
+    p.regs.set(A1, ret.value);
+    p.advance_pc();
+}
+
+fn arg<M>(p: &Processor<M>, n: usize) -> u64 {
+    p.regs.get(A0 + n)
+}
+
+fn legacy<M: Memory>(p: &mut Processor<M>, eid: u64) -> Option<i64> {
+    let a0 = arg(p, 0);
+    match eid {
+        LEGACY_SET_TIMER => {
+            trace!("legacy set timer {}", a0);
+            p.set_timer_at(a0);
+            Some(SUCCESS)
+        }
+        LEGACY_CONSOLE_PUTCHAR => {
+            trace!("legacy putchar {}", a0 as u8 as char);
+            p.putchar(&[a0 as u8]);
+            Some(SUCCESS)
+        }
+        LEGACY_CONSOLE_GETCHAR => Some(p.getchar() as i64),
+        LEGACY_CLEAR_IPI => {
+            p.csrs_mut().mip.set_supervisor_software_interrupt(0);
+            let hartid = p.hartid() as usize;
+            if let Some(smp) = p.smp() {
+                smp.lock().clear_ipi(hartid);
+            }
+            Some(SUCCESS)
+        }
+        LEGACY_SEND_IPI => {
+            // a0 is a virtual address of the hart mask
+            let mask = match p.mmu_mut().read_d(a0) {
+                Ok(mask) => mask,
+                Err(()) => return Some(ERR_INVALID_PARAM),
+            };
+            send_ipi(p, mask, 0);
+            Some(SUCCESS)
+        }
+        LEGACY_REMOTE_FENCE_I | LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => {
+            let mask = match p.mmu_mut().read_d(a0) {
+                Ok(mask) => mask,
+                Err(()) => return Some(ERR_INVALID_PARAM),
+            };
+            remote_fence(p, mask, 0);
+            Some(SUCCESS)
+        }
+        LEGACY_SHUTDOWN => {
+            p.system_reset(ResetType::Shutdown);
+            None
+        }
+        _ => Some(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn base<M>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    match fid {
+        0 => SbiRet::ok(SPEC_VERSION),
+        1 => SbiRet::ok(IMPL_ID),
+        2 => SbiRet::ok(IMPL_VERSION),
+        3 => {
+            let supported = match arg(p, 0) {
+                EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST | EXT_DBCN => 1,
+                eid if eid <= LEGACY_SHUTDOWN => 1,
+                _ => 0,
+            };
+            SbiRet::ok(supported)
+        }
+        // mvendorid, marchid, mimpid
+        4..=6 => SbiRet::ok(0),
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn time<M>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    match fid {
+        0 => {
+            p.set_timer_at(arg(p, 0));
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+// Returns true if `hartid` is selected by the mask. A base
+// of -1 means all harts.
+fn hart_selected(hartid: u64, mask: u64, base: u64) -> bool {
+    if base == u64::MAX {
+        return true;
+    }
+    hartid >= base && hartid - base < 64 && (mask >> (hartid - base)) & 1 == 1
+}
+
+fn send_ipi<M>(p: &mut Processor<M>, mask: u64, base: u64) {
+    for hartid in 0..p.hart_count() {
+        if !hart_selected(hartid, mask, base) {
+            continue;
+        }
+        if hartid == p.hartid() {
+            p.csrs_mut().mip.set_supervisor_software_interrupt(1);
+        } else if let Some(smp) = p.smp() {
+            smp.lock().send_ipi(hartid as usize);
+        }
+    }
+}
+
+fn remote_fence<M>(p: &mut Processor<M>, mask: u64, base: u64) {
+    for hartid in 0..p.hart_count() {
+        if !hart_selected(hartid, mask, base) {
+            continue;
+        }
+        if hartid == p.hartid() {
+            // as for the other harts, and a local fence.i
+            p.mmu_mut().flush_cache();
+            p.mmu_mut().flush_blocks();
+        } else if let Some(smp) = p.smp() {
+            smp.lock().remote_fence(hartid as usize);
+        }
+    }
+}
+
+// Every hart in the mask must exist
+fn valid_hart_mask<M>(p: &Processor<M>, mask: u64, base: u64) -> bool {
+    if base == u64::MAX || mask == 0 {
+        return true;
+    }
+    let harts = p.hart_count();
+    if base >= harts {
+        return false;
+    }
+    let span = harts - base;
+    span >= 64 || mask >> span == 0
+}
+
+fn ipi<M>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    match fid {
+        0 => {
+            let (mask, base) = (arg(p, 0), arg(p, 1));
+            if !valid_hart_mask(p, mask, base) {
+                return SbiRet::err(ERR_INVALID_PARAM);
+            }
+            send_ipi(p, mask, base);
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn rfence<M>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    let (mask, base) = (arg(p, 0), arg(p, 1));
+    match fid {
+        // remote_fence_i, remote_sfence_vma, remote_sfence_vma_asid
+        0..=2 => {
+            if !valid_hart_mask(p, mask, base) {
+                return SbiRet::err(ERR_INVALID_PARAM);
+            }
+            remote_fence(p, mask, base);
+            SbiRet::ok(0)
+        }
+        // hypervisor fences
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn hsm<M>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    let hartid = arg(p, 0);
+    if fid != 1 && fid != 3 && hartid >= p.hart_count() {
+        return SbiRet::err(ERR_INVALID_PARAM);
+    }
+    match fid {
+        // hart_start
+        0 => {
+            let (addr, opaque) = (arg(p, 1), arg(p, 2));
+            match p.smp() {
+                Some(smp) => match smp.lock().request_start(hartid as usize, addr, opaque) {
+                    Ok(()) => SbiRet::ok(0),
+                    Err(_) => SbiRet::err(ERR_ALREADY_AVAILABLE),
+                },
+                None => SbiRet::err(ERR_ALREADY_AVAILABLE),
+            }
+        }
+        // hart_stop
+        1 => {
+            p.stop();
+            SbiRet::ok(0)
+        }
+        // hart_get_status
+        2 => {
+            let state = match p.smp() {
+                Some(smp) => smp.lock().state(hartid as usize),
+                None if p.is_stopped() => HartState::Stopped,
+                None => HartState::Started,
+            };
+            SbiRet::ok(match state {
+                HartState::Started => HSM_STARTED,
+                HartState::Stopped => HSM_STOPPED,
+                HartState::StartPending => HSM_START_PENDING,
+            })
+        }
+        // hart_suspend
+        3 => {
+            let suspend_type = arg(p, 0) as u32;
+            if suspend_type != 0 {
+                // only default retentive suspend. Resume is immediate
+                // as pending interrupts are checked by the caller.
+                return SbiRet::err(ERR_NOT_SUPPORTED);
+            }
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn srst<M>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    if fid != 0 {
+        return SbiRet::err(ERR_NOT_SUPPORTED);
+    }
+    let reset_type = match arg(p, 0) {
+        0 => ResetType::Shutdown,
+        1 => ResetType::ColdReboot,
+        2 => ResetType::WarmReboot,
+        _ => return SbiRet::err(ERR_INVALID_PARAM),
+    };
+    if arg(p, 1) > 1 {
+        return SbiRet::err(ERR_INVALID_PARAM);
+    }
+    p.system_reset(reset_type);
+    SbiRet::ok(0)
+}
+
+fn dbcn<M: Memory>(p: &mut Processor<M>, fid: u64) -> SbiRet {
+    match fid {
+        // console_write
+        0 => {
+            let (len, addr_lo, addr_hi) = (arg(p, 0), arg(p, 1), arg(p, 2));
+            if addr_hi != 0 || !p.mmu_mut().is_ram(addr_lo, len) {
+                return SbiRet::err(ERR_INVALID_PARAM);
+            }
+            let bytes: Vec<u8> = (0..len)
+                .map(|i| p.mmu_mut().bare_mut().read_b(addr_lo + i))
+                .collect();
+            p.putchar(&bytes);
+            SbiRet::ok(len)
+        }
+        // console_read
+        1 => {
+            let (len, addr_lo, addr_hi) = (arg(p, 0), arg(p, 1), arg(p, 2));
+            if addr_hi != 0 || !p.mmu_mut().is_ram(addr_lo, len) {
+                return SbiRet::err(ERR_INVALID_PARAM);
+            }
+            let mut read = 0;
+            while read < len {
+                let c = p.getchar();
+                if c as i64 == -1 {
+                    break;
+                }
+                p.mmu_mut().write_phys_b(addr_lo + read, c as u8);
+                read += 1;
+            }
+            SbiRet::ok(read)
+        }
+        // console_write_byte
+        2 => {
+            let c = arg(p, 0) as u8;
+            p.putchar(&[c]);
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::BlockMemory;
+
+    fn sbi_call<M: Memory>(p: &mut Processor<M>, eid: u64, fid: u64, args: &[u64]) -> (i64, u64) {
+        p.regs.set(A7, eid);
+        p.regs.set(A6, fid);
+        for (i, a) in args.iter().enumerate() {
+            p.regs.set(A0 + i, *a);
+        }
+        call(p);
+        (p.regs.get(A0) as i64, p.regs.get(A1))
+    }
+
+    fn processor() -> Processor<BlockMemory> {
+        let mut mem = BlockMemory::new(0);
+        mem.add_block(0x8000_0000, 0x1000);
+        Processor::new(mem)
+    }
+
+    #[test]
+    fn base() {
+        let mut p = processor();
+        assert_eq!(sbi_call(&mut p, EXT_BASE, 0, &[]), (SUCCESS, SPEC_VERSION));
+        assert_eq!(sbi_call(&mut p, EXT_BASE, 3, &[EXT_HSM]), (SUCCESS, 1));
+        assert_eq!(sbi_call(&mut p, EXT_BASE, 3, &[0x1234_5678]), (SUCCESS, 0));
+        assert_eq!(sbi_call(&mut p, 0x1234_5678, 0, &[]).0, ERR_NOT_SUPPORTED);
+        assert_eq!(p.pc(), 0x1000 + 4 * 4);
+    }
+
+    #[test]
+    fn ipi_and_hsm() {
+        let mut p = processor();
+        assert_eq!(sbi_call(&mut p, EXT_IPI, 0, &[1, 0]).0, SUCCESS);
+        assert_eq!(p.csrs().mip.supervisor_software_interrupt(), 1);
+        assert_eq!(sbi_call(&mut p, EXT_IPI, 0, &[1, 5]).0, ERR_INVALID_PARAM);
+
+        assert_eq!(sbi_call(&mut p, EXT_HSM, 2, &[0]), (SUCCESS, HSM_STARTED));
+        assert_eq!(sbi_call(&mut p, EXT_HSM, 0, &[0]).0, ERR_ALREADY_AVAILABLE);
+        assert_eq!(sbi_call(&mut p, EXT_HSM, 2, &[3]).0, ERR_INVALID_PARAM);
+    }
+
+    #[test]
+    fn remote_fence_i() {
+        let matchers = &mut crate::build_matchers();
+        let mut p = processor();
+        p.mmu_mut().write_w(0x8000_0000, 0x0010_0293).expect("li"); // li t0, 1
+        p.mmu_mut().write_w(0x8000_0004, 0x0000_006f).expect("j"); // j .
+        p.set_pc(0x8000_0000);
+        p.run(matchers, 2);
+        assert_eq!(p.regs.get(5usize), 1);
+
+        // code changed under the predecoded block, as by another hart,
+        // and a fence aimed at this one drops it
+        p.mmu_mut().mem_mut().write_w(0x8000_0000, 0x0020_0293); // li t0, 2
+        assert_eq!(sbi_call(&mut p, EXT_RFENCE, 0, &[1, 0]).0, SUCCESS);
+        p.set_pc(0x8000_0000);
+        p.run(matchers, 2);
+        assert_eq!(p.regs.get(5usize), 2);
+    }
+
+    #[test]
+    fn smp_ipi_and_hsm() {
+        let mut mem = BlockMemory::new(0);
+        mem.add_block(0x8000_0000, 0x1000);
+        let mut sys = crate::smp::System::new(mem, 2, None, 10);
+        sys.boot_supervisor(0x8000_0000, 0);
+        let smp = sys.smp().clone();
+        let p = &mut sys.harts_mut()[0];
+
+        assert_eq!(sbi_call(p, EXT_HSM, 2, &[1]), (SUCCESS, HSM_STOPPED));
+        assert_eq!(sbi_call(p, EXT_HSM, 0, &[1, 0x8000_0100, 0]).0, SUCCESS);
+        assert_eq!(sbi_call(p, EXT_HSM, 2, &[1]), (SUCCESS, HSM_START_PENDING));
+        assert_eq!(
+            sbi_call(p, EXT_HSM, 0, &[1, 0x8000_0100, 0]).0,
+            ERR_ALREADY_AVAILABLE
+        );
+        assert_eq!(sbi_call(p, EXT_HSM, 2, &[2]).0, ERR_INVALID_PARAM);
+
+        assert_eq!(sbi_call(p, EXT_IPI, 0, &[0b10, 0]).0, SUCCESS);
+        assert_eq!(p.csrs().mip.supervisor_software_interrupt(), 0);
+        assert_eq!(sbi_call(p, EXT_IPI, 0, &[0b100, 0]).0, ERR_INVALID_PARAM);
+        assert_eq!(smp.lock().state(1), HartState::StartPending);
+    }
+
+    #[test]
+    fn debug_console() {
+        let mut p = processor();
+        assert_eq!(
+            sbi_call(&mut p, EXT_DBCN, 0, &[4, 0x8000_0000, 0]),
+            (SUCCESS, 4)
+        );
+        // running off the end of RAM
+        assert_eq!(
+            sbi_call(&mut p, EXT_DBCN, 0, &[2, 0x8000_0fff, 0]).0,
+            ERR_INVALID_PARAM
+        );
+        assert_eq!(
+            sbi_call(&mut p, EXT_DBCN, 1, &[1, 0x4000_0000, 0]).0,
+            ERR_INVALID_PARAM
+        );
+        assert_eq!(sbi_call(&mut p, EXT_DBCN, 3, &[]).0, ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn srst() {
+        let mut p = processor();
+        assert_eq!(sbi_call(&mut p, EXT_SRST, 0, &[7, 0]).0, ERR_INVALID_PARAM);
+        assert_eq!(p.reset_type(), None);
+        assert_eq!(sbi_call(&mut p, EXT_SRST, 0, &[0, 0]).0, SUCCESS);
+        assert_eq!(p.reset_type(), Some(ResetType::Shutdown));
+    }
+}
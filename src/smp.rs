@@ -0,0 +1,577 @@
+use crate::matcher::Matchers;
+use crate::sbi::ResetType;
+use crate::{Memory, Processor};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+mod threaded;
+
+pub use self::threaded::{ThreadedMemory, ThreadedSystem};
+
+/*
+ *
+ * SMP
+ * ---
+ * Harts share one physical memory through `SharedMemory` handles.
+ * Alongside the memory they share `SmpState`, which carries what
+ * harts use to signal each other: CLINT msip/mtimecmp, SBI timers, IPIs
+ * and remote fences, HSM start requests and LR/SC reservations.
+ *
+ * `System` steps every running hart for a quantum of instructions
+ * in hart order. Signals are delivered at quantum boundaries so a
+ * run is deterministic. A hart in wfi sits its quanta out until an
+ * interrupt is pending, and once every hart waits time skips ahead
+ * to the earliest timer.
+ *
+ * `ThreadedSystem` is the throughput alternative: one host thread
+ * per hart over atomic memory, see `threaded`.
+ *
+ */
+
+// CLINT register offsets
+const CLINT_MSIP: u64 = 0x0;
+const CLINT_MTIMECMP: u64 = 0x4000;
+const CLINT_MTIME: u64 = 0xbff8;
+pub(crate) const CLINT_SIZE: u64 = 0xc000;
+
+// reservations cover an aligned doubleword
+const RESERVATION_MASK: u64 = !0x7;
+
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+}
+
+#[derive(Debug, Clone)]
+struct HartSignals {
+    state: HartState,
+    // (start_addr, opaque) from an HSM hart_start
+    start: Option<(u64, u64)>,
+    msip: bool,
+    ssip: bool,
+    fence: bool,
+    // in wfi
+    waiting: bool,
+    mtimecmp: u64,
+    // supervisor timer set through the SBI
+    timer: u64,
+    reservation: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct SmpState {
+    harts: Vec<HartSignals>,
+    time: u64,
+}
+
+/// Handle on the `SmpState` shared by every hart
+#[derive(Debug, Clone)]
+pub struct Smp(Arc<Mutex<SmpState>>);
+
+impl Smp {
+    pub fn new(harts: usize) -> Smp {
+        let hart = HartSignals {
+            state: HartState::Started,
+            start: None,
+            msip: false,
+            ssip: false,
+            fence: false,
+            waiting: false,
+            mtimecmp: u64::MAX,
+            timer: u64::MAX,
+            reservation: None,
+        };
+        Smp(Arc::new(Mutex::new(SmpState {
+            harts: vec![hart; harts],
+            time: 0,
+        })))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, SmpState> {
+        self.0.lock().expect("smp lock")
+    }
+}
+
+impl SmpState {
+    pub fn harts(&self) -> usize {
+        self.harts.len()
+    }
+
+    /// True once every hart has stopped
+    pub fn all_stopped(&self) -> bool {
+        self.harts.iter().all(|h| h.state == HartState::Stopped)
+    }
+
+    pub fn state(&self, hart: usize) -> HartState {
+        self.harts[hart].state
+    }
+
+    pub(crate) fn set_state(&mut self, hart: usize, state: HartState) {
+        debug!("Hart {} is now {:?}", hart, state);
+        self.harts[hart].state = state;
+    }
+
+    /// True while every hart that has not stopped waits in wfi
+    pub fn all_waiting(&self) -> bool {
+        self.harts
+            .iter()
+            .all(|h| h.waiting || h.state == HartState::Stopped)
+    }
+
+    pub(crate) fn set_waiting(&mut self, hart: usize, waiting: bool) {
+        self.harts[hart].waiting = waiting;
+    }
+
+    pub(crate) fn set_timer(&mut self, hart: usize, when: u64) {
+        self.harts[hart].timer = when;
+    }
+
+    /// Earliest machine or supervisor timer of the harts that have not
+    /// stopped, `u64::MAX` when none is set
+    pub fn next_timer(&self) -> u64 {
+        self.harts
+            .iter()
+            .filter(|h| h.state != HartState::Stopped)
+            .map(|h| h.mtimecmp.min(h.timer))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Ask a stopped hart to start in S-mode at `addr` with a1=`opaque`.
+    /// Returns the current state if the hart is not stopped.
+    pub fn request_start(&mut self, hart: usize, addr: u64, opaque: u64) -> Result<(), HartState> {
+        let h = &mut self.harts[hart];
+        if h.state != HartState::Stopped {
+            return Err(h.state);
+        }
+        h.state = HartState::StartPending;
+        h.start = Some((addr, opaque));
+        Ok(())
+    }
+
+    /// Raise a supervisor software interrupt on `hart`
+    pub fn send_ipi(&mut self, hart: usize) {
+        self.harts[hart].ssip = true;
+    }
+
+    pub fn clear_ipi(&mut self, hart: usize) {
+        self.harts[hart].ssip = false;
+    }
+
+    /// Have `hart` flush its translations before it next runs
+    pub fn remote_fence(&mut self, hart: usize) {
+        self.harts[hart].fence = true;
+    }
+
+    fn reserve(&mut self, hart: usize, addr: u64) {
+        self.harts[hart].reservation = Some(addr & RESERVATION_MASK);
+    }
+
+    fn take_reservation(&mut self, hart: usize, addr: u64) -> bool {
+        self.harts[hart].reservation.take() == Some(addr & RESERVATION_MASK)
+    }
+
+    // a store by `hart` breaks every other hart's reservation on it
+    fn invalidate(&mut self, hart: usize, addr: u64) {
+        let addr = addr & RESERVATION_MASK;
+        for (i, h) in self.harts.iter_mut().enumerate() {
+            if i != hart && h.reservation == Some(addr) {
+                trace!(
+                    "Hart {} store to 0x{:x} breaks hart {} reservation",
+                    hart,
+                    addr,
+                    i
+                );
+                h.reservation = None;
+            }
+        }
+    }
+
+    // CLINT registers as doublewords so any access width can be
+    // carved out of them
+    fn clint_read(&self, offset: u64) -> u64 {
+        let hart = |base: u64, stride: u64| ((offset - base) / stride) as usize;
+        match offset {
+            CLINT_MTIME => self.time,
+            _ if offset >= CLINT_MTIMECMP => self
+                .harts
+                .get(hart(CLINT_MTIMECMP, 8))
+                .map(|h| h.mtimecmp)
+                .unwrap_or(0),
+            _ => {
+                let msip = |i: usize| self.harts.get(i).map(|h| h.msip as u64).unwrap_or(0);
+                let i = hart(CLINT_MSIP, 4);
+                msip(i) | (msip(i + 1) << 32)
+            }
+        }
+    }
+
+    fn clint_write(&mut self, offset: u64, value: u64) {
+        let hart = |base: u64, stride: u64| ((offset - base) / stride) as usize;
+        match offset {
+            CLINT_MTIME => warn!("Ignoring write to CLINT mtime"),
+            _ if offset >= CLINT_MTIMECMP => {
+                if let Some(h) = self.harts.get_mut(hart(CLINT_MTIMECMP, 8)) {
+                    h.mtimecmp = value;
+                }
+            }
+            _ => {
+                let i = hart(CLINT_MSIP, 4);
+                for (n, h) in self.harts.iter_mut().skip(i).take(2).enumerate() {
+                    h.msip = (value >> (n * 32)) & 1 == 1;
+                }
+            }
+        }
+    }
+
+    // `size` bytes of the CLINT at `offset`
+    fn clint_load(&self, offset: u64, size: u64) -> u64 {
+        let v = self.clint_read(offset & !0x7) >> ((offset & 0x7) * 8);
+        if size == 8 {
+            v
+        } else {
+            v & ((1 << (size * 8)) - 1)
+        }
+    }
+
+    fn clint_store(&mut self, offset: u64, value: u64, size: u64) {
+        let aligned = offset & !0x7;
+        let shift = (offset & 0x7) * 8;
+        let mask = if size == 8 {
+            u64::MAX
+        } else {
+            ((1 << (size * 8)) - 1) << shift
+        };
+        let reg = self.clint_read(aligned);
+        self.clint_write(aligned, (reg & !mask) | ((value << shift) & mask));
+    }
+}
+
+// offset into the CLINT if `offset` falls inside it
+fn clint_offset(clint: Option<u64>, offset: u64) -> Option<u64> {
+    match clint {
+        Some(base) if offset >= base && offset < base + CLINT_SIZE => Some(offset - base),
+        _ => None,
+    }
+}
+
+/// One hart's view of the shared memory
+pub struct SharedMemory<M> {
+    mem: Rc<RefCell<M>>,
+    smp: Smp,
+    hart: usize,
+    clint: Option<u64>,
+}
+
+macro_rules! shared_access {
+    ($read:ident, $write:ident, $t:ty, $size:expr) => {
+        fn $read(&mut self, offset: u64) -> $t {
+            if let Some(off) = clint_offset(self.clint, offset) {
+                return self.smp.lock().clint_load(off, $size) as $t;
+            }
+            self.mem.borrow_mut().$read(offset)
+        }
+
+        fn $write(&mut self, offset: u64, value: $t) {
+            if let Some(off) = clint_offset(self.clint, offset) {
+                return self.smp.lock().clint_store(off, value as u64, $size);
+            }
+            self.smp.lock().invalidate(self.hart, offset);
+            self.mem.borrow_mut().$write(offset, value)
+        }
+    };
+}
+
+impl<M: Memory> Memory for SharedMemory<M> {
+    shared_access!(read_b, write_b, u8, 1);
+    shared_access!(read_h, write_h, u16, 2);
+    shared_access!(read_w, write_w, u32, 4);
+    shared_access!(read_d, write_d, u64, 8);
+
+    fn is_ram(&mut self, offset: u64, len: u64) -> bool {
+        clint_offset(self.clint, offset).is_none()
+            && clint_offset(self.clint, offset + len.saturating_sub(1)).is_none()
+            && self.mem.borrow_mut().is_ram(offset, len)
+    }
+
+    fn reserve(&mut self, offset: u64) {
+        self.smp.lock().reserve(self.hart, offset);
+    }
+
+    fn take_reservation(&mut self, offset: u64) -> bool {
+        self.smp.lock().take_reservation(self.hart, offset)
+    }
+}
+
+/// Harts sharing a memory, run round-robin
+pub struct System<M> {
+    harts: Vec<Processor<SharedMemory<M>>>,
+    smp: Smp,
+    quantum: u64,
+}
+
+impl<M: Memory> System<M> {
+    /// All harts start at the reset vector, as after a reset
+    pub fn new(mem: M, harts: usize, clint: Option<u64>, quantum: u64) -> Self {
+        let mem = Rc::new(RefCell::new(mem));
+        let smp = Smp::new(harts);
+        let harts = (0..harts)
+            .map(|hart| {
+                let mut p = Processor::new(SharedMemory {
+                    mem: mem.clone(),
+                    smp: smp.clone(),
+                    hart,
+                    clint,
+                });
+                p.join_smp(hart as u64, smp.clone());
+                p
+            })
+            .collect();
+        System {
+            harts,
+            smp,
+            quantum: quantum.max(1),
+        }
+    }
+
+    /// Boot a kernel in S-mode on hart 0 with the built-in SBI. The
+    /// other harts wait to be started through HSM.
+    pub fn boot_supervisor(&mut self, entry: u64, dtb: u64) {
+        boot_supervisor(&mut self.harts, entry, dtb);
+    }
+
+    pub fn harts_mut(&mut self) -> &mut [Processor<SharedMemory<M>>] {
+        &mut self.harts
+    }
+
+    pub fn smp(&self) -> &Smp {
+        &self.smp
+    }
+
+    pub fn reset_type(&self) -> Option<ResetType> {
+        self.harts.iter().filter_map(|h| h.reset_type()).next()
+    }
+
+    /// True once a reset was requested or every hart has stopped
+    pub fn is_stopped(&self) -> bool {
+        self.reset_type().is_some() || self.harts.iter().all(|h| h.is_stopped())
+    }
+
+    fn deliver(&mut self, i: usize) {
+        deliver(&mut self.harts[i], &mut self.smp.lock(), i);
+    }
+
+    /// Run each hart for one quantum, in hart order
+    pub fn run_quantum(&mut self, matchers: &mut Matchers<SharedMemory<M>>) {
+        for i in 0..self.harts.len() {
+            self.deliver(i);
+
+            let hart = &mut self.harts[i];
+            if hart.is_stopped() || hart.is_waiting() {
+                continue;
+            }
+            hart.handle_interrupt();
+            hart.run(matchers, self.quantum);
+            if hart.reset_type().is_some() {
+                return;
+            }
+        }
+
+        if self.smp.lock().all_waiting() {
+            self.skip_to_next_timer();
+        }
+        let time = self.harts.iter().map(|h| h.time()).max();
+        self.smp.lock().time = time.unwrap_or(0);
+    }
+
+    // every hart waits, let time pass until the first one has work
+    fn skip_to_next_timer(&mut self) {
+        let next = self.smp.lock().next_timer();
+        let running = || self.harts.iter().filter(|h| !h.is_stopped());
+        let time = running().map(|h| h.time()).max().unwrap_or(0);
+        for hart in self.harts.iter_mut().filter(|h| !h.is_stopped()) {
+            hart.sync_clock(time);
+            hart.idle_until(next);
+        }
+    }
+}
+
+fn boot_supervisor<M>(harts: &mut [Processor<M>], entry: u64, dtb: u64) {
+    for (i, hart) in harts.iter_mut().enumerate() {
+        hart.enable_sbi();
+        if i == 0 {
+            hart.boot_supervisor(entry, dtb);
+        } else {
+            hart.stop();
+        }
+    }
+}
+
+// start requests, IPIs, fences and timers from other harts
+fn deliver<M: Memory>(hart: &mut Processor<M>, smp: &mut SmpState, i: usize) {
+    let time = smp.time;
+    let signals = &mut smp.harts[i];
+
+    if let Some((addr, opaque)) = signals.start.take() {
+        debug!("Starting hart {} at 0x{:x}", i, addr);
+        signals.state = HartState::Started;
+        signals.waiting = false;
+        hart.restart();
+        hart.sync_clock(time);
+        hart.boot_supervisor(addr, opaque);
+    }
+
+    let mip = &mut hart.csrs_mut().mip;
+    mip.set_machine_software_interrupt(signals.msip as u64);
+    mip.set_machine_timer_interrupt((time >= signals.mtimecmp) as u64);
+    if signals.ssip {
+        signals.ssip = false;
+        mip.set_supervisor_software_interrupt(1);
+    }
+    if signals.fence {
+        signals.fence = false;
+        hart.mmu_mut().flush_cache();
+        hart.mmu_mut().flush_blocks();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::BlockMemory;
+
+    fn system(harts: usize) -> System<BlockMemory> {
+        let mut mem = BlockMemory::new(0);
+        mem.add_block(0x8000_0000, 0x1000);
+        mem.add_block(0x200_0000, CLINT_SIZE);
+        System::new(mem, harts, Some(0x200_0000), 10)
+    }
+
+    #[test]
+    fn reservations() {
+        let mut sys = system(2);
+        let harts = sys.harts_mut();
+
+        harts[0].mmu_mut().load_reserved_w(0x8000_0010).expect("lr");
+        harts[1].mmu_mut().load_reserved_w(0x8000_0010).expect("lr");
+        // hart 1 stores into the reserved doubleword
+        harts[1].mmu_mut().write_w(0x8000_0014, 1).expect("write");
+
+        assert_eq!(
+            harts[0].mmu_mut().store_conditional_w(0x8000_0010, 2),
+            Ok(false)
+        );
+        assert_eq!(
+            harts[1].mmu_mut().store_conditional_w(0x8000_0010, 3),
+            Ok(true)
+        );
+        // a reservation is only good for one store conditional
+        assert_eq!(
+            harts[1].mmu_mut().store_conditional_w(0x8000_0010, 4),
+            Ok(false)
+        );
+        assert_eq!(harts[0].mmu_mut().read_w(0x8000_0010), Ok(3));
+    }
+
+    #[test]
+    fn clint() {
+        let mut sys = system(2);
+        let clint = 0x200_0000;
+        {
+            let hart = &mut sys.harts_mut()[0];
+            hart.mmu_mut().write_w(clint + 4, 1).expect("msip");
+            hart.mmu_mut()
+                .write_d(clint + CLINT_MTIMECMP + 8, 5)
+                .expect("mtimecmp");
+            assert_eq!(hart.mmu_mut().read_w(clint + 4), Ok(1));
+            assert_eq!(hart.mmu_mut().read_w(clint + CLINT_MTIMECMP + 8), Ok(5));
+        }
+
+        sys.smp().lock().time = 5;
+        sys.deliver(1);
+        let mip = &sys.harts_mut()[1].csrs().mip;
+        assert_eq!(mip.machine_software_interrupt(), 1);
+        assert_eq!(mip.machine_timer_interrupt(), 1);
+    }
+
+    #[test]
+    fn waiting_harts_skip_to_timer() {
+        let mut sys = system(2);
+        let matchers = &mut crate::build_matchers();
+        for hart in sys.harts_mut() {
+            // wfi, then spin in place
+            hart.mmu_mut()
+                .write_w(0x8000_0000, 0x1050_0073)
+                .expect("wfi");
+            hart.mmu_mut().write_w(0x8000_0004, 0x0000_006f).expect("j");
+            hart.set_pc(0x8000_0000);
+            // machine timer interrupts enabled, taking them is not
+            hart.set_csr(0x304, 1 << 7);
+        }
+        sys.smp().lock().clint_write(CLINT_MTIMECMP + 8, 1000);
+
+        sys.run_quantum(matchers);
+        assert!(sys.smp().lock().all_waiting());
+        assert_eq!(sys.smp().lock().time, 1000);
+
+        // only the hart whose timer fired wakes
+        sys.run_quantum(matchers);
+        assert!(sys.harts_mut()[0].is_waiting());
+        assert!(!sys.harts_mut()[1].is_waiting());
+        assert_eq!(sys.harts_mut()[0].insn_counter(), 1);
+        assert!(sys.harts_mut()[1].insn_counter() > 1);
+    }
+
+    #[test]
+    fn next_timer() {
+        let smp = Smp::new(2);
+        let mut smp = smp.lock();
+        assert_eq!(smp.next_timer(), u64::MAX);
+        smp.clint_store(CLINT_MTIMECMP, 900, 8);
+        smp.set_timer(0, 700);
+        smp.set_timer(1, 500);
+        assert_eq!(smp.next_timer(), 500);
+        // stopped harts don't wake anyone
+        smp.set_state(1, HartState::Stopped);
+        assert_eq!(smp.next_timer(), 700);
+    }
+
+    #[test]
+    fn hart_start() {
+        let mut sys = system(2);
+        sys.boot_supervisor(0x8000_0000, 0);
+        assert_eq!(sys.smp().lock().state(1), HartState::Stopped);
+        {
+            // left over from before the hart stopped
+            let hart = &mut sys.harts_mut()[1];
+            hart.set_csr(0x180, 8 << 60 | 0x8_0000);
+            hart.csrs_mut().mstatus.set_supervisor_interrupt_enabled(1);
+        }
+        assert!(!sys.is_stopped());
+
+        sys.smp()
+            .lock()
+            .request_start(1, 0x8000_0100, 42)
+            .expect("start");
+        assert_eq!(
+            sys.smp().lock().request_start(1, 0, 0),
+            Err(HartState::StartPending)
+        );
+
+        sys.deliver(1);
+        let hart = &mut sys.harts_mut()[1];
+        assert!(!hart.is_stopped());
+        assert_eq!(hart.pc(), 0x8000_0100);
+        assert_eq!(hart.prv(), 1);
+        assert_eq!(hart.get_reg(10), 1);
+        assert_eq!(hart.get_reg(11), 42);
+        assert_eq!(hart.csrs().satp.mode(), 0);
+        assert_eq!(hart.machine_status().supervisor_interrupt_enabled(), 0);
+        assert_eq!(sys.smp().lock().state(1), HartState::Started);
+    }
+}
@@ -0,0 +1,276 @@
+use super::{boot_supervisor, clint_offset, deliver, Smp};
+use crate::build_matchers;
+use crate::memory::{AtomicMemory, BlockMemory};
+use crate::sbi::ResetType;
+use crate::{Memory, Processor};
+use std::sync::atomic::{self, AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/*
+ *
+ * Threaded SMP
+ * ------------
+ * Each hart runs on its own host thread for throughput. Memory is an
+ * `AtomicMemory` so AMOs are host atomics. LR keeps the loaded value
+ * and SC is a compare-and-swap against it, so unlike `SharedMemory`
+ * a store that writes back the same value does not break a
+ * reservation.
+ *
+ * Signals still go through `SmpState` and are picked up at quantum
+ * boundaries, but quanta of different harts interleave as the host
+ * schedules them so runs are not reproducible.
+ *
+ */
+
+// how often a stopped or waiting hart checks for a start request or
+// an interrupt
+const IDLE_POLL: Duration = Duration::from_millis(1);
+
+/// One hart's view of the atomic memory
+pub struct ThreadedMemory {
+    mem: Arc<AtomicMemory>,
+    smp: Smp,
+    clint: Option<u64>,
+    // address and value of the last load-reserved
+    reservation: Option<(u64, u64)>,
+}
+
+impl ThreadedMemory {
+    fn load_reserved(&mut self, offset: u64, size: u64) -> u64 {
+        let v = self.mem.load(offset, size);
+        self.reservation = Some((offset, v));
+        v
+    }
+
+    fn store_conditional(&mut self, offset: u64, size: u64, value: u64) -> bool {
+        match self.reservation.take() {
+            Some((addr, v)) if addr == offset => self.mem.compare_exchange(offset, size, v, value),
+            _ => false,
+        }
+    }
+}
+
+macro_rules! threaded_access {
+    ($read:ident, $write:ident, $t:ty, $size:expr) => {
+        fn $read(&mut self, offset: u64) -> $t {
+            if let Some(off) = clint_offset(self.clint, offset) {
+                return self.smp.lock().clint_load(off, $size) as $t;
+            }
+            self.mem.load(offset, $size) as $t
+        }
+
+        fn $write(&mut self, offset: u64, value: $t) {
+            if let Some(off) = clint_offset(self.clint, offset) {
+                return self.smp.lock().clint_store(off, value as u64, $size);
+            }
+            self.mem.store(offset, $size, value as u64)
+        }
+    };
+}
+
+impl Memory for ThreadedMemory {
+    threaded_access!(read_b, write_b, u8, 1);
+    threaded_access!(read_h, write_h, u16, 2);
+    threaded_access!(read_w, write_w, u32, 4);
+    threaded_access!(read_d, write_d, u64, 8);
+
+    fn is_ram(&mut self, offset: u64, len: u64) -> bool {
+        clint_offset(self.clint, offset).is_none()
+            && clint_offset(self.clint, offset + len.saturating_sub(1)).is_none()
+            && self.mem.contains(offset, len)
+    }
+
+    fn load_reserved_w(&mut self, offset: u64) -> u32 {
+        self.load_reserved(offset, 4) as u32
+    }
+
+    fn load_reserved_d(&mut self, offset: u64) -> u64 {
+        self.load_reserved(offset, 8)
+    }
+
+    fn store_conditional_w(&mut self, offset: u64, value: u32) -> bool {
+        self.store_conditional(offset, 4, value as u64)
+    }
+
+    fn store_conditional_d(&mut self, offset: u64, value: u64) -> bool {
+        self.store_conditional(offset, 8, value)
+    }
+
+    fn fetch_update_w<F: Fn(u32) -> u32>(&mut self, offset: u64, f: F) -> u32 {
+        self.mem.fetch_update(offset, 4, |v| f(v as u32) as u64) as u32
+    }
+
+    fn fetch_update_d<F: Fn(u64) -> u64>(&mut self, offset: u64, f: F) -> u64 {
+        self.mem.fetch_update(offset, 8, f)
+    }
+
+    fn fence(&mut self) {
+        atomic::fence(Ordering::SeqCst);
+    }
+}
+
+/// Harts sharing a memory, each run on its own host thread
+pub struct ThreadedSystem {
+    harts: Vec<Processor<ThreadedMemory>>,
+    smp: Smp,
+    quantum: u64,
+}
+
+impl ThreadedSystem {
+    /// All harts start at the reset vector, as after a reset
+    pub fn new(mem: &BlockMemory, harts: usize, clint: Option<u64>, quantum: u64) -> Self {
+        let mem = Arc::new(AtomicMemory::from(mem));
+        let smp = Smp::new(harts);
+        let harts = (0..harts)
+            .map(|hart| {
+                let mut p = Processor::new(ThreadedMemory {
+                    mem: mem.clone(),
+                    smp: smp.clone(),
+                    clint,
+                    reservation: None,
+                });
+                p.join_smp(hart as u64, smp.clone());
+                p
+            })
+            .collect();
+        ThreadedSystem {
+            harts,
+            smp,
+            quantum: quantum.max(1),
+        }
+    }
+
+    /// Boot a kernel in S-mode on hart 0 with the built-in SBI. The
+    /// other harts wait to be started through HSM.
+    pub fn boot_supervisor(&mut self, entry: u64, dtb: u64) {
+        boot_supervisor(&mut self.harts, entry, dtb);
+    }
+
+    pub fn harts_mut(&mut self) -> &mut [Processor<ThreadedMemory>] {
+        &mut self.harts
+    }
+
+    /// Run every hart on its own thread until a reset is requested or
+    /// all harts have stopped
+    pub fn run(self, trigger: &Arc<RwLock<bool>>) -> Option<ResetType> {
+        let ThreadedSystem {
+            harts,
+            smp,
+            quantum,
+        } = self;
+        let done = Arc::new(AtomicBool::new(false));
+
+        let threads: Vec<_> = harts
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut hart)| {
+                let smp = smp.clone();
+                let done = done.clone();
+                let trigger = trigger.clone();
+                thread::Builder::new()
+                    .name(format!("hart{}", i))
+                    .spawn(move || {
+                        run_hart(&mut hart, i, &smp, &done, quantum, &trigger);
+                        hart.reset_type()
+                    })
+                    .expect("spawn hart")
+            })
+            .collect();
+
+        let mut reset_type = None;
+        for t in threads {
+            reset_type = reset_type.or(t.join().expect("hart thread"));
+        }
+        reset_type
+    }
+}
+
+fn run_hart(
+    hart: &mut Processor<ThreadedMemory>,
+    i: usize,
+    smp: &Smp,
+    done: &AtomicBool,
+    quantum: u64,
+    trigger: &RwLock<bool>,
+) {
+    let matchers = &mut build_matchers();
+
+    while !done.load(Ordering::Acquire) {
+        deliver(hart, &mut smp.lock(), i);
+
+        if hart.is_stopped() {
+            if hart.reset_type().is_some() || smp.lock().all_stopped() {
+                break;
+            }
+            thread::sleep(IDLE_POLL);
+            continue;
+        }
+
+        if hart.is_waiting() {
+            let next = {
+                let smp = smp.lock();
+                hart.sync_clock(smp.time);
+                // only skip ahead when no hart has work
+                if smp.all_waiting() {
+                    Some(smp.next_timer())
+                } else {
+                    None
+                }
+            };
+            match next {
+                Some(next) => hart.idle_until(next),
+                None => thread::sleep(IDLE_POLL),
+            }
+        } else {
+            hart.handle_interrupt();
+            hart.run(matchers, quantum);
+        }
+
+        {
+            let mut smp = smp.lock();
+            smp.time = smp.time.max(hart.time());
+        }
+
+        hart.trigger = *trigger.read().expect("read lock");
+    }
+
+    // a reset stops the whole machine
+    if hart.reset_type().is_some() {
+        done.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reservations() {
+        let mut mem = BlockMemory::new(0);
+        mem.add_block(0x8000_0000, 0x1000);
+        let mut sys = ThreadedSystem::new(&mem, 2, None, 10);
+        let harts = sys.harts_mut();
+
+        harts[0].mmu_mut().load_reserved_w(0x8000_0010).expect("lr");
+        harts[1].mmu_mut().write_w(0x8000_0010, 1).expect("write");
+        assert_eq!(
+            harts[0].mmu_mut().store_conditional_w(0x8000_0010, 2),
+            Ok(false)
+        );
+
+        harts[0].mmu_mut().load_reserved_d(0x8000_0010).expect("lr");
+        assert_eq!(
+            harts[0].mmu_mut().store_conditional_d(0x8000_0010, 3),
+            Ok(true)
+        );
+        assert_eq!(harts[1].mmu_mut().read_d(0x8000_0010), Ok(3));
+
+        assert_eq!(
+            harts[1].mmu_mut().fetch_update_w(0x8000_0010, |v| v + 4),
+            Ok(3)
+        );
+        assert_eq!(harts[0].mmu_mut().read_w(0x8000_0010), Ok(7));
+    }
+}
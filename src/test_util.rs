@@ -0,0 +1,25 @@
+use crate::memory::{BlockMemory, Memory};
+use crate::Processor;
+
+/// Adds 5 down to 1 into t0, then spins
+pub(crate) const PROGRAM: &[u32] = &[
+    0x0010_0293, // li   t0, 1
+    0x0050_0313, // li   t1, 5
+    0x0062_82b3, // add  t0, t0, t1
+    0xfff3_0313, // addi t1, t1, -1
+    0xfe03_1ce3, // bnez t1, -8
+    0x0000_006f, // j    .
+];
+
+/// A hart about to run `program` from 0x8000_0000, with two pages of
+/// RAM there
+pub(crate) fn machine(program: &[u32]) -> Processor<BlockMemory> {
+    let mut mem = BlockMemory::new(0);
+    mem.add_block(0x8000_0000, 0x2000);
+    for (i, insn) in program.iter().enumerate() {
+        mem.write_w(0x8000_0000 + 4 * i as u64, *insn);
+    }
+    let mut p = Processor::new(mem);
+    p.set_pc(0x8000_0000);
+    p
+}